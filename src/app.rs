@@ -1,16 +1,21 @@
 use async_trait::async_trait;
+use axum::{Extension, Router as AxumRouter};
 use loco_rs::{
-    Result,
+    Error, Result,
     app::{AppContext, Hooks},
-    bgworker::Queue,
+    bgworker::{BackgroundWorker, Queue},
     boot::{BootResult, StartMode, create_app},
     controller::AppRoutes,
     environment::Environment,
     task::Tasks,
 };
 
-use crate::controllers;
-#[allow(unused_imports)] use crate::tasks;
+use crate::{
+    controllers,
+    core::{kubecontroller::State, webhook},
+    tasks,
+    workers::replication::ReplicationWorker,
+};
 
 pub struct App;
 #[async_trait]
@@ -38,14 +43,72 @@ impl Hooks for App {
             .add_route(controllers::metrics::routes())
             .add_route(controllers::health::routes())
             .add_route(controllers::home::routes())
+            .add_route(controllers::conversion::routes())
+            .add_route(controllers::ready::routes())
+            .add_route(controllers::replications::routes())
+            .add_route(controllers::notifications::routes())
     }
 
-    async fn connect_workers(_ctx: &AppContext, _queue: &Queue) -> Result<()> {
+    /// Queue workers loco builds have no controller context, so their jobs fail with an error
+    /// saying so instead of replicating with settings nobody configured
+    async fn connect_workers(ctx: &AppContext, queue: &Queue) -> Result<()> {
+        queue.register(ReplicationWorker::build(ctx)).await?;
         Ok(())
     }
 
-    #[allow(unused_variables)]
     fn register_tasks(tasks: &mut Tasks) {
-        // tasks.register(TASK);
+        tasks.register(tasks::replicate::Replicate);
+        tasks.register(tasks::verify::Verify);
+        tasks.register(tasks::export_ledger::ExportLedger);
     }
 }
+
+/// The app's routes with the controller state their handlers read
+pub fn with_state(router: AxumRouter, state: &State) -> AxumRouter {
+    router.layer(Extension(state.clone()))
+}
+
+/// Like loco's own `serve`, but with the controller state, and staying up until in-flight copies
+/// drained so the readiness probe keeps answering during shutdown
+///
+/// The conversion webhook is served over TLS next to it when `settings.yair.webhook` has a
+/// certificate.
+///
+/// # Errors
+///
+/// When the app was booted without routes, or a server can not bind or fails
+pub async fn serve(boot: BootResult, state: State) -> Result<()> {
+    let ctx = boot.app_context;
+    let router = boot
+        .router
+        .ok_or_else(|| Error::Message("the app was booted without routes".to_string()))?;
+    let binding = &ctx.config.server.binding;
+    let listener = tokio::net::TcpListener::bind(&format!("{binding}:{}", ctx.config.server.port)).await?;
+    let app = async {
+        axum::serve(
+            listener,
+            with_state(router, &state).into_make_service_with_connect_info::<std::net::SocketAddr>(),
+        )
+        .with_graceful_shutdown(state.shutdown().finished())
+        .await
+        .map_err(Error::from)
+    };
+    let settings = &state.settings().webhook;
+    let conversion = async {
+        let Some((cert, key)) = settings.tls() else {
+            return Ok(());
+        };
+        let listener = tokio::net::TcpListener::bind(&format!("{binding}:{}", settings.port)).await?;
+        webhook::serve(
+            listener,
+            cert,
+            key,
+            controllers::conversion::webhook(),
+            state.shutdown().finished(),
+        )
+        .await
+    };
+    tokio::try_join!(app, conversion)?;
+    App::on_shutdown(&ctx).await;
+    Ok(())
+}
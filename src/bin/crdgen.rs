@@ -1,5 +1,5 @@
 use kube::CustomResourceExt;
-use yair::controllers::kubecontroller;
+use yair::{controllers::kubecontroller, core::crd};
 
 #[allow(dead_code)]
 fn main() {
@@ -7,4 +7,7 @@ fn main() {
         "{}",
         serde_yaml::to_string(&kubecontroller::Document::crd()).unwrap()
     );
+    for crd in crd::crds() {
+        print!("---\n{}", serde_yaml::to_string(&crd).unwrap());
+    }
 }
@@ -1,48 +1,87 @@
-#[allow(unused_imports)]
 use yair::{
-    app::App,
+    app::{self, App},
     controllers::{kubecontroller::run, telemetry},
-    core::kubecontroller::State,
+    core::{
+        kubecontroller::State,
+        mirrorcontroller, replicatorcontroller,
+        settings::{self, Settings},
+    },
 };
 
 #[tokio::main]
 async fn main() -> loco_rs::Result<()> {
-    let loco_rs_handle = tokio::spawn(async {
-        if let Err(e) = run_loco_rs().await {
+    let environment = settings::environment();
+    if std::env::args().nth(1).as_deref() == Some("task") {
+        return run_task(environment).await;
+    }
+    let config = environment.load()?;
+    let state = State::new(Settings::from_config(&config)?);
+    let shutdown = state.shutdown().clone();
+    tokio::spawn(
+        shutdown
+            .clone()
+            .on_signal(state.settings().shutdown.grace_period()),
+    );
+
+    telemetry::init().await;
+    println!("Starting loco_rs in {environment}...");
+    let start_mode = loco_rs::boot::StartMode::ServerOnly;
+    let boot_result = loco_rs::boot::create_app::<App>(start_mode, &environment).await?;
+    // before the controllers start, so every replication they hand off is queued
+    state.connect_workers(&boot_result.app_context);
+
+    let server_state = state.clone();
+    let loco_rs_handle = tokio::spawn(async move {
+        if let Err(e) = app::serve(boot_result, server_state).await {
             eprintln!("Error in loco_rs: {e:?}");
         }
     });
 
-    telemetry::init().await;
-    let kubecontroller_handle = tokio::spawn(async {
-        if let Err(e) = run_kubecontroller().await {
-            eprintln!("Error in loco_rs: {e:?}");
+    let kubecontroller_handle = tokio::spawn(async move {
+        if let Err(e) = run_kubecontroller(state).await {
+            eprintln!("Error in kubecontroller: {e:?}");
         }
     });
 
-    // Await all tasks to complete
-    let _ = tokio::try_join!(kubecontroller_handle, loco_rs_handle)?;
+    // Exit once both stopped on their own, or once shutdown drained what it could; copies still
+    // running after the grace period are abandoned with the runtime
+    tokio::select! {
+        res = async { tokio::try_join!(kubecontroller_handle, loco_rs_handle) } => {
+            res?;
+        }
+        () = shutdown.finished() => {}
+    }
 
     Ok(())
 }
 
-async fn run_kubecontroller() -> Result<(), Box<dyn std::error::Error>> {
-    let state = State::default();
-    run(state.clone()).await;
+async fn run_kubecontroller(state: State) -> Result<(), Box<dyn std::error::Error>> {
+    tokio::join!(
+        run(state.clone()),
+        replicatorcontroller::run(state.clone()),
+        mirrorcontroller::run(state)
+    );
     Ok(())
 }
 
-async fn run_loco_rs() -> loco_rs::Result<()> {
-    println!("Starting loco_rs...");
-
-    let start_mode = loco_rs::boot::StartMode::ServerOnly;
-    let environment = loco_rs::environment::Environment::Development;
-    let boot_result = loco_rs::boot::create_app::<App>(start_mode, &environment).await?;
-
-    let server_params = loco_rs::boot::ServeParams {
-        port: 8080,
-        binding: "localhost".to_string(),
-    };
-    loco_rs::boot::start::<App>(boot_result, server_params, true).await?;
-    Ok(())
+/// `task [NAME] [KEY:VALUE...]`, like `cargo loco task`: run an operational task instead of the
+/// controller, or list the tasks without a name
+async fn run_task(environment: loco_rs::environment::Environment) -> loco_rs::Result<()> {
+    let mut args = std::env::args().skip(2).collect::<Vec<_>>().into_iter();
+    let name = args.next();
+    let params = args
+        .map(|arg| match arg.split_once(':') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => Err(loco_rs::Error::Message(format!(
+                "invalid task argument `{arg}`, expected KEY:VALUE"
+            ))),
+        })
+        .collect::<loco_rs::Result<Vec<_>>>()?;
+    let app_context = loco_rs::boot::create_context::<App>(&environment).await?;
+    loco_rs::boot::run_task::<App>(
+        &app_context,
+        name.as_ref(),
+        &loco_rs::task::Vars::from_cli_args(params),
+    )
+    .await
 }
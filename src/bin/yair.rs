@@ -0,0 +1,43 @@
+//! `yair plan [FILE...]`: print the images the `ContainerReplicator` objects in the given manifests
+//! would promote, and where to, without a cluster. Reads stdin without files or for `-`.
+use std::{io::Read, process::ExitCode};
+use yair::core::offline::Manifests;
+
+static USAGE: &str = "usage: yair plan [FILE...]";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some("plan") {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    }
+    let mut files: Vec<String> = args.collect();
+    if files.is_empty() {
+        files.push("-".into());
+    }
+    let mut manifests = Manifests::default();
+    for file in &files {
+        let read = if file == "-" {
+            let mut yaml = String::new();
+            std::io::stdin().read_to_string(&mut yaml).map(|_| yaml)
+        } else {
+            std::fs::read_to_string(file)
+        };
+        let added = read
+            .map_err(|e| e.to_string())
+            .and_then(|yaml| manifests.add(&yaml).map_err(|e| e.to_string()));
+        if let Err(e) = added {
+            eprintln!("{file}: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    for plan in manifests.plan() {
+        for warning in &plan.warnings {
+            eprintln!("{}: warning: {warning}", plan.replicator);
+        }
+        for job in &plan.jobs {
+            println!("{}: {job}", plan.replicator);
+        }
+    }
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,22 @@
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::unused_async)]
+use crate::core::crd::{WEBHOOK_PATH, conversion};
+use axum::{Router as AxumRouter, debug_handler};
+use kube::core::conversion::ConversionReview;
+use loco_rs::prelude::*;
+
+/// CRD conversion webhook, which `spec.conversion` of the generated CRDs points the apiserver at
+/// on the TLS listener of [`webhook`]
+#[debug_handler]
+pub async fn convert(Json(review): Json<ConversionReview>) -> Result<Response> {
+    format::json(conversion::review(review))
+}
+
+pub fn routes() -> Routes {
+    Routes::new().prefix("api/conversion").add("/", post(convert))
+}
+
+/// The webhook alone, for the TLS listener on `settings.yair.webhook.port`
+pub fn webhook() -> AxumRouter {
+    AxumRouter::new().route(WEBHOOK_PATH, post(convert))
+}
@@ -1,3 +1,7 @@
+pub mod conversion;
 pub mod health;
 pub mod home;
+pub mod notifications;
+pub mod ready;
+pub mod replications;
 pub use crate::core::*;
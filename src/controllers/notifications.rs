@@ -0,0 +1,43 @@
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::unused_async)]
+use crate::core::{kubecontroller::State as ControllerState, notifications, registry::Reference};
+use axum::{Extension, body::Bytes, debug_handler};
+use loco_rs::prelude::*;
+use serde_json::json;
+
+/// Hand the pushed images to the controller, answering with how many there were
+fn accept(state: &ControllerState, pushed: crate::core::Result<Vec<Reference>>) -> Result<Response> {
+    let images = pushed.map_err(|e| Error::BadRequest(format!("invalid notification: {e}")))?;
+    let count = images.len();
+    state.notify(images);
+    format::json(json!({ "images": count }))
+}
+
+/// Docker Distribution notification endpoint, see `notifications.endpoints` of the registry config
+#[debug_handler]
+pub async fn distribution(Extension(state): Extension<ControllerState>, body: Bytes) -> Result<Response> {
+    accept(&state, notifications::distribution(&body))
+}
+
+/// Harbor webhook, of a project webhook policy with the `http` notify type
+#[debug_handler]
+pub async fn harbor(Extension(state): Extension<ControllerState>, body: Bytes) -> Result<Response> {
+    accept(&state, notifications::harbor(&body))
+}
+
+/// Push endpoint of a Pub/Sub subscription to the `gcr` topic of Artifact Registry
+#[debug_handler]
+pub async fn artifact_registry(
+    Extension(state): Extension<ControllerState>,
+    body: Bytes,
+) -> Result<Response> {
+    accept(&state, notifications::artifact_registry(&body))
+}
+
+pub fn routes() -> Routes {
+    Routes::new()
+        .prefix("api/notifications")
+        .add("/distribution", post(distribution))
+        .add("/harbor", post(harbor))
+        .add("/artifact-registry", post(artifact_registry))
+}
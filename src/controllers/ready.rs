@@ -0,0 +1,24 @@
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::unused_async)]
+use crate::core::kubecontroller::State as ControllerState;
+use axum::{Extension, debug_handler, http::StatusCode};
+use loco_rs::prelude::*;
+use serde_json::json;
+
+/// Readiness probe, failing once shutdown started so no new work is routed to a draining pod
+#[debug_handler]
+pub async fn index(Extension(state): Extension<ControllerState>) -> Result<Response> {
+    let shutdown = state.shutdown();
+    let status = if shutdown.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    format::render()
+        .status(status)
+        .json(json!({ "ready": shutdown.is_ready(), "inFlight": shutdown.in_flight() }))
+}
+
+pub fn routes() -> Routes {
+    Routes::new().prefix("api/ready/").add("/", get(index))
+}
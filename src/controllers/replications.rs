@@ -0,0 +1,15 @@
+#![allow(clippy::missing_errors_doc)]
+#![allow(clippy::unused_async)]
+use crate::core::kubecontroller::State as ControllerState;
+use axum::{Extension, debug_handler};
+use loco_rs::prelude::*;
+
+/// Dry-run plans of the replicators in dry-run mode, by `namespace/name`
+#[debug_handler]
+pub async fn plan(Extension(state): Extension<ControllerState>) -> Result<Response> {
+    format::json(state.plans().await)
+}
+
+pub fn routes() -> Routes {
+    Routes::new().prefix("api/replications").add("/plan", get(plan))
+}
@@ -0,0 +1,416 @@
+//! Blobs kept on local disk between jobs, so an image fanned out to several destinations is pulled
+//! from its source once.
+//!
+//! The cache lives in `replication.blob_cache_dir`, one file per digest under `<algorithm>/<hex>`,
+//! and is bounded by `replication.blob_cache_size_bytes`: the least recently used blobs are evicted
+//! when a new one pushes it over. Blobs larger than the whole cache bypass it. A blob is only cached
+//! once all of it arrived and hashed to its digest, and it is hashed again on every read; one that
+//! no longer matches is evicted and the copy reading it fails like a corrupt download would.
+use crate::core::{
+    ErrorWrapper, Result,
+    registry::{RegistryClient, client::ByteStream, digest},
+};
+use bytes::BytesMut;
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::{debug, warn};
+
+/// Bytes read from a cached blob at a time
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Where a blob was read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lookup {
+    /// Served from the cache, without pulling it from the source
+    Hit,
+    /// Pulled from the source into the cache
+    Miss,
+    /// Streamed straight from the source, as it does not fit the cache
+    Bypass,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Size and last use of every cached blob, by digest
+    blobs: HashMap<String, (u64, u64)>,
+    /// Bytes cached in total
+    size: u64,
+    /// Incremented on every use, orders the blobs from least to most recently used
+    clock: u64,
+}
+
+pub struct BlobCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+    /// Blobs being pulled into the cache, so concurrent jobs wait for one pull instead of each pulling
+    filling: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl BlobCache {
+    /// Open the cache in `dir`, creating the directory if needed; blobs cached by a previous process
+    /// are kept, least recently modified first in line for eviction
+    ///
+    /// # Errors
+    ///
+    /// When the directory can not be created or listed
+    pub fn open(dir: &Path, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|e| io_error(dir, &e))?;
+        let mut found: Vec<(SystemTime, String, u64)> = vec![];
+        for algorithm in std::fs::read_dir(dir).map_err(|e| io_error(dir, &e))? {
+            let algorithm = algorithm.map_err(|e| io_error(dir, &e))?;
+            let path = algorithm.path();
+            if !path.is_dir() {
+                continue;
+            }
+            for blob in std::fs::read_dir(&path).map_err(|e| io_error(&path, &e))? {
+                let blob = blob.map_err(|e| io_error(&path, &e))?;
+                let name = blob.file_name().to_string_lossy().into_owned();
+                // left behind by a pull that did not finish
+                if name.ends_with(".partial") {
+                    let _ = std::fs::remove_file(blob.path());
+                    continue;
+                }
+                let metadata = blob.metadata().map_err(|e| io_error(&blob.path(), &e))?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                let digest = format!("{}:{name}", algorithm.file_name().to_string_lossy());
+                found.push((modified, digest, metadata.len()));
+            }
+        }
+        found.sort();
+        let cache = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            entries: Mutex::default(),
+            filling: Mutex::default(),
+        };
+        for (_, digest, size) in found {
+            cache.insert(&digest, size);
+        }
+        Ok(cache)
+    }
+
+    /// Bytes currently cached
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn size(&self) -> u64 {
+        self.entries.lock().expect("blob cache lock poisoned").size
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn contains(&self, digest: &str) -> bool {
+        self.entries
+            .lock()
+            .expect("blob cache lock poisoned")
+            .blobs
+            .contains_key(digest)
+    }
+
+    fn path(&self, digest: &str) -> Result<PathBuf> {
+        match digest.split_once(':') {
+            Some((algorithm, hex))
+                if ["sha256", "sha512"].contains(&algorithm)
+                    && !hex.is_empty()
+                    && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Ok(self.dir.join(algorithm).join(hex))
+            }
+            _ => Err(ErrorWrapper::from_custom(&format!(
+                "unsupported digest `{digest}`"
+            ))),
+        }
+    }
+
+    /// A blob of `size` bytes from `repository`, read from the cache, pulled into it first, or
+    /// streamed from the source if it does not fit; the stream fails at its end if the content does
+    /// not hash to `digest`
+    ///
+    /// # Errors
+    ///
+    /// When `digest` is not supported, or the blob can be neither read from the cache nor pulled
+    ///
+    /// # Panics
+    ///
+    /// Panics if another job panicked while holding the cache lock.
+    pub async fn blob(
+        self: &Arc<Self>,
+        source: &RegistryClient,
+        repository: &str,
+        digest: &str,
+        size: u64,
+    ) -> Result<(ByteStream, Lookup)> {
+        let what = format!("blob {repository}@{digest}");
+        if size > self.max_bytes {
+            let body = source.blob(repository, digest).await?;
+            return Ok((digest::verified(body, digest, &what)?.boxed(), Lookup::Bypass));
+        }
+        if let Some(body) = self.read(digest, &what).await? {
+            return Ok((body, Lookup::Hit));
+        }
+        let filling = self
+            .filling
+            .lock()
+            .expect("blob cache lock poisoned")
+            .entry(digest.to_string())
+            .or_default()
+            .clone();
+        let cached = {
+            let _filling = filling.lock().await;
+            // another job may have pulled it while this one waited
+            match self.read(digest, &what).await {
+                Ok(None) => self.fill(source, repository, digest, &what).await.map(|()| None),
+                read => read,
+            }
+        };
+        {
+            let mut fills = self.filling.lock().expect("blob cache lock poisoned");
+            // a job sharing this fill may have removed it already, and a later job started its own
+            if fills
+                .get(digest)
+                .is_some_and(|current| Arc::ptr_eq(current, &filling))
+            {
+                fills.remove(digest);
+            }
+        }
+        if let Some(body) = cached? {
+            return Ok((body, Lookup::Hit));
+        }
+        let body = self.read(digest, &what).await?.ok_or_else(|| {
+            ErrorWrapper::from_custom(&format!("{what} was evicted as soon as it was cached"))
+        })?;
+        Ok((body, Lookup::Miss))
+    }
+
+    /// Stream a cached blob, evicting it if its content no longer matches `digest`
+    async fn read(self: &Arc<Self>, digest: &str, what: &str) -> Result<Option<ByteStream>> {
+        if !self.touch(digest) {
+            return Ok(None);
+        }
+        let path = self.path(digest)?;
+        let file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.remove(digest);
+                return Ok(None);
+            }
+            Err(e) => return Err(io_error(&path, &e)),
+        };
+        let chunks = futures::stream::try_unfold((file, path), |(mut file, path)| async move {
+            let mut buffer = BytesMut::with_capacity(READ_CHUNK);
+            let read = file
+                .read_buf(&mut buffer)
+                .await
+                .map_err(|e| io_error(&path, &e))?;
+            Ok((read > 0).then(|| (buffer.freeze(), (file, path))))
+        });
+        let (cache, digest) = (self.clone(), digest.to_string());
+        let body = digest::verified(chunks.boxed(), &digest, what)?.inspect(move |chunk| {
+            if let Err(e) = chunk {
+                warn!(%digest, error = %e, "Evicting unreadable cached blob");
+                cache.evict(&digest);
+            }
+        });
+        Ok(Some(body.boxed()))
+    }
+
+    /// Pull a blob into the cache, making room for it
+    async fn fill(&self, source: &RegistryClient, repository: &str, digest: &str, what: &str) -> Result<()> {
+        let path = self.path(digest)?;
+        let staged = path.with_extension("partial");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error(parent, &e))?;
+        }
+        let body = digest::verified(source.blob(repository, digest).await?, digest, what)?;
+        let mut body = std::pin::pin!(body);
+        let mut file = fs::File::create(&staged)
+            .await
+            .map_err(|e| io_error(&staged, &e))?;
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let written = match chunk {
+                Ok(chunk) => {
+                    size += chunk.len() as u64;
+                    file.write_all(&chunk).await.map_err(|e| io_error(&staged, &e))
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                let _ = fs::remove_file(&staged).await;
+                return Err(e);
+            }
+        }
+        file.flush().await.map_err(|e| io_error(&staged, &e))?;
+        fs::rename(&staged, &path)
+            .await
+            .map_err(|e| io_error(&path, &e))?;
+        debug!(%digest, size, "Cached blob");
+        self.insert(digest, size);
+        Ok(())
+    }
+
+    /// Mark a blob as just used, if it is cached
+    fn touch(&self, digest: &str) -> bool {
+        let mut entries = self.entries.lock().expect("blob cache lock poisoned");
+        entries.clock += 1;
+        let clock = entries.clock;
+        match entries.blobs.get_mut(digest) {
+            Some((_, used)) => {
+                *used = clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Account for a newly cached blob, then evict least recently used ones until the cache fits
+    fn insert(&self, digest: &str, size: u64) {
+        let evicted = {
+            let mut entries = self.entries.lock().expect("blob cache lock poisoned");
+            entries.clock += 1;
+            let clock = entries.clock;
+            if let Some((previous, _)) = entries.blobs.insert(digest.to_string(), (size, clock)) {
+                entries.size -= previous;
+            }
+            entries.size += size;
+            let mut evicted = vec![];
+            while entries.size > self.max_bytes {
+                let oldest = entries
+                    .blobs
+                    .iter()
+                    .filter(|(cached, _)| cached.as_str() != digest)
+                    .min_by_key(|(_, (_, used))| *used)
+                    .map(|(cached, _)| cached.clone());
+                let Some(oldest) = oldest else {
+                    break;
+                };
+                if let Some((size, _)) = entries.blobs.remove(&oldest) {
+                    entries.size -= size;
+                }
+                evicted.push(oldest);
+            }
+            drop(entries);
+            evicted
+        };
+        for digest in evicted {
+            debug!(%digest, "Evicting least recently used blob");
+            self.delete(&digest);
+        }
+    }
+
+    /// Drop a blob from the cache
+    fn evict(&self, digest: &str) {
+        self.remove(digest);
+        self.delete(digest);
+    }
+
+    fn remove(&self, digest: &str) {
+        let mut entries = self.entries.lock().expect("blob cache lock poisoned");
+        if let Some((size, _)) = entries.blobs.remove(digest) {
+            entries.size -= size;
+        }
+    }
+
+    fn delete(&self, digest: &str) {
+        // readers that still have the file open keep reading it
+        if let Ok(path) = self.path(digest)
+            && let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(%digest, error = %e, "Can not delete cached blob");
+        }
+    }
+}
+
+fn io_error(path: &Path, e: &std::io::Error) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("blob cache {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BlobCache, Lookup};
+    use crate::core::{
+        registry::{
+            digest::{DigestMismatch, sha256},
+            fake::FakeRegistry,
+        },
+        replication::Replicator,
+    };
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    async fn read(
+        cache: &Arc<BlobCache>,
+        registry: &FakeRegistry,
+        digest: &str,
+        size: u64,
+    ) -> (Vec<u8>, Lookup) {
+        let client = Replicator::default().client(&registry.endpoint());
+        let (body, lookup) = cache.blob(&client, "ci/app", digest, size).await.unwrap();
+        let chunks: Vec<Bytes> = body.try_collect().await.unwrap();
+        (chunks.concat(), lookup)
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_blobs_are_evicted() {
+        let registry = FakeRegistry::start().await;
+        let blobs = [&b"aaaa"[..], b"bbbb", b"cccc", b"far too large"];
+        for blob in blobs {
+            registry.push_blob("ci/app", "application/octet-stream", blob);
+        }
+        let digests: Vec<String> = blobs.into_iter().map(sha256).collect();
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlobCache::open(dir.path(), 8).unwrap());
+
+        assert_eq!(
+            read(&cache, &registry, &digests[0], 4).await,
+            (b"aaaa".to_vec(), Lookup::Miss)
+        );
+        assert_eq!(read(&cache, &registry, &digests[1], 4).await.1, Lookup::Miss);
+        assert_eq!(read(&cache, &registry, &digests[0], 4).await.1, Lookup::Hit);
+        assert_eq!(read(&cache, &registry, &digests[2], 4).await.1, Lookup::Miss);
+        assert!(cache.contains(&digests[0]) && cache.contains(&digests[2]));
+        assert!(!cache.contains(&digests[1]), "least recently used");
+        assert_eq!(cache.size(), 8);
+        assert_eq!(read(&cache, &registry, &digests[3], 13).await.1, Lookup::Bypass);
+
+        let reopened = BlobCache::open(dir.path(), 8).unwrap();
+        assert_eq!(reopened.size(), 8);
+        assert!(reopened.contains(&digests[2]));
+    }
+
+    #[tokio::test]
+    async fn corrupted_blobs_fail_and_are_evicted() {
+        let registry = FakeRegistry::start().await;
+        let digest = sha256(b"layer");
+        registry.push_blob("ci/app", "application/octet-stream", b"layer");
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(BlobCache::open(dir.path(), 1024).unwrap());
+        read(&cache, &registry, &digest, 5).await;
+        let hex = digest.trim_start_matches("sha256:");
+        std::fs::write(dir.path().join("sha256").join(hex), b"flipped").unwrap();
+
+        let client = Replicator::default().client(&registry.endpoint());
+        let (body, lookup) = cache.blob(&client, "ci/app", &digest, 5).await.unwrap();
+        assert_eq!(lookup, Lookup::Hit);
+        let err = body.try_collect::<Vec<Bytes>>().await.unwrap_err();
+        assert!(DigestMismatch::find(&err).is_some());
+        assert!(!cache.contains(&digest));
+        assert_eq!(
+            read(&cache, &registry, &digest, 5).await,
+            (b"layer".to_vec(), Lookup::Miss)
+        );
+    }
+}
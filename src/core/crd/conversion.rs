@@ -0,0 +1,487 @@
+//! Conversion between the served versions, used by the CRD conversion webhook.
+//!
+//! Conversions are written as plain `From` impls between the typed structs of each version. Fields
+//! that only exist in `v1beta1` are kept in an annotation while an object is served as `v1alpha1`,
+//! so a read-modify-write through the older version does not silently drop them.
+use super::{GROUP, RepositoryProvider, v1alpha1, v1beta1};
+use crate::core::{ErrorWrapper, Result};
+use kube::{
+    ResourceExt,
+    core::{
+        Status,
+        conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+    },
+};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+
+/// Annotation holding the `v1beta1` spec of an object while it is served as `v1alpha1`
+pub static PRESERVED_SPEC_ANNOTATION: &str = "replicator.yair.example.com/v1beta1-spec";
+
+impl From<v1alpha1::RepositoryProvider> for RepositoryProvider {
+    fn from(p: v1alpha1::RepositoryProvider) -> Self {
+        match p {
+            v1alpha1::RepositoryProvider::Gcp => Self::Gcp,
+            v1alpha1::RepositoryProvider::Generic => Self::Generic,
+        }
+    }
+}
+
+impl From<RepositoryProvider> for v1alpha1::RepositoryProvider {
+    fn from(p: RepositoryProvider) -> Self {
+        match p {
+            RepositoryProvider::Gcp => Self::Gcp,
+            // served as the closest older provider, the preserved spec restores it on the way back
+            RepositoryProvider::Generic | RepositoryProvider::OciLayout => Self::Generic,
+        }
+    }
+}
+
+impl From<v1alpha1::RepositorySpec> for v1beta1::RepositorySpec {
+    fn from(r: v1alpha1::RepositorySpec) -> Self {
+        Self {
+            provider: r.provider.into(),
+            name: r.name,
+            location: r.location,
+            format: r.format,
+            project_id: r.project_id,
+            service_account: r.service_account,
+            registry: None,
+            insecure: false,
+            path: None,
+        }
+    }
+}
+
+impl From<v1beta1::RepositorySpec> for v1alpha1::RepositorySpec {
+    fn from(r: v1beta1::RepositorySpec) -> Self {
+        Self {
+            name: r.name,
+            location: r.location,
+            format: r.format,
+            project_id: r.project_id,
+            provider: r.provider.into(),
+            service_account: r.service_account,
+        }
+    }
+}
+
+impl From<v1alpha1::SourceRepositorySpec> for v1beta1::SourceRepositorySpec {
+    fn from(s: v1alpha1::SourceRepositorySpec) -> Self {
+        Self {
+            repository: s.repository.into(),
+        }
+    }
+}
+
+impl From<v1beta1::SourceRepositorySpec> for v1alpha1::SourceRepositorySpec {
+    fn from(s: v1beta1::SourceRepositorySpec) -> Self {
+        Self {
+            repository: s.repository.into(),
+        }
+    }
+}
+
+impl From<v1alpha1::DestinationRepositorySpec> for v1beta1::DestinationRepositorySpec {
+    fn from(s: v1alpha1::DestinationRepositorySpec) -> Self {
+        Self {
+            repository: s.repository.into(),
+            tag_mutability: v1beta1::TagMutability::default(),
+            layer_compression: None,
+            manifest_format: None,
+        }
+    }
+}
+
+impl From<v1beta1::DestinationRepositorySpec> for v1alpha1::DestinationRepositorySpec {
+    fn from(s: v1beta1::DestinationRepositorySpec) -> Self {
+        Self {
+            repository: s.repository.into(),
+        }
+    }
+}
+
+impl From<v1alpha1::RepositoryRef> for v1beta1::RepositoryRef {
+    fn from(r: v1alpha1::RepositoryRef) -> Self {
+        Self {
+            name: r.name,
+            namespace: r.namespace,
+        }
+    }
+}
+
+impl From<v1beta1::RepositoryRef> for v1alpha1::RepositoryRef {
+    fn from(r: v1beta1::RepositoryRef) -> Self {
+        Self {
+            name: r.name,
+            namespace: r.namespace,
+        }
+    }
+}
+
+impl From<v1alpha1::WorkloadSelector> for v1beta1::WorkloadSelector {
+    fn from(w: v1alpha1::WorkloadSelector) -> Self {
+        Self {
+            name: w.name,
+            images: w.images,
+            auto_detect_images: w.auto_detect_images,
+        }
+    }
+}
+
+impl From<v1beta1::WorkloadSelector> for v1alpha1::WorkloadSelector {
+    fn from(w: v1beta1::WorkloadSelector) -> Self {
+        Self {
+            name: w.name,
+            images: w.images,
+            auto_detect_images: w.auto_detect_images,
+        }
+    }
+}
+
+impl From<v1alpha1::PromotionSelectors> for v1beta1::PromotionSelectors {
+    fn from(p: v1alpha1::PromotionSelectors) -> Self {
+        Self {
+            deployments: p.deployments.into_iter().map(Into::into).collect(),
+            jobs: p.jobs.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<v1beta1::PromotionSelectors> for v1alpha1::PromotionSelectors {
+    fn from(p: v1beta1::PromotionSelectors) -> Self {
+        Self {
+            deployments: p.deployments.into_iter().map(Into::into).collect(),
+            jobs: p.jobs.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<v1alpha1::ContainerReplicatorSpec> for v1beta1::ContainerReplicatorSpec {
+    fn from(s: v1alpha1::ContainerReplicatorSpec) -> Self {
+        Self {
+            source_ref: s.repository_selector.repository_ref.into(),
+            destination_refs: s
+                .destination_repositories_selector
+                .repository_ref
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            promotion_selectors: s.promotion_selectors.into(),
+            tag_selectors: vec![],
+            rewrite: None,
+            verification: None,
+            vulnerability_gate: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl From<v1beta1::ContainerReplicatorSpec> for v1alpha1::ContainerReplicatorSpec {
+    fn from(s: v1beta1::ContainerReplicatorSpec) -> Self {
+        Self {
+            repository_selector: v1alpha1::RepositorySelector {
+                repository_ref: s.source_ref.into(),
+            },
+            destination_repositories_selector: v1alpha1::DestinationRepositoriesSelector {
+                repository_ref: s.destination_refs.into_iter().map(Into::into).collect(),
+            },
+            promotion_selectors: s.promotion_selectors.into(),
+        }
+    }
+}
+
+/// Implements `From` in both directions for the root objects of a kind, given the spec conversions
+macro_rules! convert_root {
+    ($kind:ident $(, $status:ident)?) => {
+        impl From<v1alpha1::$kind> for v1beta1::$kind {
+            fn from(o: v1alpha1::$kind) -> Self {
+                Self {
+                    metadata: o.metadata,
+                    spec: o.spec.into(),
+                    $($status: o.$status,)?
+                }
+            }
+        }
+
+        impl From<v1beta1::$kind> for v1alpha1::$kind {
+            fn from(o: v1beta1::$kind) -> Self {
+                Self {
+                    metadata: o.metadata,
+                    spec: o.spec.into(),
+                    $($status: o.$status,)?
+                }
+            }
+        }
+    };
+}
+
+convert_root!(SourceRepository);
+convert_root!(DestinationRepository);
+convert_root!(ContainerReplicator, status);
+
+/// Convert a single object of any kind in the group to `desired_api_version`
+///
+/// # Errors
+///
+/// When the object is of an unknown kind or version, or does not match its schema
+pub fn convert_object(object: Value, desired_api_version: &str) -> Result<Value> {
+    let api_version = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+    let kind = object
+        .get("kind")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let up = format!("{GROUP}/v1beta1");
+    let down = format!("{GROUP}/v1alpha1");
+    match (kind.as_str(), desired_api_version) {
+        ("SourceRepository", v) if v == up => {
+            upgrade::<v1alpha1::SourceRepository, v1beta1::SourceRepository>(object)
+        }
+        ("SourceRepository", v) if v == down => {
+            downgrade::<v1beta1::SourceRepository, v1alpha1::SourceRepository>(object)
+        }
+        ("DestinationRepository", v) if v == up => {
+            upgrade::<v1alpha1::DestinationRepository, v1beta1::DestinationRepository>(object)
+        }
+        ("DestinationRepository", v) if v == down => {
+            downgrade::<v1beta1::DestinationRepository, v1alpha1::DestinationRepository>(object)
+        }
+        ("ContainerReplicator", v) if v == up => {
+            upgrade::<v1alpha1::ContainerReplicator, v1beta1::ContainerReplicator>(object)
+        }
+        ("ContainerReplicator", v) if v == down => {
+            downgrade::<v1beta1::ContainerReplicator, v1alpha1::ContainerReplicator>(object)
+        }
+        _ => Err(ErrorWrapper::from_custom(&format!(
+            "cannot convert {kind} from {api_version} to {desired_api_version}"
+        ))),
+    }
+}
+
+/// `v1alpha1` -> `v1beta1`, restoring any fields preserved by a previous downgrade
+fn upgrade<Old, New>(object: Value) -> Result<Value>
+where
+    Old: DeserializeOwned + Into<New>,
+    New: Serialize + DeserializeOwned + ResourceExt + Into<Old>,
+{
+    let old: Old = serde_json::from_value(object).map_err(ErrorWrapper::from_serde)?;
+    let mut new: New = old.into();
+    let preserved = new.annotations_mut().remove(PRESERVED_SPEC_ANNOTATION);
+    let mut value = serde_json::to_value(new).map_err(ErrorWrapper::from_serde)?;
+    if let Some(preserved) = preserved {
+        let preserved: Value = serde_json::from_str(&preserved).map_err(ErrorWrapper::from_serde)?;
+        // the preserved spec as it reads after a trip through v1alpha1, with the fields v1alpha1
+        // lacks at their defaults; only the fields the v1alpha1 object changed differ from it
+        let mut unchanged = value.clone();
+        unchanged["spec"] = preserved.clone();
+        let unchanged: New = serde_json::from_value(unchanged).map_err(ErrorWrapper::from_serde)?;
+        let unchanged: New = Into::<Old>::into(unchanged).into();
+        let unchanged = serde_json::to_value(unchanged).map_err(ErrorWrapper::from_serde)?;
+        value["spec"] = merge(preserved, &unchanged["spec"], value["spec"].take());
+    }
+    Ok(value)
+}
+
+/// `v1beta1` -> `v1alpha1`, keeping the full spec in an annotation
+fn downgrade<New, Old>(object: Value) -> Result<Value>
+where
+    New: DeserializeOwned + Into<Old>,
+    Old: Serialize + ResourceExt,
+{
+    let spec = object.get("spec").cloned().unwrap_or_default();
+    let new: New = serde_json::from_value(object).map_err(ErrorWrapper::from_serde)?;
+    let mut old: Old = new.into();
+    old.annotations_mut()
+        .insert(PRESERVED_SPEC_ANNOTATION.into(), spec.to_string());
+    serde_json::to_value(old).map_err(ErrorWrapper::from_serde)
+}
+
+/// Recursively take the fields of `converted` that differ from `unchanged`, and the `preserved`
+/// ones otherwise, keeping keys only present in `preserved`
+fn merge(preserved: Value, unchanged: &Value, converted: Value) -> Value {
+    match (preserved, converted) {
+        (Value::Object(mut preserved), Value::Object(converted)) => {
+            for (key, value) in converted {
+                let unchanged = unchanged.get(&key).unwrap_or(&Value::Null);
+                let merged = match preserved.remove(&key) {
+                    Some(kept) => merge(kept, unchanged, value),
+                    None => value,
+                };
+                preserved.insert(key, merged);
+            }
+            Value::Object(preserved)
+        }
+        (preserved, converted) if converted == *unchanged => preserved,
+        (_, converted) => converted,
+    }
+}
+
+/// Answer a `ConversionReview` sent by the apiserver
+#[must_use]
+pub fn review(review: ConversionReview) -> ConversionReview {
+    let request = match ConversionRequest::from_review(review) {
+        Ok(request) => request,
+        Err(e) => {
+            return ConversionResponse::invalid(Status::failure(&e.to_string(), "InvalidRequest"))
+                .into_review();
+        }
+    };
+    let desired = request.desired_api_version.clone();
+    let converted = request
+        .objects
+        .iter()
+        .cloned()
+        .map(|o| convert_object(o, &desired))
+        .collect::<Result<Vec<_>>>();
+    let response = ConversionResponse::for_request(request);
+    match converted {
+        Ok(objects) => response.success(objects),
+        Err(e) => response.failure(Status::failure(&e.to_string(), "ConversionFailed")),
+    }
+    .into_review()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PRESERVED_SPEC_ANNOTATION, convert_object};
+    use crate::core::crd::{WEBHOOK_PATH, crds, v1alpha1, v1beta1};
+    use serde_json::{Value, json};
+
+    fn alpha_replicator() -> Value {
+        json!({
+            "apiVersion": "replicator.yair.example.com/v1alpha1",
+            "kind": "ContainerReplicator",
+            "metadata": { "name": "my-app-promotion", "namespace": "my-team" },
+            "spec": {
+                "repositorySelector": { "repositoryRef": { "name": "ci", "namespace": "my-team" } },
+                "destinationRepositoriesSelector": { "repositoryRef": [{ "name": "prod-eu" }, { "name": "prod-us" }] },
+                "promotionSelectors": {
+                    "deployments": [{ "name": "my-app", "images": ["my-app"] }],
+                    "jobs": [{ "name": "some-job", "autoDetectImages": true }]
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn replicator_upgrades_to_beta() {
+        let value = convert_object(alpha_replicator(), "replicator.yair.example.com/v1beta1").unwrap();
+        let beta: v1beta1::ContainerReplicator = serde_json::from_value(value).unwrap();
+        assert_eq!(beta.spec.source_ref.name, "ci");
+        assert_eq!(beta.spec.destination_refs.len(), 2);
+        assert_eq!(beta.spec.destination_refs[1].name, "prod-us");
+        assert!(beta.spec.promotion_selectors.jobs[0].auto_detect_images);
+    }
+
+    #[test]
+    fn repository_provider_casing_is_normalised() {
+        let alpha = json!({
+            "apiVersion": "replicator.yair.example.com/v1alpha1",
+            "kind": "SourceRepository",
+            "metadata": { "name": "ci" },
+            "spec": { "repository": {
+                "name": "myair-docker-registry",
+                "location": "europe-west1",
+                "format": "Docker",
+                "projectID": "example-app-ci-a1",
+                "Provider": "GCP",
+                "serviceAccount": "ci-sa"
+            }}
+        });
+        let beta = convert_object(alpha, "replicator.yair.example.com/v1beta1").unwrap();
+        assert_eq!(beta["spec"]["repository"]["provider"], "GCP");
+        assert_eq!(beta["spec"]["repository"]["projectId"], "example-app-ci-a1");
+        assert!(beta["spec"]["repository"].get("Provider").is_none());
+    }
+
+    #[test]
+    fn round_trip_through_alpha_keeps_beta_only_fields() {
+        let beta = convert_object(alpha_replicator(), "replicator.yair.example.com/v1beta1").unwrap();
+        let mut beta_with_extra = beta.clone();
+        beta_with_extra["spec"]["futureField"] = json!("kept");
+        beta_with_extra["spec"]["dryRun"] = json!(true);
+
+        let alpha = convert_object(beta_with_extra, "replicator.yair.example.com/v1alpha1").unwrap();
+        let typed: v1alpha1::ContainerReplicator = serde_json::from_value(alpha.clone()).unwrap();
+        assert!(
+            typed
+                .metadata
+                .annotations
+                .unwrap()
+                .contains_key(PRESERVED_SPEC_ANNOTATION)
+        );
+
+        let back = convert_object(alpha, "replicator.yair.example.com/v1beta1").unwrap();
+        assert_eq!(back["spec"]["futureField"], "kept");
+        assert_eq!(back["spec"]["dryRun"], true);
+        assert_eq!(back["spec"]["sourceRef"], beta["spec"]["sourceRef"]);
+        assert!(
+            back["metadata"]["annotations"]
+                .get(PRESERVED_SPEC_ANNOTATION)
+                .is_none()
+        );
+
+        let destination = json!({
+            "apiVersion": "replicator.yair.example.com/v1beta1",
+            "kind": "DestinationRepository",
+            "metadata": { "name": "prod" },
+            "spec": {
+                "repository": {
+                    "provider": "Generic", "name": "prod", "location": "", "format": "Docker",
+                    "registry": "registry.local:5000", "insecure": true
+                },
+                "tagMutability": "Immutable"
+            }
+        });
+        let mut alpha = convert_object(destination, "replicator.yair.example.com/v1alpha1").unwrap();
+        // fields v1alpha1 has still follow the v1alpha1 object
+        alpha["spec"]["repository"]["name"] = json!("prod-eu");
+        let back = convert_object(alpha, "replicator.yair.example.com/v1beta1").unwrap();
+        assert_eq!(back["spec"]["tagMutability"], "Immutable");
+        assert_eq!(back["spec"]["repository"]["insecure"], true);
+        assert_eq!(back["spec"]["repository"]["registry"], "registry.local:5000");
+        assert_eq!(back["spec"]["repository"]["name"], "prod-eu");
+    }
+
+    #[test]
+    fn providers_newer_than_alpha_are_served_as_generic() {
+        let layout = json!({
+            "apiVersion": "replicator.yair.example.com/v1beta1",
+            "kind": "SourceRepository",
+            "metadata": { "name": "export" },
+            "spec": { "repository": {
+                "provider": "OciLayout", "name": "export", "location": "", "format": "Docker",
+                "path": "/var/lib/yair-layouts/export"
+            }}
+        });
+        let alpha = convert_object(layout, "replicator.yair.example.com/v1alpha1").unwrap();
+        assert_eq!(alpha["spec"]["repository"]["Provider"], "Generic");
+        let back = convert_object(alpha, "replicator.yair.example.com/v1beta1").unwrap();
+        assert_eq!(back["spec"]["repository"]["provider"], "OciLayout");
+    }
+
+    #[test]
+    fn generated_crds_convert_through_the_webhook() {
+        for crd in crds() {
+            let conversion = crd.spec.conversion.expect("a conversion stanza");
+            assert_eq!(conversion.strategy, "Webhook");
+            let webhook = conversion.webhook.expect("a webhook");
+            assert_eq!(webhook.conversion_review_versions, vec!["v1".to_string()]);
+            let service = webhook.client_config.and_then(|c| c.service).expect("a service");
+            assert_eq!(service.path.as_deref(), Some(WEBHOOK_PATH));
+        }
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        let doc = json!({ "apiVersion": "kube.rs/v1", "kind": "Document", "metadata": { "name": "x" } });
+        assert!(convert_object(doc, "replicator.yair.example.com/v1beta1").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+//! Custom resources in the `replicator.yair.example.com` group.
+//!
+//! Every served version has its own module with typed structs; the storage version is re-exported
+//! from here so the rest of the controller never names a version explicitly.
+pub mod conversion;
+pub mod v1alpha1;
+pub mod v1beta1;
+
+use chrono::{DateTime, Utc};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::{CustomResourceExt, core::crd::merge_crds};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+pub use v1beta1::*;
+
+pub static GROUP: &str = "replicator.yair.example.com";
+pub static STORAGE_VERSION: &str = "v1beta1";
+/// Service the generated CRDs send conversion reviews to, as installed by the chart's defaults
+pub static WEBHOOK_SERVICE: (&str, &str) = ("default", "yair-controller");
+/// Path and service port the conversion webhook is served on over TLS
+pub static WEBHOOK_PATH: &str = "/api/conversion";
+pub static WEBHOOK_PORT: i32 = 443;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub enum RepositoryProvider {
+    /// Google Artifact Registry
+    #[default]
+    #[serde(rename = "GCP")]
+    Gcp,
+    /// Any OCI distribution registry, addressed by `registry`
+    Generic,
+    /// An OCI image layout on disk, addressed by `path`, for carrying images into air-gapped sites
+    OciLayout,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub enum RepositoryFormat {
+    #[default]
+    Docker,
+}
+
+/// Severity of a vulnerability finding, in increasing order
+#[derive(
+    Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub enum Severity {
+    Unknown,
+    Low,
+    Medium,
+    #[default]
+    High,
+    Critical,
+}
+
+/// Condition types set on `ContainerReplicator` objects
+pub mod conditions {
+    /// Every selected image is present in every destination
+    pub static READY: &str = "Ready";
+    /// Promoted images went missing or changed in a destination, or pods run unpromoted digests
+    pub static DRIFT_DETECTED: &str = "DriftDetected";
+    /// Images were refused for lacking an accepted signature
+    pub static SIGNATURE_VERIFICATION_FAILED: &str = "SignatureVerificationFailed";
+    /// Images were refused for scan findings at or above the gate's severity, or a missing report
+    pub static VULNERABILITY_GATE_FAILED: &str = "VulnerabilityGateFailed";
+}
+
+/// Status shared by all versions of `ContainerReplicator`
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerReplicatorStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_replication_time: Option<DateTime<Utc>>,
+    /// Images present in their destinations after the last replication
+    #[serde(default)]
+    pub images: Vec<ReplicatedImage>,
+    /// Scan findings that kept images from being promoted in the last replication
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocking_findings: Vec<BlockingFinding>,
+    /// What the last dry run found replication would do, empty unless in dry-run mode
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plan: Vec<PlannedImage>,
+    /// How far the replication running on a worker got, unset once it finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub progress: Option<ReplicationProgress>,
+}
+
+/// Jobs of a replication running on a background worker
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicationProgress {
+    pub start_time: DateTime<Utc>,
+    pub jobs: usize,
+    pub completed: usize,
+    pub failed: usize,
+}
+
+/// What replicating an image would do, from a dry run
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedImage {
+    pub source: String,
+    pub destination: String,
+    pub digest: String,
+    pub tag: TagAction,
+    pub manifests_to_copy: usize,
+    pub manifests_present: usize,
+    pub blobs_to_copy: usize,
+    pub blobs_present: usize,
+    /// Estimated upload size, from the sizes the source manifests declare
+    pub bytes_to_copy: u64,
+}
+
+/// What replication would do to the destination tag
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub enum TagAction {
+    #[default]
+    Create,
+    /// The tag already points at the image
+    Unchanged,
+    Overwrite,
+    /// Keep the current image under a backup tag, then move the tag
+    Backup,
+    /// The tag points at another image and the destination does not allow moving it
+    Conflict,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplicatedImage {
+    pub source: String,
+    pub destination: String,
+    /// Digest of the image in the destination
+    pub digest: String,
+    /// Digest of the image in the source, if the destination holds a converted copy under `digest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_digest: Option<String>,
+    /// Rewrite rules that made the destination name differ from the source
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rewrites: Vec<String>,
+}
+
+/// A vulnerability at or above the gate's severity that is not allowlisted
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockingFinding {
+    pub image: String,
+    /// Vulnerability id, e.g. `CVE-2024-1234`
+    pub id: String,
+    pub severity: Severity,
+    /// Affected package, if the report names one
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub package: String,
+}
+
+impl ContainerReplicatorStatus {
+    #[must_use]
+    pub fn condition(&self, type_: &str) -> Option<&Condition> {
+        self.conditions.iter().find(|c| c.type_ == type_)
+    }
+
+    /// Add or replace the condition of the same type, keeping its transition time if unchanged
+    pub fn set_condition(&mut self, condition: Condition) {
+        match self.conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+            Some(existing) if existing.status == condition.status => {
+                existing.reason = condition.reason;
+                existing.message = condition.message;
+            }
+            Some(existing) => *existing = condition,
+            None => self.conditions.push(condition),
+        }
+    }
+}
+
+/// A trimmed down `metav1.Condition`
+#[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// One of `True`, `False` or `Unknown`
+    pub status: String,
+    pub reason: String,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_transition_time: Option<DateTime<Utc>>,
+}
+
+impl Condition {
+    #[must_use]
+    pub fn new(type_: &str, status: bool, reason: &str, message: impl Into<String>) -> Self {
+        Self {
+            type_: type_.into(),
+            status: if status { "True" } else { "False" }.into(),
+            reason: reason.into(),
+            message: message.into(),
+            last_transition_time: Some(Utc::now()),
+        }
+    }
+
+    #[must_use]
+    pub fn is_true(&self) -> bool {
+        self.status == "True"
+    }
+}
+
+/// Multi-version CRDs for every kind in the group, storing `STORAGE_VERSION` and converted by the
+/// webhook on [`WEBHOOK_SERVICE`]
+///
+/// The apiserver only calls the webhook over TLS, the chart fills in the `caBundle` it trusts.
+///
+/// # Panics
+///
+/// Panics if the per-version CRDs disagree on names or scope, which is a programming error.
+#[must_use]
+pub fn crds() -> Vec<CustomResourceDefinition> {
+    [
+        vec![
+            v1alpha1::SourceRepository::crd(),
+            v1beta1::SourceRepository::crd(),
+        ],
+        vec![
+            v1alpha1::DestinationRepository::crd(),
+            v1beta1::DestinationRepository::crd(),
+        ],
+        vec![
+            v1alpha1::ContainerReplicator::crd(),
+            v1beta1::ContainerReplicator::crd(),
+        ],
+    ]
+    .into_iter()
+    .map(|versions| {
+        let mut crd = merge_crds(versions, STORAGE_VERSION).expect("versions of a kind are mergeable");
+        crd.spec.conversion = Some(webhook_conversion());
+        crd
+    })
+    .collect()
+}
+
+fn webhook_conversion() -> CustomResourceConversion {
+    let (namespace, name) = WEBHOOK_SERVICE;
+    CustomResourceConversion {
+        strategy: "Webhook".into(),
+        webhook: Some(WebhookConversion {
+            conversion_review_versions: vec!["v1".into()],
+            client_config: Some(WebhookClientConfig {
+                service: Some(ServiceReference {
+                    name: name.into(),
+                    namespace: namespace.into(),
+                    path: Some(WEBHOOK_PATH.into()),
+                    port: Some(WEBHOOK_PORT),
+                }),
+                ..WebhookClientConfig::default()
+            }),
+        }),
+    }
+}
@@ -0,0 +1,109 @@
+//! The original `replicator.yair.example.com/v1alpha1` schema.
+//!
+//! This version is kept so that objects written before `v1beta1` existed can still be read and
+//! converted. New fields only ever go into [`super::v1beta1`].
+use super::{ContainerReplicatorStatus, RepositoryFormat};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "SourceRepository",
+    group = "replicator.yair.example.com",
+    version = "v1alpha1",
+    namespaced
+)]
+pub struct SourceRepositorySpec {
+    pub repository: RepositorySpec,
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "DestinationRepository",
+    group = "replicator.yair.example.com",
+    version = "v1alpha1",
+    namespaced
+)]
+pub struct DestinationRepositorySpec {
+    pub repository: RepositorySpec,
+}
+
+/// Registry coordinates, with the casing used by the first concept manifests
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositorySpec {
+    pub name: String,
+    pub location: String,
+    pub format: RepositoryFormat,
+    #[serde(rename = "projectID")]
+    pub project_id: String,
+    #[serde(rename = "Provider")]
+    pub provider: RepositoryProvider,
+    #[serde(default)]
+    pub service_account: Option<String>,
+}
+
+/// The providers this version was published with, frozen so providers added later stay `v1beta1` only
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub enum RepositoryProvider {
+    /// Google Artifact Registry
+    #[default]
+    #[serde(rename = "GCP")]
+    Gcp,
+    /// Any OCI distribution registry
+    Generic,
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "ContainerReplicator",
+    group = "replicator.yair.example.com",
+    version = "v1alpha1",
+    namespaced
+)]
+#[kube(status = "ContainerReplicatorStatus")]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerReplicatorSpec {
+    pub repository_selector: RepositorySelector,
+    pub destination_repositories_selector: DestinationRepositoriesSelector,
+    #[serde(default)]
+    pub promotion_selectors: PromotionSelectors,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositorySelector {
+    pub repository_ref: RepositoryRef,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationRepositoriesSelector {
+    pub repository_ref: Vec<RepositoryRef>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct RepositoryRef {
+    pub name: String,
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct PromotionSelectors {
+    #[serde(default)]
+    pub deployments: Vec<WorkloadSelector>,
+    #[serde(default)]
+    pub jobs: Vec<WorkloadSelector>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadSelector {
+    pub name: String,
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub auto_detect_images: bool,
+}
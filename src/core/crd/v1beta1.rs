@@ -0,0 +1,270 @@
+//! The `replicator.yair.example.com/v1beta1` schema, which is the storage version.
+//!
+//! Compared to `v1alpha1` all keys are consistently camelCased and the single-field selector
+//! wrappers on `ContainerReplicator` are flattened into plain references.
+use super::{ContainerReplicatorStatus, RepositoryFormat, RepositoryProvider, Severity};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "SourceRepository",
+    group = "replicator.yair.example.com",
+    version = "v1beta1",
+    namespaced
+)]
+#[kube(shortname = "srcrepo")]
+pub struct SourceRepositorySpec {
+    pub repository: RepositorySpec,
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "DestinationRepository",
+    group = "replicator.yair.example.com",
+    version = "v1beta1",
+    namespaced
+)]
+#[kube(shortname = "dstrepo")]
+#[serde(rename_all = "camelCase")]
+pub struct DestinationRepositorySpec {
+    pub repository: RepositorySpec,
+    /// What happens when a tag already points at another image in this repository
+    #[serde(default)]
+    pub tag_mutability: TagMutability,
+    /// Recompress gzip and zstd layers to this compression on their way in; layers of other types are
+    /// copied as they are
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_compression: Option<LayerCompression>,
+    /// Convert images and indexes to this manifest format on their way in, for registries that only
+    /// accept one of them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_format: Option<ManifestFormat>,
+}
+
+/// How an existing destination tag is treated when the source tag moved to another digest
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq, Hash)]
+pub enum TagMutability {
+    /// Never move a tag; the conflicting image is refused
+    Immutable,
+    /// Move the tag to the new digest
+    #[default]
+    Overwrite,
+    /// Tag the old digest as `<tag>-backup-<hex>` before moving the tag
+    OverwriteWithBackup,
+}
+
+/// Compression of image layers
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq, Eq, Hash)]
+pub enum LayerCompression {
+    /// `tar+gzip`, which every registry and runtime accepts
+    Gzip,
+    /// `tar+zstd`, smaller and faster to unpack, for OCI images only
+    Zstd,
+}
+
+/// Media types of image manifests, indexes and their configs
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, PartialEq, Eq, Hash)]
+pub enum ManifestFormat {
+    /// OCI image manifests and indexes
+    Oci,
+    /// Docker schema2 manifests and manifest lists
+    Docker,
+}
+
+/// Registry coordinates for a source or destination
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositorySpec {
+    pub provider: RepositoryProvider,
+    /// Registry name for `GCP`, repository path prefix for `Generic` and `OciLayout`
+    pub name: String,
+    #[serde(default)]
+    pub location: String,
+    pub format: RepositoryFormat,
+    #[serde(default)]
+    pub project_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service_account: Option<String>,
+    /// Registry host, overriding the provider default (required for `Generic`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// Talk plain http to the registry
+    #[serde(default)]
+    pub insecure: bool,
+    /// Directory or `.tar` file of the OCI image layout in the controller's filesystem (required for
+    /// `OciLayout`), e.g. on a mounted volume
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[kube(
+    kind = "ContainerReplicator",
+    group = "replicator.yair.example.com",
+    version = "v1beta1",
+    namespaced
+)]
+#[kube(status = "ContainerReplicatorStatus", shortname = "crep")]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerReplicatorSpec {
+    /// The `SourceRepository` images are promoted from
+    pub source_ref: RepositoryRef,
+    /// The `DestinationRepository` objects images are promoted to
+    pub destination_refs: Vec<RepositoryRef>,
+    #[serde(default)]
+    pub promotion_selectors: PromotionSelectors,
+    /// Source images whose tags are picked by policy instead of being read from workloads
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tag_selectors: Vec<TagSelector>,
+    /// How destination repository paths and tags differ from the source, names are kept if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewrite: Option<RewriteRules>,
+    /// Signatures images need before they are promoted, nothing is checked if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    /// Scan reports images need to pass before they are promoted, nothing is checked if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vulnerability_gate: Option<VulnerabilityGate>,
+    /// Only work out what replication would copy, into `status.plan`, without pushing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Promotes the tags of a source image that match a policy, like a Flux `ImagePolicy`
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TagSelector {
+    /// Image path below the source repository, e.g. `team/app`
+    pub image: String,
+    /// Only consider tags matching a pattern, optionally ordering by part of the tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_tags: Option<TagFilter>,
+    pub policy: TagPolicy,
+    /// Promote only the newest `latest` tags by the policy's order, every matching tag if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latest: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq, Eq)]
+pub struct TagFilter {
+    /// Regular expression a tag has to match
+    pub pattern: String,
+    /// Replacement built from the pattern's capture groups, e.g. `$ts`, that the policy orders by
+    /// instead of the whole tag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extract: Option<String>,
+}
+
+/// How tags are accepted and ordered
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum TagPolicy {
+    /// Semantic versions within a range such as `>=1.4 <2`, highest first
+    Semver { range: String },
+    Alphabetical {
+        #[serde(default)]
+        order: SortOrder,
+    },
+    /// Tags that parse as numbers, e.g. build numbers or timestamps
+    Numerical {
+        #[serde(default)]
+        order: SortOrder,
+    },
+}
+
+/// `asc` makes the highest value the newest, `desc` the lowest
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Rewrites of the image path below the source repository, applied in field order, and of the tag
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RewriteRules {
+    /// Leading path segments to drop, e.g. `my-team`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_prefix: Option<String>,
+    /// Regular expression mappings of the path; the first matching one applies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub repositories: Vec<RepositoryMapping>,
+    /// Path segments to put in front, e.g. `apps`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add_prefix: Option<String>,
+    /// Destination tag template, e.g. `{label:org.opencontainers.image.version}` or
+    /// `git-{shortRevision}`; also knows `{tag}`, `{revision}` and `{annotation:<key>}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct RepositoryMapping {
+    pub pattern: String,
+    /// Replacement, referring to capture groups as `$1` or `$name`
+    pub replacement: String,
+}
+
+/// Blocks images whose attached Trivy or SARIF scan report has findings at or above `severity`
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct VulnerabilityGate {
+    /// Lowest severity that blocks promotion
+    #[serde(default)]
+    pub severity: Severity,
+    /// Vulnerability ids that never block, e.g. `CVE-2024-1234`
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+}
+
+/// Accepted cosign signers; an image is promoted if any of them signed it
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Verification {
+    /// PEM encoded cosign public keys
+    #[serde(default)]
+    pub public_keys: Vec<String>,
+    /// Keyless signers, checked against the Fulcio roots configured for the controller
+    #[serde(default)]
+    pub keyless: Vec<KeylessIdentity>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct KeylessIdentity {
+    /// Email or URI subject of the signing certificate
+    pub identity: String,
+    /// OIDC issuer the signer authenticated with, e.g. `https://token.actions.githubusercontent.com`
+    pub issuer: String,
+}
+
+/// Reference to a repository object, defaulting to the namespace of the referrer
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq, Hash)]
+pub struct RepositoryRef {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+}
+
+/// Workloads whose images are promoted
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+pub struct PromotionSelectors {
+    #[serde(default)]
+    pub deployments: Vec<WorkloadSelector>,
+    #[serde(default)]
+    pub jobs: Vec<WorkloadSelector>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadSelector {
+    pub name: String,
+    /// Image names (without registry or tag) to promote from the workload
+    #[serde(default)]
+    pub images: Vec<String>,
+    /// Promote every image found in the workload's pod template
+    #[serde(default)]
+    pub auto_detect_images: bool,
+}
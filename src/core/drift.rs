@@ -0,0 +1,240 @@
+//! Drift between what a `ContainerReplicator` promoted and what is really out there.
+//!
+//! Every reconcile, whether it replicates or only plans a dry run, first audits the outcome of the
+//! previous replication. Every promoted image must still be in its destination under the digest it
+//! was copied with, and every running container of the watched namespaces pulled from a repository
+//! yair promotes to must run a digest yair replicated. Missing and mutated destination
+//! images are usually repaired by the replication that follows; foreign images are only reported.
+use crate::core::{
+    crd::ReplicatedImage,
+    registry::Reference,
+    replication::{ReplicationJob, Replicator},
+};
+use k8s_openapi::api::core::v1::Pod;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+use tracing::warn;
+
+/// How an image drifted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DriftKind {
+    /// The destination no longer has the promoted tag
+    Missing,
+    /// The destination tag points at another digest than the one promoted
+    Mutated,
+    /// A pod runs a digest from a destination repository that yair never promoted
+    Foreign,
+}
+
+impl DriftKind {
+    pub const ALL: [Self; 3] = [Self::Missing, Self::Mutated, Self::Foreign];
+}
+
+impl fmt::Display for DriftKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "missing",
+            Self::Mutated => "mutated",
+            Self::Foreign => "foreign",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Drift {
+    pub kind: DriftKind,
+    pub image: String,
+    /// The digest found instead of the promoted one, if any
+    pub digest: Option<String>,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is {}", self.image, self.kind)?;
+        if let Some(digest) = &self.digest {
+            write!(f, " ({digest})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Destination images of `promoted` that are gone or point elsewhere
+///
+/// They are looked up through the jobs that still copy them, under the tag they were promoted with;
+/// destinations that can not be reached are skipped.
+pub async fn destinations(
+    replicator: &Replicator,
+    jobs: &[ReplicationJob],
+    promoted: &[ReplicatedImage],
+) -> Vec<Drift> {
+    let mut drift = vec![];
+    for job in jobs {
+        let Some((promoted, reference)) = job.promoted(promoted) else {
+            continue;
+        };
+        let image = promoted.destination.clone();
+        let client = replicator.client(&job.destination.endpoint);
+        let found = client
+            .manifest_digest(&job.destination.repository, reference)
+            .await;
+        let (kind, digest) = match found {
+            Ok(None) => (DriftKind::Missing, None),
+            Ok(Some(found)) if found != promoted.digest => (DriftKind::Mutated, Some(found)),
+            Ok(Some(_)) => continue,
+            Err(e) => {
+                warn!(%image, error = %e, "Can not audit destination image");
+                continue;
+            }
+        };
+        drift.push(Drift { kind, image, digest });
+    }
+    drift
+}
+
+/// Containers of running `pods` whose image comes from a repository `promoted` copied to, but with a
+/// digest that was not promoted there
+#[must_use]
+pub fn pods(pods: &[Pod], promoted: &[ReplicatedImage]) -> Vec<Drift> {
+    let mut digests: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+    for image in promoted {
+        if let Ok(destination) = image.destination.parse::<Reference>() {
+            digests
+                .entry(destination.name())
+                .or_default()
+                .insert(&image.digest);
+        }
+    }
+    let mut drift = BTreeSet::new();
+    let running = pods
+        .iter()
+        .filter(|pod| pod.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Running"));
+    for status in running.filter_map(|pod| pod.status.as_ref()) {
+        let containers = status
+            .init_container_statuses
+            .iter()
+            .flatten()
+            .chain(status.container_statuses.iter().flatten());
+        for container in containers {
+            let (Ok(image), Some((_, digest))) = (
+                container.image.parse::<Reference>(),
+                container.image_id.split_once('@'),
+            ) else {
+                continue;
+            };
+            let Some(promoted) = digests.get(&image.name()) else {
+                continue;
+            };
+            if !promoted.contains(digest) {
+                drift.insert(Drift {
+                    kind: DriftKind::Foreign,
+                    image: container.image.clone(),
+                    digest: Some(digest.to_string()),
+                });
+            }
+        }
+    }
+    drift.into_iter().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Drift, DriftKind, destinations, pods};
+    use crate::core::{
+        crd::{ReplicatedImage, TagMutability},
+        registry::fake::FakeRegistry,
+        replication::{Conversion, ImageLocation, ReplicationJob, Replicator},
+    };
+    use k8s_openapi::api::core::v1::Pod;
+    use serde_json::json;
+
+    fn pod(phase: &str, image: &str, image_id: &str) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": { "name": "app-1" },
+            "status": {
+                "phase": phase,
+                "containerStatuses": [{
+                    "name": "app", "image": image, "imageID": image_id,
+                    "ready": true, "restartCount": 0,
+                }],
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pods_running_unpromoted_digests_are_foreign() {
+        let promoted = [ReplicatedImage {
+            source: "ci.example.com/ci/app:1.0.0".into(),
+            destination: "prod.example.com/prod/app:1.0.0".into(),
+            digest: "sha256:aaa".into(),
+            source_digest: None,
+            rewrites: vec![],
+        }];
+        let running = [
+            pod(
+                "Running",
+                "prod.example.com/prod/app:1.0.0",
+                "prod.example.com/prod/app@sha256:aaa",
+            ),
+            pod(
+                "Running",
+                "prod.example.com/prod/app:hotfix",
+                "prod.example.com/prod/app@sha256:bbb",
+            ),
+            pod(
+                "Pending",
+                "prod.example.com/prod/app:other",
+                "prod.example.com/prod/app@sha256:ccc",
+            ),
+            pod(
+                "Running",
+                "docker.io/library/nginx:1",
+                "docker.io/library/nginx@sha256:ddd",
+            ),
+            pod("Running", "prod.example.com/prod/app:pulling", ""),
+        ];
+        assert_eq!(pods(&running, &promoted), [Drift {
+            kind: DriftKind::Foreign,
+            image: "prod.example.com/prod/app:hotfix".into(),
+            digest: Some("sha256:bbb".into()),
+        }]);
+    }
+
+    #[tokio::test]
+    async fn destinations_are_audited_under_their_rendered_tags() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let foreign = destination.push_image("prod/app", "1.0.0-abc", &[b"pushed by hand"]);
+        // jobs name the source tag until the tag template is rendered while copying
+        let job = ReplicationJob {
+            source: ImageLocation {
+                endpoint: source.endpoint(),
+                repository: "ci/app".into(),
+                reference: "1.0.0".into(),
+            },
+            destination: ImageLocation {
+                endpoint: destination.endpoint(),
+                repository: "prod/app".into(),
+                reference: "1.0.0".into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        };
+        let promoted = [ReplicatedImage {
+            source: job.source.to_string(),
+            destination: format!("{}/prod/app:1.0.0-abc", destination.host),
+            digest,
+            source_digest: None,
+            rewrites: vec!["tag {tag}-{shortRevision}".into()],
+        }];
+        let drift = destinations(&Replicator::default(), &[job], &promoted).await;
+        assert_eq!(drift, [Drift {
+            kind: DriftKind::Mutated,
+            image: promoted[0].destination.clone(),
+            digest: Some(foreign),
+        }]);
+    }
+}
@@ -1,7 +1,12 @@
 //! Helper methods only available for tests
 use crate::core::{
     Result,
-    kubecontroller::{Context, DOCUMENT_FINALIZER, Document, DocumentSpec, DocumentStatus},
+    crd::{
+        ContainerReplicator, ContainerReplicatorSpec, ContainerReplicatorStatus, DestinationRepository,
+        DestinationRepositorySpec, PromotionSelectors, RepositoryProvider, RepositoryRef, RepositorySpec,
+        SourceRepository, SourceRepositorySpec, TagMutability, WorkloadSelector, conditions,
+    },
+    kubecontroller::{Context, DOCUMENT_FINALIZER, Document, DocumentSpec, DocumentStatus, State},
 };
 use assert_json_diff::assert_json_include;
 use http::{Request, Response};
@@ -57,6 +62,115 @@ impl Document {
     }
 }
 
+fn generic_repository(registry: &str, name: &str) -> RepositorySpec {
+    RepositorySpec {
+        provider: RepositoryProvider::Generic,
+        name: name.into(),
+        registry: Some(registry.into()),
+        insecure: true,
+        ..RepositorySpec::default()
+    }
+}
+
+impl SourceRepository {
+    /// The `ci` source repository, on a plain http registry at `registry`
+    #[must_use]
+    pub fn test(registry: &str) -> Self {
+        let mut r = Self::new("ci", SourceRepositorySpec {
+            repository: generic_repository(registry, "ci"),
+        });
+        r.meta_mut().namespace = Some("default".into());
+        r
+    }
+}
+
+impl DestinationRepository {
+    /// The `prod` destination repository, on a plain http registry at `registry`
+    #[must_use]
+    pub fn test(registry: &str) -> Self {
+        let mut r = Self::new("prod", DestinationRepositorySpec {
+            repository: generic_repository(registry, "prod"),
+            tag_mutability: TagMutability::default(),
+            layer_compression: None,
+            manifest_format: None,
+        });
+        r.meta_mut().namespace = Some("default".into());
+        r
+    }
+}
+
+impl ContainerReplicator {
+    /// Promotes every image of the `app` deployment from `ci` to `prod`
+    #[must_use]
+    pub fn test() -> Self {
+        let mut r = Self::new("app", ContainerReplicatorSpec {
+            source_ref: RepositoryRef {
+                name: "ci".into(),
+                namespace: None,
+            },
+            destination_refs: vec![RepositoryRef {
+                name: "prod".into(),
+                namespace: None,
+            }],
+            promotion_selectors: PromotionSelectors {
+                deployments: vec![WorkloadSelector {
+                    name: "app".into(),
+                    images: vec![],
+                    auto_detect_images: true,
+                }],
+                jobs: vec![],
+            },
+            tag_selectors: vec![],
+            rewrite: None,
+            verification: None,
+            vulnerability_gate: None,
+            dry_run: false,
+        });
+        r.meta_mut().namespace = Some("default".into());
+        r
+    }
+}
+
+/// A deployment named `app` running the given images
+///
+/// # Panics
+///
+/// This function will panic if the images do not make a valid deployment.
+#[must_use]
+pub fn deployment(images: &[&str]) -> k8s_openapi::api::apps::v1::Deployment {
+    serde_json::from_value(serde_json::json!({
+        "metadata": { "name": "app", "namespace": "default" },
+        "spec": {
+            "selector": { "matchLabels": { "app": "app" } },
+            "template": {
+                "spec": {
+                    "containers": images
+                        .iter()
+                        .enumerate()
+                        .map(|(i, image)| serde_json::json!({ "name": format!("c{i}"), "image": image }))
+                        .collect::<Vec<_>>(),
+                }
+            }
+        }
+    }))
+    .expect("valid deployment")
+}
+
+/// The objects a `ContainerReplicator` reconcile reads from the apiserver
+pub struct ReplicationObjects {
+    pub replicator: ContainerReplicator,
+    pub source: SourceRepository,
+    pub destination: DestinationRepository,
+    pub deployment: k8s_openapi::api::apps::v1::Deployment,
+}
+
+/// The objects a pod reconcile of the pull-through mirror reads from the apiserver
+pub struct MirrorObjects {
+    pub pod: k8s_openapi::api::core::v1::Pod,
+    pub source: SourceRepository,
+    pub destination: DestinationRepository,
+}
+
 // We wrap tower_test::mock::Handle
 type ApiServerHandle = tower_test::mock::Handle<Request<Body>, Response<Body>>;
 pub struct ApiServerVerifier(ApiServerHandle);
@@ -73,6 +187,25 @@ pub enum Scenario {
     RadioSilence,
     /// objects with a deletion timestamp will run the cleanup loop sending event and removing the finalizer
     Cleanup(String, Document),
+    /// replicators read their repositories and workloads, publish events with the given reasons
+    /// and patch their status, which must have the given `Ready` status
+    Replication(Box<ReplicationObjects>, Vec<&'static str>, &'static str),
+    /// queued replicators are read again, then replicate like `Replication` while patching their
+    /// progress after each of the given number of jobs
+    Queued(Box<ReplicationObjects>, usize, Vec<&'static str>, &'static str),
+    /// replicators with promoted images in their status also list the pods of the cluster before
+    /// replicating, or planning, like `Replication`
+    Audit(
+        Box<ReplicationObjects>,
+        Vec<k8s_openapi::api::core::v1::Pod>,
+        Vec<&'static str>,
+        &'static str,
+    ),
+    /// pods failing to pull a source image read the mirror and the sources, then get an event and
+    /// are deleted
+    Mirror(Box<MirrorObjects>),
+    /// replicators only read their repositories (and with `true` their workloads), as the tasks do
+    Lookup(Box<ReplicationObjects>, bool),
 }
 
 /// Runs the given handle with a timeout of 1 second.
@@ -124,6 +257,47 @@ impl ApiServerVerifier {
                         .handle_finalizer_removal(doc)
                         .await
                 }
+                Scenario::Replication(objects, reasons, ready) => {
+                    let mut this = self.handle_lookup(&objects, true).await.unwrap();
+                    for reason in &reasons {
+                        this = this.handle_event_create((*reason).into()).await.unwrap();
+                    }
+                    this.handle_replicator_status_patch(objects.replicator, ready, &reasons)
+                        .await
+                }
+                Scenario::Queued(objects, jobs, reasons, ready) => {
+                    let mut this = self
+                        .handle_get(&objects.replicator, "/apis/replicator.yair.example.com/v1beta1/namespaces/default/containerreplicators/app")
+                        .await
+                        .unwrap()
+                        .handle_lookup(&objects, true)
+                        .await
+                        .unwrap();
+                    for completed in 0..=jobs {
+                        this = this.handle_progress_patch(completed).await.unwrap();
+                    }
+                    for reason in &reasons {
+                        this = this.handle_event_create((*reason).into()).await.unwrap();
+                    }
+                    this.handle_replicator_status_patch(objects.replicator, ready, &reasons)
+                        .await
+                }
+                Scenario::Audit(objects, pods, reasons, ready) => {
+                    let mut this = self
+                        .handle_lookup(&objects, true)
+                        .await
+                        .unwrap()
+                        .handle_list(pods, "/api/v1/pods")
+                        .await
+                        .unwrap();
+                    for reason in &reasons {
+                        this = this.handle_event_create((*reason).into()).await.unwrap();
+                    }
+                    this.handle_replicator_status_patch(objects.replicator, ready, &reasons)
+                        .await
+                }
+                Scenario::Mirror(objects) => self.handle_mirror(*objects).await,
+                Scenario::Lookup(objects, workloads) => self.handle_lookup(&objects, workloads).await,
             }
             .expect("scenario completed without errors");
         })
@@ -200,6 +374,137 @@ impl ApiServerVerifier {
         Ok(self)
     }
 
+    async fn handle_lookup(self, objects: &ReplicationObjects, workloads: bool) -> Result<Self> {
+        let this = self
+            .handle_get(
+                &objects.source,
+                "/apis/replicator.yair.example.com/v1beta1/namespaces/default/sourcerepositories/ci",
+            )
+            .await?
+            .handle_get(
+                &objects.destination,
+                "/apis/replicator.yair.example.com/v1beta1/namespaces/default/destinationrepositories/prod",
+            )
+            .await?;
+        if !workloads {
+            return Ok(this);
+        }
+        this.handle_get(
+            &objects.deployment,
+            "/apis/apps/v1/namespaces/default/deployments/app",
+        )
+        .await
+    }
+
+    async fn handle_list<K: serde::Serialize>(mut self, items: Vec<K>, path: &str) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri().path(), path);
+        let list = serde_json::json!({ "metadata": {}, "items": items });
+        let response = serde_json::to_vec(&list).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_mirror(self, objects: MirrorObjects) -> Result<Self> {
+        self.handle_get(
+            &objects.destination,
+            "/apis/replicator.yair.example.com/v1beta1/namespaces/default/destinationrepositories/prod",
+        )
+        .await
+        .unwrap()
+        .handle_list(
+            vec![objects.source],
+            "/apis/replicator.yair.example.com/v1beta1/sourcerepositories",
+        )
+        .await
+        .unwrap()
+        .handle_event_create("Mirrored".into())
+        .await
+        .unwrap()
+        .handle_delete(&objects.pod, "/api/v1/namespaces/default/pods/app-1")
+        .await
+    }
+
+    async fn handle_get<K: serde::Serialize + Sync>(mut self, object: &K, path: &str) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::GET);
+        assert_eq!(request.uri().path(), path);
+        let response = serde_json::to_vec(object).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_delete<K: serde::Serialize + Sync>(mut self, object: &K, path: &str) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::DELETE);
+        assert_eq!(request.uri().path(), path);
+        let response = serde_json::to_vec(object).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_replicator_status_patch(
+        mut self,
+        replicator: ContainerReplicator,
+        ready: &str,
+        reasons: &[&str],
+    ) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        assert_eq!(
+            request.uri().to_string(),
+            format!(
+                "/apis/replicator.yair.example.com/v1beta1/namespaces/default/containerreplicators/{}/status?&force=true&fieldManager=cntrlr",
+                replicator.name_any()
+            )
+        );
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch_status object is json");
+        let status: ContainerReplicatorStatus =
+            serde_json::from_value(json.get("status").expect("status object").clone()).expect("valid status");
+        let condition = status.condition(conditions::READY).expect("Ready condition");
+        assert_eq!(condition.status, ready, "{}", condition.message);
+        // conditions named after a warning are set exactly when it is published
+        for condition in status.conditions.iter().filter(|c| c.type_ != conditions::READY) {
+            assert_eq!(
+                condition.is_true(),
+                reasons.contains(&condition.type_.as_str()),
+                "{}: {}",
+                condition.type_,
+                condition.message
+            );
+        }
+        assert_eq!(
+            status.progress, None,
+            "progress is cleared once replication finished"
+        );
+        let mut replicator = replicator;
+        replicator.status = Some(status);
+        let response = serde_json::to_vec(&replicator).unwrap();
+        send.send_response(Response::builder().body(Body::from(response)).unwrap());
+        Ok(self)
+    }
+
+    async fn handle_progress_patch(mut self, completed: usize) -> Result<Self> {
+        let (request, send) = self.0.next_request().await.expect("service not called");
+        assert_eq!(request.method(), http::Method::PATCH);
+        let req_body = request.into_body().collect_bytes().await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&req_body).expect("patch_status object is json");
+        let status: ContainerReplicatorStatus =
+            serde_json::from_value(json.get("status").expect("status object").clone()).expect("valid status");
+        let progress = status.progress.clone().expect("progress");
+        assert_eq!(progress.completed, completed);
+        let mut replicator = ContainerReplicator::test();
+        replicator.status = Some(status);
+        send.send_response(
+            Response::builder()
+                .body(Body::from(serde_json::to_vec(&replicator).unwrap()))
+                .unwrap(),
+        );
+        Ok(self)
+    }
+
     async fn handle_status_patch(mut self, doc: Document) -> Result<Self> {
         let (request, send) = self.0.next_request().await.expect("service not called");
         assert_eq!(request.method(), http::Method::PATCH);
@@ -232,8 +537,24 @@ impl Context {
             client: mock_client,
             metrics: Arc::default(),
             diagnostics: Arc::default(),
+            settings: Arc::default(),
+            replicator: Arc::default(),
+            trust: Arc::default(),
+            plans: Arc::default(),
+            workers: Arc::default(),
+            queued: Arc::default(),
             recorder: mock_recorder,
         };
         (Arc::new(ctx), ApiServerVerifier(handle))
     }
 }
+
+impl State {
+    /// A controller context sharing this state, on a mock apiserver, so the web server serves what
+    /// its reconciles record
+    pub async fn test_context(&self) -> (Arc<Context>, ApiServerVerifier) {
+        let (mock_service, handle) = tower_test::mock::pair::<Request<Body>, Response<Body>>();
+        let ctx = self.to_context(Client::new(mock_service, "default")).await;
+        (ctx, ApiServerVerifier(handle))
+    }
+}
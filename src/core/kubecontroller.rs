@@ -4,11 +4,19 @@
 #![allow(unused_imports, unused_variables)]
 pub use crate::controllers::telemetry;
 use crate::controllers::{kubecontroller, metrics::Metrics};
-use loco_rs::Error as LocoError;
-
-use crate::core::{ErrorWrapper, LocoErrorExt, Result};
+use loco_rs::{Error as LocoError, app::AppContext, config::WorkerMode};
+
+use crate::core::{
+    ErrorWrapper, LocoErrorExt, Result,
+    registry::Reference,
+    replication::{JobPlan, Replicator},
+    scope::{Concurrency, WatchScope},
+    settings::Settings,
+    shutdown::Shutdown,
+    verification::TrustRoot,
+};
 use chrono::{DateTime, Utc};
-use futures::StreamExt;
+use futures::{Stream, StreamExt};
 pub use kube::runtime::{
     controller,
     controller::{Action, Controller},
@@ -26,8 +34,11 @@ use kube::{
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::Arc;
-use tokio::{sync::RwLock, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex, OnceLock},
+};
+use tokio::sync::{RwLock, broadcast};
 use tracing::{Callsite, Span, Subscriber, Value, field, info, instrument, warn};
 
 pub static DOCUMENT_FINALIZER: &str = "documents.kube.rs";
@@ -60,6 +71,18 @@ pub struct Context {
     pub recorder: Recorder,
     pub diagnostics: Arc<RwLock<Diagnostics>>,
     pub metrics: Arc<Metrics>,
+    pub settings: Arc<Settings>,
+    /// Copies images, shared by all reconciles so its limits apply globally
+    pub replicator: Arc<Replicator>,
+    /// Fulcio roots and Rekor keys for keyless signature verification
+    pub trust: Arc<TrustRoot>,
+    /// Dry-run plans of the replicators in dry-run mode
+    pub plans: Arc<RwLock<Plans>>,
+    /// The loco app once replication can be queued onto its workers; copies run in the reconcile
+    /// until then
+    pub workers: Arc<OnceLock<AppContext>>,
+    /// `namespace/name` of the replicators queued or running on a worker
+    pub queued: Arc<Mutex<BTreeSet<String>>>,
 }
 
 #[instrument(skip(ctx, doc), fields(trace_id, document = ?doc.name_any()))]
@@ -94,8 +117,8 @@ async fn reconcile(doc: Arc<Document>, ctx: Arc<Context>) -> Result<Action> {
 #[allow(clippy::borrow_deref_ref)] // mutually exclusive with making tests work
 fn error_policy(doc: &Arc<Document>, error: &LocoError, ctx: &Arc<Context>) -> Action {
     warn!("reconcile failed: {:?}", error);
-    ctx.metrics.reconcile.set_failure(&*doc, error); // `error` is now `LocoError`
-    Action::requeue(Duration::from_secs(5 * 60))
+    ctx.metrics.reconcile.set_failure(doc.as_ref(), error); // `error` is now `LocoError`
+    Action::requeue(ctx.settings.requeue.error_interval())
 }
 
 impl Document {
@@ -140,7 +163,7 @@ impl Document {
             .await
             .map_err(ErrorWrapper::from_kube);
 
-        Ok(Action::requeue(Duration::from_secs(5 * 60)))
+        Ok(Action::requeue(ctx.settings.requeue.interval()))
     }
 
     #[allow(dead_code)]
@@ -174,29 +197,97 @@ pub struct Diagnostics {
 }
 impl Default for Diagnostics {
     fn default() -> Self {
+        Self::new(&Settings::default().reporter)
+    }
+}
+impl Diagnostics {
+    #[must_use]
+    pub fn new(reporter: &str) -> Self {
         Self {
             last_event: Utc::now(),
-            reporter: "doc-controller".into(),
+            reporter: reporter.into(),
         }
     }
-}
-impl Diagnostics {
+
     fn recorder(&self, client: Client) -> Recorder {
         Recorder::new(client, self.reporter.clone())
     }
 }
 
+/// Dry-run plans by `namespace/name` of their `ContainerReplicator`
+pub type Plans = BTreeMap<String, Vec<JobPlan>>;
+
 /// State shared between the controller and the web server
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct State {
     /// Diagnostics populated by the reconciler
     diagnostics: Arc<RwLock<Diagnostics>>,
     /// Metrics
     metrics: Arc<Metrics>,
+    /// Runtime settings from the loco config
+    settings: Arc<Settings>,
+    /// Image copier with the global job and per-registry limits
+    replicator: Arc<Replicator>,
+    /// Trust material for keyless signature verification
+    trust: Arc<TrustRoot>,
+    /// Dry-run plans, served on `/api/replications/plan`
+    plans: Arc<RwLock<Plans>>,
+    /// Loco app context replication is queued with, once connected
+    workers: Arc<OnceLock<AppContext>>,
+    /// Replicators queued or running on a worker
+    queued: Arc<Mutex<BTreeSet<String>>>,
+    /// Images registries notified us about, for the controllers watching them
+    pushes: broadcast::Sender<Reference>,
+    /// Stops the controllers and the web server together
+    shutdown: Shutdown,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new(Settings::default())
+    }
 }
 
+/// Pushes a slow controller may fall behind on before it misses some; those replicate at their next
+/// requeue
+const PUSH_BACKLOG: usize = 1024;
+
 /// State wrapper around the controller outputs for the web server
 impl State {
+    /// State for the given runtime settings
+    #[must_use]
+    pub fn new(settings: Settings) -> Self {
+        let shutdown = Shutdown::default();
+        let trust = TrustRoot::load(&settings.verification).unwrap_or_else(|e| {
+            warn!(error = %e, "Can not load the verification trust root, keyless signatures will be rejected");
+            TrustRoot::default()
+        });
+        Self {
+            diagnostics: Arc::new(RwLock::new(Diagnostics::new(&settings.reporter))),
+            metrics: Arc::default(),
+            replicator: Arc::new(Replicator::new(&settings, shutdown.clone())),
+            trust: Arc::new(trust),
+            plans: Arc::default(),
+            workers: Arc::default(),
+            queued: Arc::default(),
+            pushes: broadcast::channel(PUSH_BACKLOG).0,
+            settings: Arc::new(settings),
+            shutdown,
+        }
+    }
+
+    /// Shutdown getter
+    #[must_use]
+    pub const fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Settings getter
+    #[must_use]
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
     /// Metrics getter
     #[allow(clippy::missing_panics_doc)]
     #[must_use]
@@ -212,6 +303,42 @@ impl State {
         self.diagnostics.read().await.clone()
     }
 
+    /// Plans of the replicators in dry-run mode
+    pub async fn plans(&self) -> Plans {
+        self.plans.read().await.clone()
+    }
+
+    /// Queue replication onto the workers of `app` from now on. Without a queue provider in
+    /// `BackgroundQueue` mode nothing would run the jobs, so reconciles keep copying themselves.
+    pub fn connect_workers(&self, app: &AppContext) {
+        if app.config.workers.mode != WorkerMode::BackgroundQueue || app.queue_provider.is_some() {
+            let _ = self.workers.set(app.clone());
+        }
+    }
+
+    /// Announce images pushed to a registry to the controllers
+    pub fn notify(&self, images: Vec<Reference>) {
+        for image in images {
+            // without a controller listening there is nobody to tell
+            let _ = self.pushes.send(image);
+        }
+    }
+
+    /// Images pushed from now on
+    pub fn pushes(&self) -> impl Stream<Item = Reference> + Send + 'static {
+        futures::stream::unfold(self.pushes.subscribe(), |mut pushes| async move {
+            loop {
+                match pushes.recv().await {
+                    Ok(image) => return Some((image, pushes)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Missed push notifications");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
     // Create a Controller Context that can update State
     pub async fn to_context(&self, client: Client) -> Arc<Context> {
         Arc::new(Context {
@@ -219,6 +346,12 @@ impl State {
             recorder: self.diagnostics.read().await.recorder(client),
             metrics: self.metrics.clone(),
             diagnostics: self.diagnostics.clone(),
+            settings: self.settings.clone(),
+            replicator: self.replicator.clone(),
+            trust: self.trust.clone(),
+            plans: self.plans.clone(),
+            workers: self.workers.clone(),
+            queued: self.queued.clone(),
         })
     }
 }
@@ -228,24 +361,40 @@ impl State {
 #[allow(clippy::unnecessary_literal_unwrap)]
 pub async fn run(state: State) {
     let client = Client::try_default().await.expect("failed to create kube Client");
-    let docs = Api::<Document>::all(client.clone());
-    if let Err(e) = docs.list(&ListParams::default().limit(1)).await {
-        Err::<(), loco_rs::Error>(ErrorWrapper::from_custom(&format!(
-            "CRD is not queryable; {e:?}. Is the CRD installed?"
-        )))
-        .expect("TODO: panic message");
-        info!("Installation: cargo run --bin crdgen | kubectl apply -f -");
-        std::process::exit(1);
+    let scope = WatchScope::from_settings(&state.settings);
+    let apis = scope.apis::<Document>(&client);
+    for docs in &apis {
+        if let Err(e) = docs.list(&ListParams::default().limit(1)).await {
+            Err::<(), loco_rs::Error>(ErrorWrapper::from_custom(&format!(
+                "CRD is not queryable; {e:?}. Is the CRD installed?"
+            )))
+            .expect("TODO: panic message");
+            info!("Installation: cargo run --bin crdgen | kubectl apply -f -");
+            std::process::exit(1);
+        }
     }
-    Controller::new(docs, Config::default().any_semantic())
-        .shutdown_on_signal()
-        .run(
-            reconcile,
-            |doc: Arc<Document>, error: &loco_rs::Error, ctx: Arc<kubecontroller::Context>| {
-                error_policy(&doc, error, &ctx)
-            },
-            state.to_context(client).await,
-        )
+    info!(?scope, "Watching Documents");
+    let ctx = state.to_context(client).await;
+    // one controller per watched namespace, driven together as a single stream and sharing the
+    // concurrency limit
+    let concurrency = Concurrency::new(state.settings.concurrency);
+    let controllers = apis.into_iter().map(|docs| {
+        let concurrency = concurrency.clone();
+        Controller::new(docs, Config::default().any_semantic())
+            .graceful_shutdown_on(state.shutdown.stopping())
+            .run(
+                move |doc, ctx| {
+                    let concurrency = concurrency.clone();
+                    async move { concurrency.run(reconcile(doc, ctx)).await }
+                },
+                |doc: Arc<Document>, error: &loco_rs::Error, ctx: Arc<kubecontroller::Context>| {
+                    error_policy(&doc, error, &ctx)
+                },
+                ctx.clone(),
+            )
+            .boxed()
+    });
+    futures::stream::select_all(controllers)
         .filter_map(|x| async move { std::result::Result::ok(x) })
         .for_each(|_| futures::future::ready(()))
         .await;
@@ -355,8 +504,7 @@ mod test {
             .await
             .unwrap()
             .into_iter()
-            .filter(|e| e.reason.as_deref() == Some("HideRequested"))
-            .last()
+            .rfind(|e| e.reason.as_deref() == Some("HideRequested"))
             .unwrap();
         dbg!("got ev: {:?}", &event);
         assert_eq!(event.action.as_deref(), Some("Hiding"));
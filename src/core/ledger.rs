@@ -0,0 +1,210 @@
+//! The local ledger: replication state that has to survive a controller restart.
+//!
+//! It is a single JSON document in `replication.ledger_dir`, rewritten atomically on every change.
+//! Without a directory the ledger only lives in memory, which still lets a copy retry its own
+//! upload but not resume one started by a previous process.
+//!
+//! Besides upload checkpoints it maps the digests of images and layers a destination converts to the
+//! digests they got there, so an image that was converted once is recognised without converting it
+//! again.
+use crate::core::{ErrorWrapper, Result, registry::manifest::Descriptor};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+static LEDGER_FILE: &str = "ledger.json";
+
+/// How far a chunked blob upload got
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadCheckpoint {
+    /// Upload session URL to continue with
+    pub location: String,
+    /// Bytes the registry acknowledged
+    pub offset: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+struct Entries {
+    /// Upload checkpoints by `host/repository@digest`
+    uploads: BTreeMap<String, UploadCheckpoint>,
+    /// What manifests and blobs became once converted, by `conversion/digest`
+    conversions: BTreeMap<String, Descriptor>,
+}
+
+#[derive(Default)]
+pub struct Ledger {
+    path: Option<PathBuf>,
+    entries: Mutex<Entries>,
+}
+
+/// Ledger key of the upload of `digest` to `repository` on `host`
+#[must_use]
+pub fn upload_key(host: &str, repository: &str, digest: &str) -> String {
+    format!("{host}/{repository}@{digest}")
+}
+
+/// Ledger key of `digest` converted as `conversion` describes, e.g. `zstd/sha256:…`
+#[must_use]
+pub fn conversion_key(conversion: &str, digest: &str) -> String {
+    format!("{conversion}/{digest}")
+}
+
+impl Ledger {
+    /// Open the ledger in `dir`, creating the directory if needed
+    ///
+    /// # Errors
+    ///
+    /// When the directory can not be created, or holds a ledger that can not be read
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).map_err(|e| io_error(dir, &e))?;
+        let path = dir.join(LEDGER_FILE);
+        let entries = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(ErrorWrapper::from_serde)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Entries::default(),
+            Err(e) => return Err(io_error(&path, &e)),
+        };
+        Ok(Self {
+            path: Some(path),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn upload(&self, key: &str) -> Option<UploadCheckpoint> {
+        self.entries
+            .lock()
+            .expect("ledger lock poisoned")
+            .uploads
+            .get(key)
+            .cloned()
+    }
+
+    /// Record how far an upload got
+    ///
+    /// # Errors
+    ///
+    /// When the ledger can not be written
+    pub fn checkpoint_upload(&self, key: &str, checkpoint: UploadCheckpoint) -> Result<()> {
+        self.update(|entries| {
+            entries.uploads.insert(key.to_string(), checkpoint);
+        })
+    }
+
+    /// Forget a finished or abandoned upload
+    ///
+    /// # Errors
+    ///
+    /// When the ledger can not be written
+    pub fn finish_upload(&self, key: &str) -> Result<()> {
+        self.update(|entries| {
+            entries.uploads.remove(key);
+        })
+    }
+
+    /// What a manifest or blob became once converted, if it was converted before
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn conversion(&self, key: &str) -> Option<Descriptor> {
+        self.entries
+            .lock()
+            .expect("ledger lock poisoned")
+            .conversions
+            .get(key)
+            .cloned()
+    }
+
+    /// Record what a manifest or blob became once converted
+    ///
+    /// # Errors
+    ///
+    /// When the ledger can not be written
+    pub fn record_conversion(&self, key: &str, converted: &Descriptor) -> Result<()> {
+        if self.conversion(key).as_ref() == Some(converted) {
+            return Ok(());
+        }
+        self.update(|entries| {
+            entries.conversions.insert(key.to_string(), converted.clone());
+        })
+    }
+
+    /// The ledger as the JSON document it is stored as
+    ///
+    /// # Errors
+    ///
+    /// When the entries can not be serialized
+    #[allow(clippy::missing_panics_doc)]
+    pub fn export(&self) -> Result<String> {
+        let entries = self.entries.lock().expect("ledger lock poisoned");
+        serde_json::to_string_pretty(&*entries).map_err(ErrorWrapper::from_serde)
+    }
+
+    #[allow(clippy::significant_drop_tightening)] // held while writing, so writes land in order
+    fn update(&self, change: impl FnOnce(&mut Entries)) -> Result<()> {
+        let mut entries = self.entries.lock().expect("ledger lock poisoned");
+        change(&mut entries);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let bytes = serde_json::to_vec_pretty(&*entries).map_err(ErrorWrapper::from_serde)?;
+        // write aside and rename, so a crash never leaves a torn ledger behind
+        let staged = path.with_extension("json.tmp");
+        std::fs::write(&staged, bytes).map_err(|e| io_error(&staged, &e))?;
+        std::fs::rename(&staged, path).map_err(|e| io_error(path, &e))
+    }
+}
+
+fn io_error(path: &Path, e: &std::io::Error) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("ledger {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ledger, UploadCheckpoint, conversion_key, upload_key};
+    use crate::core::registry::manifest::{Descriptor, media_types};
+
+    #[test]
+    fn checkpoints_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = upload_key("registry.example.com", "prod/app", "sha256:abc");
+        let checkpoint = UploadCheckpoint {
+            location: "https://registry.example.com/v2/prod/app/blobs/uploads/1".into(),
+            offset: 1024,
+        };
+        Ledger::open(dir.path())
+            .unwrap()
+            .checkpoint_upload(&key, checkpoint.clone())
+            .unwrap();
+
+        let reopened = Ledger::open(dir.path()).unwrap();
+        assert_eq!(reopened.upload(&key), Some(checkpoint));
+        reopened.finish_upload(&key).unwrap();
+        assert_eq!(Ledger::open(dir.path()).unwrap().upload(&key), None);
+    }
+
+    #[test]
+    fn conversions_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = conversion_key("zstd", "sha256:abc");
+        let converted = Descriptor {
+            media_type: media_types::OCI_LAYER_ZSTD.into(),
+            digest: "sha256:def".into(),
+            size: 512,
+            ..Descriptor::default()
+        };
+        Ledger::open(dir.path())
+            .unwrap()
+            .record_conversion(&key, &converted)
+            .unwrap();
+
+        let reopened = Ledger::open(dir.path()).unwrap();
+        assert_eq!(reopened.conversion(&key), Some(converted));
+        assert_eq!(reopened.conversion(&conversion_key("gzip", "sha256:abc")), None);
+        assert!(reopened.export().unwrap().contains(&key));
+    }
+}
@@ -20,9 +20,34 @@ impl ErrorWrapper {
         LocoError::wrap(err)
     }
 
+    #[must_use]
+    pub fn from_http(err: reqwest::Error) -> LocoError {
+        LocoError::wrap(err)
+    }
+
     #[must_use]
     pub fn from_custom(err: &str) -> LocoError {
-        LocoError::wrap(std::io::Error::new(std::io::ErrorKind::Other, err))
+        LocoError::wrap(std::io::Error::other(err))
+    }
+
+    /// The error of type `T` behind `err`, anywhere in its source chain
+    #[must_use]
+    pub fn find<T>(err: &LocoError) -> Option<&T>
+    where
+        T: std::error::Error + 'static,
+    {
+        let mut current: Option<&(dyn std::error::Error + 'static)> = Some(err);
+        while let Some(e) = current {
+            if let Some(found) = e.downcast_ref() {
+                return Some(found);
+            }
+            // wrapped errors are transparent, their `source` skips the wrapped error itself
+            current = match e.downcast_ref::<LocoError>() {
+                Some(LocoError::Any(inner)) => Some(inner.as_ref()),
+                _ => e.source(),
+            };
+        }
+        None
     }
 }
 
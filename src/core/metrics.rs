@@ -1,14 +1,18 @@
 #![allow(clippy::missing_errors_doc)]
 #![allow(clippy::unnecessary_struct_initialization)]
 #![allow(clippy::unused_async)]
-use crate::core::{LocoErrorExt, kubecontroller::Document};
+use crate::core::{
+    LocoErrorExt,
+    drift::{Drift, DriftKind},
+    replication::CopyReport,
+};
 use axum::debug_handler;
 use kube::ResourceExt;
 use loco_rs::{Error as LocoError, prelude::*};
 use opentelemetry::trace::TraceId;
 use prometheus_client::{
     encoding::EncodeLabelSet,
-    metrics::{counter::Counter, exemplar::HistogramWithExemplars, family::Family},
+    metrics::{counter::Counter, exemplar::HistogramWithExemplars, family::Family, gauge::Gauge},
     registry::{Registry, Unit},
 };
 use std::sync::Arc;
@@ -26,17 +30,31 @@ pub fn routes() -> Routes {
 
 #[derive(Clone)]
 pub struct Metrics {
+    /// `Document` reconciles
     pub reconcile: ReconcileMetrics,
+    /// `ContainerReplicator` reconciles
+    pub replicator: ReconcileMetrics,
+    pub replication: ReplicationMetrics,
+    pub drift: DriftMetrics,
     pub registry: Arc<Registry>,
 }
 
 impl Default for Metrics {
     fn default() -> Self {
-        let mut registry = Registry::with_prefix("doc_ctrl_reconcile");
-        let reconcile = ReconcileMetrics::default().register(&mut registry);
+        let mut registry = Registry::default();
+        let reconcile =
+            ReconcileMetrics::default().register(registry.sub_registry_with_prefix("doc_ctrl_reconcile"));
+        let replicator = ReconcileMetrics::default()
+            .register(registry.sub_registry_with_prefix("yair_replicator_reconcile"));
+        let replication =
+            ReplicationMetrics::default().register(registry.sub_registry_with_prefix("yair_replication"));
+        let drift = DriftMetrics::default().register(registry.sub_registry_with_prefix("yair_drift"));
         Self {
             registry: Arc::new(registry),
             reconcile,
+            replicator,
+            replication,
+            drift,
         }
     }
 }
@@ -96,10 +114,10 @@ impl ReconcileMetrics {
         self
     }
 
-    pub fn set_failure(&self, doc: &Document, e: &LocoError) {
+    pub fn set_failure(&self, obj: &impl ResourceExt, e: &LocoError) {
         self.failures
             .get_or_create(&ErrorLabels {
-                instance: doc.name_any(),
+                instance: obj.name_any(),
                 error: e.metric_label(),
             })
             .inc();
@@ -116,6 +134,110 @@ impl ReconcileMetrics {
     }
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct OutcomeLabels {
+    /// `copied`, `unchanged` or `failed`
+    pub outcome: String,
+}
+
+/// Image copies done by the replicator
+#[derive(Clone, Default)]
+pub struct ReplicationMetrics {
+    pub jobs: Family<OutcomeLabels, Counter>,
+    pub bytes: Counter,
+    /// Blobs read from the blob cache instead of the source
+    pub cache_hits: Counter,
+    /// Blobs pulled from the source into the blob cache
+    pub cache_misses: Counter,
+    /// Bytes the blob cache saved pulling from sources
+    pub cache_saved: Counter,
+    /// Layers recompressed for destinations with a `layerCompression`
+    pub recompressed: Counter,
+}
+
+impl ReplicationMetrics {
+    #[must_use]
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register("jobs", "finished replication jobs", self.jobs.clone());
+        r.register_with_unit("copied", "blob bytes copied", Unit::Bytes, self.bytes.clone());
+        r.register(
+            "cache_hits",
+            "blobs read from the blob cache",
+            self.cache_hits.clone(),
+        );
+        r.register(
+            "cache_misses",
+            "blobs pulled into the blob cache",
+            self.cache_misses.clone(),
+        );
+        r.register_with_unit(
+            "cache_saved",
+            "blob bytes not pulled from sources thanks to the blob cache",
+            Unit::Bytes,
+            self.cache_saved.clone(),
+        );
+        r.register(
+            "recompressed",
+            "layers recompressed for their destination",
+            self.recompressed.clone(),
+        );
+        self
+    }
+
+    pub fn observe(&self, result: &crate::core::Result<CopyReport>) {
+        let outcome = result.as_ref().map_or("failed", |report| {
+            self.bytes.inc_by(report.bytes_copied);
+            self.cache_hits.inc_by(report.cache_hits as u64);
+            self.cache_misses.inc_by(report.cache_misses as u64);
+            self.cache_saved.inc_by(report.bytes_from_cache);
+            self.recompressed.inc_by(report.layers_recompressed as u64);
+            if report.manifests_copied == 0 {
+                "unchanged"
+            } else {
+                "copied"
+            }
+        });
+        self.jobs
+            .get_or_create(&OutcomeLabels {
+                outcome: outcome.into(),
+            })
+            .inc();
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct DriftLabels {
+    /// `namespace/name` of the `ContainerReplicator`
+    pub replicator: String,
+    /// `missing`, `mutated` or `foreign`
+    pub kind: String,
+}
+
+/// Drifted images found by the last audit of every replicator
+#[derive(Clone, Default)]
+pub struct DriftMetrics {
+    pub images: Family<DriftLabels, Gauge>,
+}
+
+impl DriftMetrics {
+    #[must_use]
+    pub fn register(self, r: &mut Registry) -> Self {
+        r.register("images", "drifted images by kind", self.images.clone());
+        self
+    }
+
+    pub fn set(&self, replicator: &str, drift: &[Drift]) {
+        for kind in DriftKind::ALL {
+            let count = drift.iter().filter(|d| d.kind == kind).count();
+            self.images
+                .get_or_create(&DriftLabels {
+                    replicator: replicator.into(),
+                    kind: kind.to_string(),
+                })
+                .set(i64::try_from(count).unwrap_or(i64::MAX));
+        }
+    }
+}
 
 pub struct ReconcileMeasurer {
     start: Instant,
@@ -0,0 +1,297 @@
+//! Pull-through mirroring for pods that can not pull their images.
+//!
+//! With `mirror.enabled`, pods stuck in `ErrImagePull` or `ImagePullBackOff` on an image that lives in
+//! a `SourceRepository` get that image replicated on demand to the cluster-local
+//! `DestinationRepository` named by `mirror.destination`, e.g. for nodes pulling through that mirror
+//! whose workloads were rolled out before it caught up. Pods owned by a controller are then deleted so
+//! their replacement pulls again; bare pods are left to the kubelet's back-off.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{DestinationRepository, SourceRepository},
+    kubecontroller::{Context, State},
+    promotion::{self, SelectedImage},
+    registry::{Reference, RepositoryLocation},
+    replication::{Destination, ReplicationJob},
+    replicatorcontroller,
+    scope::{Concurrency, WatchScope},
+};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    Resource,
+    api::{Api, DeleteParams, ListParams, ResourceExt},
+    client::Client,
+    runtime::{
+        controller::{Action, Controller},
+        events::{Event, EventType},
+        watcher::Config,
+    },
+};
+use std::{collections::HashSet, sync::Arc};
+use tracing::{error, info, instrument, warn};
+
+/// Container waiting reasons of failed image pulls
+pub static PULL_FAILURES: [&str; 2] = ["ErrImagePull", "ImagePullBackOff"];
+
+/// Images of the containers of `pod` waiting on a failed pull, init containers first
+#[must_use]
+pub fn failed_pulls(pod: &Pod) -> Vec<&str> {
+    let Some(status) = &pod.status else {
+        return vec![];
+    };
+    let mut images: Vec<&str> = status
+        .init_container_statuses
+        .iter()
+        .flatten()
+        .chain(status.container_statuses.iter().flatten())
+        .filter(|container| {
+            let waiting = container.state.as_ref().and_then(|s| s.waiting.as_ref());
+            waiting
+                .and_then(|w| w.reason.as_deref())
+                .is_some_and(|reason| PULL_FAILURES.contains(&reason))
+        })
+        .map(|container| container.image.as_str())
+        .collect();
+    let mut seen = HashSet::new();
+    images.retain(|image| seen.insert(*image));
+    images
+}
+
+#[instrument(skip(ctx, pod), fields(pod = %pod.name_any()))]
+pub async fn reconcile(pod: Arc<Pod>, ctx: Arc<Context>) -> Result<Action> {
+    let images = failed_pulls(&pod);
+    if images.is_empty() {
+        return Ok(Action::await_change());
+    }
+    let ns = pod.namespace().unwrap_or_default();
+    let Some(reference) = &ctx.settings.mirror.destination else {
+        return Err(ErrorWrapper::from_custom(
+            "mirror.destination is not set, can not mirror images",
+        ));
+    };
+    let destination: DestinationRepository = replicatorcontroller::get(&ctx.client, &ns, reference).await?;
+    let destinations = [Destination::from(&destination.spec)];
+    let sources = sources(&ctx).await?;
+
+    let mut mirrored = vec![];
+    for image in images {
+        let selected = SelectedImage {
+            workload: format!("pod/{}", pod.name_any()),
+            image: image.parse::<Reference>()?,
+        };
+        let Some(job) = job(&selected, &sources, &destinations)? else {
+            info!(%image, "Image is in no source repository, not mirroring");
+            continue;
+        };
+        let result = ctx.replicator.replicate(&job).await;
+        ctx.metrics.replication.observe(&result);
+        let report = result?;
+        if report.manifests_copied == 0 {
+            // the mirror already had it, so the pull fails for another reason
+            warn!(%image, "Mirror already has the image");
+            continue;
+        }
+        mirrored.push(job.destination.to_string());
+    }
+    ctx.replicator
+        .flush(destinations.iter().map(|d| &d.location.endpoint))
+        .await?;
+    if mirrored.is_empty() {
+        return Ok(Action::await_change());
+    }
+
+    let owned = pod.owner_references().iter().any(|o| o.controller == Some(true));
+    let note = if owned {
+        format!("Mirrored {}, restarting the pod", mirrored.join(", "))
+    } else {
+        format!("Mirrored {}", mirrored.join(", "))
+    };
+    ctx.recorder
+        .publish(
+            &Event {
+                type_: EventType::Normal,
+                reason: "Mirrored".into(),
+                note: Some(note),
+                action: "Mirroring".into(),
+                secondary: None,
+            },
+            &pod.object_ref(&()),
+        )
+        .await
+        .map_err(ErrorWrapper::from_kube)?;
+    if owned {
+        let pods: Api<Pod> = Api::namespaced(ctx.client.clone(), &ns);
+        pods.delete(&pod.name_any(), &DeleteParams::default())
+            .await
+            .map_err(ErrorWrapper::from_kube)?;
+        info!(images = mirrored.len(), "Mirrored images and deleted the pod");
+    }
+    Ok(Action::await_change())
+}
+
+/// Every source repository the controller can see
+async fn sources(ctx: &Context) -> Result<Vec<RepositoryLocation>> {
+    let mut sources = vec![];
+    for api in WatchScope::from_settings(&ctx.settings).apis::<SourceRepository>(&ctx.client) {
+        let list = api
+            .list(&ListParams::default())
+            .await
+            .map_err(ErrorWrapper::from_kube)?;
+        sources.extend(list.iter().map(|s| RepositoryLocation::from(&s.spec.repository)));
+    }
+    Ok(sources)
+}
+
+/// The job copying `selected` from the first source repository that has it to the mirror
+fn job(
+    selected: &SelectedImage,
+    sources: &[RepositoryLocation],
+    destinations: &[Destination],
+) -> Result<Option<ReplicationJob>> {
+    for source in sources {
+        let jobs = promotion::plan(std::slice::from_ref(selected), source, destinations, None)?;
+        if let Some(job) = jobs.into_iter().next() {
+            return Ok(Some(job));
+        }
+    }
+    Ok(None)
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn error_policy(pod: Arc<Pod>, error: &loco_rs::Error, ctx: Arc<Context>) -> Action {
+    warn!(pod = %pod.name_any(), "mirroring failed: {:?}", error);
+    Action::requeue(ctx.settings.requeue.error_interval())
+}
+
+/// Watch pods for failed pulls until shutdown, if `mirror.enabled`
+///
+/// # Panics
+///
+/// Panics if no kube client can be created.
+pub async fn run(state: State) {
+    let settings = &state.settings().mirror;
+    if !settings.enabled {
+        return;
+    }
+    if settings.destination.is_none() {
+        error!("mirror.enabled needs a mirror.destination, not watching pods");
+        return;
+    }
+    let client = Client::try_default().await.expect("failed to create kube Client");
+    let scope = WatchScope::from_settings(state.settings());
+    info!(?scope, "Watching Pods for failed image pulls");
+    let ctx = state.to_context(client.clone()).await;
+    let concurrency = Concurrency::new(state.settings().concurrency);
+    let controllers = scope.apis::<Pod>(&client).into_iter().map(|pods| {
+        let concurrency = concurrency.clone();
+        Controller::new(pods, Config::default())
+            .graceful_shutdown_on(state.shutdown().stopping())
+            .run(
+                move |pod, ctx| {
+                    let concurrency = concurrency.clone();
+                    async move { concurrency.run(reconcile(pod, ctx)).await }
+                },
+                error_policy,
+                ctx.clone(),
+            )
+            .boxed()
+    });
+    futures::stream::select_all(controllers)
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()))
+        .await;
+}
+
+// Mock tests relying on fixtures.rs, with a fake registry on both ends
+#[cfg(test)]
+mod test {
+    use super::{failed_pulls, reconcile};
+    use crate::core::{
+        crd::{DestinationRepository, RepositoryRef, SourceRepository},
+        fixtures::{MirrorObjects, Scenario, timeout_after_1s},
+        kubecontroller::Context,
+        registry::fake::FakeRegistry,
+        settings::{MirrorSettings, Settings},
+    };
+    use k8s_openapi::api::core::v1::Pod;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn pod(images: &[(&str, &str)]) -> Pod {
+        let statuses: Vec<_> = images
+            .iter()
+            .enumerate()
+            .map(|(i, (image, reason))| {
+                json!({
+                    "name": format!("c{i}"), "image": image, "imageID": "",
+                    "ready": false, "restartCount": 0,
+                    "state": { "waiting": { "reason": reason } },
+                })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "metadata": {
+                "name": "app-1",
+                "namespace": "default",
+                "ownerReferences": [{
+                    "apiVersion": "apps/v1", "kind": "ReplicaSet", "name": "app", "uid": "1",
+                    "controller": true,
+                }],
+            },
+            "status": { "phase": "Pending", "containerStatuses": statuses },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_failed_pulls_are_picked() {
+        let pod = pod(&[
+            ("ci.example.com/ci/app:1", "ImagePullBackOff"),
+            ("ci.example.com/ci/sidecar:1", "ContainerCreating"),
+            ("ci.example.com/ci/proxy:1", "ErrImagePull"),
+        ]);
+        assert_eq!(failed_pulls(&pod), [
+            "ci.example.com/ci/app:1",
+            "ci.example.com/ci/proxy:1"
+        ]);
+        assert!(failed_pulls(&Pod::default()).is_empty());
+    }
+
+    fn mirroring(testctx: &Context) -> Arc<Context> {
+        let mut ctx = testctx.clone();
+        ctx.settings = Arc::new(Settings {
+            mirror: MirrorSettings {
+                enabled: true,
+                destination: Some(RepositoryRef {
+                    name: "prod".into(),
+                    namespace: None,
+                }),
+            },
+            ..Settings::default()
+        });
+        Arc::new(ctx)
+    }
+
+    #[tokio::test]
+    async fn missing_images_are_mirrored_and_the_pod_restarted() {
+        let (source, mirror) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let pod = pod(&[
+            (&image, "ErrImagePull"),
+            ("docker.io/library/nginx:1", "ImagePullBackOff"),
+        ]);
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::Mirror(Box::new(MirrorObjects {
+            pod: pod.clone(),
+            source: SourceRepository::test(&source.host),
+            destination: DestinationRepository::test(&mirror.host),
+        })));
+        reconcile(Arc::new(pod), mirroring(&testctx))
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        let manifests = &mirror.store().manifests;
+        assert!(manifests.contains_key(&("prod/app".to_string(), "1.0.0".to_string())));
+    }
+}
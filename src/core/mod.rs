@@ -1,8 +1,27 @@
+pub mod blobcache;
+pub mod crd;
+pub mod drift;
 pub mod fixtures;
 pub mod kubecontroller;
+pub mod ledger;
 
 #[allow(clippy::module_inception)] // Allow module inception, as it is used in the controller module
 pub mod lib;
 pub mod metrics;
+pub mod mirrorcontroller;
+pub mod notifications;
+pub mod offline;
+pub mod promotion;
+pub mod registry;
+pub mod replication;
+pub mod replicatorcontroller;
+pub mod rewrite;
+pub mod scope;
+pub mod settings;
+pub mod shutdown;
+pub mod tagpolicy;
 pub mod telemetry;
+pub mod verification;
+pub mod vulnerabilities;
+pub mod webhook;
 pub use lib::*;
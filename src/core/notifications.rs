@@ -0,0 +1,319 @@
+//! Registry push notifications, so replicators reconcile as soon as an image lands in their source.
+//!
+//! Three payloads are understood: Docker Distribution notification envelopes, Harbor webhooks and
+//! the Pub/Sub push messages of GCP Artifact Registry. Only the references of pushed images are read
+//! from them. The replicators whose `SourceRepository` holds a pushed image are then reconciled as
+//! usual, so a notification can make yair replicate sooner but never copy anything else. They are
+//! matched against the controller's caches, a push costs no request to the API server.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{ContainerReplicator, SourceRepository},
+    registry::{Reference, RepositoryLocation},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use kube::{
+    ResourceExt,
+    runtime::reflector::{ObjectRef, Store},
+};
+use serde::Deserialize;
+
+/// Images pushed according to a Docker Distribution notification envelope
+///
+/// # Errors
+///
+/// When `body` is not a notification envelope
+pub fn distribution(body: &[u8]) -> Result<Vec<Reference>> {
+    #[derive(Deserialize)]
+    struct Envelope {
+        events: Vec<Event>,
+    }
+    #[derive(Deserialize)]
+    struct Event {
+        action: String,
+        target: Target,
+        #[serde(default)]
+        request: Request,
+    }
+    #[derive(Deserialize)]
+    struct Target {
+        repository: String,
+        #[serde(default)]
+        digest: Option<String>,
+        #[serde(default)]
+        tag: Option<String>,
+        #[serde(default)]
+        url: String,
+    }
+    #[derive(Deserialize, Default)]
+    struct Request {
+        #[serde(default)]
+        host: String,
+    }
+
+    let envelope: Envelope = serde_json::from_slice(body).map_err(ErrorWrapper::from_serde)?;
+    Ok(envelope
+        .events
+        .into_iter()
+        // layer uploads are announced as pushes too
+        .filter(|event| event.action == "push" && event.target.url.contains("/manifests/"))
+        .map(|event| {
+            let host = match event.request.host.as_str() {
+                "" => url_host(&event.target.url),
+                host => host.to_string(),
+            };
+            Reference {
+                registry: host,
+                repository: event.target.repository,
+                tag: event.target.tag,
+                digest: event.target.digest,
+            }
+        })
+        .collect())
+}
+
+/// Images pushed according to a Harbor `PUSH_ARTIFACT` webhook; other event types are ignored
+///
+/// # Errors
+///
+/// When `body` is not a Harbor webhook, or names an invalid image
+pub fn harbor(body: &[u8]) -> Result<Vec<Reference>> {
+    #[derive(Deserialize)]
+    struct Webhook {
+        #[serde(rename = "type")]
+        type_: String,
+        event_data: EventData,
+    }
+    #[derive(Deserialize)]
+    struct EventData {
+        #[serde(default)]
+        resources: Vec<Resource>,
+    }
+    #[derive(Deserialize)]
+    struct Resource {
+        resource_url: String,
+    }
+
+    let webhook: Webhook = serde_json::from_slice(body).map_err(ErrorWrapper::from_serde)?;
+    if webhook.type_ != "PUSH_ARTIFACT" {
+        return Ok(vec![]);
+    }
+    webhook
+        .event_data
+        .resources
+        .iter()
+        .map(|resource| resource.resource_url.parse())
+        .collect()
+}
+
+/// The image of an Artifact Registry `INSERT` notification, delivered by a Pub/Sub push
+/// subscription; deletions are ignored
+///
+/// # Errors
+///
+/// When `body` is not a Pub/Sub push, or its message does not decode to a notification
+pub fn artifact_registry(body: &[u8]) -> Result<Vec<Reference>> {
+    #[derive(Deserialize)]
+    struct Push {
+        message: Message,
+    }
+    #[derive(Deserialize)]
+    struct Message {
+        data: String,
+    }
+    #[derive(Deserialize)]
+    struct Notification {
+        action: String,
+        #[serde(default)]
+        digest: Option<String>,
+        #[serde(default)]
+        tag: Option<String>,
+    }
+
+    let push: Push = serde_json::from_slice(body).map_err(ErrorWrapper::from_serde)?;
+    let data = STANDARD
+        .decode(push.message.data)
+        .map_err(|e| ErrorWrapper::from_custom(&format!("invalid Pub/Sub message data: {e}")))?;
+    let notification: Notification = serde_json::from_slice(&data).map_err(ErrorWrapper::from_serde)?;
+    if notification.action != "INSERT" {
+        return Ok(vec![]);
+    }
+    notification
+        .tag
+        .or(notification.digest)
+        .map(|image| image.parse())
+        .into_iter()
+        .collect()
+}
+
+fn url_host(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or_default().to_string()
+}
+
+/// The replicators cached in `replicators` whose source repository, cached in one of `sources`,
+/// holds `image`
+#[must_use]
+pub fn replicators(
+    replicators: &Store<ContainerReplicator>,
+    sources: &[Store<SourceRepository>],
+    image: &Reference,
+) -> Vec<ObjectRef<ContainerReplicator>> {
+    replicators
+        .state()
+        .iter()
+        .filter(|replicator| {
+            let reference = &replicator.spec.source_ref;
+            let namespace = reference.namespace.clone().or_else(|| replicator.namespace());
+            let mut key = ObjectRef::new(&reference.name);
+            if let Some(namespace) = &namespace {
+                key = key.within(namespace);
+            }
+            sources
+                .iter()
+                .find_map(|store| store.get(&key))
+                .is_some_and(|source| {
+                    RepositoryLocation::from(&source.spec.repository)
+                        .relative(image)
+                        .is_some()
+                })
+        })
+        .map(|replicator| ObjectRef::from_obj(&**replicator))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{artifact_registry, distribution, harbor, replicators};
+    use crate::core::crd::{ContainerReplicator, SourceRepository};
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use kube::runtime::{
+        reflector::{self, ObjectRef, Store},
+        watcher,
+    };
+    use serde_json::json;
+
+    fn images(references: &[super::Reference]) -> Vec<String> {
+        references.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn reads_manifest_pushes_from_distribution_envelopes() {
+        let envelope = json!({ "events": [
+            {
+                "action": "push",
+                "target": {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "repository": "ci/app", "digest": "sha256:aaa", "tag": "1.0.0",
+                    "url": "https://ci.example.com/v2/ci/app/manifests/sha256:aaa",
+                },
+                "request": { "host": "ci.example.com" },
+            },
+            {
+                "action": "push",
+                "target": {
+                    "repository": "ci/app", "digest": "sha256:bbb",
+                    "url": "https://ci.example.com/v2/ci/app/blobs/sha256:bbb",
+                },
+            },
+            {
+                "action": "pull",
+                "target": {
+                    "repository": "ci/app", "digest": "sha256:aaa",
+                    "url": "https://ci.example.com/v2/ci/app/manifests/sha256:aaa",
+                },
+            },
+            {
+                "action": "push",
+                "target": {
+                    "repository": "ci/job", "digest": "sha256:ccc",
+                    "url": "http://registry:5000/v2/ci/job/manifests/sha256:ccc",
+                },
+            },
+        ]});
+        let pushed = distribution(envelope.to_string().as_bytes()).unwrap();
+        assert_eq!(images(&pushed), [
+            "ci.example.com/ci/app:1.0.0@sha256:aaa",
+            "registry:5000/ci/job@sha256:ccc",
+        ]);
+        assert!(distribution(b"{}").is_err());
+    }
+
+    #[test]
+    fn reads_artifact_pushes_from_harbor_webhooks() {
+        let webhook = |type_: &str| {
+            json!({
+                "type": type_,
+                "occur_at": 1_700_000_000,
+                "operator": "ci",
+                "event_data": {
+                    "resources": [{
+                        "digest": "sha256:aaa", "tag": "1.0.0",
+                        "resource_url": "harbor.example.com/ci/app:1.0.0",
+                    }],
+                    "repository": { "name": "app", "namespace": "ci", "repo_full_name": "ci/app" },
+                },
+            })
+            .to_string()
+        };
+        let pushed = harbor(webhook("PUSH_ARTIFACT").as_bytes()).unwrap();
+        assert_eq!(images(&pushed), ["harbor.example.com/ci/app:1.0.0"]);
+        assert!(harbor(webhook("DELETE_ARTIFACT").as_bytes()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_inserts_from_artifact_registry_pubsub_pushes() {
+        let push = |notification: serde_json::Value| {
+            json!({
+                "message": {
+                    "data": STANDARD.encode(notification.to_string()),
+                    "messageId": "1",
+                    "publishTime": "2026-10-19T09:00:00Z",
+                },
+                "subscription": "projects/example-ci/subscriptions/yair",
+            })
+            .to_string()
+        };
+        let inserted = push(json!({
+            "action": "INSERT",
+            "digest": "europe-west1-docker.pkg.dev/example-ci/ci/app@sha256:aaa",
+            "tag": "europe-west1-docker.pkg.dev/example-ci/ci/app:1.0.0",
+        }));
+        let pushed = artifact_registry(inserted.as_bytes()).unwrap();
+        assert_eq!(images(&pushed), [
+            "europe-west1-docker.pkg.dev/example-ci/ci/app:1.0.0"
+        ]);
+        let deleted = push(json!({
+            "action": "DELETE",
+            "digest": "europe-west1-docker.pkg.dev/example-ci/ci/app@sha256:aaa",
+        }));
+        assert!(artifact_registry(deleted.as_bytes()).unwrap().is_empty());
+        assert!(artifact_registry(br#"{"message":{"data":"not base64!"}}"#).is_err());
+    }
+
+    fn cached<K>(objects: Vec<K>) -> Store<K>
+    where
+        K: kube::Resource<DynamicType = ()> + Clone + 'static,
+    {
+        let (store, mut writer) = reflector::store();
+        for object in objects {
+            writer.apply_watcher_event(&watcher::Event::Apply(object));
+        }
+        store
+    }
+
+    #[test]
+    fn pushes_match_replicators_of_their_source() {
+        let mut elsewhere = ContainerReplicator::test();
+        elsewhere.metadata.name = Some("other".into());
+        elsewhere.spec.source_ref.name = "missing".into();
+        let replicators_cache = cached(vec![ContainerReplicator::test(), elsewhere]);
+        let sources = [cached(vec![SourceRepository::test("ci.example.com")])];
+
+        let image = "ci.example.com/ci/app:1.0.0".parse().unwrap();
+        assert_eq!(replicators(&replicators_cache, &sources, &image), [
+            ObjectRef::new("app").within("default")
+        ]);
+        let image = "other.example.com/ci/app:1.0.0".parse().unwrap();
+        assert!(replicators(&replicators_cache, &sources, &image).is_empty());
+    }
+}
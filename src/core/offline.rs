@@ -0,0 +1,280 @@
+//! Promotion planning over Kubernetes YAML instead of a cluster, for `yair plan`.
+//!
+//! Deployments, Jobs and the yair objects are read from manifests (`v1alpha1` objects are upgraded
+//! like the conversion webhook would), and every `ContainerReplicator` is planned with the same
+//! image selection and rewrite rules the controller uses. Nothing that needs a registry is
+//! evaluated: tag selectors are skipped and tag templates are left unrendered, with a warning each.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{
+        ContainerReplicator, DestinationRepository, GROUP, RepositoryProvider, RepositoryRef,
+        STORAGE_VERSION, SourceRepository, WorkloadSelector, conversion,
+    },
+    promotion::{self, SelectedImage},
+    registry::RepositoryLocation,
+    replication::{Destination, ReplicationJob},
+};
+use k8s_openapi::api::{apps::v1::Deployment, batch::v1::Job, core::v1::PodSpec};
+use kube::ResourceExt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// `(namespace, name)` of an object, defaulting to the `default` namespace
+type Key = (String, String);
+
+/// The objects found in a set of manifests
+#[derive(Default)]
+pub struct Manifests {
+    sources: BTreeMap<Key, SourceRepository>,
+    destinations: BTreeMap<Key, DestinationRepository>,
+    replicators: Vec<ContainerReplicator>,
+    deployments: BTreeMap<Key, PodSpec>,
+    jobs: BTreeMap<Key, PodSpec>,
+}
+
+/// What promoting one replicator would copy
+#[derive(Debug, Default)]
+pub struct ReplicatorPlan {
+    /// `namespace/name` of the replicator
+    pub replicator: String,
+    pub jobs: Vec<ReplicationJob>,
+    /// Parts of the replicator that could not be evaluated offline
+    pub warnings: Vec<String>,
+}
+
+impl Manifests {
+    /// Add the objects of a (multi-document) YAML stream; unrelated kinds are ignored
+    ///
+    /// # Errors
+    ///
+    /// When the stream is not valid YAML, or an object of a known kind does not match its schema
+    pub fn add(&mut self, yaml: &str) -> Result<()> {
+        for document in serde_yaml::Deserializer::from_str(yaml) {
+            let value =
+                Value::deserialize(document).map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?;
+            self.add_object(value)?;
+        }
+        Ok(())
+    }
+
+    fn add_object(&mut self, object: Value) -> Result<()> {
+        let field = |name| {
+            object
+                .get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        let (kind, api_version) = (field("kind"), field("apiVersion"));
+        if kind.ends_with("List") {
+            let items = object.get("items").and_then(Value::as_array).cloned();
+            for item in items.unwrap_or_default() {
+                self.add_object(item)?;
+            }
+            return Ok(());
+        }
+        let object = if api_version.starts_with(GROUP) {
+            conversion::convert_object(object, &format!("{GROUP}/{STORAGE_VERSION}"))?
+        } else {
+            object
+        };
+        match (api_version.split('/').next(), kind.as_str()) {
+            (Some(group), "SourceRepository") if group == GROUP => {
+                let source: SourceRepository = parse(object)?;
+                self.sources.insert(key(&source), source);
+            }
+            (Some(group), "DestinationRepository") if group == GROUP => {
+                let destination: DestinationRepository = parse(object)?;
+                self.destinations.insert(key(&destination), destination);
+            }
+            (Some(group), "ContainerReplicator") if group == GROUP => self.replicators.push(parse(object)?),
+            (Some("apps"), "Deployment") => {
+                let deployment: Deployment = parse(object)?;
+                if let Some(spec) = deployment.spec.clone().and_then(|s| s.template.spec) {
+                    self.deployments.insert(key(&deployment), spec);
+                }
+            }
+            (Some("batch"), "Job") => {
+                let job: Job = parse(object)?;
+                if let Some(spec) = job.spec.clone().and_then(|s| s.template.spec) {
+                    self.jobs.insert(key(&job), spec);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Plan every replicator found, in the order they were read
+    #[must_use]
+    pub fn plan(&self) -> Vec<ReplicatorPlan> {
+        self.replicators.iter().map(|r| self.plan_replicator(r)).collect()
+    }
+
+    fn plan_replicator(&self, replicator: &ContainerReplicator) -> ReplicatorPlan {
+        let namespace = namespace(replicator);
+        let spec = &replicator.spec;
+        let mut plan = ReplicatorPlan {
+            replicator: format!("{namespace}/{}", replicator.name_any()),
+            ..ReplicatorPlan::default()
+        };
+        let Some(source) = self.sources.get(&reference(&namespace, &spec.source_ref)) else {
+            plan.warnings
+                .push(format!("SourceRepository {} not found", spec.source_ref.name));
+            return plan;
+        };
+        let mut destinations = vec![];
+        for destination_ref in &spec.destination_refs {
+            match self.destinations.get(&reference(&namespace, destination_ref)) {
+                Some(destination) => destinations.push(Destination::from(&destination.spec)),
+                None => plan.warnings.push(format!(
+                    "DestinationRepository {} not found",
+                    destination_ref.name
+                )),
+            }
+        }
+        let mut images = vec![];
+        let workloads = [
+            (
+                "deployment",
+                &spec.promotion_selectors.deployments,
+                &self.deployments,
+            ),
+            ("job", &spec.promotion_selectors.jobs, &self.jobs),
+        ];
+        for (kind, selectors, specs) in workloads {
+            for selector in selectors {
+                match Self::select(kind, selector, specs, &namespace) {
+                    Ok(selected) => images.extend(selected),
+                    Err(e) => plan.warnings.push(e.to_string()),
+                }
+            }
+        }
+        for selector in &spec.tag_selectors {
+            plan.warnings.push(format!(
+                "tag selector for {} needs the source registry, skipped",
+                selector.image
+            ));
+        }
+        if let Some(template) = spec.rewrite.as_ref().and_then(|rules| rules.tag.as_deref()) {
+            plan.warnings.push(format!(
+                "tag template `{template}` needs the source image, not rendered"
+            ));
+        }
+        if source.spec.repository.provider == RepositoryProvider::OciLayout {
+            plan.warnings.push(format!(
+                "OCI layout {} is not read offline, its images are skipped",
+                source.spec.repository.path.as_deref().unwrap_or_default()
+            ));
+        }
+        let source = RepositoryLocation::from(&source.spec.repository);
+        match promotion::plan(&images, &source, &destinations, spec.rewrite.as_ref()) {
+            Ok(jobs) => plan.jobs = jobs,
+            Err(e) => plan.warnings.push(e.to_string()),
+        }
+        plan
+    }
+
+    fn select(
+        kind: &str,
+        selector: &WorkloadSelector,
+        specs: &BTreeMap<Key, PodSpec>,
+        namespace: &str,
+    ) -> Result<Vec<SelectedImage>> {
+        let spec = specs
+            .get(&(namespace.to_string(), selector.name.clone()))
+            .ok_or_else(|| {
+                ErrorWrapper::from_custom(&format!("{kind} {namespace}/{} not found", selector.name))
+            })?;
+        promotion::select(selector, &format!("{kind}/{}", selector.name), spec)
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(object: Value) -> Result<T> {
+    serde_json::from_value(object).map_err(ErrorWrapper::from_serde)
+}
+
+fn namespace<K: ResourceExt>(object: &K) -> String {
+    object.namespace().unwrap_or_else(|| "default".into())
+}
+
+fn key<K: ResourceExt>(object: &K) -> Key {
+    (namespace(object), object.name_any())
+}
+
+fn reference(namespace: &str, reference: &RepositoryRef) -> Key {
+    (
+        reference.namespace.clone().unwrap_or_else(|| namespace.into()),
+        reference.name.clone(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::Manifests;
+
+    static MANIFESTS: &str = r#"
+apiVersion: replicator.yair.example.com/v1alpha1
+kind: SourceRepository
+metadata: { name: ci, namespace: my-team }
+spec:
+  repository: { name: ci, location: europe-west1, projectID: example-ci, Provider: GCP, format: Docker }
+---
+apiVersion: replicator.yair.example.com/v1beta1
+kind: DestinationRepository
+metadata: { name: prod, namespace: my-team }
+spec:
+  repository: { name: prod, provider: Generic, format: Docker, registry: prod.example.com }
+---
+apiVersion: replicator.yair.example.com/v1beta1
+kind: ContainerReplicator
+metadata: { name: my-app, namespace: my-team }
+spec:
+  sourceRef: { name: ci }
+  destinationRefs: [{ name: prod }, { name: prod-us }]
+  promotionSelectors:
+    deployments: [{ name: my-app, autoDetectImages: true }]
+    jobs: [{ name: migrate, images: [migrate] }]
+  rewrite: { stripPrefix: my-team }
+---
+apiVersion: apps/v1
+kind: Deployment
+metadata: { name: my-app, namespace: my-team }
+spec:
+  selector: { matchLabels: { app: my-app } }
+  template:
+    metadata: { labels: { app: my-app } }
+    spec:
+      containers:
+      - { name: app, image: europe-west1-docker.pkg.dev/example-ci/ci/my-team/app:1.2.3 }
+      - { name: proxy, image: envoyproxy/envoy:v1.31.0 }
+---
+apiVersion: v1
+kind: ConfigMap
+metadata: { name: unrelated }
+"#;
+
+    #[test]
+    fn plans_replicators_from_manifests() {
+        let mut manifests = Manifests::default();
+        manifests.add(MANIFESTS).unwrap();
+        let plans = manifests.plan();
+        assert_eq!(plans.len(), 1);
+        let plan = &plans[0];
+        assert_eq!(plan.replicator, "my-team/my-app");
+        let jobs: Vec<_> = plan.jobs.iter().map(ToString::to_string).collect();
+        assert_eq!(jobs, [
+            "europe-west1-docker.pkg.dev/example-ci/ci/my-team/app:1.2.3 -> prod.example.com/prod/app:1.2.3"
+        ]);
+        assert_eq!(plan.warnings, [
+            "DestinationRepository prod-us not found",
+            "job my-team/migrate not found",
+        ]);
+    }
+
+    #[test]
+    fn invalid_yaml_is_an_error() {
+        assert!(Manifests::default().add("kind: [unclosed").is_err());
+    }
+}
@@ -0,0 +1,393 @@
+//! Which images a `ContainerReplicator` promotes, and where they end up.
+//!
+//! Images are read from the pod templates of the selected workloads; only images that live in the
+//! source repository are promoted, every other image (base images, sidecars from public registries)
+//! is left alone. A source that is an OCI image layout, carried into an air-gapped site, also has
+//! every image tagged in its `index.json` promoted.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{PromotionSelectors, RewriteRules, WorkloadSelector},
+    registry::{Reference, RegistryClient, RepositoryLocation},
+    replication::{Destination, ImageLocation, ReplicationJob},
+    rewrite,
+};
+use k8s_openapi::api::{apps::v1::Deployment, batch::v1::Job, core::v1::PodSpec};
+use kube::{Api, Client};
+use std::collections::HashSet;
+use tracing::debug;
+
+/// An image found in a selected workload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectedImage {
+    /// `kind/name` of the workload the image was found in
+    pub workload: String,
+    pub image: Reference,
+}
+
+/// Every container image of a pod spec, init containers first
+#[must_use]
+pub fn pod_images(spec: &PodSpec) -> Vec<&str> {
+    spec.init_containers
+        .iter()
+        .flatten()
+        .chain(&spec.containers)
+        .filter_map(|c| c.image.as_deref())
+        .collect()
+}
+
+/// The images of `spec` that `selector` asks for
+///
+/// # Errors
+///
+/// When an image of `spec` is not a valid reference
+pub fn select(selector: &WorkloadSelector, workload: &str, spec: &PodSpec) -> Result<Vec<SelectedImage>> {
+    let mut selected = vec![];
+    for image in pod_images(spec) {
+        let image: Reference = image.parse()?;
+        if selector.auto_detect_images || selector.images.iter().any(|name| name == image.image_name()) {
+            selected.push(SelectedImage {
+                workload: workload.to_string(),
+                image,
+            });
+        }
+    }
+    Ok(selected)
+}
+
+/// Look up the selected workloads in `namespace` and collect their images
+///
+/// # Errors
+///
+/// When a selected workload does not exist or can not be read
+pub async fn resolve(
+    client: &Client,
+    namespace: &str,
+    selectors: &PromotionSelectors,
+) -> Result<Vec<SelectedImage>> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let jobs: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let mut selected = vec![];
+    for selector in &selectors.deployments {
+        let spec = deployments
+            .get_opt(&selector.name)
+            .await
+            .map_err(ErrorWrapper::from_kube)?
+            .and_then(|d| d.spec)
+            .and_then(|s| s.template.spec)
+            .ok_or_else(|| missing("Deployment", namespace, &selector.name))?;
+        selected.extend(select(selector, &format!("deployment/{}", selector.name), &spec)?);
+    }
+    for selector in &selectors.jobs {
+        let spec = jobs
+            .get_opt(&selector.name)
+            .await
+            .map_err(ErrorWrapper::from_kube)?
+            .and_then(|j| j.spec)
+            .and_then(|s| s.template.spec)
+            .ok_or_else(|| missing("Job", namespace, &selector.name))?;
+        selected.extend(select(selector, &format!("job/{}", selector.name), &spec)?);
+    }
+    Ok(selected)
+}
+
+/// Every image tagged in the OCI image layout `client` reads from, tagged as named by its
+/// `org.opencontainers.image.ref.name` annotation; nothing for registries
+///
+/// Images named by a bare tag are taken for the image `bare` below the source, pinned to their
+/// digest since the layout has no `<repository>:<tag>` entry to read them by.
+///
+/// # Errors
+///
+/// When `index.json` of the layout can not be read
+pub async fn layout_images(
+    client: &RegistryClient,
+    source: &RepositoryLocation,
+    bare: &str,
+) -> Result<Vec<SelectedImage>> {
+    let Some(layout) = client.layout() else {
+        return Ok(vec![]);
+    };
+    Ok(layout
+        .images()
+        .await?
+        .into_iter()
+        .map(|image| SelectedImage {
+            workload: "layout/index.json".into(),
+            image: Reference {
+                registry: source.endpoint.host.clone(),
+                digest: image.repository.is_none().then_some(image.digest),
+                repository: image.repository.unwrap_or_else(|| source.repository(bare)),
+                tag: Some(image.tag),
+            },
+        })
+        .collect())
+}
+
+fn missing(kind: &str, namespace: &str, name: &str) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("{kind} {namespace}/{name} not found"))
+}
+
+/// One job per image and destination, for images found in `source`
+///
+/// Tagged images keep their tag in the destination; images pinned only by digest are pushed by
+/// digest. Pinned images are always read by digest, so a moved tag can not sneak in. With `rewrite`
+/// rules the path below the destination prefix is mapped by them; tag templates are rendered later,
+/// when the image is read.
+///
+/// # Errors
+///
+/// When a `rewrite` rule can not map the path of an image
+pub fn plan(
+    images: &[SelectedImage],
+    source: &RepositoryLocation,
+    destinations: &[Destination],
+    rewrite: Option<&RewriteRules>,
+) -> Result<Vec<ReplicationJob>> {
+    let mut seen = HashSet::new();
+    let mut jobs = vec![];
+    for selected in images {
+        let Some(path) = source.relative(&selected.image) else {
+            debug!(image = %selected.image, "Image is not in the source repository, skipping");
+            continue;
+        };
+        let (path, rewrites) = match rewrite {
+            Some(rules) => rewrite::map_path(rules, path)?,
+            None => (path.to_string(), vec![]),
+        };
+        let target = selected
+            .image
+            .tag
+            .clone()
+            .unwrap_or_else(|| selected.image.reference().to_string());
+        for destination in destinations {
+            let job = ReplicationJob {
+                source: ImageLocation {
+                    endpoint: source.endpoint.clone(),
+                    repository: selected.image.repository.clone(),
+                    reference: selected.image.reference().to_string(),
+                },
+                destination: ImageLocation {
+                    endpoint: destination.location.endpoint.clone(),
+                    repository: destination.location.repository(&path),
+                    reference: target.clone(),
+                },
+                tag_mutability: destination.tag_mutability,
+                conversion: destination.conversion,
+                rewrites: rewrites.clone(),
+            };
+            if seen.insert(job.clone()) {
+                jobs.push(job);
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SelectedImage, layout_images, plan, select};
+    use crate::core::{
+        crd::{RepositoryProvider, RepositorySpec, RewriteRules, TagMutability, WorkloadSelector},
+        registry::{
+            RepositoryLocation, client::RawManifest, digest::sha256, layout::OciLayout, manifest::media_types,
+        },
+        replication::{Conversion, Destination, Replicator},
+    };
+    use bytes::Bytes;
+    use k8s_openapi::api::core::v1::{Container, PodSpec};
+
+    fn container(image: &str) -> Container {
+        Container {
+            name: image.rsplit('/').next().unwrap().into(),
+            image: Some(image.into()),
+            ..Container::default()
+        }
+    }
+
+    fn location(registry: &str, name: &str) -> RepositoryLocation {
+        RepositoryLocation::from(&RepositorySpec {
+            provider: RepositoryProvider::Generic,
+            name: name.into(),
+            registry: Some(registry.into()),
+            ..RepositorySpec::default()
+        })
+    }
+
+    fn destination(registry: &str, name: &str) -> Destination {
+        Destination {
+            location: location(registry, name),
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+        }
+    }
+
+    fn selected(image: &str) -> SelectedImage {
+        SelectedImage {
+            workload: "deployment/app".into(),
+            image: image.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn selects_images_by_name_or_everything() {
+        let spec = PodSpec {
+            init_containers: Some(vec![container("ci.example.com/ci/migrate:1")]),
+            containers: vec![
+                container("ci.example.com/ci/app:1"),
+                container("envoyproxy/envoy:v1"),
+            ],
+            ..PodSpec::default()
+        };
+        let by_name = WorkloadSelector {
+            name: "app".into(),
+            images: vec!["app".into()],
+            auto_detect_images: false,
+        };
+        let images = select(&by_name, "deployment/app", &spec).unwrap();
+        assert_eq!(images, vec![selected("ci.example.com/ci/app:1")]);
+
+        let everything = WorkloadSelector {
+            auto_detect_images: true,
+            ..by_name
+        };
+        assert_eq!(select(&everything, "deployment/app", &spec).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn plans_a_job_per_destination_for_source_images_only() {
+        let images = [
+            selected("ci.example.com/ci/team/app:1.0.0"),
+            selected("ci.example.com/ci/team/app:1.0.0"),
+            selected("docker.io/library/nginx:1"),
+        ];
+        let destinations = [
+            destination("prod.example.com", "prod"),
+            destination("dr.example.com", ""),
+        ];
+        let jobs = plan(&images, &location("ci.example.com", "ci"), &destinations, None).unwrap();
+        let rendered: Vec<_> = jobs.iter().map(ToString::to_string).collect();
+        assert_eq!(rendered, [
+            "ci.example.com/ci/team/app:1.0.0 -> prod.example.com/prod/team/app:1.0.0",
+            "ci.example.com/ci/team/app:1.0.0 -> dr.example.com/team/app:1.0.0",
+        ]);
+    }
+
+    #[test]
+    fn pinned_images_are_read_by_digest() {
+        let images = [
+            selected("ci.example.com/ci/app:1@sha256:aaa"),
+            selected("ci.example.com/ci/job@sha256:bbb"),
+        ];
+        let jobs = plan(
+            &images,
+            &location("ci.example.com", "ci"),
+            &[destination("prod.example.com", "prod")],
+            None,
+        )
+        .unwrap();
+        assert_eq!(jobs[0].source.reference, "sha256:aaa");
+        assert_eq!(jobs[0].destination.reference, "1");
+        assert_eq!(jobs[1].destination.reference, "sha256:bbb");
+    }
+
+    #[test]
+    fn rewrite_rules_map_destination_paths() {
+        let images = [selected("ci.example.com/ci/my-team/app:sha-abc")];
+        let rules = RewriteRules {
+            strip_prefix: Some("my-team".into()),
+            ..RewriteRules::default()
+        };
+        let jobs = plan(
+            &images,
+            &location("ci.example.com", "ci"),
+            &[destination("prod.example.com", "prod")],
+            Some(&rules),
+        )
+        .unwrap();
+        assert_eq!(
+            jobs[0].destination.to_string(),
+            "prod.example.com/prod/app:sha-abc"
+        );
+        assert_eq!(jobs[0].rewrites, ["stripPrefix my-team"]);
+    }
+
+    #[tokio::test]
+    async fn layouts_promote_every_tagged_image_below_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = OciLayout::new(dir.path());
+        let bytes = Bytes::from_static(br#"{"schemaVersion":2}"#);
+        let manifest = RawManifest {
+            media_type: media_types::OCI_MANIFEST.into(),
+            digest: sha256(&bytes),
+            bytes,
+        };
+        for (repository, tag) in [("ci/app", "1.0.0"), ("ci/app", "1.1.0"), ("other/app", "2.0.0")] {
+            layout.put_manifest(repository, tag, &manifest).await.unwrap();
+        }
+        let source = RepositoryLocation::from(&RepositorySpec {
+            provider: RepositoryProvider::OciLayout,
+            name: "ci".into(),
+            path: Some(dir.path().display().to_string()),
+            ..RepositorySpec::default()
+        });
+        let client = Replicator::default().client(&source.endpoint);
+        let images = layout_images(&client, &source, "bundle").await.unwrap();
+        assert_eq!(images.len(), 3);
+        let jobs = plan(&images, &source, &[destination("prod.example.com", "prod")], None).unwrap();
+        let rendered: Vec<_> = jobs.iter().map(|job| job.destination.to_string()).collect();
+        assert_eq!(rendered, [
+            "prod.example.com/prod/app:1.0.0",
+            "prod.example.com/prod/app:1.1.0"
+        ]);
+
+        let registry = Replicator::default().client(&location("ci.example.com", "ci").endpoint);
+        assert!(
+            layout_images(&registry, &source, "bundle")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn bare_tags_of_layouts_are_promoted_under_the_source_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = OciLayout::new(dir.path());
+        let bytes = Bytes::from_static(br#"{"schemaVersion":2}"#);
+        let manifest = RawManifest {
+            media_type: media_types::OCI_MANIFEST.into(),
+            digest: sha256(&bytes),
+            bytes,
+        };
+        layout.put_manifest("app", "1.0.0", &manifest).await.unwrap();
+        layout
+            .put_manifest("registry", "5000/app", &manifest)
+            .await
+            .unwrap();
+        // skopeo and crane name entries by their tag alone
+        let index = std::fs::read_to_string(dir.path().join("index.json")).unwrap();
+        let index = index.replace("\"app:1.0.0\"", "\"1.0.0\"");
+        std::fs::write(dir.path().join("index.json"), index).unwrap();
+        let source = RepositoryLocation::from(&RepositorySpec {
+            provider: RepositoryProvider::OciLayout,
+            name: "ci".into(),
+            path: Some(dir.path().display().to_string()),
+            ..RepositorySpec::default()
+        });
+        let client = Replicator::default().client(&source.endpoint);
+        let images = layout_images(&client, &source, "bundle").await.unwrap();
+        assert_eq!(images.len(), 1, "names that are no tag are skipped");
+        let jobs = plan(&images, &source, &[destination("prod.example.com", "prod")], None).unwrap();
+        assert_eq!(jobs[0].source.repository, "ci/bundle");
+        assert_eq!(jobs[0].source.reference, manifest.digest);
+        assert_eq!(
+            jobs[0].destination.to_string(),
+            "prod.example.com/prod/bundle:1.0.0"
+        );
+        let read = client
+            .manifest(&jobs[0].source.repository, &jobs[0].source.reference)
+            .await
+            .unwrap();
+        assert_eq!(read.digest, manifest.digest);
+    }
+}
@@ -0,0 +1,737 @@
+//! HTTP client for the OCI distribution API of a single registry host.
+//!
+//! Authentication follows the docker token flow: requests are sent anonymously (or with a cached
+//! token) and a `401` challenge is answered once, either with basic credentials or by fetching a
+//! bearer token for the repository scope from the advertised realm.
+//!
+//! Endpoints backed by an OCI image layout are served from disk by [`OciLayout`] instead, which has
+//! no upload sessions: blobs are always written whole.
+use super::{
+    Credentials, Endpoint,
+    layout::OciLayout,
+    limits::HostLimiter,
+    manifest::{Descriptor, Manifest, media_types, referrers_tag},
+};
+use crate::core::{ErrorWrapper, Result};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt, TryStreamExt, stream::BoxStream};
+use reqwest::{
+    RequestBuilder, Response, StatusCode, Url,
+    header::{ACCEPT, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE, WWW_AUTHENTICATE},
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+pub static DIGEST_HEADER: &str = "Docker-Content-Digest";
+static GCP_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// A blob body as it comes off the wire
+pub type ByteStream = BoxStream<'static, Result<Bytes>>;
+
+#[derive(Clone, Debug)]
+enum Authorization {
+    Basic(String, String),
+    Bearer(String),
+}
+
+/// A manifest exactly as served by the registry
+#[derive(Clone, Debug)]
+pub struct RawManifest {
+    pub media_type: String,
+    pub digest: String,
+    pub bytes: Bytes,
+}
+
+pub struct RegistryClient {
+    http: reqwest::Client,
+    endpoint: Endpoint,
+    limiter: Arc<HostLimiter>,
+    /// Authorization that worked last, per token scope
+    auth: Mutex<HashMap<String, Authorization>>,
+    /// Set for endpoints that are an OCI image layout rather than a registry
+    layout: Option<OciLayout>,
+}
+
+impl RegistryClient {
+    #[must_use]
+    pub fn new(http: reqwest::Client, endpoint: Endpoint, limiter: Arc<HostLimiter>) -> Self {
+        let layout = endpoint.layout.as_deref().map(OciLayout::new);
+        Self {
+            http,
+            endpoint,
+            limiter,
+            auth: Mutex::default(),
+            layout,
+        }
+    }
+
+    #[must_use]
+    pub const fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// The OCI image layout this endpoint is, if it is not a registry
+    #[must_use]
+    pub const fn layout(&self) -> Option<&OciLayout> {
+        self.layout.as_ref()
+    }
+
+    fn url(&self, path: &str) -> String {
+        let scheme = if self.endpoint.insecure { "http" } else { "https" };
+        format!("{scheme}://{}/v2/{path}", self.endpoint.host)
+    }
+
+    fn scope(repository: &str, push: bool) -> String {
+        let actions = if push { "pull,push" } else { "pull" };
+        format!("repository:{repository}:{actions}")
+    }
+
+    fn authorize(&self, request: RequestBuilder, scope: &str) -> RequestBuilder {
+        let auth = self.auth.lock().expect("auth lock poisoned").get(scope).cloned();
+        match auth {
+            Some(Authorization::Basic(user, password)) => request.basic_auth(user, Some(password)),
+            Some(Authorization::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// Send a (replayable) request, answering one authentication challenge if needed
+    async fn send<F>(&self, repository: &str, push: bool, build: F) -> Result<Response>
+    where
+        F: Fn(&reqwest::Client) -> RequestBuilder,
+    {
+        let scope = Self::scope(repository, push);
+        self.limiter.request().await;
+        let response = self
+            .authorize(build(&self.http), &scope)
+            .send()
+            .await
+            .map_err(ErrorWrapper::from_http)?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let challenge = response
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        self.login(&challenge, &scope).await?;
+        self.limiter.request().await;
+        self.authorize(build(&self.http), &scope)
+            .send()
+            .await
+            .map_err(ErrorWrapper::from_http)
+    }
+
+    async fn basic_credentials(&self) -> Result<Option<(String, String)>> {
+        match self.endpoint.credentials {
+            Credentials::Anonymous => Ok(None),
+            Credentials::GcpMetadata => {
+                #[derive(Deserialize)]
+                struct Token {
+                    access_token: String,
+                }
+                let token: Token = self
+                    .http
+                    .get(GCP_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await
+                    .and_then(Response::error_for_status)
+                    .map_err(ErrorWrapper::from_http)?
+                    .json()
+                    .await
+                    .map_err(ErrorWrapper::from_http)?;
+                Ok(Some(("oauth2accesstoken".into(), token.access_token)))
+            }
+        }
+    }
+
+    /// Answer a `WWW-Authenticate` challenge for `scope`
+    async fn login(&self, challenge: &str, scope: &str) -> Result<()> {
+        #[derive(Deserialize)]
+        struct Token {
+            #[serde(alias = "access_token")]
+            token: String,
+        }
+        let credentials = self.basic_credentials().await?;
+        let auth = if let Some(params) = challenge.strip_prefix("Bearer ") {
+            let params = parse_challenge(params);
+            let realm = params
+                .get("realm")
+                .ok_or_else(|| ErrorWrapper::from_custom("bearer challenge without realm"))?;
+            let mut query = vec![("scope", scope.to_string())];
+            if let Some(service) = params.get("service") {
+                query.push(("service", service.clone()));
+            }
+            let mut request = self.http.get(realm).query(&query);
+            if let Some((user, password)) = credentials {
+                request = request.basic_auth(user, Some(password));
+            }
+            let token: Token = request
+                .send()
+                .await
+                .and_then(Response::error_for_status)
+                .map_err(ErrorWrapper::from_http)?
+                .json()
+                .await
+                .map_err(ErrorWrapper::from_http)?;
+            Authorization::Bearer(token.token)
+        } else if let Some((user, password)) = credentials {
+            Authorization::Basic(user, password)
+        } else {
+            return Err(ErrorWrapper::from_custom(&format!(
+                "{} requires credentials for {scope}",
+                self.endpoint.host
+            )));
+        };
+        self.auth
+            .lock()
+            .expect("auth lock poisoned")
+            .insert(scope.to_string(), auth);
+        Ok(())
+    }
+
+    /// Fetch a manifest by tag or digest
+    ///
+    /// # Errors
+    ///
+    /// When the registry does not have the manifest, or can not be reached
+    pub async fn manifest(&self, repository: &str, reference: &str) -> Result<RawManifest> {
+        if let Some(layout) = &self.layout {
+            return layout.manifest(repository, reference).await;
+        }
+        let url = self.url(&format!("{repository}/manifests/{reference}"));
+        let response = self
+            .send(repository, false, |http| {
+                http.get(&url)
+                    .header(ACCEPT, media_types::ALL_MANIFESTS.join(", "))
+            })
+            .await?;
+        let what = format!("manifest {repository}:{reference}");
+        let response = expect_success(response, &format!("GET {what}")).await?;
+        let media_type = header(&response, CONTENT_TYPE.as_str()).unwrap_or_default();
+        let served_digest = header(&response, DIGEST_HEADER);
+        let bytes = response.bytes().await.map_err(ErrorWrapper::from_http)?;
+        // by digest the reference itself is the expectation, by tag the registry's word is
+        if reference.contains(':') {
+            super::digest::verify(&bytes, reference, &what)?;
+        } else if let Some(served) = served_digest {
+            super::digest::verify(&bytes, &served, &what)?;
+        }
+        let media_type = if media_type.is_empty() || media_type == "application/json" {
+            Manifest::parse(&bytes)?
+                .media_type
+                .unwrap_or_else(|| media_types::OCI_MANIFEST.to_string())
+        } else {
+            media_type
+        };
+        Ok(RawManifest {
+            media_type,
+            digest: super::digest::sha256(&bytes),
+            bytes,
+        })
+    }
+
+    /// Digest of a manifest, or `None` if the registry does not have it
+    ///
+    /// # Errors
+    ///
+    /// When the registry can not be reached or answers with an unexpected status
+    pub async fn manifest_digest(&self, repository: &str, reference: &str) -> Result<Option<String>> {
+        if let Some(layout) = &self.layout {
+            return layout.manifest_digest(repository, reference).await;
+        }
+        let url = self.url(&format!("{repository}/manifests/{reference}"));
+        let response = self
+            .send(repository, false, |http| {
+                http.head(&url)
+                    .header(ACCEPT, media_types::ALL_MANIFESTS.join(", "))
+            })
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = expect_success(response, &format!("HEAD manifest {repository}:{reference}")).await?;
+        match header(&response, DIGEST_HEADER) {
+            Some(digest) => Ok(Some(digest)),
+            // not every registry sends the digest on HEAD, fall back to hashing the body
+            None => Ok(Some(self.manifest(repository, reference).await?.digest)),
+        }
+    }
+
+    /// Digest of a manifest by tag or digest, failing if the registry does not have it
+    ///
+    /// # Errors
+    ///
+    /// When the registry does not have the manifest, or can not be reached
+    pub async fn resolve(&self, repository: &str, reference: &str) -> Result<String> {
+        if reference.contains(':') {
+            return Ok(reference.to_string());
+        }
+        self.manifest_digest(repository, reference)
+            .await?
+            .ok_or_else(|| ErrorWrapper::from_custom(&format!("manifest {repository}:{reference} not found")))
+    }
+
+    /// Push a manifest under a tag or its digest
+    ///
+    /// # Errors
+    ///
+    /// When the registry refuses the manifest
+    pub async fn put_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        manifest: &RawManifest,
+    ) -> Result<()> {
+        if let Some(layout) = &self.layout {
+            return layout.put_manifest(repository, reference, manifest).await;
+        }
+        let url = self.url(&format!("{repository}/manifests/{reference}"));
+        let response = self
+            .send(repository, true, |http| {
+                http.put(&url)
+                    .header(CONTENT_TYPE, &manifest.media_type)
+                    .body(manifest.bytes.clone())
+            })
+            .await?;
+        expect_success(response, &format!("PUT manifest {repository}:{reference}")).await?;
+        Ok(())
+    }
+
+    /// Whether the registry has a blob
+    ///
+    /// # Errors
+    ///
+    /// When the registry can not be reached or answers with an unexpected status
+    pub async fn blob_exists(&self, repository: &str, digest: &str) -> Result<bool> {
+        if let Some(layout) = &self.layout {
+            return layout.blob_exists(digest).await;
+        }
+        let url = self.url(&format!("{repository}/blobs/{digest}"));
+        let response = self.send(repository, false, |http| http.head(&url)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        expect_success(response, &format!("HEAD blob {repository}@{digest}")).await?;
+        Ok(true)
+    }
+
+    /// Stream a blob, throttled to the host's bandwidth limit
+    ///
+    /// # Errors
+    ///
+    /// When the registry does not have the blob, or can not be reached
+    pub async fn blob(&self, repository: &str, digest: &str) -> Result<ByteStream> {
+        self.blob_from(repository, digest, 0).await
+    }
+
+    /// A small blob read into memory and checked against its digest
+    ///
+    /// # Errors
+    ///
+    /// When the blob can not be read, or does not match its digest
+    pub async fn blob_bytes(&self, repository: &str, digest: &str) -> Result<Bytes> {
+        let mut bytes = BytesMut::new();
+        let mut body = self.blob(repository, digest).await?;
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk?);
+        }
+        super::digest::verify(&bytes, digest, &format!("blob {repository}@{digest}"))?;
+        Ok(bytes.freeze())
+    }
+
+    /// Stream a blob starting at `offset`, using a range request where the registry supports it
+    ///
+    /// # Errors
+    ///
+    /// When the registry does not have the blob, or can not be reached
+    pub async fn blob_from(&self, repository: &str, digest: &str, offset: u64) -> Result<ByteStream> {
+        if let Some(layout) = &self.layout {
+            return layout.blob_from(digest, offset).await;
+        }
+        let url = self.url(&format!("{repository}/blobs/{digest}"));
+        let response = self
+            .send(repository, false, |http| {
+                let request = http.get(&url);
+                if offset > 0 {
+                    request.header(RANGE, format!("bytes={offset}-"))
+                } else {
+                    request
+                }
+            })
+            .await?;
+        let response = expect_success(response, &format!("GET blob {repository}@{digest}")).await?;
+        let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+        let stream = response.bytes_stream().map_err(ErrorWrapper::from_http);
+        let stream = self.limiter.clone().throttle(stream);
+        if partial || offset == 0 {
+            Ok(stream.boxed())
+        } else {
+            Ok(skip_bytes(stream, offset).boxed())
+        }
+    }
+
+    /// Try to mount a blob from another repository on the same registry instead of copying it
+    ///
+    /// # Errors
+    ///
+    /// When the registry can not be reached; a refused mount is `Ok(false)`
+    pub async fn mount_blob(&self, repository: &str, digest: &str, from: &str) -> Result<bool> {
+        if self.layout.is_some() {
+            // repositories of a layout share their blobs, so there is never anything to mount
+            return Ok(false);
+        }
+        let url = self.url(&format!("{repository}/blobs/uploads/"));
+        let response = self
+            .send(repository, true, |http| {
+                http.post(&url)
+                    .query(&[("mount", digest), ("from", from)])
+                    .header(CONTENT_LENGTH, 0)
+            })
+            .await?;
+        Ok(response.status() == StatusCode::CREATED)
+    }
+
+    /// Push a blob in a single streamed request
+    ///
+    /// # Errors
+    ///
+    /// When the registry refuses the blob, or `body` fails
+    pub async fn push_blob<S>(&self, repository: &str, digest: &str, size: u64, body: S) -> Result<()>
+    where
+        S: Stream<Item = Result<Bytes>> + Send + 'static,
+    {
+        if let Some(layout) = &self.layout {
+            return layout.push_blob(digest, body).await;
+        }
+        let mut url = self.start_upload(repository).await?;
+        url.query_pairs_mut().append_pair("digest", digest);
+
+        // a stream can not be replayed, so this relies on the POST above having authenticated
+        let body = reqwest::Body::wrap_stream(self.limiter.clone().throttle(body));
+        let request = self
+            .http
+            .put(url)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .header(CONTENT_LENGTH, size)
+            .body(body);
+        self.limiter.request().await;
+        let response = self
+            .authorize(request, &Self::scope(repository, true))
+            .send()
+            .await
+            .map_err(ErrorWrapper::from_http)?;
+        expect_success(response, &format!("PUT blob {repository}@{digest}")).await?;
+        Ok(())
+    }
+
+    /// Open an upload session, returning the URL to send the blob to
+    ///
+    /// # Errors
+    ///
+    /// When the registry refuses to open an upload session
+    pub async fn start_upload(&self, repository: &str) -> Result<Url> {
+        if self.layout.is_some() {
+            return Err(ErrorWrapper::from_custom(&format!(
+                "{} is an OCI image layout, it has no upload sessions",
+                self.endpoint.host
+            )));
+        }
+        let start = self.url(&format!("{repository}/blobs/uploads/"));
+        let response = self
+            .send(repository, true, |http| {
+                http.post(&start).header(CONTENT_LENGTH, 0)
+            })
+            .await?;
+        let response = expect_success(response, &format!("POST upload {repository}")).await?;
+        self.upload_url(&response)
+    }
+
+    /// The URL to continue an upload session with and the bytes the registry already has, or
+    /// `None` if the session expired
+    ///
+    /// # Errors
+    ///
+    /// When the registry answers with an unexpected status
+    pub async fn upload_status(&self, repository: &str, location: &str) -> Result<Option<(Url, u64)>> {
+        let response = self.send(repository, true, |http| http.get(location)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = expect_success(response, &format!("GET upload {repository}")).await?;
+        let url = match header(&response, LOCATION.as_str()) {
+            Some(_) => self.upload_url(&response)?,
+            None => Url::parse(location).map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?,
+        };
+        let offset = upload_offset(header(&response, RANGE.as_str()).as_deref());
+        Ok(Some((url, offset)))
+    }
+
+    /// Append `chunk` at `offset` to an upload session, returning the URL for the next request
+    ///
+    /// # Errors
+    ///
+    /// When the registry refuses the chunk
+    pub async fn upload_chunk(&self, repository: &str, url: &Url, offset: u64, chunk: Bytes) -> Result<Url> {
+        let end = offset + chunk.len() as u64 - 1;
+        self.limiter.transfer(chunk.len()).await;
+        let response = self
+            .send(repository, true, |http| {
+                http.patch(url.clone())
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(CONTENT_RANGE, format!("{offset}-{end}"))
+                    .header(CONTENT_LENGTH, chunk.len())
+                    .body(chunk.clone())
+            })
+            .await?;
+        let response = expect_success(response, &format!("PATCH upload {repository} {offset}-{end}")).await?;
+        self.upload_url(&response)
+    }
+
+    /// Complete an upload session whose bytes were all sent with [`Self::upload_chunk`]
+    ///
+    /// # Errors
+    ///
+    /// When the registry refuses to complete the upload, e.g. because the digest does not match
+    pub async fn finish_upload(&self, repository: &str, url: &Url, digest: &str) -> Result<()> {
+        let mut url = url.clone();
+        url.query_pairs_mut().append_pair("digest", digest);
+        let response = self
+            .send(repository, true, |http| {
+                http.put(url.clone()).header(CONTENT_LENGTH, 0)
+            })
+            .await?;
+        expect_success(response, &format!("PUT blob {repository}@{digest}")).await?;
+        Ok(())
+    }
+
+    /// The absolute upload URL from the `Location` of an upload response
+    fn upload_url(&self, response: &Response) -> Result<Url> {
+        let location = header(response, LOCATION.as_str())
+            .ok_or_else(|| ErrorWrapper::from_custom("upload response without Location"))?;
+        let base = Url::parse(&self.url("")).map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?;
+        base.join(&location)
+            .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))
+    }
+
+    /// All tags of a repository
+    ///
+    /// # Errors
+    ///
+    /// When the registry can not be reached or answers with an unexpected status
+    pub async fn tags(&self, repository: &str) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct TagList {
+            #[serde(default)]
+            tags: Option<Vec<String>>,
+        }
+        if let Some(layout) = &self.layout {
+            return layout.tags(repository).await;
+        }
+        let mut url = self.url(&format!("{repository}/tags/list"));
+        let mut tags = vec![];
+        loop {
+            let response = self.send(repository, false, |http| http.get(&url)).await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(tags);
+            }
+            let response = expect_success(response, &format!("GET tags {repository}")).await?;
+            let next = header(&response, "Link").and_then(|link| next_link(&link));
+            let page: TagList = response.json().await.map_err(ErrorWrapper::from_http)?;
+            tags.extend(page.tags.unwrap_or_default());
+            match next {
+                Some(next) => url = join_url(&url, &next)?,
+                None => return Ok(tags),
+            }
+        }
+    }
+
+    /// Manifests whose `subject` is `digest`, from the OCI 1.1 referrers API, or `None` if the
+    /// registry does not implement it
+    ///
+    /// # Errors
+    ///
+    /// When the registry can not be reached or answers with an unexpected status
+    pub async fn referrers(&self, repository: &str, digest: &str) -> Result<Option<Vec<Descriptor>>> {
+        if self.layout.is_some() {
+            return Ok(None);
+        }
+        let mut url = self.url(&format!("{repository}/referrers/{digest}"));
+        let mut referrers = vec![];
+        loop {
+            let response = self
+                .send(repository, false, |http| {
+                    http.get(&url).header(ACCEPT, media_types::OCI_INDEX)
+                })
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND && referrers.is_empty() {
+                return Ok(None);
+            }
+            let response = expect_success(response, &format!("GET referrers {repository}@{digest}")).await?;
+            let next = header(&response, "Link").and_then(|link| next_link(&link));
+            let bytes = response.bytes().await.map_err(ErrorWrapper::from_http)?;
+            referrers.extend(Manifest::parse(&bytes)?.manifests);
+            match next {
+                Some(next) => url = join_url(&url, &next)?,
+                None => return Ok(Some(referrers)),
+            }
+        }
+    }
+
+    /// Referrers of `digest` listed in the tag schema fallback index, for registries without the
+    /// referrers API
+    ///
+    /// # Errors
+    ///
+    /// When the fallback index can not be read
+    pub async fn tagged_referrers(&self, repository: &str, digest: &str) -> Result<Vec<Descriptor>> {
+        let tag = referrers_tag(digest);
+        if self.manifest_digest(repository, &tag).await?.is_none() {
+            return Ok(vec![]);
+        }
+        let index = self.manifest(repository, &tag).await?;
+        Ok(Manifest::parse(&index.bytes)?.manifests)
+    }
+}
+
+/// Resolve a pagination link against the URL of the page it came with
+fn join_url(base: &str, next: &str) -> Result<String> {
+    let base = Url::parse(base).map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?;
+    Ok(base
+        .join(next)
+        .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?
+        .to_string())
+}
+
+fn header(response: &Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(ToString::to_string)
+}
+
+async fn expect_success(response: Response, what: &str) -> Result<Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(ErrorWrapper::from_custom(&format!(
+        "{what}: registry answered {status}: {}",
+        body.trim()
+    )))
+}
+
+/// The last byte of a `Range: 0-<end>` upload status header
+fn range_end(range: &str) -> Option<u64> {
+    range.trim_start_matches("bytes=").split_once('-')?.1.parse().ok()
+}
+
+/// The bytes an upload session holds by its `Range` header; registries answer `0-0`, or no header at
+/// all, for a session nothing was appended to yet
+fn upload_offset(range: Option<&str>) -> u64 {
+    match range.and_then(range_end) {
+        None | Some(0) => 0,
+        Some(end) => end + 1,
+    }
+}
+
+/// Drop the first `offset` bytes of a stream, for registries that ignore range requests
+pub fn skip_bytes<S>(stream: S, offset: u64) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    stream
+        .scan(offset, |remaining, chunk| {
+            let chunk = chunk.map(|mut bytes| {
+                let skip = usize::try_from(*remaining).unwrap_or(usize::MAX).min(bytes.len());
+                *remaining -= skip as u64;
+                bytes.split_off(skip)
+            });
+            futures::future::ready(Some(chunk))
+        })
+        .try_filter(|bytes| futures::future::ready(!bytes.is_empty()))
+}
+
+/// `key="value"` pairs of an authentication challenge
+fn parse_challenge(params: &str) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, tail)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let (value, tail) = tail.strip_prefix('"').map_or_else(
+            || tail.split_once(',').unwrap_or((tail, "")),
+            |quoted| quoted.split_once('"').unwrap_or((quoted, "")),
+        );
+        out.insert(key, value.to_string());
+        rest = tail.trim_start_matches(',').trim();
+    }
+    out
+}
+
+/// Target of a `Link: <…>; rel="next"` pagination header
+fn next_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (target, params) = part.split_once(';')?;
+        params.contains("rel=\"next\"").then(|| {
+            target
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_link, parse_challenge, range_end, skip_bytes, upload_offset};
+    use bytes::Bytes;
+    use futures::{StreamExt, TryStreamExt};
+
+    #[test]
+    fn parses_bearer_challenges() {
+        let params = parse_challenge(
+            r#"realm="https://auth.example.com/token",service="registry.example.com",scope="repository:a:pull""#,
+        );
+        assert_eq!(params["realm"], "https://auth.example.com/token");
+        assert_eq!(params["service"], "registry.example.com");
+        assert_eq!(params["scope"], "repository:a:pull");
+    }
+
+    #[test]
+    fn follows_next_links() {
+        assert_eq!(
+            next_link(r#"</v2/app/tags/list?n=2&last=b>; rel="next""#).as_deref(),
+            Some("/v2/app/tags/list?n=2&last=b")
+        );
+        assert_eq!(next_link(r#"</v2/app/tags/list>; rel="prev""#), None);
+    }
+
+    #[test]
+    fn reads_upload_ranges() {
+        assert_eq!(range_end("0-1023"), Some(1023));
+        assert_eq!(range_end("bytes=0-9"), Some(9));
+        assert_eq!(range_end("garbage"), None);
+        assert_eq!(upload_offset(Some("0-1023")), 1024);
+        assert_eq!(upload_offset(Some("0-0")), 0);
+        assert_eq!(upload_offset(None), 0);
+    }
+
+    #[tokio::test]
+    async fn skips_the_resumed_prefix() {
+        let chunks = futures::stream::iter([Ok(Bytes::from_static(b"abc")), Ok(Bytes::from_static(b"defg"))]);
+        let rest: Vec<Bytes> = skip_bytes(chunks, 4).try_collect().await.unwrap();
+        assert_eq!(rest, [Bytes::from_static(b"efg")]);
+        let none = skip_bytes(futures::stream::iter([Ok(Bytes::from_static(b"ab"))]), 2);
+        assert_eq!(none.count().await, 0);
+    }
+}
@@ -0,0 +1,217 @@
+//! Recompressing image layers between gzip and zstd.
+//!
+//! A layer is staged in a temporary file, decompressed and compressed again into a second one while
+//! it is hashed, so its new digest is known before anything is pushed. Only the compressed blob
+//! changes: the uncompressed tar, and so the `diff_ids` in the image config, stay the same.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::LayerCompression,
+    registry::{client::ByteStream, manifest::media_types},
+};
+use bytes::BytesMut;
+use futures::StreamExt;
+use sha2::{Digest as _, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+use tempfile::{NamedTempFile, TempPath};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Bytes read from a recompressed layer at a time
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// The compression of a layer media type, `None` for uncompressed layers and other blobs
+#[must_use]
+pub fn of(media_type: &str) -> Option<LayerCompression> {
+    if media_type == media_types::OCI_LAYER_GZIP || media_type == media_types::DOCKER_LAYER_GZIP {
+        Some(LayerCompression::Gzip)
+    } else if media_type == media_types::OCI_LAYER_ZSTD {
+        Some(LayerCompression::Zstd)
+    } else {
+        None
+    }
+}
+
+/// The media type of a layer of type `media_type` once compressed with `compression`, if its
+/// manifest format has one; Docker schema2 has no zstd layers
+#[must_use]
+pub fn media_type(media_type: &str, compression: LayerCompression) -> Option<&'static str> {
+    let docker = media_type.starts_with("application/vnd.docker.");
+    match (compression, docker) {
+        (LayerCompression::Gzip, true) => Some(media_types::DOCKER_LAYER_GZIP),
+        (LayerCompression::Gzip, false) => Some(media_types::OCI_LAYER_GZIP),
+        (LayerCompression::Zstd, true) => None,
+        (LayerCompression::Zstd, false) => Some(media_types::OCI_LAYER_ZSTD),
+    }
+}
+
+/// A recompressed layer, deleted once dropped
+pub struct Transcoded {
+    path: TempPath,
+    pub digest: String,
+    pub size: u64,
+}
+
+impl Transcoded {
+    /// Stream the recompressed layer
+    ///
+    /// # Errors
+    ///
+    /// When the recompressed layer can not be opened
+    pub async fn stream(self) -> Result<ByteStream> {
+        let file = tokio::fs::File::open(&self.path)
+            .await
+            .map_err(|e| io_error(&self.path, &e))?;
+        let stream = futures::stream::try_unfold((file, self.path), |(mut file, path)| async move {
+            let mut buffer = BytesMut::with_capacity(READ_CHUNK);
+            let read = file
+                .read_buf(&mut buffer)
+                .await
+                .map_err(|e| io_error(&path, &e))?;
+            Ok((read > 0).then(|| (buffer.freeze(), (file, path))))
+        });
+        Ok(stream.boxed())
+    }
+}
+
+/// Recompress the layer `body` from `from` to `to`
+///
+/// # Errors
+///
+/// When `body` fails, is not compressed with `from`, or the layer can not be staged on disk
+pub async fn transcode(
+    mut body: ByteStream,
+    from: LayerCompression,
+    to: LayerCompression,
+) -> Result<Transcoded> {
+    let staged = NamedTempFile::new().map_err(|e| io_error(Path::new("temporary file"), &e))?;
+    let mut file = tokio::fs::File::from_std(staged.reopen().map_err(|e| io_error(staged.path(), &e))?);
+    while let Some(chunk) = body.next().await {
+        file.write_all(&chunk?)
+            .await
+            .map_err(|e| io_error(staged.path(), &e))?;
+    }
+    file.flush().await.map_err(|e| io_error(staged.path(), &e))?;
+    drop(file);
+    tokio::task::spawn_blocking(move || recompress(staged.path(), from, to))
+        .await
+        .map_err(|e| ErrorWrapper::from_custom(&format!("recompressing a layer panicked: {e}")))?
+}
+
+fn recompress(input: &Path, from: LayerCompression, to: LayerCompression) -> Result<Transcoded> {
+    let output = NamedTempFile::new().map_err(|e| io_error(Path::new("temporary file"), &e))?;
+    let compressed = BufReader::new(File::open(input).map_err(|e| io_error(input, &e))?);
+    let mut tar: Box<dyn io::Read> = match from {
+        LayerCompression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(compressed)),
+        LayerCompression::Zstd => {
+            Box::new(zstd::stream::read::Decoder::with_buffer(compressed).map_err(|e| io_error(input, &e))?)
+        }
+    };
+    let mut hashing = Hashing {
+        inner: BufWriter::new(output.reopen().map_err(|e| io_error(output.path(), &e))?),
+        hasher: Sha256::new(),
+        size: 0,
+    };
+    let copied = match to {
+        LayerCompression::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(&mut hashing, flate2::Compression::default());
+            io::copy(&mut tar, &mut encoder).and_then(|_| encoder.finish().map(drop))
+        }
+        LayerCompression::Zstd => {
+            zstd::stream::write::Encoder::new(&mut hashing, 0).and_then(|mut encoder| {
+                io::copy(&mut tar, &mut encoder)?;
+                encoder.finish().map(drop)
+            })
+        }
+    };
+    copied
+        .and_then(|()| hashing.flush())
+        .map_err(|e| io_error(input, &e))?;
+    Ok(Transcoded {
+        path: output.into_temp_path(),
+        digest: format!("sha256:{:x}", hashing.hasher.finalize()),
+        size: hashing.size,
+    })
+}
+
+/// Hashes and counts what is written through it
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn io_error(path: &Path, e: &io::Error) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("recompressing layer {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{media_type, of, transcode};
+    use crate::core::{
+        crd::LayerCompression,
+        registry::{digest, manifest::media_types},
+    };
+    use bytes::Bytes;
+    use futures::{StreamExt, TryStreamExt};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn docker_layers_have_no_zstd_media_type() {
+        assert_eq!(of(media_types::DOCKER_LAYER_GZIP), Some(LayerCompression::Gzip));
+        assert_eq!(of("application/vnd.oci.image.layer.v1.tar"), None);
+        assert_eq!(
+            media_type(media_types::OCI_LAYER_GZIP, LayerCompression::Zstd),
+            Some(media_types::OCI_LAYER_ZSTD)
+        );
+        assert_eq!(
+            media_type(media_types::DOCKER_LAYER_GZIP, LayerCompression::Zstd),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn layers_round_trip_through_zstd() {
+        let tar = b"layer contents ".repeat(1000);
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&tar).unwrap();
+        let gzip = encoder.finish().unwrap();
+        let body = futures::stream::iter([Ok(Bytes::from(gzip))]).boxed();
+
+        let zstd = transcode(body, LayerCompression::Gzip, LayerCompression::Zstd)
+            .await
+            .unwrap();
+        let (digest, size) = (zstd.digest.clone(), zstd.size);
+        let chunks: Vec<Bytes> = zstd.stream().await.unwrap().try_collect().await.unwrap();
+        let compressed = chunks.concat();
+        assert_eq!(compressed.len() as u64, size);
+        assert_eq!(digest::sha256(&compressed), digest);
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), tar);
+
+        let body = futures::stream::iter([Ok(Bytes::from(compressed))]).boxed();
+        let gzip = transcode(body, LayerCompression::Zstd, LayerCompression::Gzip)
+            .await
+            .unwrap();
+        let chunks: Vec<Bytes> = gzip.stream().await.unwrap().try_collect().await.unwrap();
+        let mut unpacked = vec![];
+        flate2::read::GzDecoder::new(chunks.concat().as_slice())
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert_eq!(unpacked, tar);
+    }
+}
@@ -0,0 +1,191 @@
+//! Content digests as used by the distribution spec, e.g. `sha256:e3b0c442…`
+use crate::core::{ErrorWrapper, Result};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use sha2::{Digest as _, Sha256, Sha512};
+use std::fmt;
+
+/// `sha256:<hex>` of `bytes`
+#[must_use]
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(bytes))
+}
+
+/// Content that did not hash to the digest it was addressed by
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DigestMismatch {
+    /// What was being copied, e.g. `blob prod/app@sha256:…`
+    pub what: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "digest mismatch for {}: expected {}, got {}",
+            self.what, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+impl DigestMismatch {
+    /// The mismatch behind `error`, if that is what failed; it may be buried in the HTTP error of
+    /// an upload whose body stream it aborted
+    #[must_use]
+    pub fn find(error: &loco_rs::Error) -> Option<&Self> {
+        ErrorWrapper::find(error)
+    }
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// Hashes content incrementally and checks it against the digest it is expected to have
+pub struct Verifier {
+    expected: String,
+    hasher: Hasher,
+}
+
+impl Verifier {
+    /// A verifier for `expected`, which has to use a supported algorithm (`sha256` or `sha512`)
+    ///
+    /// # Errors
+    ///
+    /// When `expected` is not a digest of a supported algorithm
+    pub fn new(expected: &str) -> Result<Self> {
+        let hasher = match expected.split_once(':') {
+            Some(("sha256", _)) => Hasher::Sha256(Sha256::new()),
+            Some(("sha512", _)) => Hasher::Sha512(Sha512::new()),
+            _ => {
+                return Err(ErrorWrapper::from_custom(&format!(
+                    "unsupported digest `{expected}`"
+                )));
+            }
+        };
+        Ok(Self {
+            expected: expected.to_string(),
+            hasher,
+        })
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match &mut self.hasher {
+            Hasher::Sha256(h) => h.update(bytes),
+            Hasher::Sha512(h) => h.update(bytes),
+        }
+    }
+
+    /// Fail with a [`DigestMismatch`] unless everything seen hashes to the expected digest
+    ///
+    /// # Errors
+    ///
+    /// A [`DigestMismatch`] when the digests differ
+    pub fn finish(self, what: &str) -> Result<()> {
+        let actual = match self.hasher {
+            Hasher::Sha256(h) => format!("sha256:{:x}", h.finalize()),
+            Hasher::Sha512(h) => format!("sha512:{:x}", h.finalize()),
+        };
+        if actual == self.expected {
+            return Ok(());
+        }
+        Err(loco_rs::Error::wrap(DigestMismatch {
+            what: what.to_string(),
+            expected: self.expected,
+            actual,
+        }))
+    }
+}
+
+/// Check `bytes` against `expected`
+///
+/// # Errors
+///
+/// When `expected` is not supported, or a [`DigestMismatch`] when `bytes` do not match it
+pub fn verify(bytes: &[u8], expected: &str, what: &str) -> Result<()> {
+    let mut verifier = Verifier::new(expected)?;
+    verifier.update(bytes);
+    verifier.finish(what)
+}
+
+/// Pass a stream through, ending it with an error instead of the last chunk boundary if the content
+/// does not hash to `expected`; an upload fed from it is aborted before it can complete
+///
+/// # Errors
+///
+/// When `expected` is not a digest of a supported algorithm
+pub fn verified<S>(
+    stream: S,
+    expected: &str,
+    what: &str,
+) -> Result<impl Stream<Item = Result<Bytes>> + use<S>>
+where
+    S: Stream<Item = Result<Bytes>> + Unpin,
+{
+    let verifier = Verifier::new(expected)?;
+    let what = what.to_string();
+    Ok(futures::stream::unfold(
+        (stream, Some(verifier), what),
+        |(mut stream, mut verifier, what)| async move {
+            let current = verifier.as_mut()?;
+            match stream.next().await {
+                Some(Ok(bytes)) => {
+                    current.update(&bytes);
+                    Some((Ok(bytes), (stream, verifier, what)))
+                }
+                Some(Err(e)) => Some((Err(e), (stream, None, what))),
+                None => {
+                    let result = verifier.take()?.finish(&what);
+                    result.err().map(|e| (Err(e), (stream, None, what)))
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DigestMismatch, sha256, verified, verify};
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn sha256_of_empty_input() {
+        assert_eq!(
+            super::sha256(b""),
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn verifies_sha512() {
+        let empty = "sha512:cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e";
+        assert!(verify(b"", empty, "blob").is_ok());
+        assert!(verify(b"x", empty, "blob").is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_fail_at_the_end_on_mismatch() {
+        let chunks = || futures::stream::iter([Ok(Bytes::from_static(b"ab")), Ok(Bytes::from_static(b"c"))]);
+        let ok: Vec<Bytes> = verified(chunks(), &sha256(b"abc"), "blob")
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(ok.len(), 2);
+
+        let err = verified(chunks(), &sha256(b"abd"), "blob a")
+            .unwrap()
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap_err();
+        let mismatch = DigestMismatch::find(&err).expect("a digest mismatch");
+        assert_eq!(mismatch.actual, sha256(b"abc"));
+        assert_eq!(mismatch.what, "blob a");
+    }
+}
@@ -0,0 +1,418 @@
+//! An in-memory OCI registry for tests, serving just enough of the distribution API for the
+//! replicator. It keeps every request it saw so tests can assert on traffic.
+use super::{
+    Credentials, Endpoint, digest,
+    manifest::{Manifest, media_types},
+};
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{Query, State},
+    http::{HeaderMap, Method, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+#[derive(Default)]
+pub struct Store {
+    /// Blobs by `(repository, digest)`
+    pub blobs: HashMap<(String, String), Bytes>,
+    /// Manifests `(media type, bytes)` by `(repository, tag or digest)`
+    pub manifests: HashMap<(String, String), (String, Bytes)>,
+    /// Upload sessions in progress
+    pub uploads: HashMap<String, Vec<u8>>,
+    /// Every `(method, path)` served, in order
+    pub requests: Vec<(Method, String)>,
+    /// Answer the referrers API with 404, like registries predating OCI 1.1
+    pub without_referrers_api: bool,
+    next_upload: usize,
+}
+
+impl Store {
+    /// Requests with the given method whose path contains `fragment`
+    #[must_use]
+    pub fn count(&self, method: &Method, fragment: &str) -> usize {
+        self.requests
+            .iter()
+            .filter(|(m, path)| m == method && path.contains(fragment))
+            .count()
+    }
+}
+
+type Shared = Arc<Mutex<Store>>;
+
+pub struct FakeRegistry {
+    /// `127.0.0.1:<port>` the registry listens on
+    pub host: String,
+    store: Shared,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl Drop for FakeRegistry {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+impl FakeRegistry {
+    /// Start a registry on a random local port
+    ///
+    /// # Panics
+    ///
+    /// Panics if no local port can be bound.
+    pub async fn start() -> Self {
+        let store = Shared::default();
+        let app = Router::new().fallback(handle).with_state(store.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind fake registry");
+        let host = listener.local_addr().expect("local addr").to_string();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("fake registry serves");
+        });
+        Self { host, store, server }
+    }
+
+    #[must_use]
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            host: self.host.clone(),
+            insecure: true,
+            credentials: Credentials::Anonymous,
+            layout: None,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// Panics if a previous test thread panicked while holding the store.
+    pub fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().expect("fake registry store")
+    }
+
+    /// Add a blob to `repository`, returning its descriptor
+    #[allow(clippy::must_use_candidate)]
+    pub fn push_blob(&self, repository: &str, media_type: &str, data: &[u8]) -> serde_json::Value {
+        let digest = digest::sha256(data);
+        self.store()
+            .blobs
+            .insert((repository.into(), digest.clone()), Bytes::copy_from_slice(data));
+        json!({ "mediaType": media_type, "digest": digest, "size": data.len() })
+    }
+
+    /// Add a manifest under `reference` (and its digest), returning its digest
+    #[allow(clippy::must_use_candidate)]
+    pub fn push_manifest(&self, repository: &str, reference: &str, media_type: &str, bytes: &[u8]) -> String {
+        let digest = digest::sha256(bytes);
+        let entry = (media_type.to_string(), Bytes::copy_from_slice(bytes));
+        let mut store = self.store();
+        store
+            .manifests
+            .insert((repository.into(), digest.clone()), entry.clone());
+        store
+            .manifests
+            .insert((repository.into(), reference.into()), entry);
+        digest
+    }
+
+    /// Add an artifact referring to the manifest `subject`, under `tag` or only its digest,
+    /// returning its digest
+    #[allow(clippy::must_use_candidate)]
+    pub fn push_artifact(
+        &self,
+        repository: &str,
+        tag: Option<&str>,
+        subject: &str,
+        artifact_type: &str,
+        payload: &[u8],
+    ) -> String {
+        let (subject_type, subject_bytes) =
+            self.store().manifests[&(repository.into(), subject.into())].clone();
+        let config = self.push_blob(repository, "application/vnd.oci.empty.v1+json", b"{}");
+        let layer = self.push_blob(repository, "application/octet-stream", payload);
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "artifactType": artifact_type,
+            "config": config,
+            "layers": [layer],
+            "subject": { "mediaType": subject_type, "digest": subject, "size": subject_bytes.len() },
+        })
+        .to_string();
+        let digest = digest::sha256(manifest.as_bytes());
+        self.push_manifest(
+            repository,
+            tag.unwrap_or(&digest),
+            media_types::OCI_MANIFEST,
+            manifest.as_bytes(),
+        )
+    }
+
+    /// Add a cosign signature manifest for the image `digest` with one signature layer per
+    /// `(payload, annotations)`, returning its tag
+    #[allow(clippy::must_use_candidate)]
+    pub fn push_signatures(
+        &self,
+        repository: &str,
+        digest: &str,
+        signatures: &[(&[u8], serde_json::Value)],
+    ) -> String {
+        let config = self.push_blob(repository, "application/vnd.oci.image.config.v1+json", b"{}");
+        let layers: Vec<_> = signatures
+            .iter()
+            .map(|(payload, annotations)| {
+                let mut layer = self.push_blob(
+                    repository,
+                    "application/vnd.dev.cosign.simplesigning.v1+json",
+                    payload,
+                );
+                layer["annotations"] = annotations.clone();
+                layer
+            })
+            .collect();
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "config": config,
+            "layers": layers,
+        });
+        let tag = format!("{}.sig", super::manifest::referrers_tag(digest));
+        self.push_manifest(
+            repository,
+            &tag,
+            media_types::OCI_MANIFEST,
+            manifest.to_string().as_bytes(),
+        );
+        tag
+    }
+
+    /// Add a single-platform OCI image with the given layers, returning the manifest digest
+    #[allow(clippy::must_use_candidate)]
+    pub fn push_image(&self, repository: &str, tag: &str, layers: &[&[u8]]) -> String {
+        let config = self.push_blob(
+            repository,
+            "application/vnd.oci.image.config.v1+json",
+            format!(r#"{{"architecture":"amd64","os":"linux","tag":"{tag}"}}"#).as_bytes(),
+        );
+        let layers: Vec<_> = layers
+            .iter()
+            .map(|l| self.push_blob(repository, "application/vnd.oci.image.layer.v1.tar+gzip", l))
+            .collect();
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "config": config,
+            "layers": layers,
+        });
+        self.push_manifest(
+            repository,
+            tag,
+            media_types::OCI_MANIFEST,
+            manifest.to_string().as_bytes(),
+        )
+    }
+}
+
+fn status(code: StatusCode) -> Response {
+    code.into_response()
+}
+
+#[allow(clippy::too_many_lines)]
+async fn handle(
+    State(store): State<Shared>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let path = uri.path().to_string();
+    let mut store = store.lock().expect("fake registry store");
+    store.requests.push((method.clone(), path.clone()));
+    let Some(path) = path.strip_prefix("/v2/") else {
+        return status(StatusCode::NOT_FOUND);
+    };
+    if path.is_empty() {
+        return status(StatusCode::OK);
+    }
+
+    if let Some(repository) = path.strip_suffix("/tags/list") {
+        let mut tags: Vec<_> = store
+            .manifests
+            .keys()
+            .filter(|(repo, reference)| repo == repository && !reference.starts_with("sha256:"))
+            .map(|(_, reference)| reference.clone())
+            .collect();
+        tags.sort();
+        return axum::Json(json!({ "name": repository, "tags": tags })).into_response();
+    }
+
+    if let Some((repository, subject)) = path.split_once("/referrers/") {
+        if store.without_referrers_api {
+            return status(StatusCode::NOT_FOUND);
+        }
+        let mut referrers: Vec<_> = store
+            .manifests
+            .iter()
+            .filter(|((repo, reference), _)| repo == repository && reference.starts_with("sha256:"))
+            .filter_map(|((_, reference), (media_type, bytes))| {
+                let manifest = Manifest::parse(bytes).ok()?;
+                (manifest.subject?.digest == subject).then(|| {
+                    json!({
+                        "mediaType": media_type,
+                        "digest": reference,
+                        "size": bytes.len(),
+                        "artifactType": manifest.artifact_type,
+                    })
+                })
+            })
+            .collect();
+        referrers.sort_by_key(|r| r["digest"].to_string());
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_INDEX,
+            "manifests": referrers,
+        });
+        return (
+            [(header::CONTENT_TYPE, media_types::OCI_INDEX)],
+            index.to_string(),
+        )
+            .into_response();
+    }
+
+    if let Some((repository, rest)) = path.split_once("/blobs/uploads/") {
+        let repository = repository.to_string();
+        return match (method, rest) {
+            (Method::POST, "") => {
+                if let (Some(digest), Some(from)) = (query.get("mount"), query.get("from"))
+                    && let Some(blob) = store.blobs.get(&(from.clone(), digest.clone())).cloned()
+                {
+                    store.blobs.insert((repository, digest.clone()), blob);
+                    return status(StatusCode::CREATED);
+                }
+                store.next_upload += 1;
+                let id = format!("upload-{}", store.next_upload);
+                store.uploads.insert(id.clone(), body.to_vec());
+                (StatusCode::ACCEPTED, [(
+                    header::LOCATION,
+                    format!("/v2/{repository}/blobs/uploads/{id}"),
+                )])
+                    .into_response()
+            }
+            (Method::PATCH, id) => {
+                let Some(upload) = store.uploads.get_mut(id) else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                let start = headers
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|range| range.split_once('-'))
+                    .and_then(|(start, _)| start.parse::<usize>().ok());
+                if start.is_some_and(|start| start != upload.len()) {
+                    return status(StatusCode::RANGE_NOT_SATISFIABLE);
+                }
+                upload.extend_from_slice(&body);
+                let end = upload.len().saturating_sub(1);
+                (StatusCode::ACCEPTED, [
+                    (header::LOCATION, format!("/v2/{repository}/blobs/uploads/{id}")),
+                    (header::RANGE, format!("0-{end}")),
+                ])
+                    .into_response()
+            }
+            (Method::GET, id) => store.uploads.get(id).map_or_else(
+                || status(StatusCode::NOT_FOUND),
+                |upload| {
+                    (StatusCode::NO_CONTENT, [
+                        (header::LOCATION, format!("/v2/{repository}/blobs/uploads/{id}")),
+                        (header::RANGE, format!("0-{}", upload.len().saturating_sub(1))),
+                    ])
+                        .into_response()
+                },
+            ),
+            (Method::PUT, id) => {
+                let Some(mut upload) = store.uploads.remove(id) else {
+                    return status(StatusCode::NOT_FOUND);
+                };
+                upload.extend_from_slice(&body);
+                let Some(expected) = query.get("digest") else {
+                    return status(StatusCode::BAD_REQUEST);
+                };
+                if &digest::sha256(&upload) != expected {
+                    return (StatusCode::BAD_REQUEST, "DIGEST_INVALID").into_response();
+                }
+                store.blobs.insert((repository, expected.clone()), upload.into());
+                status(StatusCode::CREATED)
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+
+    if let Some((repository, digest)) = path.split_once("/blobs/") {
+        let key = (repository.to_string(), digest.to_string());
+        return match (method, store.blobs.get(&key)) {
+            (Method::HEAD, Some(blob)) => {
+                (StatusCode::OK, [(header::CONTENT_LENGTH, blob.len().to_string())]).into_response()
+            }
+            (Method::GET, Some(blob)) => {
+                let from = headers
+                    .get(header::RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.strip_suffix('-'))
+                    .and_then(|start| start.parse::<usize>().ok());
+                match from {
+                    Some(start) if start <= blob.len() => {
+                        (StatusCode::PARTIAL_CONTENT, blob.slice(start..)).into_response()
+                    }
+                    _ => (StatusCode::OK, blob.clone()).into_response(),
+                }
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        };
+    }
+
+    if let Some((repository, reference)) = path.split_once("/manifests/") {
+        let key = (repository.to_string(), reference.to_string());
+        return match method {
+            Method::PUT => {
+                let media_type = headers
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_string();
+                let digest = digest::sha256(&body);
+                let entry = (media_type, body);
+                store
+                    .manifests
+                    .insert((repository.to_string(), digest.clone()), entry.clone());
+                store.manifests.insert(key, entry);
+                (StatusCode::CREATED, [("Docker-Content-Digest", digest)]).into_response()
+            }
+            Method::GET | Method::HEAD => match store.manifests.get(&key) {
+                Some((media_type, bytes)) => {
+                    let headers = [
+                        (header::CONTENT_TYPE.as_str(), media_type.clone()),
+                        ("Docker-Content-Digest", digest::sha256(bytes)),
+                    ];
+                    if method == Method::HEAD {
+                        (StatusCode::OK, headers).into_response()
+                    } else {
+                        (StatusCode::OK, headers, bytes.clone()).into_response()
+                    }
+                }
+                None => status(StatusCode::NOT_FOUND),
+            },
+            Method::DELETE => match store.manifests.remove(&key) {
+                Some(_) => status(StatusCode::ACCEPTED),
+                None => status(StatusCode::NOT_FOUND),
+            },
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+
+    status(StatusCode::NOT_FOUND)
+}
@@ -0,0 +1,202 @@
+//! Converting manifests between the Docker schema2 and OCI formats.
+//!
+//! The two formats describe images the same way and differ in media types only: those of the
+//! manifest or index itself, of the image config and of the layers. Converting swaps every media
+//! type that has a counterpart and keeps everything else, so blobs are shared by both versions of
+//! an image; only the manifests get new digests. Artifacts, i.e. manifests with an `artifactType`,
+//! a `subject` or a config that is not an image config, only exist as OCI and are never converted.
+use crate::core::{
+    Result,
+    crd::ManifestFormat,
+    registry::{
+        client::RawManifest,
+        manifest::{self, Descriptor, Manifest, media_types},
+    },
+};
+use std::collections::HashMap;
+
+/// Media types of the OCI format and their Docker schema2 counterpart
+static COUNTERPARTS: [(&str, &str); 6] = [
+    (media_types::OCI_MANIFEST, media_types::DOCKER_MANIFEST),
+    (media_types::OCI_INDEX, media_types::DOCKER_MANIFEST_LIST),
+    (media_types::OCI_CONFIG, media_types::DOCKER_CONFIG),
+    (media_types::OCI_LAYER_GZIP, media_types::DOCKER_LAYER_GZIP),
+    (
+        "application/vnd.oci.image.layer.v1.tar",
+        "application/vnd.docker.image.rootfs.diff.tar",
+    ),
+    (
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+    ),
+];
+
+/// The format of a manifest or index media type
+#[must_use]
+pub fn of(media_type: &str) -> Option<ManifestFormat> {
+    if media_type == media_types::OCI_MANIFEST || media_type == media_types::OCI_INDEX {
+        Some(ManifestFormat::Oci)
+    } else if media_type == media_types::DOCKER_MANIFEST || media_type == media_types::DOCKER_MANIFEST_LIST {
+        Some(ManifestFormat::Docker)
+    } else {
+        None
+    }
+}
+
+/// The counterpart of `media_type` in `format`, if it has one and is not in `format` already
+#[must_use]
+pub fn media_type(media_type: &str, format: ManifestFormat) -> Option<&'static str> {
+    COUNTERPARTS.iter().find_map(|(oci, docker)| match format {
+        ManifestFormat::Oci => (*docker == media_type).then_some(*oci),
+        ManifestFormat::Docker => (*oci == media_type).then_some(*docker),
+    })
+}
+
+/// Whether `manifest` is an image or an index, which both formats can express
+#[must_use]
+pub fn convertible(manifest: &Manifest) -> bool {
+    let image_config = manifest.config.as_ref().is_some_and(|config| {
+        config.media_type == media_types::OCI_CONFIG || config.media_type == media_types::DOCKER_CONFIG
+    });
+    manifest.subject.is_none() && manifest.artifact_type.is_none() && (image_config || manifest.is_index())
+}
+
+/// `manifest` converted to `format`, or `None` if it is in `format` already or can not be converted
+///
+/// # Errors
+///
+/// When the manifest is not valid JSON
+pub fn convert(manifest: &RawManifest, format: ManifestFormat) -> Result<Option<RawManifest>> {
+    let Some(converted) = media_type(&manifest.media_type, format) else {
+        return Ok(None);
+    };
+    let parsed = Manifest::parse(&manifest.bytes)?;
+    if !convertible(&parsed) {
+        return Ok(None);
+    }
+    let replaced: HashMap<String, Descriptor> = parsed
+        .blobs()
+        .chain(&parsed.manifests)
+        .filter_map(|descriptor| {
+            let media_type = media_type(&descriptor.media_type, format)?;
+            Some((descriptor.digest.clone(), Descriptor {
+                media_type: media_type.to_string(),
+                ..descriptor.clone()
+            }))
+        })
+        .collect();
+    manifest::rewrite(manifest, converted, &replaced).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{convert, convertible, media_type};
+    use crate::core::{
+        crd::ManifestFormat,
+        registry::{
+            client::RawManifest,
+            digest,
+            manifest::{Manifest, media_types},
+        },
+    };
+
+    fn fixture(media_type: &str, json: &str) -> RawManifest {
+        RawManifest {
+            media_type: media_type.into(),
+            digest: digest::sha256(json.as_bytes()),
+            bytes: json.as_bytes().to_vec().into(),
+        }
+    }
+
+    fn docker_manifest() -> RawManifest {
+        fixture(
+            media_types::DOCKER_MANIFEST,
+            include_str!("testdata/docker-manifest.json"),
+        )
+    }
+
+    fn value(manifest: &RawManifest) -> serde_json::Value {
+        serde_json::from_slice(&manifest.bytes).unwrap()
+    }
+
+    #[test]
+    fn docker_images_become_oci_images_and_back() {
+        let docker = docker_manifest();
+        let oci = convert(&docker, ManifestFormat::Oci).unwrap().unwrap();
+        assert_eq!(oci.media_type, media_types::OCI_MANIFEST);
+        assert_eq!(oci.digest, digest::sha256(&oci.bytes));
+        let parsed = Manifest::parse(&oci.bytes).unwrap();
+        assert_eq!(parsed.media_type.as_deref(), Some(media_types::OCI_MANIFEST));
+        assert_eq!(
+            parsed.config.as_ref().unwrap().media_type,
+            media_types::OCI_CONFIG
+        );
+        assert!(
+            parsed
+                .layers
+                .iter()
+                .all(|l| l.media_type == media_types::OCI_LAYER_GZIP)
+        );
+        let original = Manifest::parse(&docker.bytes).unwrap();
+        let digests = |m: &Manifest| m.blobs().map(|b| (b.digest.clone(), b.size)).collect::<Vec<_>>();
+        assert_eq!(digests(&parsed), digests(&original), "blobs are shared");
+
+        assert!(convert(&oci, ManifestFormat::Oci).unwrap().is_none());
+        let back = convert(&oci, ManifestFormat::Docker).unwrap().unwrap();
+        assert_eq!(value(&back), value(&docker));
+    }
+
+    #[test]
+    fn manifest_lists_become_indexes_and_back() {
+        let list = fixture(
+            media_types::DOCKER_MANIFEST_LIST,
+            include_str!("testdata/docker-manifest-list.json"),
+        );
+        let index = convert(&list, ManifestFormat::Oci).unwrap().unwrap();
+        let converted = value(&index);
+        assert_eq!(converted["mediaType"], media_types::OCI_INDEX);
+        assert_eq!(converted["manifests"][1]["mediaType"], media_types::OCI_MANIFEST);
+        assert_eq!(converted["manifests"][1]["platform"]["variant"], "v8");
+        let back = convert(&index, ManifestFormat::Docker).unwrap().unwrap();
+        assert_eq!(value(&back), value(&list));
+    }
+
+    #[test]
+    fn oci_annotations_and_attestations_are_kept() {
+        let oci = fixture(
+            media_types::OCI_MANIFEST,
+            include_str!("testdata/oci-manifest.json"),
+        );
+        let docker = value(&convert(&oci, ManifestFormat::Docker).unwrap().unwrap());
+        assert_eq!(docker["config"]["mediaType"], media_types::DOCKER_CONFIG);
+        assert_eq!(docker["layers"][0]["mediaType"], media_types::DOCKER_LAYER_GZIP);
+        // zstd layers have no Docker media type, they have to be recompressed first
+        assert_eq!(docker["layers"][1]["mediaType"], media_types::OCI_LAYER_ZSTD);
+        assert_eq!(docker["annotations"], value(&oci)["annotations"]);
+
+        let index = fixture(media_types::OCI_INDEX, include_str!("testdata/oci-index.json"));
+        let list = value(&convert(&index, ManifestFormat::Docker).unwrap().unwrap());
+        assert_eq!(list["mediaType"], media_types::DOCKER_MANIFEST_LIST);
+        assert_eq!(
+            list["manifests"][1]["annotations"]["vnd.docker.reference.type"],
+            "attestation-manifest"
+        );
+    }
+
+    #[test]
+    fn artifacts_are_not_converted() {
+        let artifact = fixture(
+            media_types::OCI_MANIFEST,
+            include_str!("testdata/oci-artifact.json"),
+        );
+        assert!(!convertible(&Manifest::parse(&artifact.bytes).unwrap()));
+        assert!(convert(&artifact, ManifestFormat::Docker).unwrap().is_none());
+        assert_eq!(
+            media_type(
+                "application/vnd.oci.image.layer.v1.tar+zstd",
+                ManifestFormat::Docker
+            ),
+            None
+        );
+    }
+}
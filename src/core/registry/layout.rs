@@ -0,0 +1,769 @@
+//! Repositories kept in an OCI image layout on disk instead of a registry, for sites without a
+//! network path to the source.
+//!
+//! All repositories of a layout share its `blobs/<algorithm>` directories, and `index.json` lists
+//! every tagged manifest with `org.opencontainers.image.ref.name` set to `<repository>:<tag>`; bare
+//! `<tag>` names, as skopeo and crane write them, are read too.
+//! Manifests written only by digest (index children, images pinned by digest) are stored as blobs
+//! without an `index.json` entry. A layout whose path ends in `.tar` is kept as a directory next to
+//! it, without the extension, and packed into the tarball on [`OciLayout::flush`], which replication
+//! runs call once they finished, so the file holds a complete layout that can be carried over. A
+//! tarball carried over alone is read in place, and only unpacked once something is written to it.
+use super::{
+    client::{ByteStream, RawManifest},
+    manifest::{Descriptor, Manifest, media_types},
+};
+use crate::core::{ErrorWrapper, Result};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// Annotation naming a manifest listed in `index.json`
+pub static REF_NAME: &str = "org.opencontainers.image.ref.name";
+static INDEX_FILE: &str = "index.json";
+static LAYOUT_FILE: &str = "oci-layout";
+/// Bytes read from a blob file at a time
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Tells apart the staging files of blobs written at the same time
+static STAGED: AtomicU64 = AtomicU64::new(0);
+
+/// `(offset, size)` of the files in a tarball, by path
+type Members = HashMap<String, (u64, u64)>;
+
+/// A manifest tagged in `index.json`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaggedImage {
+    /// Repository of `<repository>:<tag>` names, `None` for the bare tags skopeo and crane write
+    pub repository: Option<String>,
+    pub tag: String,
+    pub digest: String,
+}
+
+pub struct OciLayout {
+    /// The layout directory
+    root: PathBuf,
+    /// Tarball the directory is packed into on flush
+    tarball: Option<PathBuf>,
+    /// Whether `index.json` changed since the tarball was last packed
+    changed: AtomicBool,
+    /// Serialises read-modify-write cycles of `index.json`, and unpacking the tarball
+    index: Mutex<()>,
+    /// Files of the tarball as of its modification time, while it is read in place
+    members: std::sync::Mutex<Option<(SystemTime, Arc<Members>)>>,
+}
+
+/// A file of the layout, read from its first byte
+struct Member {
+    reader: tokio::io::Take<fs::File>,
+    /// Where the file lives, for errors
+    path: PathBuf,
+    size: u64,
+}
+
+impl OciLayout {
+    /// The layout at `path`, a directory or a `.tar` file; nothing is created until written to
+    #[must_use]
+    pub fn new(path: &Path) -> Self {
+        let (root, tarball) = if path.extension().is_some_and(|e| e == "tar") {
+            (path.with_extension(""), Some(path.to_path_buf()))
+        } else {
+            (path.to_path_buf(), None)
+        };
+        Self {
+            root,
+            tarball,
+            changed: AtomicBool::new(false),
+            index: Mutex::new(()),
+            members: std::sync::Mutex::default(),
+        }
+    }
+
+    /// The layout directory
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path of a blob within the layout, e.g. `blobs/sha256/e3b0c442…`
+    fn blob_name(digest: &str) -> Result<String> {
+        match digest.split_once(':') {
+            Some((algorithm, hex))
+                if ["sha256", "sha512"].contains(&algorithm)
+                    && !hex.is_empty()
+                    && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Ok(format!("blobs/{algorithm}/{hex}"))
+            }
+            _ => Err(ErrorWrapper::from_custom(&format!(
+                "unsupported digest `{digest}`"
+            ))),
+        }
+    }
+
+    /// The tarball to read from in place, for tarball layouts without a directory yet
+    async fn packed(&self) -> Result<Option<&Path>> {
+        let Some(tarball) = &self.tarball else {
+            return Ok(None);
+        };
+        let unpacked = fs::try_exists(&self.root)
+            .await
+            .map_err(|e| io_error(&self.root, &e))?;
+        Ok((!unpacked).then_some(tarball.as_path()))
+    }
+
+    async fn members(&self, tarball: &Path) -> Result<Arc<Members>> {
+        let modified = match fs::metadata(tarball).await.and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Arc::default()),
+            Err(e) => return Err(io_error(tarball, &e)),
+        };
+        if let Some((at, members)) = &*self.members.lock().expect("layout members lock poisoned")
+            && *at == modified
+        {
+            return Ok(members.clone());
+        }
+        let path = tarball.to_path_buf();
+        let members = tokio::task::spawn_blocking(move || list(&path))
+            .await
+            .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))??;
+        let members = Arc::new(members);
+        *self.members.lock().expect("layout members lock poisoned") = Some((modified, members.clone()));
+        Ok(members)
+    }
+
+    /// Open a file of the layout, or `None` if the layout does not have it
+    async fn open(&self, name: &str) -> Result<Option<Member>> {
+        if let Some(tarball) = self.packed().await? {
+            let Some(&(offset, size)) = self.members(tarball).await?.get(name) else {
+                return Ok(None);
+            };
+            let mut file = fs::File::open(tarball).await.map_err(|e| io_error(tarball, &e))?;
+            file.seek(SeekFrom::Start(offset))
+                .await
+                .map_err(|e| io_error(tarball, &e))?;
+            return Ok(Some(Member {
+                reader: file.take(size),
+                path: tarball.to_path_buf(),
+                size,
+            }));
+        }
+        let path = self.root.join(name);
+        let file = match fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_error(&path, &e)),
+        };
+        let size = file.metadata().await.map_err(|e| io_error(&path, &e))?.len();
+        Ok(Some(Member {
+            reader: file.take(size),
+            path,
+            size,
+        }))
+    }
+
+    /// A whole file of the layout, or `None` if the layout does not have it
+    async fn read(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let Some(mut member) = self.open(name).await? else {
+            return Ok(None);
+        };
+        let mut bytes = Vec::with_capacity(usize::try_from(member.size).unwrap_or_default());
+        member
+            .reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|e| io_error(&member.path, &e))?;
+        Ok(Some(bytes))
+    }
+
+    /// Make sure there is a directory to write to, unpacking the tarball into it if there is one
+    async fn unpack(&self) -> Result<()> {
+        let _guard = self.index.lock().await;
+        let Some(tarball) = self.packed().await? else {
+            return Ok(());
+        };
+        if !fs::try_exists(tarball).await.map_err(|e| io_error(tarball, &e))? {
+            return fs::create_dir_all(&self.root)
+                .await
+                .map_err(|e| io_error(&self.root, &e));
+        }
+        let (tarball, root) = (tarball.to_path_buf(), self.root.clone());
+        tokio::task::spawn_blocking(move || {
+            let staged = staging_path(&root);
+            let file = std::fs::File::open(&tarball).map_err(|e| io_error(&tarball, &e))?;
+            tar::Archive::new(file)
+                .unpack(&staged)
+                .map_err(|e| io_error(&tarball, &e))?;
+            std::fs::rename(&staged, &root).map_err(|e| io_error(&root, &e))
+        })
+        .await
+        .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?
+    }
+
+    async fn read_index(&self) -> Result<Manifest> {
+        self.read(INDEX_FILE)
+            .await?
+            .map_or_else(|| Ok(Manifest::index(vec![])), |bytes| Manifest::parse(&bytes))
+    }
+
+    async fn write_index(&self, index: &Manifest) -> Result<()> {
+        let bytes = serde_json::to_vec_pretty(index).map_err(ErrorWrapper::from_serde)?;
+        let layout = json!({ "imageLayoutVersion": "1.0.0" }).to_string();
+        write_atomically(&self.root.join(LAYOUT_FILE), layout.as_bytes()).await?;
+        write_atomically(&self.root.join(INDEX_FILE), &bytes).await?;
+        self.changed.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Pack the directory into the tarball if tags were written since it was last packed
+    ///
+    /// # Errors
+    ///
+    /// When the tarball can not be written; it is packed again on the next flush
+    pub async fn flush(&self) -> Result<()> {
+        let Some(tarball) = &self.tarball else {
+            return Ok(());
+        };
+        let _guard = self.index.lock().await;
+        if !self.changed.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let (root, tarball) = (self.root.clone(), tarball.clone());
+        let packed = tokio::task::spawn_blocking(move || pack(&root, &tarball))
+            .await
+            .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))
+            .and_then(|packed| packed);
+        if packed.is_err() {
+            self.changed.store(true, Ordering::Release);
+        }
+        packed
+    }
+
+    /// The `index.json` entry of `repository:tag`
+    async fn tagged(&self, repository: &str, tag: &str) -> Result<Option<Descriptor>> {
+        let name = format!("{repository}:{tag}");
+        Ok(self
+            .read_index()
+            .await?
+            .manifests
+            .into_iter()
+            .find(|entry| entry.annotations.get(REF_NAME) == Some(&name)))
+    }
+
+    /// Every tagged manifest, read from the `<repository>:<tag>` or bare `<tag>` names of
+    /// `index.json`; entries without a name are left out, and so are names that are no tag, with a
+    /// warning
+    ///
+    /// # Errors
+    ///
+    /// When `index.json` can not be read
+    pub async fn images(&self) -> Result<Vec<TaggedImage>> {
+        Ok(self
+            .read_index()
+            .await?
+            .manifests
+            .into_iter()
+            .filter_map(|entry| {
+                let name = entry.annotations.get(REF_NAME)?;
+                let (repository, tag) = match name.rsplit_once(':') {
+                    Some((repository, tag)) => (Some(repository), tag),
+                    None => (None, name.as_str()),
+                };
+                if repository.is_some_and(str::is_empty) || tag.is_empty() || tag.contains('/') {
+                    warn!(root = %self.root.display(), name, "Layout entry is not named by a tag, skipping");
+                    return None;
+                }
+                Some(TaggedImage {
+                    repository: repository.map(str::to_string),
+                    tag: tag.to_string(),
+                    digest: entry.digest.clone(),
+                })
+            })
+            .collect())
+    }
+
+    /// Fetch a manifest by tag or digest
+    ///
+    /// # Errors
+    ///
+    /// When the layout does not have the manifest, or it does not match its digest
+    pub async fn manifest(&self, repository: &str, reference: &str) -> Result<RawManifest> {
+        let not_found = || ErrorWrapper::from_custom(&format!("manifest {repository}:{reference} not found"));
+        let (digest, media_type) = if reference.contains(':') {
+            (reference.to_string(), None)
+        } else {
+            let entry = self.tagged(repository, reference).await?.ok_or_else(not_found)?;
+            (entry.digest, Some(entry.media_type))
+        };
+        let bytes = self
+            .read(&Self::blob_name(&digest)?)
+            .await?
+            .ok_or_else(not_found)?;
+        super::digest::verify(&bytes, &digest, &format!("manifest {repository}@{digest}"))?;
+        let media_type = match media_type {
+            Some(media_type) => media_type,
+            None => Manifest::parse(&bytes)?
+                .media_type
+                .unwrap_or_else(|| media_types::OCI_MANIFEST.to_string()),
+        };
+        Ok(RawManifest {
+            media_type,
+            digest,
+            bytes: bytes.into(),
+        })
+    }
+
+    /// Digest of a manifest, or `None` if the layout does not have it
+    ///
+    /// # Errors
+    ///
+    /// When the layout can not be read
+    pub async fn manifest_digest(&self, repository: &str, reference: &str) -> Result<Option<String>> {
+        if reference.contains(':') {
+            let exists = self.blob_exists(reference).await?;
+            return Ok(exists.then(|| reference.to_string()));
+        }
+        Ok(self.tagged(repository, reference).await?.map(|e| e.digest))
+    }
+
+    /// Store a manifest, listing it in `index.json` when written under a tag
+    ///
+    /// # Errors
+    ///
+    /// When the manifest or `index.json` can not be written
+    pub async fn put_manifest(
+        &self,
+        repository: &str,
+        reference: &str,
+        manifest: &RawManifest,
+    ) -> Result<()> {
+        self.unpack().await?;
+        let path = self.root.join(Self::blob_name(&manifest.digest)?);
+        write_atomically(&path, &manifest.bytes).await?;
+        if reference.contains(':') {
+            return Ok(());
+        }
+        let name = format!("{repository}:{reference}");
+        let _guard = self.index.lock().await;
+        let mut index = self.read_index().await?;
+        index
+            .manifests
+            .retain(|entry| entry.annotations.get(REF_NAME) != Some(&name));
+        index.manifests.push(Descriptor {
+            media_type: manifest.media_type.clone(),
+            digest: manifest.digest.clone(),
+            size: manifest.bytes.len() as u64,
+            annotations: [(REF_NAME.to_string(), name)].into(),
+            ..Descriptor::default()
+        });
+        self.write_index(&index).await
+    }
+
+    /// Whether the layout has a blob
+    ///
+    /// # Errors
+    ///
+    /// When `digest` is not supported, or the layout can not be read
+    pub async fn blob_exists(&self, digest: &str) -> Result<bool> {
+        Ok(self.open(&Self::blob_name(digest)?).await?.is_some())
+    }
+
+    /// Stream a blob starting at `offset`
+    ///
+    /// # Errors
+    ///
+    /// When the layout does not have the blob
+    pub async fn blob_from(&self, digest: &str, offset: u64) -> Result<ByteStream> {
+        let Some(mut member) = self.open(&Self::blob_name(digest)?).await? else {
+            return Err(ErrorWrapper::from_custom(&format!("blob {digest} not found")));
+        };
+        let skip = offset.min(member.size);
+        let mut file = member.reader.into_inner();
+        file.seek(SeekFrom::Current(i64::try_from(skip).unwrap_or(i64::MAX)))
+            .await
+            .map_err(|e| io_error(&member.path, &e))?;
+        member.reader = file.take(member.size - skip);
+        let stream = futures::stream::try_unfold(member, |mut member| async move {
+            let mut buffer = BytesMut::with_capacity(READ_CHUNK);
+            let read = member
+                .reader
+                .read_buf(&mut buffer)
+                .await
+                .map_err(|e| io_error(&member.path, &e))?;
+            Ok((read > 0).then(|| (buffer.freeze(), member)))
+        });
+        Ok(stream.boxed())
+    }
+
+    /// Store a blob, staged aside until all of it was written
+    ///
+    /// # Errors
+    ///
+    /// When the blob can not be written, or `body` fails
+    pub async fn push_blob<S>(&self, digest: &str, body: S) -> Result<()>
+    where
+        S: Stream<Item = Result<Bytes>> + Send,
+    {
+        self.unpack().await?;
+        let path = self.root.join(Self::blob_name(digest)?);
+        let staged = staging_path(&path);
+        create_parent(&path).await?;
+        let mut file = fs::File::create(&staged)
+            .await
+            .map_err(|e| io_error(&staged, &e))?;
+        let mut body = std::pin::pin!(body);
+        while let Some(chunk) = body.next().await {
+            let written = match chunk {
+                Ok(chunk) => file.write_all(&chunk).await.map_err(|e| io_error(&staged, &e)),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                let _ = fs::remove_file(&staged).await;
+                return Err(e);
+            }
+        }
+        file.sync_all().await.map_err(|e| io_error(&staged, &e))?;
+        fs::rename(&staged, &path).await.map_err(|e| io_error(&path, &e))
+    }
+
+    /// Tags of `repository` listed in `index.json`, bare tags belong to no repository
+    ///
+    /// # Errors
+    ///
+    /// When `index.json` can not be read
+    pub async fn tags(&self, repository: &str) -> Result<Vec<String>> {
+        Ok(self
+            .images()
+            .await?
+            .into_iter()
+            .filter(|image| image.repository.as_deref() == Some(repository))
+            .map(|image| image.tag)
+            .collect())
+    }
+}
+
+/// The regular files of `tarball` and where their bytes are
+fn list(tarball: &Path) -> Result<Members> {
+    let file = std::fs::File::open(tarball).map_err(|e| io_error(tarball, &e))?;
+    let mut archive = tar::Archive::new(file);
+    let mut members = Members::new();
+    for entry in archive.entries().map_err(|e| io_error(tarball, &e))? {
+        let entry = entry.map_err(|e| io_error(tarball, &e))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path().map_err(|e| io_error(tarball, &e))?;
+        let name = path.to_string_lossy().trim_start_matches("./").to_string();
+        members.insert(name, (entry.raw_file_position(), entry.size()));
+    }
+    Ok(members)
+}
+
+fn staging_path(path: &Path) -> PathBuf {
+    let n = STAGED.fetch_add(1, Ordering::Relaxed);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}-{n}.partial", std::process::id()));
+    path.with_file_name(name)
+}
+
+async fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent).await.map_err(|e| io_error(parent, &e)),
+        None => Ok(()),
+    }
+}
+
+/// Write aside and rename, so readers never see a torn file
+async fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    create_parent(path).await?;
+    let staged = staging_path(path);
+    fs::write(&staged, bytes)
+        .await
+        .map_err(|e| io_error(&staged, &e))?;
+    fs::rename(&staged, path).await.map_err(|e| io_error(path, &e))
+}
+
+/// Pack the layout at `root` into `tarball`, skipping files still being written
+fn pack(root: &Path, tarball: &Path) -> Result<()> {
+    let staged = staging_path(tarball);
+    let file = std::fs::File::create(&staged).map_err(|e| io_error(&staged, &e))?;
+    let mut archive = tar::Builder::new(std::io::BufWriter::new(file));
+    let mut add = |path: &Path, name: &str| {
+        archive
+            .append_path_with_name(path, name)
+            .map_err(|e| io_error(path, &e))
+    };
+    add(&root.join(LAYOUT_FILE), LAYOUT_FILE)?;
+    add(&root.join(INDEX_FILE), INDEX_FILE)?;
+    let blobs = root.join("blobs");
+    for algorithm in std::fs::read_dir(&blobs).map_err(|e| io_error(&blobs, &e))? {
+        let algorithm = algorithm.map_err(|e| io_error(&blobs, &e))?;
+        let (dir, algorithm) = (algorithm.path(), algorithm.file_name());
+        for blob in std::fs::read_dir(&dir).map_err(|e| io_error(&dir, &e))? {
+            let blob = blob.map_err(|e| io_error(&dir, &e))?;
+            let name = blob.file_name();
+            if name.to_string_lossy().contains('.') {
+                continue;
+            }
+            let entry = format!("blobs/{}/{}", algorithm.to_string_lossy(), name.to_string_lossy());
+            add(&blob.path(), &entry)?;
+        }
+    }
+    archive
+        .into_inner()
+        .and_then(|mut writer| std::io::Write::flush(&mut writer))
+        .map_err(|e| io_error(&staged, &e))?;
+    std::fs::rename(&staged, tarball).map_err(|e| io_error(tarball, &e))
+}
+
+fn io_error(path: &Path, e: &std::io::Error) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("OCI layout {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OciLayout, REF_NAME};
+    use crate::core::{
+        crd::TagMutability,
+        registry::{
+            Credentials, Endpoint,
+            client::RawManifest,
+            digest::sha256,
+            fake::FakeRegistry,
+            manifest::{Manifest, media_types},
+        },
+        replication::{Conversion, ImageLocation, ReplicationJob, Replicator},
+    };
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    fn manifest(bytes: &'static [u8]) -> RawManifest {
+        RawManifest {
+            media_type: media_types::OCI_MANIFEST.into(),
+            digest: sha256(bytes),
+            bytes: Bytes::from_static(bytes),
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_are_listed_in_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = OciLayout::new(dir.path());
+        let (v1, v2) = (
+            manifest(br#"{"schemaVersion":2}"#),
+            manifest(br#"{"schemaVersion":2,"layers":[]}"#),
+        );
+        layout.put_manifest("prod/app", "1.0.0", &v1).await.unwrap();
+        layout.put_manifest("prod/app", "1.0.0", &v2).await.unwrap();
+        layout.put_manifest("prod/job", &v1.digest, &v1).await.unwrap();
+
+        let index = Manifest::parse(&std::fs::read(dir.path().join("index.json")).unwrap()).unwrap();
+        assert_eq!(index.manifests.len(), 1);
+        assert_eq!(index.manifests[0].annotations[REF_NAME], "prod/app:1.0.0");
+        assert_eq!(index.manifests[0].digest, v2.digest);
+        assert!(dir.path().join("oci-layout").exists());
+        assert_eq!(layout.tags("prod/app").await.unwrap(), ["1.0.0"]);
+        assert!(layout.tags("prod/job").await.unwrap().is_empty());
+        assert_eq!(
+            layout.manifest_digest("prod/job", &v1.digest).await.unwrap(),
+            Some(v1.digest.clone())
+        );
+        assert_eq!(
+            layout.manifest("prod/app", "1.0.0").await.unwrap().bytes,
+            v2.bytes
+        );
+        assert!(
+            layout
+                .manifest_digest("prod/app", "2.0.0")
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn blobs_are_streamed_from_an_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = OciLayout::new(dir.path());
+        let digest = sha256(b"layer");
+        let body = futures::stream::iter([Ok(Bytes::from_static(b"lay")), Ok(Bytes::from_static(b"er"))]);
+        layout.push_blob(&digest, body).await.unwrap();
+        assert!(layout.blob_exists(&digest).await.unwrap());
+        let chunks: Vec<_> = layout.blob_from(&digest, 2).await.unwrap().collect().await;
+        let read: Vec<u8> = chunks.into_iter().flat_map(|c| c.unwrap()).collect();
+        assert_eq!(read, b"yer");
+        assert!(OciLayout::blob_name("sha256:../../etc").is_err());
+    }
+
+    #[tokio::test]
+    async fn images_are_exported_to_tarballs() {
+        let source = FakeRegistry::start().await;
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = dir.path().join("bundle.tar");
+        let job = ReplicationJob {
+            source: ImageLocation {
+                endpoint: source.endpoint(),
+                repository: "ci/app".into(),
+                reference: "1.0.0".into(),
+            },
+            destination: ImageLocation {
+                endpoint: Endpoint {
+                    host: tarball.display().to_string(),
+                    insecure: false,
+                    credentials: Credentials::Anonymous,
+                    layout: Some(tarball.clone()),
+                },
+                repository: "prod/app".into(),
+                reference: "1.0.0".into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        };
+        let replicator = Replicator::default();
+        let report = replicator.replicate(&job).await.unwrap();
+        assert_eq!(report.blobs_copied, 2, "config and layer");
+        assert!(!tarball.exists(), "packed once the run is flushed");
+        replicator.flush([&job.destination.endpoint]).await.unwrap();
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&tarball).unwrap());
+        let mut entries: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        let mut expected: Vec<String> = source
+            .store()
+            .blobs
+            .keys()
+            .map(|(_, digest)| digest)
+            .chain([&digest])
+            .map(|digest| format!("blobs/sha256/{}", digest.trim_start_matches("sha256:")))
+            .chain(["index.json".into(), "oci-layout".into()])
+            .collect();
+        expected.sort();
+        assert_eq!(entries, expected);
+        let layout = OciLayout::new(&tarball);
+        assert_eq!(layout.root(), dir.path().join("bundle"));
+        assert_eq!(
+            layout.manifest_digest("prod/app", "1.0.0").await.unwrap(),
+            Some(digest)
+        );
+    }
+
+    fn layout_endpoint(path: &std::path::Path) -> Endpoint {
+        Endpoint {
+            host: path.display().to_string(),
+            insecure: false,
+            credentials: Credentials::Anonymous,
+            layout: Some(path.to_path_buf()),
+        }
+    }
+
+    #[tokio::test]
+    async fn tarballs_carried_over_alone_are_imported_in_place() {
+        let (ci, site) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = ci.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = dir.path().join("bundle.tar");
+        let replicator = Replicator::default();
+        let export = ReplicationJob {
+            source: ImageLocation {
+                endpoint: ci.endpoint(),
+                repository: "ci/app".into(),
+                reference: "1.0.0".into(),
+            },
+            destination: ImageLocation {
+                endpoint: layout_endpoint(&tarball),
+                repository: "ci/app".into(),
+                reference: "1.0.0".into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        };
+        replicator.replicate(&export).await.unwrap();
+        replicator.flush([&export.destination.endpoint]).await.unwrap();
+        std::fs::remove_dir_all(dir.path().join("bundle")).unwrap();
+
+        let import = ReplicationJob {
+            source: export.destination.clone(),
+            destination: ImageLocation {
+                endpoint: site.endpoint(),
+                repository: "prod/app".into(),
+                reference: "1.0.0".into(),
+            },
+            ..export
+        };
+        let report = Replicator::default().replicate(&import).await.unwrap();
+        assert_eq!(report.digest, digest);
+        assert_eq!(report.blobs_copied, 2);
+        assert!(!dir.path().join("bundle").exists(), "reading does not unpack");
+        assert!(
+            site.store()
+                .manifests
+                .contains_key(&("prod/app".to_string(), "1.0.0".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn tampered_blobs_are_not_imported() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = OciLayout::new(dir.path());
+        let (layer, config) = (sha256(b"layer"), sha256(b"{}"));
+        for (digest, bytes) in [(&layer, &b"tampered"[..]), (&config, &b"{}"[..])] {
+            let body = futures::stream::iter([Ok(Bytes::copy_from_slice(bytes))]);
+            layout.push_blob(digest, body).await.unwrap();
+        }
+        let image = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": config, "size": 2 },
+            "layers": [{ "mediaType": "application/vnd.oci.image.layer.v1.tar", "digest": layer, "size": 5 }],
+        })
+        .to_string();
+        let manifest = RawManifest {
+            media_type: media_types::OCI_MANIFEST.into(),
+            digest: sha256(image.as_bytes()),
+            bytes: image.into(),
+        };
+        layout.put_manifest("ci/app", "1.0.0", &manifest).await.unwrap();
+
+        let site = FakeRegistry::start().await;
+        let import = ReplicationJob {
+            source: ImageLocation {
+                endpoint: layout_endpoint(dir.path()),
+                repository: "ci/app".into(),
+                reference: "1.0.0".into(),
+            },
+            destination: ImageLocation {
+                endpoint: site.endpoint(),
+                repository: "prod/app".into(),
+                reference: "1.0.0".into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        };
+        assert!(Replicator::default().replicate(&import).await.is_err());
+        assert!(site.store().manifests.is_empty());
+    }
+}
@@ -0,0 +1,196 @@
+//! Per-registry-host request and bandwidth limits.
+//!
+//! Both limits are token buckets. A caller always gets its tokens immediately and then sleeps off any
+//! debt it left the bucket in, so large blob chunks are throttled without needing a bucket at least
+//! as big as the chunk.
+use crate::core::settings::{RateLimits, RegistrySettings};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    /// Maximum number of tokens that can be saved up
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    #[must_use]
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// How long a caller taking `amount` tokens now has to wait
+    #[allow(clippy::missing_panics_doc)]
+    pub fn reserve(&self, amount: f64) -> Duration {
+        let mut state = self.state.lock().expect("token bucket lock poisoned");
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.capacity) - amount;
+        state.updated = now;
+        if state.tokens < 0.0 {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Take `amount` tokens, waiting for the bucket to refill if it ran dry
+    pub async fn acquire(&self, amount: f64) {
+        let wait = self.reserve(amount);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The limits applying to a single registry host
+#[derive(Default)]
+pub struct HostLimiter {
+    requests: Option<TokenBucket>,
+    bandwidth: Option<TokenBucket>,
+}
+
+impl HostLimiter {
+    #[must_use]
+    pub fn new(limits: &RateLimits) -> Self {
+        let requests = (limits.requests_per_second > 0).then(|| {
+            let rate = f64::from(limits.requests_per_second);
+            TokenBucket::new(rate, f64::from(limits.burst.max(1)))
+        });
+        #[allow(clippy::cast_precision_loss)]
+        let bandwidth = (limits.bytes_per_second > 0).then(|| {
+            let rate = limits.bytes_per_second as f64;
+            TokenBucket::new(rate, rate)
+        });
+        Self { requests, bandwidth }
+    }
+
+    /// Wait for permission to send one request
+    pub async fn request(&self) {
+        if let Some(bucket) = &self.requests {
+            bucket.acquire(1.0).await;
+        }
+    }
+
+    /// Wait for permission to move `bytes` over the wire
+    pub async fn transfer(&self, bytes: usize) {
+        if let Some(bucket) = &self.bandwidth {
+            #[allow(clippy::cast_precision_loss)]
+            bucket.acquire(bytes as f64).await;
+        }
+    }
+
+    /// Throttle a body stream to the bandwidth limit
+    pub fn throttle<S, E>(self: Arc<Self>, stream: S) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        stream.then(move |chunk| {
+            let limiter = self.clone();
+            async move {
+                if let Ok(bytes) = &chunk {
+                    limiter.transfer(bytes.len()).await;
+                }
+                chunk
+            }
+        })
+    }
+}
+
+/// Lazily created limiters for every registry host the controller talks to
+pub struct Limiters {
+    settings: RegistrySettings,
+    hosts: Mutex<HashMap<String, Arc<HostLimiter>>>,
+}
+
+impl Limiters {
+    #[must_use]
+    pub fn new(settings: &RegistrySettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// The limiter for `host`, using its override from the settings if there is one
+    #[allow(clippy::missing_panics_doc)]
+    pub fn for_host(&self, host: &str) -> Arc<HostLimiter> {
+        let mut hosts = self.hosts.lock().expect("limiter lock poisoned");
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let limits = self.settings.hosts.get(host).unwrap_or(&self.settings.limits);
+                Arc::new(HostLimiter::new(limits))
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Limiters, TokenBucket};
+    use crate::core::settings::{RateLimits, RegistrySettings};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn bucket_allows_burst_then_accrues_debt() {
+        let bucket = TokenBucket::new(10.0, 2.0);
+        assert_eq!(bucket.reserve(1.0), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0), Duration::ZERO);
+        let wait = bucket.reserve(1.0);
+        assert!(
+            wait > Duration::from_millis(90) && wait <= Duration::from_millis(100),
+            "{wait:?}"
+        );
+        // the next caller queues behind the previous one
+        assert!(bucket.reserve(1.0) > wait);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn large_transfers_are_spread_over_time() {
+        let limits = RateLimits {
+            bytes_per_second: 1000,
+            ..RateLimits::default()
+        };
+        let limiter = super::HostLimiter::new(&limits);
+        let start = tokio::time::Instant::now();
+        limiter.transfer(1000).await;
+        limiter.transfer(3000).await;
+        assert_eq!(start.elapsed().as_secs(), 3);
+    }
+
+    #[test]
+    fn hosts_share_a_limiter_and_use_overrides() {
+        let mut settings = RegistrySettings::default();
+        settings.hosts.insert("slow.example.com".into(), RateLimits {
+            requests_per_second: 1,
+            ..RateLimits::default()
+        });
+        let limiters = Limiters::new(&settings);
+        let a = limiters.for_host("slow.example.com");
+        let b = limiters.for_host("slow.example.com");
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(a.requests.is_some());
+        assert!(limiters.for_host("fast.example.com").requests.is_none());
+    }
+}
@@ -0,0 +1,226 @@
+//! The subset of the OCI image and distribution specs the replicator needs to walk an image graph.
+//!
+//! Manifests are copied as the raw bytes received from the source, unless the destination converts
+//! what they refer to; these types are only used to find the blobs and child manifests a manifest
+//! refers to.
+use crate::core::{
+    ErrorWrapper, Result,
+    registry::{client::RawManifest, digest},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::BuildHasher,
+};
+
+pub mod media_types {
+    pub static OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+    pub static OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+    pub static DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub static DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+
+    pub static OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+    pub static DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+    pub static OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+    pub static OCI_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+    pub static DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+    /// Every manifest type the replicator can copy, used as the `Accept` header
+    pub static ALL_MANIFESTS: [&str; 4] = [OCI_MANIFEST, OCI_INDEX, DOCKER_MANIFEST, DOCKER_MANIFEST_LIST];
+}
+
+/// Suffixes of the tags cosign stores signatures, attestations and SBOMs of an image under
+pub static COSIGN_SUFFIXES: [&str; 3] = ["sig", "att", "sbom"];
+
+/// Tag of the referrers index registries without the OCI 1.1 referrers API keep for `digest`,
+/// e.g. `sha256-e3b0c442…`
+#[must_use]
+pub fn referrers_tag(digest: &str) -> String {
+    digest.replacen(':', "-", 1)
+}
+
+/// Tags cosign attaches to `digest`, e.g. `sha256-e3b0c442….sig`
+pub fn cosign_tags(digest: &str) -> impl Iterator<Item = String> + use<> {
+    let prefix = referrers_tag(digest);
+    COSIGN_SUFFIXES
+        .iter()
+        .map(move |suffix| format!("{prefix}.{suffix}"))
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<serde_json::Value>,
+}
+
+/// An image manifest or an index, depending on which fields are populated
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config: Option<Descriptor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub manifests: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Descriptor>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// An OCI index listing `manifests`
+    #[must_use]
+    pub fn index(manifests: Vec<Descriptor>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: Some(media_types::OCI_INDEX.to_string()),
+            manifests,
+            ..Self::default()
+        }
+    }
+
+    /// A manifest or index from its JSON
+    ///
+    /// # Errors
+    ///
+    /// When `bytes` is not a manifest
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        serde_json::from_slice(bytes).map_err(ErrorWrapper::from_serde)
+    }
+
+    /// Whether this is an index / manifest list pointing at other manifests
+    #[must_use]
+    pub const fn is_index(&self) -> bool {
+        self.config.is_none() && !self.manifests.is_empty()
+    }
+
+    /// Config and layer descriptors, in the order they should be pushed
+    pub fn blobs(&self) -> impl Iterator<Item = &Descriptor> {
+        self.config.iter().chain(self.layers.iter())
+    }
+}
+
+/// `manifest` as `media_type`, with the descriptors listed in `replaced`, by their original digest,
+/// swapped for their replacement; annotations, platforms and fields this module does not know are kept
+///
+/// # Errors
+///
+/// When `manifest` is not JSON
+pub fn rewrite<S: BuildHasher>(
+    manifest: &RawManifest,
+    media_type: &str,
+    replaced: &HashMap<String, Descriptor, S>,
+) -> Result<RawManifest> {
+    let mut value: serde_json::Value =
+        serde_json::from_slice(&manifest.bytes).map_err(ErrorWrapper::from_serde)?;
+    if media_type != manifest.media_type {
+        value["mediaType"] = media_type.into();
+    }
+    for field in ["config", "layers", "manifests"] {
+        let descriptors = match value.get_mut(field) {
+            Some(serde_json::Value::Array(items)) => items.iter_mut().collect(),
+            Some(descriptor) => vec![descriptor],
+            None => vec![],
+        };
+        for descriptor in descriptors {
+            let digest = descriptor.get("digest").and_then(serde_json::Value::as_str);
+            if let Some(replacement) = digest.and_then(|digest| replaced.get(digest)) {
+                descriptor["mediaType"] = replacement.media_type.clone().into();
+                descriptor["digest"] = replacement.digest.clone().into();
+                descriptor["size"] = replacement.size.into();
+            }
+        }
+    }
+    let bytes = serde_json::to_vec(&value).map_err(ErrorWrapper::from_serde)?;
+    Ok(RawManifest {
+        media_type: media_type.to_string(),
+        digest: digest::sha256(&bytes),
+        bytes: bytes.into(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Descriptor, Manifest, cosign_tags, referrers_tag, rewrite};
+    use crate::core::registry::client::RawManifest;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    #[test]
+    fn distinguishes_images_from_indexes() {
+        let image = Manifest::parse(
+            br#"{"schemaVersion":2,"config":{"mediaType":"c","digest":"sha256:1","size":1},
+                 "layers":[{"mediaType":"l","digest":"sha256:2","size":2}]}"#,
+        )
+        .unwrap();
+        assert!(!image.is_index());
+        assert_eq!(image.blobs().count(), 2);
+
+        let index = Manifest::parse(
+            br#"{"schemaVersion":2,"manifests":[{"mediaType":"m","digest":"sha256:3","size":3}]}"#,
+        )
+        .unwrap();
+        assert!(index.is_index());
+        assert_eq!(index.blobs().count(), 0);
+    }
+
+    #[test]
+    fn derives_artifact_tags_from_the_subject_digest() {
+        assert_eq!(referrers_tag("sha256:abc"), "sha256-abc");
+        assert_eq!(cosign_tags("sha256:abc").collect::<Vec<_>>(), [
+            "sha256-abc.sig",
+            "sha256-abc.att",
+            "sha256-abc.sbom"
+        ]);
+    }
+
+    #[test]
+    fn replaced_descriptors_keep_their_annotations() {
+        let manifest = json!({
+            "schemaVersion": 2,
+            "config": { "mediaType": "c", "digest": "sha256:1", "size": 1 },
+            "layers": [
+                { "mediaType": "gzip", "digest": "sha256:2", "size": 2, "annotations": { "a": "b" } },
+                { "mediaType": "gzip", "digest": "sha256:3", "size": 3 },
+            ],
+            "vendor": { "kept": true },
+        });
+        let bytes = serde_json::to_vec(&manifest).unwrap();
+        let raw = RawManifest {
+            media_type: "m".into(),
+            digest: super::digest::sha256(&bytes),
+            bytes: bytes.into(),
+        };
+        let replaced = HashMap::from([("sha256:2".to_string(), Descriptor {
+            media_type: "zstd".into(),
+            digest: "sha256:4".into(),
+            size: 4,
+            ..Descriptor::default()
+        })]);
+        let rewritten = rewrite(&raw, "m", &replaced).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&rewritten.bytes).unwrap();
+        assert_eq!(
+            value["layers"][0],
+            json!({ "mediaType": "zstd", "digest": "sha256:4", "size": 4, "annotations": { "a": "b" } })
+        );
+        assert_eq!(value["layers"][1], manifest["layers"][1]);
+        assert_eq!(value["vendor"], manifest["vendor"]);
+        assert_eq!(rewritten.digest, super::digest::sha256(&rewritten.bytes));
+        assert_ne!(rewritten.digest, raw.digest);
+    }
+}
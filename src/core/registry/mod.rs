@@ -0,0 +1,152 @@
+//! A minimal OCI distribution client, and how repository objects map onto registries
+pub mod client;
+pub mod compression;
+pub mod digest;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake;
+pub mod format;
+pub mod layout;
+pub mod limits;
+pub mod manifest;
+pub mod reference;
+
+use crate::core::crd::{RepositoryProvider, RepositorySpec};
+pub use client::RegistryClient;
+pub use reference::Reference;
+use std::path::PathBuf;
+
+/// How the controller authenticates against a registry
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Credentials {
+    Anonymous,
+    /// Access token of the pod's (workload identity) service account from the GCE metadata server
+    GcpMetadata,
+}
+
+/// A registry host and how to talk to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    /// Registry host, including the port if any
+    pub host: String,
+    /// Use plain http instead of https
+    pub insecure: bool,
+    pub credentials: Credentials,
+    /// The OCI image layout directory or `.tar` file to use instead of a registry; the host is then
+    /// just the path, for display and limits
+    pub layout: Option<PathBuf>,
+}
+
+/// Where the images of a `SourceRepository` or `DestinationRepository` live
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepositoryLocation {
+    pub endpoint: Endpoint,
+    /// Repository path prefix all images share, e.g. `project/registry-name`
+    pub prefix: String,
+}
+
+impl From<&RepositorySpec> for RepositoryLocation {
+    fn from(spec: &RepositorySpec) -> Self {
+        match spec.provider {
+            RepositoryProvider::Gcp => Self {
+                endpoint: Endpoint {
+                    host: spec
+                        .registry
+                        .clone()
+                        .unwrap_or_else(|| format!("{}-docker.pkg.dev", spec.location)),
+                    insecure: spec.insecure,
+                    credentials: Credentials::GcpMetadata,
+                    layout: None,
+                },
+                prefix: format!("{}/{}", spec.project_id, spec.name),
+            },
+            RepositoryProvider::Generic => Self {
+                endpoint: Endpoint {
+                    host: spec.registry.clone().unwrap_or_default(),
+                    insecure: spec.insecure,
+                    credentials: Credentials::Anonymous,
+                    layout: None,
+                },
+                prefix: spec.name.clone(),
+            },
+            RepositoryProvider::OciLayout => {
+                let path = spec.path.clone().unwrap_or_default();
+                Self {
+                    endpoint: Endpoint {
+                        host: path.clone(),
+                        insecure: false,
+                        credentials: Credentials::Anonymous,
+                        layout: Some(path.into()),
+                    },
+                    prefix: spec.name.clone(),
+                }
+            }
+        }
+    }
+}
+
+impl RepositoryLocation {
+    /// Full repository path of `image_path` (the part below the prefix)
+    #[must_use]
+    pub fn repository(&self, image_path: &str) -> String {
+        if self.prefix.is_empty() {
+            image_path.to_string()
+        } else {
+            format!("{}/{image_path}", self.prefix)
+        }
+    }
+
+    /// The path of `image` below this location's prefix, if the image lives here at all
+    #[must_use]
+    pub fn relative<'a>(&self, image: &'a Reference) -> Option<&'a str> {
+        if image.registry != self.endpoint.host {
+            return None;
+        }
+        if self.prefix.is_empty() {
+            return Some(&image.repository);
+        }
+        image
+            .repository
+            .strip_prefix(&self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .filter(|rest| !rest.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Credentials, RepositoryLocation};
+    use crate::core::crd::{RepositoryProvider, RepositorySpec};
+
+    fn gcp() -> RepositorySpec {
+        RepositorySpec {
+            provider: RepositoryProvider::Gcp,
+            name: "ci-registry".into(),
+            location: "europe-west1".into(),
+            project_id: "example-ci".into(),
+            ..RepositorySpec::default()
+        }
+    }
+
+    #[test]
+    fn gcp_repositories_live_in_artifact_registry() {
+        let location = RepositoryLocation::from(&gcp());
+        assert_eq!(location.endpoint.host, "europe-west1-docker.pkg.dev");
+        assert_eq!(location.endpoint.credentials, Credentials::GcpMetadata);
+        assert_eq!(location.repository("team/app"), "example-ci/ci-registry/team/app");
+    }
+
+    #[test]
+    fn relative_paths_only_match_below_the_prefix() {
+        let location = RepositoryLocation::from(&gcp());
+        let inside = "europe-west1-docker.pkg.dev/example-ci/ci-registry/team/app:1"
+            .parse()
+            .unwrap();
+        let sibling = "europe-west1-docker.pkg.dev/example-ci/ci-registry-2/app:1"
+            .parse()
+            .unwrap();
+        let elsewhere = "docker.io/example-ci/ci-registry/app:1".parse().unwrap();
+        assert_eq!(location.relative(&inside), Some("team/app"));
+        assert_eq!(location.relative(&sibling), None);
+        assert_eq!(location.relative(&elsewhere), None);
+    }
+}
@@ -0,0 +1,133 @@
+//! Parsing of image references such as `europe-west1-docker.pkg.dev/proj/repo/app:1.2.3@sha256:…`
+use crate::core::{ErrorWrapper, Result};
+use std::{fmt, str::FromStr};
+
+pub static DEFAULT_REGISTRY: &str = "docker.io";
+pub static DEFAULT_TAG: &str = "latest";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Reference {
+    /// Registry host, including the port if any
+    pub registry: String,
+    /// Repository path within the registry
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl Reference {
+    /// The digest if pinned, otherwise the tag (defaulting to `latest`)
+    #[must_use]
+    pub fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or(DEFAULT_TAG)
+    }
+
+    /// The last path segment of the repository, i.e. the bare image name
+    #[must_use]
+    pub fn image_name(&self) -> &str {
+        self.repository.rsplit('/').next().unwrap_or(&self.repository)
+    }
+
+    /// `registry/repository` without tag or digest
+    #[must_use]
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.registry, self.repository)
+    }
+}
+
+impl FromStr for Reference {
+    type Err = loco_rs::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s.chars().any(char::is_whitespace) {
+            return Err(ErrorWrapper::from_custom(&format!(
+                "invalid image reference `{s}`"
+            )));
+        }
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => (rest, Some(digest.to_string())),
+            None => (s, None),
+        };
+        // a tag is a `:` after the last `/`, anything before that is a registry port
+        let (name, tag) = match rest.rfind(':') {
+            Some(i) if !rest[i..].contains('/') => (&rest[..i], Some(rest[i + 1..].to_string())),
+            _ => (rest, None),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path)) if host.contains(['.', ':']) || host == "localhost" => {
+                (host.to_string(), path.to_string())
+            }
+            _ => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+        };
+        let repository = if registry == DEFAULT_REGISTRY && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository
+        };
+        if repository.is_empty() || tag.as_deref() == Some("") {
+            return Err(ErrorWrapper::from_custom(&format!(
+                "invalid image reference `{s}`"
+            )));
+        }
+        Ok(Self {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Reference;
+
+    #[test]
+    fn parses_fully_qualified_references() {
+        let r: Reference = "europe-west1-docker.pkg.dev/proj/repo/app:1.2.3".parse().unwrap();
+        assert_eq!(r.registry, "europe-west1-docker.pkg.dev");
+        assert_eq!(r.repository, "proj/repo/app");
+        assert_eq!(r.tag.as_deref(), Some("1.2.3"));
+        assert_eq!(r.image_name(), "app");
+    }
+
+    #[test]
+    fn parses_ports_and_digests() {
+        let r: Reference = "localhost:5001/app@sha256:abc".parse().unwrap();
+        assert_eq!(r.registry, "localhost:5001");
+        assert_eq!(r.repository, "app");
+        assert_eq!(r.tag, None);
+        assert_eq!(r.reference(), "sha256:abc");
+        assert_eq!(r.to_string(), "localhost:5001/app@sha256:abc");
+    }
+
+    #[test]
+    fn defaults_to_docker_hub() {
+        let r: Reference = "nginx".parse().unwrap();
+        assert_eq!(r.name(), "docker.io/library/nginx");
+        assert_eq!(r.reference(), "latest");
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert!("".parse::<Reference>().is_err());
+        assert!("app:".parse::<Reference>().is_err());
+        assert!("a b".parse::<Reference>().is_err());
+    }
+}
@@ -0,0 +1,1672 @@
+//! Copying images between registries.
+//!
+//! A [`ReplicationJob`] copies one image (or index, with all of its children) from a source
+//! repository to a destination repository. Jobs run through a shared [`Replicator`], which bounds
+//! how many copies run at once across all replicators and applies per-registry-host limits to every
+//! request and byte it sends.
+//!
+//! Artifacts attached to the image travel with it: cosign signatures, attestations and SBOMs stored
+//! under `sha256-<hex>.sig` style tags, and OCI 1.1 referrers. Referrers are listed through the
+//! referrers API, or the `sha256-<hex>` fallback index on registries without it, and the fallback
+//! index is kept up to date in destinations that lack the API.
+//!
+//! A destination tag that points at another digest is moved, kept under a backup tag first, or
+//! left alone with a [`TagConflict`], depending on the destination's `tagMutability`.
+//!
+//! A destination with a `layerCompression` gets gzip and zstd layers recompressed to it, and one with
+//! a `manifestFormat` gets images and indexes converted between Docker schema2 and OCI. Either way the
+//! manifests are rewritten, so its images have other digests than their source. What every image
+//! became is kept in the ledger. The signatures and referrers of the source image do not
+//! apply to a converted copy and stay behind.
+//! [`Replicator::plan`] walks the same graph without writing anything, for dry runs.
+use crate::core::{
+    ErrorWrapper, Result,
+    blobcache::{BlobCache, Lookup},
+    crd::{
+        DestinationRepositorySpec, LayerCompression, ManifestFormat, PlannedImage, ReplicatedImage,
+        TagAction, TagMutability,
+    },
+    ledger::{self, Ledger, UploadCheckpoint},
+    registry::{
+        Endpoint, RegistryClient, RepositoryLocation,
+        client::{ByteStream, RawManifest, skip_bytes},
+        compression, digest, format,
+        limits::Limiters,
+        manifest::{self, Descriptor, Manifest, media_types},
+    },
+    settings::Settings,
+    shutdown::Shutdown,
+};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, instrument, warn};
+
+/// An image in a repository, by tag or digest
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ImageLocation {
+    pub endpoint: Endpoint,
+    pub repository: String,
+    pub reference: String,
+}
+
+impl fmt::Display for ImageLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.reference.contains(':') { '@' } else { ':' };
+        write!(
+            f,
+            "{}/{}{separator}{}",
+            self.endpoint.host, self.repository, self.reference
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ReplicationJob {
+    pub source: ImageLocation,
+    /// Where to push; the reference is the tag to set, or the digest for untagged images
+    pub destination: ImageLocation,
+    /// What to do if the destination tag already points at another digest
+    pub tag_mutability: TagMutability,
+    /// How the image is converted for the destination
+    pub conversion: Conversion,
+    /// Rewrite rules that shaped the destination, reported in the status
+    pub rewrites: Vec<String>,
+}
+
+/// A destination repository and how images are written to it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    pub location: RepositoryLocation,
+    pub tag_mutability: TagMutability,
+    pub conversion: Conversion,
+}
+
+impl From<&DestinationRepositorySpec> for Destination {
+    fn from(spec: &DestinationRepositorySpec) -> Self {
+        Self {
+            location: RepositoryLocation::from(&spec.repository),
+            tag_mutability: spec.tag_mutability,
+            conversion: Conversion {
+                layer_compression: spec.layer_compression,
+                manifest_format: spec.manifest_format,
+            },
+        }
+    }
+}
+
+/// How images are changed on their way to a destination; the default copies them as they are
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Conversion {
+    pub layer_compression: Option<LayerCompression>,
+    pub manifest_format: Option<ManifestFormat>,
+}
+
+impl Conversion {
+    /// What the conversion is recorded as in the ledger, e.g. `zstd+oci`, `None` if images are copied
+    /// as they are
+    fn label(self) -> Option<String> {
+        let format = self.manifest_format.map(|format| match format {
+            ManifestFormat::Oci => "oci",
+            ManifestFormat::Docker => "docker",
+        });
+        let parts: Vec<_> = [self.layer_compression.map(compression_label), format]
+            .into_iter()
+            .flatten()
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("+"))
+    }
+}
+
+const fn compression_label(compression: LayerCompression) -> &'static str {
+    match compression {
+        LayerCompression::Gzip => "gzip",
+        LayerCompression::Zstd => "zstd",
+    }
+}
+
+/// A manifest copied to the destination
+struct Copied {
+    /// Digest in the source
+    source: String,
+    /// What it became in the destination, which is the source manifest unless it was converted
+    destination: Descriptor,
+}
+
+/// A destination tag that already points at another image, in a repository with immutable tags
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TagConflict {
+    /// The destination tag, e.g. `prod.example.com/prod/app:1.0.0`
+    pub image: String,
+    pub existing: String,
+    pub incoming: String,
+}
+
+impl fmt::Display for TagConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "immutable tag {} points at {}, refusing to move it to {}",
+            self.image, self.existing, self.incoming
+        )
+    }
+}
+
+impl std::error::Error for TagConflict {}
+
+impl TagConflict {
+    /// The conflict behind `error`, if that is why it failed
+    #[must_use]
+    pub fn find(error: &loco_rs::Error) -> Option<&Self> {
+        ErrorWrapper::find(error)
+    }
+}
+
+impl ReplicationJob {
+    /// What an earlier run of this job promoted according to `promoted`, with the destination
+    /// reference it was pushed under. Entries are matched by source image and destination
+    /// repository, as tag templates render the destination tag only when the image is copied.
+    #[must_use]
+    pub fn promoted<'a>(&self, promoted: &'a [ReplicatedImage]) -> Option<(&'a ReplicatedImage, &'a str)> {
+        let source = self.source.to_string();
+        let repository = format!(
+            "{}/{}",
+            self.destination.endpoint.host, self.destination.repository
+        );
+        promoted.iter().find_map(|image| {
+            let reference = image
+                .destination
+                .strip_prefix(&repository)?
+                .strip_prefix([':', '@'])?;
+            (image.source == source).then_some((image, reference))
+        })
+    }
+}
+
+impl fmt::Display for ReplicationJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.source, self.destination)
+    }
+}
+
+/// What a finished job did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    /// Digest of the top-level manifest in the destination
+    pub digest: String,
+    /// Digest of the top-level manifest in the source, if the destination holds a converted copy
+    pub source_digest: Option<String>,
+    pub manifests_copied: usize,
+    /// Signatures, attestations and other referrers pushed along with the image
+    pub artifacts_copied: usize,
+    pub blobs_copied: usize,
+    pub blobs_skipped: usize,
+    pub bytes_copied: u64,
+    /// Layers recompressed for the destination's `layerCompression`
+    pub layers_recompressed: usize,
+    /// Blobs read from the blob cache instead of the source
+    pub cache_hits: usize,
+    /// Blobs pulled from the source into the blob cache
+    pub cache_misses: usize,
+    /// Bytes not pulled from the source thanks to the blob cache
+    pub bytes_from_cache: u64,
+    /// Digest the destination tag pointed at before it was moved
+    pub overwritten: Option<String>,
+    /// Tag the overwritten digest was kept under
+    pub backup: Option<String>,
+}
+
+/// What a job would do, worked out without writing to the destination
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobPlan {
+    pub source: String,
+    pub destination: String,
+    /// Digest of the top-level manifest
+    pub digest: String,
+    pub tag: TagAction,
+    /// Manifests of the image, its children and attached artifacts
+    pub manifests: Transfer,
+    pub blobs: Transfer,
+    /// Estimated upload size, from the sizes the source manifests declare
+    pub bytes: u64,
+}
+
+/// Digests that would be copied, and those the destination already has
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Transfer {
+    pub copy: Vec<String>,
+    pub present: Vec<String>,
+}
+
+impl From<&JobPlan> for PlannedImage {
+    fn from(plan: &JobPlan) -> Self {
+        Self {
+            source: plan.source.clone(),
+            destination: plan.destination.clone(),
+            digest: plan.digest.clone(),
+            tag: plan.tag,
+            manifests_to_copy: plan.manifests.copy.len(),
+            manifests_present: plan.manifests.present.len(),
+            blobs_to_copy: plan.blobs.copy.len(),
+            blobs_present: plan.blobs.present.len(),
+            bytes_to_copy: plan.bytes,
+        }
+    }
+}
+
+/// Digests of the source image and its destination copy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comparison {
+    pub source: String,
+    /// `None` if the destination does not have the image (tag)
+    pub destination: Option<String>,
+    /// Digest of the converted copy of the source image, if the destination converted it before
+    pub converted: Option<String>,
+}
+
+impl Comparison {
+    #[must_use]
+    pub fn in_sync(&self) -> bool {
+        self.destination.as_ref() == Some(self.converted.as_ref().unwrap_or(&self.source))
+    }
+}
+
+pub struct Replicator {
+    http: reqwest::Client,
+    limiters: Limiters,
+    jobs: Arc<Semaphore>,
+    clients: Mutex<HashMap<Endpoint, Arc<RegistryClient>>>,
+    shutdown: Shutdown,
+    ledger: Ledger,
+    cache: Option<Arc<BlobCache>>,
+    chunk_size: u64,
+}
+
+impl Default for Replicator {
+    fn default() -> Self {
+        Self::new(&Settings::default(), Shutdown::default())
+    }
+}
+
+impl Replicator {
+    /// # Panics
+    ///
+    /// Panics if the TLS backend of the HTTP client can not be initialised.
+    #[must_use]
+    pub fn new(settings: &Settings, shutdown: Shutdown) -> Self {
+        let http = reqwest::Client::builder()
+            .connect_timeout(settings.registry.connect_timeout())
+            .timeout(settings.registry.request_timeout())
+            .user_agent(concat!("yair/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("registry http client");
+        let ledger = settings
+            .replication
+            .ledger_dir
+            .as_ref()
+            .map_or_else(Ledger::default, |dir| {
+                Ledger::open(dir).unwrap_or_else(|e| {
+                warn!(error = %e, "Can not open the ledger, upload checkpoints will not survive a restart");
+                Ledger::default()
+            })
+            });
+        let cache = settings.replication.blob_cache_dir.as_ref().and_then(|dir| {
+            BlobCache::open(dir, settings.replication.blob_cache_size_bytes)
+                .inspect_err(|e| warn!(error = %e, "Can not open the blob cache, blobs are pulled for every destination"))
+                .ok()
+        });
+        let permits = match settings.replication.max_concurrent_jobs {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        Self {
+            http,
+            limiters: Limiters::new(&settings.registry),
+            jobs: Arc::new(Semaphore::new(permits)),
+            clients: Mutex::default(),
+            shutdown,
+            ledger,
+            cache: cache.map(Arc::new),
+            chunk_size: settings.replication.chunk_size_bytes,
+        }
+    }
+
+    /// The shared client for `endpoint`, so tokens are reused across jobs
+    #[allow(clippy::missing_panics_doc)]
+    pub fn client(&self, endpoint: &Endpoint) -> Arc<RegistryClient> {
+        self.clients
+            .lock()
+            .expect("client cache lock poisoned")
+            .entry(endpoint.clone())
+            .or_insert_with(|| {
+                Arc::new(RegistryClient::new(
+                    self.http.clone(),
+                    endpoint.clone(),
+                    self.limiters.for_host(&endpoint.host),
+                ))
+            })
+            .clone()
+    }
+
+    /// Pack the tarball layouts among `endpoints` that were written to, once a run copied all it
+    /// had to
+    ///
+    /// # Errors
+    ///
+    /// When a layout can not be packed; it is packed again on the next flush
+    #[allow(clippy::missing_panics_doc)]
+    pub async fn flush<'a>(&self, endpoints: impl IntoIterator<Item = &'a Endpoint>) -> Result<()> {
+        let clients: Vec<_> = {
+            let clients = self.clients.lock().expect("client cache lock poisoned");
+            endpoints
+                .into_iter()
+                .filter_map(|endpoint| clients.get(endpoint).cloned())
+                .collect()
+        };
+        for layout in clients.iter().filter_map(|client| client.layout()) {
+            layout.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Copy an image, waiting for a free job slot first
+    ///
+    /// Once shutdown started no new copies are started; copies already running hold off the exit
+    /// until they finish or the grace period runs out.
+    #[instrument(skip(self), fields(job = %job))]
+    pub async fn replicate(&self, job: &ReplicationJob) -> Result<CopyReport> {
+        let _permit = tokio::select! {
+            permit = self.jobs.acquire() => permit.expect("job semaphore is never closed"),
+            () = self.shutdown.stopping() => return Err(shutting_down()),
+        };
+        let Some(_in_flight) = self.shutdown.track() else {
+            return Err(shutting_down());
+        };
+        let source = self.client(&job.source.endpoint);
+        let destination = self.client(&job.destination.endpoint);
+        let mut report = CopyReport::default();
+        let copied = self
+            .copy_manifest(
+                &source,
+                &job.source.repository,
+                &destination,
+                &job.destination.repository,
+                &job.source.reference,
+                &job.destination.reference,
+                job.tag_mutability,
+                job.conversion,
+                &mut report,
+            )
+            .await?;
+        if copied.destination.digest == copied.source {
+            self.copy_artifacts(
+                &source,
+                &job.source.repository,
+                &destination,
+                &job.destination.repository,
+                &copied.source,
+                &mut report,
+            )
+            .await?;
+        } else {
+            debug!(source = %copied.source, "Not copying the signatures and referrers of a converted image");
+            report.source_digest = Some(copied.source);
+        }
+        report.digest = copied.destination.digest;
+        info!(
+            digest = %report.digest,
+            artifacts_copied = report.artifacts_copied,
+            blobs_copied = report.blobs_copied,
+            bytes = report.bytes_copied,
+            layers_recompressed = report.layers_recompressed,
+            "Replicated image"
+        );
+        Ok(report)
+    }
+
+    /// Resolve the source image and what the destination reference points at, without copying
+    ///
+    /// # Errors
+    ///
+    /// When the source image can not be resolved or the destination can not be reached
+    pub async fn compare(&self, job: &ReplicationJob) -> Result<Comparison> {
+        let source = self.client(&job.source.endpoint);
+        let destination = self.client(&job.destination.endpoint);
+        let digest = source
+            .resolve(&job.source.repository, &job.source.reference)
+            .await?;
+        Ok(Comparison {
+            converted: self.converted(job.conversion, &digest).map(|c| c.digest),
+            source: digest,
+            destination: destination
+                .manifest_digest(&job.destination.repository, &job.destination.reference)
+                .await?,
+        })
+    }
+
+    /// Work out what `replicate` would copy, reading the source and destination but writing nothing
+    ///
+    /// A tag the destination would refuse to move ends the plan there, as the copy would. Manifests and
+    /// blobs of an image the destination converts are looked up by their source digests, so they only
+    /// count as present if the conversion left them as they were.
+    #[instrument(skip(self), fields(job = %job))]
+    pub async fn plan(&self, job: &ReplicationJob) -> Result<JobPlan> {
+        let source = self.client(&job.source.endpoint);
+        let destination = self.client(&job.destination.endpoint);
+        let mut plan = JobPlan {
+            source: job.source.to_string(),
+            destination: job.destination.to_string(),
+            digest: source
+                .resolve(&job.source.repository, &job.source.reference)
+                .await?,
+            ..JobPlan::default()
+        };
+        let expected = self
+            .converted(job.conversion, &plan.digest)
+            .map_or_else(|| plan.digest.clone(), |c| c.digest);
+        plan.tag = match destination
+            .manifest_digest(&job.destination.repository, &job.destination.reference)
+            .await?
+        {
+            None => TagAction::Create,
+            Some(existing) if existing == expected => TagAction::Unchanged,
+            Some(_) => match job.tag_mutability {
+                TagMutability::Immutable => {
+                    return Ok(JobPlan {
+                        tag: TagAction::Conflict,
+                        ..plan
+                    });
+                }
+                TagMutability::Overwrite => TagAction::Overwrite,
+                TagMutability::OverwriteWithBackup => TagAction::Backup,
+            },
+        };
+        let digest = plan.digest.clone();
+        let mut references = vec![digest.clone()];
+        references.extend(artifacts(&source, &job.source.repository, &digest).await?.0);
+        for reference in &references {
+            plan_manifest(
+                &source,
+                &job.source.repository,
+                &destination,
+                &job.destination.repository,
+                reference,
+                &mut plan,
+            )
+            .await?;
+        }
+        Ok(plan)
+    }
+
+    /// Copy a manifest and everything it references, then push it as `target`
+    ///
+    /// `tag_mutability` decides what happens if `target` is a tag that points at another digest. A
+    /// manifest `conversion` changes is pushed under its new digest when `target` is a digest.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_manifest(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        reference: &str,
+        target: &str,
+        tag_mutability: TagMutability,
+        conversion: Conversion,
+        report: &mut CopyReport,
+    ) -> Result<Copied> {
+        let manifest = source.manifest(source_repository, reference).await?;
+        let by_digest = target.contains(':');
+        // a converted manifest is only known by its new digest once it was converted before
+        let expected = match conversion.label() {
+            Some(_) => self.converted(conversion, &manifest.digest),
+            None => Some(descriptor(&manifest)),
+        };
+        let existing = existing(destination, destination_repository, target, expected.as_ref()).await?;
+        if let Some(expected) = &expected
+            && settle_tag(
+                destination,
+                destination_repository,
+                target,
+                existing.as_deref(),
+                &expected.digest,
+                tag_mutability,
+                report,
+            )
+            .await?
+        {
+            debug!(target, digest = %expected.digest, "Manifest already in destination");
+            return Ok(Copied {
+                source: manifest.digest,
+                destination: expected.clone(),
+            });
+        }
+        let parsed = Manifest::parse(&manifest.bytes)?;
+        let format = conversion.manifest_format.filter(|to| {
+            format::of(&manifest.media_type).is_some_and(|from| from != *to) && format::convertible(&parsed)
+        });
+        // descriptors the conversion changed, by their source digest
+        let mut replaced = HashMap::new();
+        if parsed.is_index() {
+            for child in &parsed.manifests {
+                let copied = Box::pin(self.copy_manifest(
+                    source,
+                    source_repository,
+                    destination,
+                    destination_repository,
+                    &child.digest,
+                    &child.digest,
+                    TagMutability::Overwrite,
+                    conversion,
+                    report,
+                ))
+                .await?;
+                // children that can not be converted keep their media type in a converted index
+                if format.is_some() || copied.destination.digest != copied.source {
+                    replaced.insert(copied.source, copied.destination);
+                }
+            }
+        }
+        let kept_docker_layers = self
+            .copy_blobs(
+                source,
+                source_repository,
+                destination,
+                destination_repository,
+                &parsed,
+                (format, conversion.layer_compression),
+                &mut replaced,
+                report,
+            )
+            .await?;
+        if kept_docker_layers {
+            warn!(digest = %manifest.digest, "Docker schema2 images have no zstd layers, copying the layers as they are");
+        }
+        let source_digest = manifest.digest.clone();
+        let manifest = convert(manifest, format, &replaced)?;
+        let copied = Copied {
+            destination: descriptor(&manifest),
+            source: source_digest,
+        };
+        if let Some(label) = conversion.label() {
+            self.ledger.record_conversion(
+                &ledger::conversion_key(&label, &copied.source),
+                &copied.destination,
+            )?;
+            if expected.is_none()
+                && settle_tag(
+                    destination,
+                    destination_repository,
+                    target,
+                    existing.as_deref(),
+                    &manifest.digest,
+                    tag_mutability,
+                    report,
+                )
+                .await?
+            {
+                debug!(target, digest = %manifest.digest, "Converted manifest already in destination");
+                return Ok(copied);
+            }
+        }
+        let target = if by_digest { &manifest.digest } else { target };
+        destination
+            .put_manifest(destination_repository, target, &manifest)
+            .await?;
+        report.manifests_copied += 1;
+        Ok(copied)
+    }
+
+    /// Copy the config and layers of `manifest`, recompressing layers for the target `format` and
+    /// `compression`; recompressed layers are added to `replaced`. Returns whether layers were kept
+    /// as they are because no media type has them in the target compression.
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_blobs(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        manifest: &Manifest,
+        (format, compression): (Option<ManifestFormat>, Option<LayerCompression>),
+        replaced: &mut HashMap<String, Descriptor>,
+        report: &mut CopyReport,
+    ) -> Result<bool> {
+        let mut kept_docker_layers = false;
+        for blob in manifest.blobs() {
+            let from = compression::of(&blob.media_type);
+            let recompress = match (format, from) {
+                // Docker schema2 has no zstd layers
+                (Some(ManifestFormat::Docker), Some(LayerCompression::Zstd)) => Some(LayerCompression::Gzip),
+                _ => compression,
+            }
+            .filter(|to| from.is_some_and(|from| from != *to));
+            let media_type = recompress.and_then(|to| {
+                let in_format = format.and_then(|format| format::media_type(&blob.media_type, format));
+                compression::media_type(in_format.unwrap_or(&blob.media_type), to)
+            });
+            if let (Some(from), Some(to), Some(media_type)) = (from, recompress, media_type) {
+                let converted = self
+                    .copy_recompressed(
+                        source,
+                        source_repository,
+                        destination,
+                        destination_repository,
+                        blob,
+                        (from, to, media_type),
+                        report,
+                    )
+                    .await?;
+                replaced.insert(blob.digest.clone(), converted);
+            } else {
+                kept_docker_layers |= recompress.is_some();
+                self.copy_blob(
+                    source,
+                    source_repository,
+                    destination,
+                    destination_repository,
+                    blob,
+                    report,
+                )
+                .await?;
+            }
+        }
+        Ok(kept_docker_layers)
+    }
+
+    /// What the manifest `digest` became in destinations converting images as `conversion` does, if
+    /// it was converted before
+    fn converted(&self, conversion: Conversion, digest: &str) -> Option<Descriptor> {
+        let label = conversion.label()?;
+        self.ledger.conversion(&ledger::conversion_key(&label, digest))
+    }
+
+    /// Copy the cosign artifacts and OCI referrers of the manifest `digest`
+    async fn copy_artifacts(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        digest: &str,
+        report: &mut CopyReport,
+    ) -> Result<()> {
+        let (artifacts, referrers) = artifacts(source, source_repository, digest).await?;
+        for artifact in &artifacts {
+            let copied = report.manifests_copied;
+            self.copy_manifest(
+                source,
+                source_repository,
+                destination,
+                destination_repository,
+                artifact,
+                artifact,
+                // signatures are appended to the same `.sig` tag, so artifact tags have to move
+                TagMutability::Overwrite,
+                Conversion::default(),
+                report,
+            )
+            .await?;
+            if report.manifests_copied > copied {
+                report.artifacts_copied += 1;
+            }
+        }
+        if referrers.is_empty()
+            || destination
+                .referrers(destination_repository, digest)
+                .await?
+                .is_some()
+        {
+            return Ok(());
+        }
+        // without the API clients discover referrers through the fallback index, which has to list them
+        let mut index = destination
+            .tagged_referrers(destination_repository, digest)
+            .await?;
+        let missing: Vec<_> = referrers
+            .into_iter()
+            .filter(|referrer| !index.iter().any(|listed| listed.digest == referrer.digest))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        index.extend(missing);
+        let bytes = serde_json::to_vec(&Manifest::index(index)).map_err(ErrorWrapper::from_serde)?;
+        let index = RawManifest {
+            media_type: media_types::OCI_INDEX.to_string(),
+            digest: digest::sha256(&bytes),
+            bytes: bytes.into(),
+        };
+        destination
+            .put_manifest(destination_repository, &manifest::referrers_tag(digest), &index)
+            .await
+    }
+
+    async fn copy_blob(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        blob: &Descriptor,
+        report: &mut CopyReport,
+    ) -> Result<()> {
+        if destination
+            .blob_exists(destination_repository, &blob.digest)
+            .await?
+        {
+            report.blobs_skipped += 1;
+            return Ok(());
+        }
+        // same registry: let it link the blob instead of moving the bytes through us
+        if source.endpoint() == destination.endpoint()
+            && destination
+                .mount_blob(destination_repository, &blob.digest, source_repository)
+                .await?
+        {
+            report.blobs_skipped += 1;
+            return Ok(());
+        }
+        let body = self.source_blob(source, source_repository, blob, report).await?;
+        report.bytes_copied += self
+            .push_blob(destination, destination_repository, blob, body)
+            .await?;
+        report.blobs_copied += 1;
+        Ok(())
+    }
+
+    /// Copy a layer recompressed `from` one compression `to` another under a new `media_type`,
+    /// returning its descriptor in the destination
+    #[allow(clippy::too_many_arguments)]
+    async fn copy_recompressed(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        layer: &Descriptor,
+        (from, to, media_type): (LayerCompression, LayerCompression, &str),
+        report: &mut CopyReport,
+    ) -> Result<Descriptor> {
+        let key = ledger::conversion_key(compression_label(to), &layer.digest);
+        if let Some(converted) = self.ledger.conversion(&key)
+            && destination
+                .blob_exists(destination_repository, &converted.digest)
+                .await?
+        {
+            report.blobs_skipped += 1;
+            // the same blob is listed as Docker or OCI, depending on the manifest
+            return Ok(Descriptor {
+                media_type: media_type.to_string(),
+                ..converted
+            });
+        }
+        let body = self.source_blob(source, source_repository, layer, report).await?;
+        let transcoded = compression::transcode(body, from, to).await?;
+        let converted = Descriptor {
+            media_type: media_type.to_string(),
+            digest: transcoded.digest.clone(),
+            size: transcoded.size,
+            ..Descriptor::default()
+        };
+        debug!(source = %layer.digest, digest = %converted.digest, size = converted.size, "Recompressed layer");
+        if destination
+            .blob_exists(destination_repository, &converted.digest)
+            .await?
+        {
+            report.blobs_skipped += 1;
+        } else {
+            let body = transcoded.stream().await?;
+            report.bytes_copied += self
+                .push_blob(destination, destination_repository, &converted, body)
+                .await?;
+            report.blobs_copied += 1;
+        }
+        report.layers_recompressed += 1;
+        self.ledger.record_conversion(&key, &converted)?;
+        Ok(converted)
+    }
+
+    /// Push `body` as `blob`, in chunks if it is large. Returns the bytes sent.
+    async fn push_blob(
+        &self,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        blob: &Descriptor,
+        body: ByteStream,
+    ) -> Result<u64> {
+        // a layout is written to disk, there is no session to resume
+        if self.chunk_size == 0 || blob.size <= self.chunk_size || destination.layout().is_some() {
+            destination
+                .push_blob(destination_repository, &blob.digest, blob.size, body)
+                .await?;
+            Ok(blob.size)
+        } else {
+            self.upload_chunked(destination, destination_repository, blob, body)
+                .await
+        }
+    }
+
+    /// A source blob, checked against its digest, through the blob cache if there is one
+    async fn source_blob(
+        &self,
+        source: &RegistryClient,
+        source_repository: &str,
+        blob: &Descriptor,
+        report: &mut CopyReport,
+    ) -> Result<ByteStream> {
+        let Some(cache) = &self.cache else {
+            let what = format!("blob {source_repository}@{}", blob.digest);
+            let body = source.blob(source_repository, &blob.digest).await?;
+            return Ok(digest::verified(body, &blob.digest, &what)?.boxed());
+        };
+        let (body, lookup) = cache
+            .blob(source, source_repository, &blob.digest, blob.size)
+            .await?;
+        match lookup {
+            Lookup::Hit => {
+                report.cache_hits += 1;
+                report.bytes_from_cache += blob.size;
+            }
+            Lookup::Miss => report.cache_misses += 1,
+            Lookup::Bypass => {}
+        }
+        Ok(body)
+    }
+
+    /// Upload a blob in chunks, checkpointing the session in the ledger after each one so a restarted
+    /// controller continues where the last one stopped. Returns the bytes sent.
+    async fn upload_chunked(
+        &self,
+        destination: &RegistryClient,
+        destination_repository: &str,
+        blob: &Descriptor,
+        body: ByteStream,
+    ) -> Result<u64> {
+        let key = ledger::upload_key(&destination.endpoint().host, destination_repository, &blob.digest);
+        let resumed = match self.ledger.upload(&key) {
+            Some(checkpoint) => destination
+                .upload_status(destination_repository, &checkpoint.location)
+                .await?
+                .filter(|(_, offset)| *offset <= blob.size),
+            None => None,
+        };
+        let (mut url, mut offset) = match resumed {
+            Some((url, offset)) => {
+                info!(digest = %blob.digest, offset, size = blob.size, "Resuming upload");
+                (url, offset)
+            }
+            None => (destination.start_upload(destination_repository).await?, 0),
+        };
+        let resumed_at = offset;
+        // the whole blob is read again so the digest covers what the previous process sent too
+        let body = skip_bytes(body, offset).boxed();
+        let mut chunks = std::pin::pin!(rechunk(body, self.chunk_size));
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            let len = chunk.len() as u64;
+            url = destination
+                .upload_chunk(destination_repository, &url, offset, chunk)
+                .await?;
+            offset += len;
+            self.ledger.checkpoint_upload(&key, UploadCheckpoint {
+                location: url.to_string(),
+                offset,
+            })?;
+        }
+        destination
+            .finish_upload(destination_repository, &url, &blob.digest)
+            .await?;
+        self.ledger.finish_upload(&key)?;
+        Ok(offset - resumed_at)
+    }
+}
+
+/// Regroup a byte stream into chunks of `size` bytes (the last one may be shorter)
+fn rechunk(stream: ByteStream, size: u64) -> impl Stream<Item = Result<Bytes>> {
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+    futures::stream::unfold(
+        (stream, BytesMut::new(), false),
+        move |(mut stream, mut buffer, mut done)| async move {
+            while !done && buffer.len() < size {
+                match stream.next().await {
+                    Some(Ok(bytes)) => buffer.extend_from_slice(&bytes),
+                    Some(Err(e)) => return Some((Err(e), (stream, buffer, true))),
+                    None => done = true,
+                }
+            }
+            if buffer.is_empty() {
+                return None;
+            }
+            let chunk = buffer.split_to(size.min(buffer.len())).freeze();
+            Some((Ok(chunk), (stream, buffer, done)))
+        },
+    )
+}
+
+/// Add a manifest and what it references to `plan`, by what the destination already has
+async fn plan_manifest(
+    source: &RegistryClient,
+    source_repository: &str,
+    destination: &RegistryClient,
+    destination_repository: &str,
+    reference: &str,
+    plan: &mut JobPlan,
+) -> Result<()> {
+    let manifest = source.manifest(source_repository, reference).await?;
+    if plan.manifests.copy.contains(&manifest.digest) || plan.manifests.present.contains(&manifest.digest) {
+        return Ok(());
+    }
+    if destination
+        .manifest_digest(destination_repository, &manifest.digest)
+        .await?
+        .is_some()
+    {
+        plan.manifests.present.push(manifest.digest);
+        return Ok(());
+    }
+    let parsed = Manifest::parse(&manifest.bytes)?;
+    for child in &parsed.manifests {
+        Box::pin(plan_manifest(
+            source,
+            source_repository,
+            destination,
+            destination_repository,
+            &child.digest,
+            plan,
+        ))
+        .await?;
+    }
+    for blob in parsed.blobs() {
+        if plan.blobs.copy.contains(&blob.digest) || plan.blobs.present.contains(&blob.digest) {
+            continue;
+        }
+        if destination
+            .blob_exists(destination_repository, &blob.digest)
+            .await?
+        {
+            plan.blobs.present.push(blob.digest.clone());
+        } else {
+            plan.blobs.copy.push(blob.digest.clone());
+            plan.bytes += blob.size;
+        }
+    }
+    plan.bytes += manifest.bytes.len() as u64;
+    plan.manifests.copy.push(manifest.digest);
+    Ok(())
+}
+
+/// The digest `target` points at in the destination; a digest `target` is looked up by the digest
+/// the manifest is `expected` to have there, unknown until a converted manifest was converted once
+async fn existing(
+    destination: &RegistryClient,
+    repository: &str,
+    target: &str,
+    expected: Option<&Descriptor>,
+) -> Result<Option<String>> {
+    match (expected, target.contains(':')) {
+        (Some(expected), true) => destination.manifest_digest(repository, &expected.digest).await,
+        (None, true) => Ok(None),
+        (_, false) => destination.manifest_digest(repository, target).await,
+    }
+}
+
+/// `manifest` in `format`, with the descriptors in `replaced` swapped for their replacement
+fn convert(
+    mut manifest: RawManifest,
+    format: Option<ManifestFormat>,
+    replaced: &HashMap<String, Descriptor>,
+) -> Result<RawManifest> {
+    if let Some(format) = format
+        && let Some(converted) = format::convert(&manifest, format)?
+    {
+        manifest = converted;
+    }
+    if !replaced.is_empty() {
+        manifest = manifest::rewrite(&manifest, &manifest.media_type, replaced)?;
+    }
+    Ok(manifest)
+}
+
+/// The descriptor of `manifest`
+fn descriptor(manifest: &RawManifest) -> Descriptor {
+    Descriptor {
+        media_type: manifest.media_type.clone(),
+        digest: manifest.digest.clone(),
+        size: manifest.bytes.len() as u64,
+        ..Descriptor::default()
+    }
+}
+
+/// Whether `target`, which points at `existing`, already points at `incoming`; a tag pointing
+/// elsewhere is made way for first
+async fn settle_tag(
+    destination: &RegistryClient,
+    repository: &str,
+    target: &str,
+    existing: Option<&str>,
+    incoming: &str,
+    tag_mutability: TagMutability,
+    report: &mut CopyReport,
+) -> Result<bool> {
+    match existing {
+        Some(existing) if existing == incoming => Ok(true),
+        // only a tag can point at another digest
+        Some(existing) => {
+            replace_tag(
+                destination,
+                repository,
+                target,
+                existing,
+                incoming,
+                tag_mutability,
+                report,
+            )
+            .await?;
+            Ok(false)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Make way for `incoming` under `tag`, which points at `existing`, as far as `tag_mutability` allows
+async fn replace_tag(
+    destination: &RegistryClient,
+    repository: &str,
+    tag: &str,
+    existing: &str,
+    incoming: &str,
+    tag_mutability: TagMutability,
+    report: &mut CopyReport,
+) -> Result<()> {
+    match tag_mutability {
+        TagMutability::Immutable => {
+            return Err(loco_rs::Error::wrap(TagConflict {
+                image: format!("{}/{repository}:{tag}", destination.endpoint().host),
+                existing: existing.to_string(),
+                incoming: incoming.to_string(),
+            }));
+        }
+        TagMutability::Overwrite => {}
+        TagMutability::OverwriteWithBackup => {
+            let backup = backup_tag(tag, existing);
+            let previous = destination.manifest(repository, existing).await?;
+            destination.put_manifest(repository, &backup, &previous).await?;
+            info!(tag, %backup, %existing, "Kept the overwritten image");
+            report.backup = Some(backup);
+        }
+    }
+    warn!(tag, %existing, %incoming, "Moving destination tag");
+    report.overwritten = Some(existing.to_string());
+    Ok(())
+}
+
+/// `<tag>-backup-<12 hex digits of digest>`, shortening the tag to stay within the 128 characters
+/// a tag may have
+fn backup_tag(tag: &str, digest: &str) -> String {
+    let hex = digest.split_once(':').map_or(digest, |(_, hex)| hex);
+    let suffix = format!("-backup-{}", &hex[..hex.len().min(12)]);
+    let keep = tag.len().min(128 - suffix.len());
+    format!("{}{suffix}", &tag[..keep])
+}
+
+/// References of the cosign artifacts and referrers attached to `digest`, and the referrers
+async fn artifacts(
+    source: &RegistryClient,
+    repository: &str,
+    digest: &str,
+) -> Result<(Vec<String>, Vec<Descriptor>)> {
+    let mut artifacts = vec![];
+    for tag in manifest::cosign_tags(digest) {
+        if source.manifest_digest(repository, &tag).await?.is_some() {
+            artifacts.push(tag);
+        }
+    }
+    let referrers = match source.referrers(repository, digest).await? {
+        Some(referrers) => referrers,
+        None => source.tagged_referrers(repository, digest).await?,
+    };
+    artifacts.extend(referrers.iter().map(|referrer| referrer.digest.clone()));
+    Ok((artifacts, referrers))
+}
+
+fn shutting_down() -> loco_rs::Error {
+    ErrorWrapper::from_custom("controller is shutting down, not starting new copies")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Conversion, ImageLocation, ReplicationJob, Replicator, TagConflict};
+    use crate::core::{
+        crd::{LayerCompression, ManifestFormat, TagAction, TagMutability},
+        ledger::{self, UploadCheckpoint},
+        registry::{
+            digest::{self, DigestMismatch},
+            fake::FakeRegistry,
+            manifest::{self, Descriptor, Manifest, media_types},
+        },
+        settings::Settings,
+        shutdown::Shutdown,
+    };
+    use bytes::Bytes;
+    use http::Method;
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+
+    fn job(source: &FakeRegistry, destination: &FakeRegistry, tag: &str) -> ReplicationJob {
+        ReplicationJob {
+            source: ImageLocation {
+                endpoint: source.endpoint(),
+                repository: "ci/app".into(),
+                reference: tag.into(),
+            },
+            destination: ImageLocation {
+                endpoint: destination.endpoint(),
+                repository: "prod/app".into(),
+                reference: tag.into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn copies_an_image_and_skips_it_the_second_time() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer-one", b"layer-two"]);
+        let replicator = Replicator::default();
+
+        let report = replicator
+            .replicate(&job(&source, &destination, "1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(report.digest, digest);
+        assert_eq!(report.blobs_copied, 3, "config and two layers");
+        let source_bytes: usize = source.store().blobs.values().map(|b| b.len()).sum();
+        assert_eq!(report.bytes_copied, source_bytes as u64);
+        assert!(
+            destination
+                .store()
+                .manifests
+                .contains_key(&("prod/app".into(), "1.0.0".into()))
+        );
+
+        let again = replicator
+            .replicate(&job(&source, &destination, "1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(again.digest, digest);
+        assert_eq!(again.manifests_copied, 0);
+        assert_eq!(destination.store().count(&Method::PUT, "/blobs/uploads/"), 3);
+    }
+
+    #[tokio::test]
+    async fn moved_tags_follow_the_destination_mutability() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let incoming = source.push_image("ci/app", "1.0.0", &[b"rebuilt"]);
+        let existing = destination.push_image("prod/app", "1.0.0", &[b"released"]);
+        let replicator = Replicator::default();
+
+        let mut immutable = job(&source, &destination, "1.0.0");
+        immutable.tag_mutability = TagMutability::Immutable;
+        let err = replicator.replicate(&immutable).await.unwrap_err();
+        let conflict = TagConflict::find(&err).expect("a tag conflict");
+        assert_eq!((&conflict.existing, &conflict.incoming), (&existing, &incoming));
+
+        let mut backup = immutable;
+        backup.tag_mutability = TagMutability::OverwriteWithBackup;
+        let report = replicator.replicate(&backup).await.unwrap();
+        assert_eq!(report.overwritten, Some(existing.clone()));
+        let store = destination.store();
+        let tag = |reference: &str| {
+            store
+                .manifests
+                .get(&("prod/app".into(), reference.into()))
+                .map(|(_, bytes)| digest::sha256(bytes))
+        };
+        assert_eq!(tag("1.0.0"), Some(incoming));
+        assert_eq!(tag(report.backup.as_deref().unwrap()), Some(existing.clone()));
+        assert_eq!(
+            report.backup.as_deref(),
+            Some(format!("1.0.0-backup-{}", &existing[7..19]).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn plans_only_what_the_destination_lacks() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1.0.0", &[b"base", b"app"]);
+        destination.push_blob("prod/app", "application/octet-stream", b"base");
+        let replicator = Replicator::default();
+
+        let plan = replicator
+            .plan(&job(&source, &destination, "1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(plan.digest, digest);
+        assert_eq!(plan.tag, TagAction::Create);
+        assert_eq!(plan.manifests.copy, [digest]);
+        assert_eq!(plan.blobs.present, [digest::sha256(b"base")]);
+        assert_eq!(plan.blobs.copy.len(), 2, "config and the app layer");
+        assert_eq!(destination.store().count(&Method::PUT, "/"), 0);
+
+        replicator
+            .replicate(&job(&source, &destination, "1.0.0"))
+            .await
+            .unwrap();
+        let plan = replicator
+            .plan(&job(&source, &destination, "1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(plan.tag, TagAction::Unchanged);
+        assert_eq!((plan.manifests.present.len(), plan.bytes), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn copies_indexes_with_their_children() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let amd64 = source.push_image("ci/app", "amd64", &[b"amd64"]);
+        let arm64 = source.push_image("ci/app", "arm64", &[b"arm64"]);
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_INDEX,
+            "manifests": [
+                { "mediaType": media_types::OCI_MANIFEST, "digest": amd64, "size": 1 },
+                { "mediaType": media_types::OCI_MANIFEST, "digest": arm64, "size": 1 },
+            ]
+        });
+        let digest = source.push_manifest(
+            "ci/app",
+            "2.0.0",
+            media_types::OCI_INDEX,
+            index.to_string().as_bytes(),
+        );
+
+        let report = Replicator::default()
+            .replicate(&job(&source, &destination, "2.0.0"))
+            .await
+            .unwrap();
+        assert_eq!(report.digest, digest);
+        assert_eq!(report.manifests_copied, 3);
+        let store = destination.store();
+        assert!(store.manifests.contains_key(&("prod/app".into(), amd64)));
+        assert!(store.manifests.contains_key(&("prod/app".into(), arm64)));
+    }
+
+    #[tokio::test]
+    async fn missing_source_images_fail() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let res = Replicator::default()
+            .replicate(&job(&source, &destination, "nope"))
+            .await;
+        assert!(res.unwrap_err().to_string().contains("404"));
+    }
+
+    #[tokio::test]
+    async fn jobs_are_bounded_by_the_global_limit() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1", &[b"one"]);
+        let mut settings = Settings::default();
+        settings.replication.max_concurrent_jobs = 1;
+        let replicator = Arc::new(Replicator::new(&settings, Shutdown::default()));
+
+        // hold the only permit, the job has to wait for it
+        let permit = replicator.jobs.clone().acquire_owned().await.unwrap();
+        let job = job(&source, &destination, "1");
+        let running = tokio::spawn({
+            let replicator = replicator.clone();
+            async move { replicator.replicate(&job).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!running.is_finished());
+        assert!(source.store().requests.is_empty());
+        drop(permit);
+        running.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn queued_jobs_are_dropped_on_shutdown() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1", &[b"one"]);
+        let mut settings = Settings::default();
+        settings.replication.max_concurrent_jobs = 1;
+        let shutdown = Shutdown::default();
+        let replicator = Arc::new(Replicator::new(&settings, shutdown.clone()));
+
+        let _permit = replicator.jobs.clone().acquire_owned().await.unwrap();
+        let job = job(&source, &destination, "1");
+        let queued = tokio::spawn({
+            let replicator = replicator.clone();
+            async move { replicator.replicate(&job).await }
+        });
+        shutdown.trigger();
+        let err = queued.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("shutting down"));
+        assert!(source.store().requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn cached_blobs_are_pulled_once_for_every_destination() {
+        let source = FakeRegistry::start().await;
+        let destinations = [FakeRegistry::start().await, FakeRegistry::start().await];
+        source.push_image("ci/app", "1.0.0", &[b"layer-one", b"layer-two"]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        settings.replication.blob_cache_dir = Some(dir.path().into());
+        let replicator = Replicator::new(&settings, Shutdown::default());
+
+        let first = replicator
+            .replicate(&job(&source, &destinations[0], "1.0.0"))
+            .await
+            .unwrap();
+        let second = replicator
+            .replicate(&job(&source, &destinations[1], "1.0.0"))
+            .await
+            .unwrap();
+        assert_eq!((first.cache_misses, first.cache_hits), (3, 0));
+        assert_eq!((second.cache_misses, second.cache_hits), (0, 3));
+        assert_eq!(second.bytes_from_cache, second.bytes_copied);
+        assert_eq!(source.store().count(&Method::GET, "/blobs/"), 3);
+        for destination in &destinations {
+            assert_eq!(destination.store().blobs.len(), 3);
+        }
+    }
+
+    fn chunked(chunk_size_bytes: u64, ledger_dir: Option<&std::path::Path>) -> Settings {
+        let mut settings = Settings::default();
+        settings.replication.chunk_size_bytes = chunk_size_bytes;
+        settings.replication.ledger_dir = ledger_dir.map(Into::into);
+        settings
+    }
+
+    #[tokio::test]
+    async fn large_blobs_are_uploaded_in_chunks() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1", &[b"0123456789"]);
+        let replicator = Replicator::new(&chunked(4, None), Shutdown::default());
+
+        replicator
+            .replicate(&job(&source, &destination, "1"))
+            .await
+            .unwrap();
+        let layer = digest::sha256(b"0123456789");
+        let store = destination.store();
+        assert_eq!(store.blobs[&("prod/app".into(), layer)], &b"0123456789"[..]);
+        assert!(store.count(&Method::PATCH, "/blobs/uploads/") >= 3);
+    }
+
+    #[tokio::test]
+    async fn interrupted_uploads_resume_from_the_ledger() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1", &[b"0123456789"]);
+        let layer = digest::sha256(b"0123456789");
+        let dir = tempfile::tempdir().unwrap();
+
+        // a previous controller got the first chunk across before it was stopped
+        let before = Replicator::new(&chunked(4, Some(dir.path())), Shutdown::default());
+        let client = before.client(&destination.endpoint());
+        let url = client.start_upload("prod/app").await.unwrap();
+        let url = client
+            .upload_chunk("prod/app", &url, 0, Bytes::from_static(b"0123"))
+            .await
+            .unwrap();
+        let key = ledger::upload_key(&destination.host, "prod/app", &layer);
+        before
+            .ledger
+            .checkpoint_upload(&key, UploadCheckpoint {
+                location: url.to_string(),
+                offset: 4,
+            })
+            .unwrap();
+        drop(before);
+
+        let after = Replicator::new(&chunked(4, Some(dir.path())), Shutdown::default());
+        let report = after.replicate(&job(&source, &destination, "1")).await.unwrap();
+        let store = destination.store();
+        assert_eq!(store.blobs[&("prod/app".into(), layer)], &b"0123456789"[..]);
+        assert_eq!(store.count(&Method::GET, "/blobs/uploads/upload-1"), 1);
+        assert_eq!(store.count(&Method::PATCH, "/blobs/uploads/upload-1"), 3);
+        assert_eq!(after.ledger.upload(&key), None);
+        let config_size = store.blobs.values().map(|b| b.len() as u64).sum::<u64>() - 10;
+        assert_eq!(report.bytes_copied, config_size + 6);
+    }
+
+    #[tokio::test]
+    async fn uploads_resume_from_an_empty_session() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1", &[b"0123456789"]);
+        let layer = digest::sha256(b"0123456789");
+        let dir = tempfile::tempdir().unwrap();
+
+        // a previous controller opened the session but was stopped before sending anything
+        let before = Replicator::new(&chunked(4, Some(dir.path())), Shutdown::default());
+        let url = before
+            .client(&destination.endpoint())
+            .start_upload("prod/app")
+            .await
+            .unwrap();
+        let key = ledger::upload_key(&destination.host, "prod/app", &layer);
+        before
+            .ledger
+            .checkpoint_upload(&key, UploadCheckpoint {
+                location: url.to_string(),
+                offset: 0,
+            })
+            .unwrap();
+        drop(before);
+
+        let after = Replicator::new(&chunked(4, Some(dir.path())), Shutdown::default());
+        after.replicate(&job(&source, &destination, "1")).await.unwrap();
+        let store = destination.store();
+        assert_eq!(store.blobs[&("prod/app".into(), layer)], &b"0123456789"[..]);
+        assert_eq!(store.count(&Method::GET, "/blobs/uploads/upload-1"), 1);
+        assert_eq!(store.count(&Method::PATCH, "/blobs/uploads/upload-1"), 3);
+    }
+
+    #[tokio::test]
+    async fn manifests_are_checked_against_their_digest() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1", &[b"one"]);
+        let tampered = (media_types::OCI_MANIFEST.to_string(), Bytes::from_static(b"{}"));
+        source
+            .store()
+            .manifests
+            .insert(("ci/app".into(), digest.clone()), tampered);
+
+        let err = Replicator::default()
+            .replicate(&job(&source, &destination, &digest))
+            .await
+            .unwrap_err();
+        assert_eq!(DigestMismatch::find(&err).unwrap().expected, digest);
+        assert!(destination.store().manifests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn signatures_and_referrers_travel_with_the_image() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1", &[b"one"]);
+        let signature = format!("{}.sig", manifest::referrers_tag(&digest));
+        source.push_artifact(
+            "ci/app",
+            Some(&signature),
+            &digest,
+            "application/vnd.dev.cosign.simplesigning.v1+json",
+            b"sig",
+        );
+        let sbom = source.push_artifact("ci/app", None, &digest, "application/spdx+json", b"sbom");
+        let replicator = Replicator::default();
+
+        let report = replicator
+            .replicate(&job(&source, &destination, "1"))
+            .await
+            .unwrap();
+        assert_eq!(report.artifacts_copied, 2);
+        {
+            let store = destination.store();
+            assert!(store.manifests.contains_key(&("prod/app".into(), signature)));
+            assert!(store.manifests.contains_key(&("prod/app".into(), sbom)));
+            assert!(
+                !store
+                    .manifests
+                    .contains_key(&("prod/app".into(), manifest::referrers_tag(&digest))),
+                "the destination serves referrers itself"
+            );
+        }
+
+        let again = replicator
+            .replicate(&job(&source, &destination, "1"))
+            .await
+            .unwrap();
+        assert_eq!(again.artifacts_copied, 0);
+    }
+
+    #[tokio::test]
+    async fn referrers_fall_back_to_the_tag_schema() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.store().without_referrers_api = true;
+        destination.store().without_referrers_api = true;
+        let digest = source.push_image("ci/app", "1", &[b"one"]);
+        let sbom = source.push_artifact("ci/app", None, &digest, "application/spdx+json", b"sbom");
+        let (media_type, bytes) = source.store().manifests[&("ci/app".into(), sbom.clone())].clone();
+        let index = Manifest::index(vec![Descriptor {
+            media_type,
+            digest: sbom.clone(),
+            size: bytes.len() as u64,
+            artifact_type: Some("application/spdx+json".into()),
+            ..Descriptor::default()
+        }]);
+        source.push_manifest(
+            "ci/app",
+            &manifest::referrers_tag(&digest),
+            media_types::OCI_INDEX,
+            &serde_json::to_vec(&index).unwrap(),
+        );
+
+        let report = Replicator::default()
+            .replicate(&job(&source, &destination, "1"))
+            .await
+            .unwrap();
+        assert_eq!(report.artifacts_copied, 1);
+        let store = destination.store();
+        let (_, copied) = &store.manifests[&("prod/app".into(), manifest::referrers_tag(&digest))];
+        let listed = Manifest::parse(copied).unwrap().manifests;
+        assert_eq!(listed.iter().map(|d| &d.digest).collect::<Vec<_>>(), [&sbom]);
+    }
+
+    #[tokio::test]
+    async fn layers_are_recompressed_for_the_destination() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &b"layer contents ".repeat(100)).unwrap();
+        let layer = source.push_blob("ci/app", media_types::OCI_LAYER_GZIP, &encoder.finish().unwrap());
+        let config = source.push_blob("ci/app", "application/vnd.oci.image.config.v1+json", b"{}");
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "config": config,
+            "layers": [layer],
+        });
+        let digest = source.push_manifest(
+            "ci/app",
+            "1.0.0",
+            media_types::OCI_MANIFEST,
+            manifest.to_string().as_bytes(),
+        );
+        let mut job = job(&source, &destination, "1.0.0");
+        job.conversion = Conversion {
+            layer_compression: Some(LayerCompression::Zstd),
+            ..Conversion::default()
+        };
+        let replicator = Replicator::default();
+
+        let report = replicator.replicate(&job).await.unwrap();
+        assert_eq!(report.source_digest.as_deref(), Some(digest.as_str()));
+        assert_ne!(report.digest, digest);
+        assert_eq!(report.layers_recompressed, 1);
+        let (_, copied) =
+            destination.store().manifests[&("prod/app".to_string(), "1.0.0".to_string())].clone();
+        assert_eq!(digest::sha256(&copied), report.digest);
+        let copied = Manifest::parse(&copied).unwrap();
+        assert_eq!(copied.layers[0].media_type, media_types::OCI_LAYER_ZSTD);
+        assert_eq!(copied.config.unwrap().digest, config["digest"]);
+        let stored =
+            destination.store().blobs[&("prod/app".to_string(), copied.layers[0].digest.clone())].clone();
+        assert_eq!(
+            zstd::decode_all(&stored[..]).unwrap(),
+            b"layer contents ".repeat(100)
+        );
+
+        let again = replicator.replicate(&job).await.unwrap();
+        assert_eq!((again.manifests_copied, again.layers_recompressed), (0, 0));
+        assert_eq!(again.digest, report.digest);
+        assert!(replicator.compare(&job).await.unwrap().in_sync());
+    }
+
+    #[tokio::test]
+    async fn docker_manifest_lists_are_converted_to_oci() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let config = source.push_blob("ci/app", media_types::DOCKER_CONFIG, b"{}");
+        let layer = source.push_blob("ci/app", media_types::DOCKER_LAYER_GZIP, b"layer");
+        let image = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::DOCKER_MANIFEST,
+            "config": config,
+            "layers": [layer],
+        })
+        .to_string();
+        let child = source.push_manifest("ci/app", "amd64", media_types::DOCKER_MANIFEST, image.as_bytes());
+        let list = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::DOCKER_MANIFEST_LIST,
+            "manifests": [{
+                "mediaType": media_types::DOCKER_MANIFEST,
+                "digest": child,
+                "size": image.len(),
+                "platform": { "architecture": "amd64", "os": "linux" },
+            }],
+        });
+        let digest = source.push_manifest(
+            "ci/app",
+            "1.0.0",
+            media_types::DOCKER_MANIFEST_LIST,
+            list.to_string().as_bytes(),
+        );
+        let mut job = job(&source, &destination, "1.0.0");
+        job.conversion = Conversion {
+            manifest_format: Some(ManifestFormat::Oci),
+            ..Conversion::default()
+        };
+        let replicator = Replicator::default();
+
+        let report = replicator.replicate(&job).await.unwrap();
+        assert_eq!(report.source_digest.as_deref(), Some(digest.as_str()));
+        assert_eq!((report.manifests_copied, report.blobs_copied), (2, 2));
+        {
+            let store = destination.store();
+            let (media_type, bytes) = &store.manifests[&("prod/app".to_string(), "1.0.0".to_string())];
+            assert_eq!(media_type, media_types::OCI_INDEX);
+            assert_eq!(digest::sha256(bytes), report.digest);
+            let index = Manifest::parse(bytes).unwrap();
+            assert_eq!(index.manifests[0].media_type, media_types::OCI_MANIFEST);
+            let (media_type, bytes) =
+                &store.manifests[&("prod/app".to_string(), index.manifests[0].digest.clone())];
+            assert_eq!(media_type, media_types::OCI_MANIFEST);
+            let image = Manifest::parse(bytes).unwrap();
+            assert_eq!(image.config.unwrap().media_type, media_types::OCI_CONFIG);
+            assert_eq!(image.layers[0].media_type, media_types::OCI_LAYER_GZIP);
+            assert_eq!(image.layers[0].digest, layer["digest"], "blobs are not touched");
+        }
+
+        let again = replicator.replicate(&job).await.unwrap();
+        assert_eq!((again.manifests_copied, again.digest), (0, report.digest));
+        assert!(replicator.compare(&job).await.unwrap().in_sync());
+    }
+}
@@ -0,0 +1,1177 @@
+//! Reconciler for `ContainerReplicator` objects.
+//!
+//! Every reconcile resolves the images of the selected workloads and the tags picked by tag
+//! selectors, copies those found in the source repository to every destination and records the
+//! outcome in the object's status. With a `verification` block or a `vulnerabilityGate`, images
+//! without an accepted signature or with blocking scan findings are refused before anything is
+//! copied. Moving a tag that already exists in a destination is reported as an event, and refused
+//! for destinations with immutable tags. In dry-run mode only the plan of what would be copied is
+//! recorded. Once loco's workers are connected the copies run on a background worker, which
+//! records its progress in the status, and the reconcile only queues them.
+use crate::{
+    core::{
+        ErrorWrapper, Result,
+        crd::{
+            self, Condition, ContainerReplicator, ContainerReplicatorSpec, ContainerReplicatorStatus,
+            DestinationRepository, PlannedImage, ReplicatedImage, ReplicationProgress, RepositoryRef,
+            SourceRepository, TagAction, conditions,
+        },
+        drift::{self, Drift},
+        kubecontroller::{Context, State},
+        notifications,
+        promotion::{self, SelectedImage},
+        registry::{Reference, RepositoryLocation, digest::DigestMismatch},
+        replication::{Comparison, CopyReport, Destination, ReplicationJob, TagConflict},
+        rewrite,
+        scope::{Concurrency, WatchScope},
+        tagpolicy,
+        verification::{self, VerificationFailed},
+        vulnerabilities::{self, GateFailed},
+    },
+    workers::replication::{ReplicationWorker, ReplicationWorkerArgs},
+};
+use chrono::Utc;
+use futures::{Stream, StreamExt, stream::FuturesUnordered};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    Resource,
+    api::{Api, ListParams, Patch, PatchParams, ResourceExt},
+    client::Client,
+    runtime::{
+        WatchStreamExt,
+        controller::{Action, Controller},
+        events::{Event, EventType},
+        reflector::{self, ObjectRef, Store},
+        watcher::{Config, watcher},
+    },
+};
+use loco_rs::app::AppContext;
+use serde_json::json;
+use std::sync::Arc;
+use tracing::{error, info, instrument, warn};
+
+#[instrument(skip(ctx, replicator), fields(replicator = %replicator.name_any()))]
+pub async fn reconcile(replicator: Arc<ContainerReplicator>, ctx: Arc<Context>) -> Result<Action> {
+    let trace_id = crate::core::telemetry::get_trace_id();
+    let _timer = ctx.metrics.replicator.count_and_measure(&trace_id);
+    ctx.diagnostics.write().await.last_event = Utc::now();
+
+    let Some(ns) = replicator.namespace() else {
+        return Err(ErrorWrapper::from_custom(
+            "ContainerReplicator namespace is missing",
+        ));
+    };
+    let spec = &replicator.spec;
+    let key = format!("{ns}/{}", replicator.name_any());
+    if spec.dry_run || ctx.settings.replication.dry_run {
+        let mut jobs = jobs(&ctx, &ns, spec).await?;
+        info!(jobs = jobs.len(), "Planning replication");
+        return dry_run(&replicator, &ctx, &key, &mut jobs).await;
+    }
+    ctx.plans.write().await.remove(&key);
+    if let Some(app) = ctx.workers.get() {
+        return enqueue(app, ctx.clone(), &replicator, key).await;
+    }
+    replicate(&ctx, &replicator, false).await
+}
+
+/// Hand the replication of a replicator to a background worker, unless one already has it
+async fn enqueue(
+    app: &AppContext,
+    ctx: Arc<Context>,
+    replicator: &ContainerReplicator,
+    key: String,
+) -> Result<Action> {
+    let requeue = Action::requeue(ctx.settings.requeue.interval());
+    if !ctx
+        .queued
+        .lock()
+        .expect("queue lock poisoned")
+        .insert(key.clone())
+    {
+        info!("Replication is already queued");
+        return Ok(requeue);
+    }
+    let args = ReplicationWorkerArgs {
+        namespace: replicator.namespace().unwrap_or_default(),
+        name: replicator.name_any(),
+    };
+    if let Err(e) = ReplicationWorker::new(app, ctx.clone()).queue(args).await {
+        ctx.queued.lock().expect("queue lock poisoned").remove(&key);
+        return Err(e);
+    }
+    info!("Queued replication");
+    Ok(requeue)
+}
+
+/// Replicate a queued replicator, on a worker; a replicator deleted in the meantime is skipped
+///
+/// # Errors
+///
+/// When the replicator can not be read, or a copy fails
+///
+/// # Panics
+///
+/// Panics if another worker panicked while holding the queue lock.
+pub async fn replicate_queued(ctx: &Context, namespace: &str, name: &str) -> Result<()> {
+    let replicators: Api<ContainerReplicator> = Api::namespaced(ctx.client.clone(), namespace);
+    let result = match replicators.get_opt(name).await.map_err(ErrorWrapper::from_kube) {
+        Ok(Some(replicator)) => replicate(ctx, &replicator, true)
+            .await
+            .map(|_| ())
+            .inspect_err(|e| ctx.metrics.replicator.set_failure(&replicator, e)),
+        Ok(None) => Ok(()),
+        Err(e) => Err(e),
+    };
+    ctx.queued
+        .lock()
+        .expect("queue lock poisoned")
+        .remove(&format!("{namespace}/{name}"));
+    result
+}
+
+/// Copy the images of a replicator and record the outcome in its status and events. With
+/// `progress`, as on a worker, the status also follows the jobs as they finish.
+async fn replicate(ctx: &Context, replicator: &ContainerReplicator, progress: bool) -> Result<Action> {
+    let ns = replicator.namespace().unwrap_or_default();
+    let spec = &replicator.spec;
+    let mut jobs = jobs(ctx, &ns, spec).await?;
+    let mut status = replicator.status.clone().unwrap_or_default();
+    let drift = audit(ctx, replicator, &jobs).await?;
+    info!(jobs = jobs.len(), drift = drift.len(), "Replicating images");
+
+    status.progress = progress.then(|| ReplicationProgress {
+        start_time: Utc::now(),
+        jobs: jobs.len(),
+        completed: 0,
+        failed: 0,
+    });
+    let results = run_jobs(ctx, replicator, &mut jobs, &mut status).await;
+    status.progress = None;
+    status.observed_generation = replicator.meta().generation;
+    status.last_replication_time = Some(Utc::now());
+    status.images = promoted_images(&status.images, &jobs, &results);
+    status.blocking_findings.clear();
+    status.plan.clear();
+    let mut failures = vec![];
+    let mut events: Vec<Event> = vec![];
+    for (job, result) in jobs.iter().zip(&results) {
+        match result {
+            Ok(report) => {
+                if let Some(event) = overwrite(job, report) {
+                    events.push(event);
+                }
+            }
+            Err(e) => {
+                warn!(%job, error = %e, "Replication failed");
+                failures.push(failure(job, e));
+                if let Some(refused) = GateFailed::find(e) {
+                    status.blocking_findings.extend(refused.findings.iter().cloned());
+                }
+                if let Some(event) = refusal(job, e) {
+                    events.push(event);
+                }
+            }
+        }
+    }
+    // tarball layouts are packed once for the whole run rather than after every image
+    if let Err(e) = ctx
+        .replicator
+        .flush(jobs.iter().map(|job| &job.destination.endpoint))
+        .await
+    {
+        warn!(error = %e, "Packing the destination layout failed");
+        failures.push(e.to_string());
+    }
+    status.blocking_findings.sort();
+    status.blocking_findings.dedup();
+    let copied = results
+        .iter()
+        .flatten()
+        .filter(|report| report.manifests_copied > 0)
+        .count();
+    status.set_condition(if failures.is_empty() {
+        Condition::new(
+            conditions::READY,
+            true,
+            "Replicated",
+            format!("{} images in sync", jobs.len()),
+        )
+    } else {
+        let reason = REFUSALS
+            .into_iter()
+            .find(|reason| events.iter().any(|event| event.reason == *reason))
+            .unwrap_or("ReplicationFailed");
+        Condition::new(conditions::READY, false, reason, failures.join("; "))
+    });
+    for condition in refusal_conditions(&events) {
+        status.set_condition(condition);
+    }
+    events.extend(summary(failures.len(), jobs.len(), copied));
+    status.set_condition(drift_condition(&drift, &mut events));
+    for event in &events {
+        ctx.recorder
+            .publish(event, &replicator.object_ref(&()))
+            .await
+            .map_err(ErrorWrapper::from_kube)?;
+    }
+    patch_status(&ctx.client, &ns, &replicator.name_any(), status).await?;
+
+    if failures.is_empty() {
+        Ok(Action::requeue(ctx.settings.requeue.interval()))
+    } else {
+        Err(ErrorWrapper::from_custom(&format!(
+            "{} of {} replication jobs failed",
+            failures.len(),
+            jobs.len()
+        )))
+    }
+}
+
+/// The images a replicator promoted after a run: what its jobs copied, and for jobs that failed what
+/// an earlier run promoted, so it is still audited for drift
+fn promoted_images(
+    previous: &[ReplicatedImage],
+    jobs: &[ReplicationJob],
+    results: &[Result<CopyReport>],
+) -> Vec<ReplicatedImage> {
+    jobs.iter()
+        .zip(results)
+        .filter_map(|(job, result)| {
+            result.as_ref().map_or_else(
+                |_| job.promoted(previous).map(|(image, _)| image.clone()),
+                |report| {
+                    Some(ReplicatedImage {
+                        source: job.source.to_string(),
+                        destination: job.destination.to_string(),
+                        digest: report.digest.clone(),
+                        source_digest: report.source_digest.clone(),
+                        rewrites: job.rewrites.clone(),
+                    })
+                },
+            )
+        })
+        .collect()
+}
+
+/// Run the jobs of a replicator concurrently, returning their results in job order; with
+/// `status.progress` set, the status follows the jobs as they finish
+async fn run_jobs(
+    ctx: &Context,
+    replicator: &ContainerReplicator,
+    jobs: &mut [ReplicationJob],
+    status: &mut ContainerReplicatorStatus,
+) -> Vec<Result<CopyReport>> {
+    let spec = &replicator.spec;
+    let mut finished: Vec<Option<Result<CopyReport>>> = jobs.iter().map(|_| None).collect();
+    let mut pending: FuturesUnordered<_> = jobs
+        .iter_mut()
+        .enumerate()
+        .map(|(i, job)| async move { (i, promote(ctx, job, spec).await) })
+        .collect();
+    if status.progress.is_some() {
+        record_progress(ctx, replicator, status).await;
+    }
+    while let Some((i, result)) = pending.next().await {
+        if let Some(progress) = &mut status.progress {
+            progress.completed += 1;
+            progress.failed += usize::from(result.is_err());
+            record_progress(ctx, replicator, status).await;
+        }
+        finished[i] = Some(result);
+    }
+    drop(pending);
+    finished.into_iter().flatten().collect()
+}
+
+/// The signature and scan conditions, true with the notes of the refusals among `events`
+fn refusal_conditions(events: &[Event]) -> Vec<Condition> {
+    [
+        (conditions::SIGNATURE_VERIFICATION_FAILED, "SignaturesAccepted"),
+        (conditions::VULNERABILITY_GATE_FAILED, "NoBlockingFindings"),
+    ]
+    .into_iter()
+    .map(|(type_, passed)| {
+        let refused: Vec<&str> = events
+            .iter()
+            .filter(|event| event.reason == type_)
+            .filter_map(|event| event.note.as_deref())
+            .collect();
+        if refused.is_empty() {
+            Condition::new(type_, false, passed, "No image was refused")
+        } else {
+            Condition::new(type_, true, type_, refused.join("; "))
+        }
+    })
+    .collect()
+}
+
+/// The event summing up a replication, if there is anything to tell
+fn summary(failures: usize, jobs: usize, copied: usize) -> Option<Event> {
+    if failures > 0 {
+        Some(Event {
+            type_: EventType::Warning,
+            reason: "ReplicationFailed".into(),
+            note: Some(format!("{failures} of {jobs} images failed to replicate")),
+            action: "Replicating".into(),
+            secondary: None,
+        })
+    } else if copied > 0 {
+        Some(Event {
+            type_: EventType::Normal,
+            reason: "Replicated".into(),
+            note: Some(format!("Copied {copied} images")),
+            action: "Replicating".into(),
+            secondary: None,
+        })
+    } else {
+        None
+    }
+}
+
+/// The source and destinations a replicator refers to
+async fn repositories(
+    ctx: &Context,
+    namespace: &str,
+    spec: &ContainerReplicatorSpec,
+) -> Result<(RepositoryLocation, Vec<Destination>)> {
+    let source: SourceRepository = get(&ctx.client, namespace, &spec.source_ref).await?;
+    let mut destinations = vec![];
+    for reference in &spec.destination_refs {
+        let destination: DestinationRepository = get(&ctx.client, namespace, reference).await?;
+        destinations.push(Destination::from(&destination.spec));
+    }
+    Ok((RepositoryLocation::from(&source.spec.repository), destinations))
+}
+
+/// The jobs a replicator currently asks for, from its workloads and tag selectors
+async fn jobs(ctx: &Context, namespace: &str, spec: &ContainerReplicatorSpec) -> Result<Vec<ReplicationJob>> {
+    let (source, destinations) = repositories(ctx, namespace, spec).await?;
+    let mut images = promotion::resolve(&ctx.client, namespace, &spec.promotion_selectors).await?;
+    let client = ctx.replicator.client(&source.endpoint);
+    images.extend(promotion::layout_images(&client, &source, &spec.source_ref.name).await?);
+    if !spec.tag_selectors.is_empty() {
+        images.extend(tagpolicy::resolve(&client, &source, &spec.tag_selectors).await?);
+    }
+    promotion::plan(&images, &source, &destinations, spec.rewrite.as_ref())
+}
+
+/// Copy one image to every destination of a replicator now, whether or not a workload uses it,
+/// with the same checks and rewrite rules as a reconcile
+///
+/// # Errors
+///
+/// When the repositories of the replicator can not be read, `image` is not in its source, or a copy fails
+pub async fn replicate_image(
+    ctx: &Context,
+    replicator: &ContainerReplicator,
+    image: &Reference,
+) -> Result<Vec<(ReplicationJob, CopyReport)>> {
+    let ns = replicator.namespace().unwrap_or_default();
+    let spec = &replicator.spec;
+    let (source, destinations) = repositories(ctx, &ns, spec).await?;
+    let selected = SelectedImage {
+        workload: "task/replicate".into(),
+        image: image.clone(),
+    };
+    let jobs = promotion::plan(&[selected], &source, &destinations, spec.rewrite.as_ref())?;
+    if jobs.is_empty() {
+        return Err(ErrorWrapper::from_custom(&format!(
+            "{image} is not in the source repository of {ns}/{}",
+            replicator.name_any()
+        )));
+    }
+    let endpoints: Vec<_> = jobs.iter().map(|job| job.destination.endpoint.clone()).collect();
+    let reports = async {
+        let mut reports = vec![];
+        for mut job in jobs {
+            let report = promote(ctx, &mut job, spec).await?;
+            reports.push((job, report));
+        }
+        Ok(reports)
+    }
+    .await;
+    ctx.replicator.flush(&endpoints).await?;
+    reports
+}
+
+/// Compare the digest of every image a replicator promotes with what its destination has
+///
+/// # Errors
+///
+/// When the repositories or workloads of the replicator can not be read, or a registry can not be reached
+pub async fn verify(
+    ctx: &Context,
+    replicator: &ContainerReplicator,
+) -> Result<Vec<(ReplicationJob, Comparison)>> {
+    let ns = replicator.namespace().unwrap_or_default();
+    let spec = &replicator.spec;
+    let mut comparisons = vec![];
+    for mut job in jobs(ctx, &ns, spec).await? {
+        render_tag(ctx, &mut job, spec).await?;
+        let comparison = ctx.replicator.compare(&job).await?;
+        comparisons.push((job, comparison));
+    }
+    Ok(comparisons)
+}
+
+/// Check what the previous replication promoted against the destinations and the pods running in
+/// the watched namespaces, and count the drift in the metrics
+async fn audit(
+    ctx: &Context,
+    replicator: &ContainerReplicator,
+    jobs: &[ReplicationJob],
+) -> Result<Vec<Drift>> {
+    let promoted = replicator
+        .status
+        .as_ref()
+        .map_or(&[][..], |status| status.images.as_slice());
+    let mut found = vec![];
+    if !promoted.is_empty() {
+        found = drift::destinations(&ctx.replicator, jobs, promoted).await;
+        for pods in WatchScope::from_settings(&ctx.settings).apis::<Pod>(&ctx.client) {
+            let pods = pods
+                .list(&ListParams::default())
+                .await
+                .map_err(ErrorWrapper::from_kube)?;
+            found.extend(drift::pods(&pods.items, promoted));
+        }
+    }
+    let key = format!(
+        "{}/{}",
+        replicator.namespace().unwrap_or_default(),
+        replicator.name_any()
+    );
+    ctx.metrics.drift.set(&key, &found);
+    Ok(found)
+}
+
+/// The `DriftDetected` condition for `drift`, adding a warning to `events` if there is any
+fn drift_condition(drift: &[Drift], events: &mut Vec<Event>) -> Condition {
+    if drift.is_empty() {
+        return Condition::new(
+            conditions::DRIFT_DETECTED,
+            false,
+            "NoDrift",
+            "Destinations and pods match the promoted images",
+        );
+    }
+    let drifted: Vec<_> = drift.iter().map(ToString::to_string).collect();
+    events.push(Event {
+        type_: EventType::Warning,
+        reason: "DriftDetected".into(),
+        note: Some(format!("{} images drifted: {}", drift.len(), drifted.join(", "))),
+        action: "Auditing".into(),
+        secondary: None,
+    });
+    Condition::new(
+        conditions::DRIFT_DETECTED,
+        true,
+        "DriftDetected",
+        drifted.join("; "),
+    )
+}
+
+/// Patch the progress of a running replication into the status; failing to is not worth stopping
+/// the copies for
+async fn record_progress(
+    ctx: &Context,
+    replicator: &ContainerReplicator,
+    status: &ContainerReplicatorStatus,
+) {
+    let ns = replicator.namespace().unwrap_or_default();
+    if let Err(e) = patch_status(&ctx.client, &ns, &replicator.name_any(), status.clone()).await {
+        warn!(error = %e, "Can not record replication progress");
+    }
+}
+
+/// Reasons an image is refused rather than failing to copy, in the order they win the `Ready` reason;
+/// refused signatures and scans have conditions of their own
+static REFUSALS: [&str; 2] = ["TagConflict", "DigestMismatch"];
+
+/// Work out what replication would do and record it, without pushing anything; drift is audited
+/// as when replicating
+async fn dry_run(
+    replicator: &ContainerReplicator,
+    ctx: &Context,
+    key: &str,
+    jobs: &mut [ReplicationJob],
+) -> Result<Action> {
+    let spec = &replicator.spec;
+    let drift = audit(ctx, replicator, jobs).await?;
+    let results = futures::future::join_all(jobs.iter_mut().map(|job| async {
+        render_tag(ctx, job, spec).await?;
+        ctx.replicator.plan(job).await
+    }))
+    .await;
+    let mut status = replicator.status.clone().unwrap_or_default();
+    status.observed_generation = replicator.meta().generation;
+    status.plan.clear();
+    let mut plans = vec![];
+    let mut failures = vec![];
+    for (job, result) in jobs.iter().zip(results) {
+        match result {
+            Ok(plan) => {
+                status.plan.push(PlannedImage::from(&plan));
+                plans.push(plan);
+            }
+            Err(e) => {
+                warn!(%job, error = %e, "Planning failed");
+                failures.push(failure(job, &e));
+            }
+        }
+    }
+    let pending = status
+        .plan
+        .iter()
+        .filter(|plan| plan.tag != TagAction::Unchanged || plan.manifests_to_copy > 0)
+        .count();
+    let bytes: u64 = status.plan.iter().map(|plan| plan.bytes_to_copy).sum();
+    let mut message = format!(
+        "Dry run: {pending} of {} images would be copied, {bytes} bytes",
+        jobs.len()
+    );
+    if !failures.is_empty() {
+        message = format!("{message}; {}", failures.join("; "));
+    }
+    status.set_condition(Condition::new(
+        conditions::READY,
+        pending == 0 && failures.is_empty(),
+        "DryRun",
+        message,
+    ));
+    let mut events = vec![];
+    status.set_condition(drift_condition(&drift, &mut events));
+    ctx.plans.write().await.insert(key.to_string(), plans);
+    for event in &events {
+        ctx.recorder
+            .publish(event, &replicator.object_ref(&()))
+            .await
+            .map_err(ErrorWrapper::from_kube)?;
+    }
+    let ns = replicator.namespace().unwrap_or_default();
+    patch_status(&ctx.client, &ns, &replicator.name_any(), status).await?;
+    if failures.is_empty() {
+        Ok(Action::requeue(ctx.settings.requeue.interval()))
+    } else {
+        Err(ErrorWrapper::from_custom(&format!(
+            "{} of {} replication jobs could not be planned",
+            failures.len(),
+            jobs.len()
+        )))
+    }
+}
+
+/// Render the tag template of the rewrite rules into the job's destination, so the job names where
+/// the image really goes
+async fn render_tag(ctx: &Context, job: &mut ReplicationJob, spec: &ContainerReplicatorSpec) -> Result<()> {
+    let Some(template) = spec.rewrite.as_ref().and_then(|rules| rules.tag.as_deref()) else {
+        return Ok(());
+    };
+    let source = ctx.replicator.client(&job.source.endpoint);
+    let tag = (!job.destination.reference.contains(':')).then(|| job.destination.reference.clone());
+    let rendered = rewrite::render_tag(&source, &job.source, tag.as_deref(), template).await?;
+    job.rewrites.push(format!("tag {template}"));
+    job.destination.reference = rendered;
+    Ok(())
+}
+
+/// Copy an image as [`checked_copy`] does, counting the copy in the replication metrics
+async fn promote(
+    ctx: &Context,
+    job: &mut ReplicationJob,
+    spec: &ContainerReplicatorSpec,
+) -> Result<CopyReport> {
+    let result = checked_copy(ctx, job, spec).await;
+    ctx.metrics.replication.observe(&result);
+    result
+}
+
+/// Copy an image, after the signature and vulnerability checks the replicator asks for
+async fn checked_copy(
+    ctx: &Context,
+    job: &mut ReplicationJob,
+    spec: &ContainerReplicatorSpec,
+) -> Result<CopyReport> {
+    render_tag(ctx, job, spec).await?;
+    if spec.verification.is_none() && spec.vulnerability_gate.is_none() {
+        return ctx.replicator.replicate(job).await;
+    }
+    let source = ctx.replicator.client(&job.source.endpoint);
+    let digest = match &spec.verification {
+        Some(policy) => verification::verify(&source, &job.source, policy, &ctx.trust).await?,
+        None => {
+            source
+                .resolve(&job.source.repository, &job.source.reference)
+                .await?
+        }
+    };
+    if let Some(gate) = &spec.vulnerability_gate {
+        vulnerabilities::check(&source, &job.source, &digest, gate).await?;
+    }
+    // copy exactly what was checked, even if the tag moved since
+    let mut job = job.clone();
+    job.source.reference = digest;
+    ctx.replicator.replicate(&job).await
+}
+
+/// A warning event for a job whose image was refused, `None` for plain copy failures
+fn refusal(job: &ReplicationJob, e: &loco_rs::Error) -> Option<Event> {
+    let (reason, note) = if let Some(rejected) = VerificationFailed::find(e) {
+        (conditions::SIGNATURE_VERIFICATION_FAILED, rejected.to_string())
+    } else if let Some(refused) = GateFailed::find(e) {
+        (conditions::VULNERABILITY_GATE_FAILED, refused.to_string())
+    } else if let Some(conflict) = TagConflict::find(e) {
+        (REFUSALS[0], conflict.to_string())
+    } else {
+        (REFUSALS[1], format!("{job}: {}", DigestMismatch::find(e)?))
+    };
+    Some(Event {
+        type_: EventType::Warning,
+        reason: reason.into(),
+        note: Some(note),
+        action: "Verifying".into(),
+        secondary: None,
+    })
+}
+
+/// A normal event for a job that moved an existing destination tag
+fn overwrite(job: &ReplicationJob, report: &CopyReport) -> Option<Event> {
+    let previous = report.overwritten.as_ref()?;
+    let backup = report
+        .backup
+        .as_ref()
+        .map(|tag| format!(", kept as {tag}"))
+        .unwrap_or_default();
+    Some(Event {
+        type_: EventType::Normal,
+        reason: "TagOverwritten".into(),
+        note: Some(format!(
+            "{} moved from {previous} to {}{backup}",
+            job.destination, report.digest
+        )),
+        action: "Replicating".into(),
+        secondary: None,
+    })
+}
+
+fn failure(job: &ReplicationJob, e: &loco_rs::Error) -> String {
+    format!("{}: {e}", job.destination)
+}
+
+/// Fetch a referenced repository object, defaulting to the referrer's namespace
+///
+/// # Errors
+///
+/// When the object does not exist or can not be read
+pub async fn get<K>(client: &Client, namespace: &str, reference: &RepositoryRef) -> Result<K>
+where
+    K: Resource<Scope = k8s_openapi::NamespaceResourceScope, DynamicType = ()>
+        + Clone
+        + serde::de::DeserializeOwned
+        + std::fmt::Debug,
+{
+    let namespace = reference.namespace.as_deref().unwrap_or(namespace);
+    Api::<K>::namespaced(client.clone(), namespace)
+        .get_opt(&reference.name)
+        .await
+        .map_err(ErrorWrapper::from_kube)?
+        .ok_or_else(|| {
+            ErrorWrapper::from_custom(&format!(
+                "{} {namespace}/{} not found",
+                K::kind(&()),
+                reference.name
+            ))
+        })
+}
+
+async fn patch_status(
+    client: &Client,
+    namespace: &str,
+    name: &str,
+    status: ContainerReplicatorStatus,
+) -> Result<()> {
+    let replicators: Api<ContainerReplicator> = Api::namespaced(client.clone(), namespace);
+    let patch = Patch::Apply(json!({
+        "apiVersion": format!("{}/{}", crd::GROUP, crd::STORAGE_VERSION),
+        "kind": "ContainerReplicator",
+        "status": status,
+    }));
+    replicators
+        .patch_status(name, &PatchParams::apply("cntrlr").force(), &patch)
+        .await
+        .map_err(ErrorWrapper::from_kube)?;
+    Ok(())
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn error_policy(replicator: Arc<ContainerReplicator>, error: &loco_rs::Error, ctx: Arc<Context>) -> Action {
+    warn!("reconcile failed: {:?}", error);
+    ctx.metrics.replicator.set_failure(replicator.as_ref(), error);
+    Action::requeue(ctx.settings.requeue.error_interval())
+}
+
+/// The replicators to reconcile for each pushed image, among those cached in `replicators`
+fn pushed(
+    replicators: Store<ContainerReplicator>,
+    sources: Vec<Store<SourceRepository>>,
+    pushes: impl Stream<Item = Reference> + Send + 'static,
+) -> impl Stream<Item = ObjectRef<ContainerReplicator>> + Send + 'static {
+    pushes.flat_map(move |image| {
+        let matching = notifications::replicators(&replicators, &sources, &image);
+        info!(%image, replicators = matching.len(), "Image pushed");
+        futures::stream::iter(matching)
+    })
+}
+
+/// Run the `ContainerReplicator` controller until shutdown (given the CRDs are installed)
+///
+/// # Panics
+///
+/// Panics if no kube client can be created.
+pub async fn run(state: State) {
+    let client = Client::try_default().await.expect("failed to create kube Client");
+    let scope = WatchScope::from_settings(state.settings());
+    let apis = scope.apis::<ContainerReplicator>(&client);
+    for replicators in &apis {
+        if let Err(e) = replicators.list(&ListParams::default().limit(1)).await {
+            error!("ContainerReplicator CRD is not queryable; {e:?}. Is the CRD installed?");
+            info!("Installation: cargo run --bin crdgen | kubectl apply -f -");
+            return;
+        }
+    }
+    info!(?scope, "Watching ContainerReplicators");
+    // the source repositories pushed images are matched against, cached for as long as the
+    // controllers run
+    let (sources, watches): (Vec<_>, Vec<_>) = scope
+        .apis::<SourceRepository>(&client)
+        .into_iter()
+        .map(|api| {
+            let (store, writer) = reflector::store();
+            let watch = watcher(api, Config::default())
+                .default_backoff()
+                .reflect(writer)
+                .boxed();
+            (store, watch)
+        })
+        .collect();
+    let watches = tokio::spawn(futures::stream::select_all(watches).for_each(|_| futures::future::ready(())));
+    let ctx = state.to_context(client).await;
+    // one controller per watched namespace, all within the same concurrency limit
+    let concurrency = Concurrency::new(state.settings().concurrency);
+    let controllers = apis.into_iter().map(|replicators| {
+        let controller = Controller::new(replicators, Config::default().any_semantic());
+        let pushed = pushed(controller.store(), sources.clone(), state.pushes());
+        let concurrency = concurrency.clone();
+        controller
+            .reconcile_on(pushed)
+            .graceful_shutdown_on(state.shutdown().stopping())
+            .run(
+                move |replicator, ctx| {
+                    let concurrency = concurrency.clone();
+                    async move { concurrency.run(reconcile(replicator, ctx)).await }
+                },
+                error_policy,
+                ctx.clone(),
+            )
+            .boxed()
+    });
+    futures::stream::select_all(controllers)
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()))
+        .await;
+    watches.abort();
+}
+
+// Mock tests relying on fixtures.rs, with a fake registry on both ends
+#[cfg(test)]
+mod test {
+    use super::{error_policy, promoted_images, reconcile, replicate_image, replicate_queued, verify};
+    use crate::core::{
+        ErrorWrapper, LocoErrorExt,
+        crd::{
+            ContainerReplicator, ContainerReplicatorStatus, DestinationRepository, ReplicatedImage,
+            SourceRepository, TagMutability, Verification, VulnerabilityGate,
+        },
+        fixtures::{ReplicationObjects, Scenario, deployment, timeout_after_1s},
+        kubecontroller::Context,
+        metrics::{ErrorLabels, OutcomeLabels},
+        registry::{digest::sha256, fake::FakeRegistry},
+        replication::{Conversion, CopyReport, ImageLocation, ReplicationJob},
+    };
+    use bytes::Bytes;
+    use k8s_openapi::api::core::v1::Pod;
+    use std::sync::Arc;
+
+    fn objects(
+        source: &FakeRegistry,
+        destination: &FakeRegistry,
+        images: &[&str],
+    ) -> Box<ReplicationObjects> {
+        Box::new(ReplicationObjects {
+            replicator: ContainerReplicator::test(),
+            source: SourceRepository::test(&source.host),
+            destination: DestinationRepository::test(&destination.host),
+            deployment: deployment(images),
+        })
+    }
+
+    #[tokio::test]
+    async fn images_of_selected_workloads_are_copied() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let (testctx, fakeserver) = Context::test();
+        let objects = objects(&source, &destination, &[&image, "docker.io/library/nginx:1"]);
+        let mocksrv = fakeserver.run(Scenario::Replication(objects, vec!["Replicated"], "True"));
+        reconcile(Arc::new(ContainerReplicator::test()), testctx)
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        let copied = destination
+            .store()
+            .manifests
+            .get(&("prod/app".into(), "1.0.0".into()))
+            .cloned();
+        assert_eq!(copied.map(|(_, bytes)| sha256(&bytes)), Some(digest));
+    }
+
+    #[tokio::test]
+    async fn failed_copies_are_reported_and_bump_the_failure_metric() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let image = format!("{}/ci/app:missing", source.host);
+        let (testctx, fakeserver) = Context::test();
+        let objects = objects(&source, &destination, &[&image]);
+        let mocksrv = fakeserver.run(Scenario::Replication(objects, vec!["ReplicationFailed"], "False"));
+        let replicator = Arc::new(ContainerReplicator::test());
+        let err = reconcile(replicator.clone(), testctx.clone()).await.unwrap_err();
+        timeout_after_1s(mocksrv).await;
+        assert!(err.to_string().contains("1 of 1 replication jobs failed"));
+
+        error_policy(replicator, &err, testctx.clone());
+        let labels = ErrorLabels {
+            instance: "app".into(),
+            error: err.metric_label(),
+        };
+        assert_eq!(
+            testctx.metrics.replicator.failures.get_or_create(&labels).get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_keep_what_earlier_runs_promoted() {
+        let registry = FakeRegistry::start().await;
+        let job = |image: &str| ReplicationJob {
+            source: ImageLocation {
+                endpoint: registry.endpoint(),
+                repository: format!("ci/{image}"),
+                reference: "1.0.0".into(),
+            },
+            destination: ImageLocation {
+                endpoint: registry.endpoint(),
+                repository: format!("prod/{image}"),
+                reference: "1.0.0".into(),
+            },
+            tag_mutability: TagMutability::default(),
+            conversion: Conversion::default(),
+            rewrites: vec![],
+        };
+        let (app, worker) = (job("app"), job("worker"));
+        // promoted under a rendered tag template, the failed job still names the source tag
+        let earlier = ReplicatedImage {
+            source: worker.source.to_string(),
+            destination: format!("{}/prod/worker:1.0.0-abc", registry.host),
+            digest: "sha256:aaa".into(),
+            source_digest: None,
+            rewrites: vec!["tag {tag}-{shortRevision}".into()],
+        };
+        let results = [
+            Ok(CopyReport {
+                digest: "sha256:bbb".into(),
+                ..CopyReport::default()
+            }),
+            Err(ErrorWrapper::from_custom("registry unavailable")),
+        ];
+        let images = promoted_images(std::slice::from_ref(&earlier), &[app.clone(), worker], &results);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].destination, app.destination.to_string());
+        assert_eq!(images[0].digest, "sha256:bbb");
+        assert_eq!(images[1], earlier);
+    }
+
+    #[tokio::test]
+    async fn tampered_blobs_are_not_pushed() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        source
+            .store()
+            .blobs
+            .insert(("ci/app".into(), sha256(b"layer")), Bytes::from_static(b"evil"));
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let (testctx, fakeserver) = Context::test();
+        let objects = objects(&source, &destination, &[&image]);
+        let scenario = Scenario::Replication(objects, vec!["DigestMismatch", "ReplicationFailed"], "False");
+        let mocksrv = fakeserver.run(scenario);
+        let res = reconcile(Arc::new(ContainerReplicator::test()), testctx).await;
+        timeout_after_1s(mocksrv).await;
+        assert!(res.is_err());
+        let store = destination.store();
+        assert!(!store.blobs.contains_key(&("prod/app".into(), sha256(b"layer"))));
+        assert!(store.manifests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsigned_images_are_refused() {
+        use p256::pkcs8::{EncodePublicKey, LineEnding};
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let key = p256::ecdsa::SigningKey::from_slice(&[1; 32]).unwrap();
+        let mut replicator = ContainerReplicator::test();
+        replicator.spec.verification = Some(Verification {
+            public_keys: vec![key.verifying_key().to_public_key_pem(LineEnding::LF).unwrap()],
+            keyless: vec![],
+        });
+        let (testctx, fakeserver) = Context::test();
+        let mut objects = objects(&source, &destination, &[&image]);
+        objects.replicator = replicator.clone();
+        let scenario = Scenario::Replication(
+            objects,
+            vec!["SignatureVerificationFailed", "ReplicationFailed"],
+            "False",
+        );
+        let mocksrv = fakeserver.run(scenario);
+        let res = reconcile(Arc::new(replicator), testctx).await;
+        timeout_after_1s(mocksrv).await;
+        assert!(res.is_err());
+        assert!(destination.store().manifests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn images_without_a_scan_report_are_blocked() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let mut replicator = ContainerReplicator::test();
+        replicator.spec.vulnerability_gate = Some(VulnerabilityGate::default());
+        let (testctx, fakeserver) = Context::test();
+        let mut objects = objects(&source, &destination, &[&image]);
+        objects.replicator = replicator.clone();
+        let scenario = Scenario::Replication(
+            objects,
+            vec!["VulnerabilityGateFailed", "ReplicationFailed"],
+            "False",
+        );
+        let mocksrv = fakeserver.run(scenario);
+        let res = reconcile(Arc::new(replicator), testctx).await;
+        timeout_after_1s(mocksrv).await;
+        assert!(res.is_err());
+        assert!(destination.store().manifests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn immutable_destination_tags_are_not_moved() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"rebuilt"]);
+        let existing = destination.push_image("prod/app", "1.0.0", &[b"released"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let (testctx, fakeserver) = Context::test();
+        let mut objects = objects(&source, &destination, &[&image]);
+        objects.destination.spec.tag_mutability = TagMutability::Immutable;
+        let scenario = Scenario::Replication(objects, vec!["TagConflict", "ReplicationFailed"], "False");
+        let mocksrv = fakeserver.run(scenario);
+        let res = reconcile(Arc::new(ContainerReplicator::test()), testctx).await;
+        timeout_after_1s(mocksrv).await;
+        assert!(res.is_err());
+        let kept = destination
+            .store()
+            .manifests
+            .get(&("prod/app".into(), "1.0.0".into()))
+            .cloned();
+        assert_eq!(kept.map(|(_, bytes)| sha256(&bytes)), Some(existing));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_plan_without_pushing() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let mut replicator = ContainerReplicator::test();
+        replicator.spec.dry_run = true;
+        let (testctx, fakeserver) = Context::test();
+        let mut objects = objects(&source, &destination, &[&image]);
+        objects.replicator = replicator.clone();
+        let mocksrv = fakeserver.run(Scenario::Replication(objects, vec![], "False"));
+        reconcile(Arc::new(replicator), testctx.clone())
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        assert!(destination.store().manifests.is_empty());
+        let plans = testctx.plans.read().await;
+        let plan = &plans["default/app"][0];
+        assert_eq!(plan.destination, format!("{}/prod/app:1.0.0", destination.host));
+        assert_eq!((plan.manifests.copy.len(), plan.blobs.copy.len()), (1, 2));
+    }
+
+    /// A replicator that promoted `ci/app:1.0.0` before the destination tag was overwritten by hand,
+    /// and a pod in another namespace running the overwritten image
+    fn drifted(source: &FakeRegistry, destination: &FakeRegistry) -> (Box<ReplicationObjects>, Pod) {
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        let foreign = destination.push_image("prod/app", "1.0.0", &[b"pushed by hand"]);
+        let image = format!("{}/ci/app:1.0.0", source.host);
+        let promoted = format!("{}/prod/app:1.0.0", destination.host);
+        let mut replicator = ContainerReplicator::test();
+        replicator.status = Some(ContainerReplicatorStatus {
+            images: vec![ReplicatedImage {
+                source: image.clone(),
+                destination: promoted.clone(),
+                digest: digest.clone(),
+                source_digest: None,
+                rewrites: vec![],
+            }],
+            ..ContainerReplicatorStatus::default()
+        });
+        let pod: Pod = serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "app-1", "namespace": "team-b" },
+            "status": { "phase": "Running", "containerStatuses": [{
+                "name": "app", "image": promoted, "imageID": format!("{promoted}@{foreign}"),
+                "ready": true, "restartCount": 0,
+            }]},
+        }))
+        .unwrap();
+        let mut objects = objects(source, destination, &[&image]);
+        objects.replicator = replicator;
+        (objects, pod)
+    }
+
+    #[tokio::test]
+    async fn drifted_destinations_and_pods_are_flagged() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let (objects, pod) = drifted(&source, &destination);
+        let replicator = objects.replicator.clone();
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::Audit(
+            objects,
+            vec![pod],
+            vec!["TagOverwritten", "Replicated", "DriftDetected"],
+            "True",
+        ));
+        reconcile(Arc::new(replicator), testctx.clone())
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        let metrics = testctx.metrics.registry.clone();
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &metrics).unwrap();
+        assert!(encoded.contains(r#"yair_drift_images{replicator="default/app",kind="mutated"} 1"#));
+        assert!(encoded.contains(r#"yair_drift_images{replicator="default/app",kind="foreign"} 1"#));
+        assert!(encoded.contains(r#"yair_drift_images{replicator="default/app",kind="missing"} 0"#));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_audit_drift_too() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let (mut objects, pod) = drifted(&source, &destination);
+        objects.replicator.spec.dry_run = true;
+        let replicator = objects.replicator.clone();
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::Audit(
+            objects,
+            vec![pod],
+            vec!["DriftDetected"],
+            "False",
+        ));
+        reconcile(Arc::new(replicator), testctx)
+            .await
+            .expect("reconciler");
+        timeout_after_1s(mocksrv).await;
+        // the tag pushed by hand is only reported
+        let tag = |registry: &FakeRegistry, repository: &str| {
+            sha256(&registry.store().manifests[&(repository.to_string(), "1.0.0".to_string())].1)
+        };
+        assert_ne!(tag(&destination, "prod/app"), tag(&source, "ci/app"));
+    }
+
+    #[tokio::test]
+    async fn queued_replication_records_progress() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        source.push_image("ci/worker", "1.0.0", &[b"worker"]);
+        let images = [
+            format!("{}/ci/app:1.0.0", source.host),
+            format!("{}/ci/worker:1.0.0", source.host),
+        ];
+        let (testctx, fakeserver) = Context::test();
+        testctx.queued.lock().unwrap().insert("default/app".into());
+        let objects = objects(&source, &destination, &[&images[0], &images[1]]);
+        let mocksrv = fakeserver.run(Scenario::Queued(objects, 2, vec!["Replicated"], "True"));
+        replicate_queued(&testctx, "default", "app").await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        let store = destination.store();
+        for repository in ["prod/app", "prod/worker"] {
+            assert!(store.manifests.contains_key(&(repository.into(), "1.0.0".into())));
+        }
+        assert!(testctx.queued.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn verify_compares_source_and_destination_digests() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+        source.push_image("ci/worker", "2.0.0", &[b"worker"]);
+        destination.push_image("prod/worker", "2.0.0", &[b"stale"]);
+        let images = [
+            format!("{}/ci/app:1.0.0", source.host),
+            format!("{}/ci/worker:2.0.0", source.host),
+        ];
+        let (testctx, fakeserver) = Context::test();
+        let objects = objects(&source, &destination, &[&images[0], &images[1]]);
+        let mocksrv = fakeserver.run(Scenario::Lookup(objects, true));
+        let comparisons = verify(&testctx, &ContainerReplicator::test()).await.unwrap();
+        timeout_after_1s(mocksrv).await;
+        let states: Vec<_> = comparisons
+            .iter()
+            .map(|(job, comparison)| (job.destination.repository.as_str(), comparison.in_sync()))
+            .collect();
+        assert_eq!(states, [("prod/app", false), ("prod/worker", false)]);
+        assert_eq!(comparisons[0].1.source, digest);
+        assert_eq!(comparisons[0].1.destination, None);
+        assert!(comparisons[1].1.destination.is_some());
+    }
+
+    #[tokio::test]
+    async fn images_can_be_replicated_without_a_workload() {
+        let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+        let digest = source.push_image("ci/hotfix", "1.0.1", &[b"layer"]);
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::Lookup(objects(&source, &destination, &[]), false));
+        let image = format!("{}/ci/hotfix:1.0.1", source.host).parse().unwrap();
+        let reports = replicate_image(&testctx, &ContainerReplicator::test(), &image)
+            .await
+            .unwrap();
+        timeout_after_1s(mocksrv).await;
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].1.digest, digest);
+        let jobs = testctx.metrics.replication.jobs.get_or_create(&OutcomeLabels {
+            outcome: "copied".into(),
+        });
+        assert_eq!(jobs.get(), 1);
+        assert!(
+            destination
+                .store()
+                .manifests
+                .contains_key(&("prod/hotfix".into(), "1.0.1".into()))
+        );
+
+        let (testctx, fakeserver) = Context::test();
+        let mocksrv = fakeserver.run(Scenario::Lookup(objects(&source, &destination, &[]), false));
+        let elsewhere = "docker.io/library/nginx:1".parse().unwrap();
+        assert!(
+            replicate_image(&testctx, &ContainerReplicator::test(), &elsewhere)
+                .await
+                .is_err()
+        );
+        timeout_after_1s(mocksrv).await;
+    }
+}
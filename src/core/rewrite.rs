@@ -0,0 +1,282 @@
+//! How source image names map onto destination names when they differ, e.g. `ci/my-team/app:sha-abc`
+//! promoted as `prod/app:1.2.3`.
+//!
+//! The image path below the source repository is rewritten by the `rewrite` rules of a
+//! `ContainerReplicator` in a fixed order: `stripPrefix`, the first matching `repositories`
+//! mapping, then `addPrefix`. A `tag` template replaces the destination tag with values read from
+//! the source image. Every rule that changed a name is recorded, so the status can show why an image
+//! ended up where it did.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::RewriteRules,
+    registry::{RegistryClient, manifest::Manifest},
+    replication::ImageLocation,
+};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Annotation (or label) holding the Git commit an image was built from
+pub static REVISION: &str = "org.opencontainers.image.revision";
+
+/// `path` after the path rules, and the rules that changed it
+///
+/// # Errors
+///
+/// When a repository pattern is not a valid regular expression
+pub fn map_path(rules: &RewriteRules, path: &str) -> Result<(String, Vec<String>)> {
+    let mut path = path.to_string();
+    let mut applied = vec![];
+    if let Some(prefix) = &rules.strip_prefix {
+        let stripped = path
+            .strip_prefix(prefix.trim_end_matches('/'))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .filter(|rest| !rest.is_empty());
+        if let Some(rest) = stripped {
+            path = rest.to_string();
+            applied.push(format!("stripPrefix {prefix}"));
+        }
+    }
+    for mapping in &rules.repositories {
+        let pattern = Regex::new(&mapping.pattern).map_err(|e| {
+            ErrorWrapper::from_custom(&format!("invalid repository pattern `{}`: {e}", mapping.pattern))
+        })?;
+        if pattern.is_match(&path) {
+            path = pattern.replace(&path, mapping.replacement.as_str()).into_owned();
+            applied.push(format!(
+                "repository {} -> {}",
+                mapping.pattern, mapping.replacement
+            ));
+            break;
+        }
+    }
+    if let Some(prefix) = &rules.add_prefix {
+        path = format!("{}/{path}", prefix.trim_end_matches('/'));
+        applied.push(format!("addPrefix {prefix}"));
+    }
+    Ok((path, applied))
+}
+
+/// What a tag template can refer to
+#[derive(Debug, Default)]
+pub struct TagValues<'a> {
+    /// The source tag, if the image was selected by tag
+    pub tag: Option<&'a str>,
+    pub annotations: BTreeMap<String, String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl TagValues<'_> {
+    fn revision(&self) -> Option<&str> {
+        self.annotations
+            .get(REVISION)
+            .or_else(|| self.labels.get(REVISION))
+            .map(String::as_str)
+    }
+
+    fn lookup(&self, placeholder: &str) -> Option<&str> {
+        match placeholder.split_once(':') {
+            Some(("label", key)) => self.labels.get(key).map(String::as_str),
+            Some(("annotation", key)) => self.annotations.get(key).map(String::as_str),
+            _ => match placeholder {
+                "tag" => self.tag,
+                "revision" => self.revision(),
+                "shortRevision" => self.revision().map(|r| &r[..r.len().min(7)]),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Fill in the `{placeholder}`s of `template`: `{tag}`, `{revision}`, `{shortRevision}`,
+/// `{label:<key>}` and `{annotation:<key>}`
+///
+/// # Errors
+///
+/// When a placeholder is unclosed or has no value, or the rendered tag is not a valid tag
+pub fn expand(template: &str, values: &TagValues<'_>) -> Result<String> {
+    let mut tag = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| ErrorWrapper::from_custom(&format!("unclosed placeholder in `{template}`")))?;
+        let placeholder = &rest[start + 1..start + end];
+        let value = values.lookup(placeholder).ok_or_else(|| {
+            ErrorWrapper::from_custom(&format!(
+                "no value for {{{placeholder}}} in tag template `{template}`"
+            ))
+        })?;
+        tag.push_str(&rest[..start]);
+        tag.push_str(value);
+        rest = &rest[start + end + 1..];
+    }
+    tag.push_str(rest);
+    let valid = tag.len() <= 128
+        && tag.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c));
+    if !valid {
+        return Err(ErrorWrapper::from_custom(&format!(
+            "tag template `{template}` rendered the invalid tag `{tag}`"
+        )));
+    }
+    Ok(tag)
+}
+
+/// Render `template` for the source image, reading annotations from its manifest and labels from its
+/// config; an index contributes its own annotations and the labels of its first image
+///
+/// # Errors
+///
+/// When the source image can not be read, or the template can not be expanded for it
+pub async fn render_tag(
+    client: &RegistryClient,
+    image: &ImageLocation,
+    tag: Option<&str>,
+    template: &str,
+) -> Result<String> {
+    #[derive(Deserialize, Default)]
+    struct Config {
+        #[serde(default)]
+        config: ContainerConfig,
+    }
+    #[derive(Deserialize, Default)]
+    struct ContainerConfig {
+        #[serde(rename = "Labels", default)]
+        labels: Option<BTreeMap<String, String>>,
+    }
+
+    let mut manifest = Manifest::parse(&client.manifest(&image.repository, &image.reference).await?.bytes)?;
+    let mut annotations = manifest.annotations.clone();
+    if manifest.is_index() {
+        let child = &manifest.manifests[0].digest;
+        manifest = Manifest::parse(&client.manifest(&image.repository, child).await?.bytes)?;
+        for (key, value) in &manifest.annotations {
+            annotations.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    let labels = match &manifest.config {
+        Some(config) => {
+            let bytes = client.blob_bytes(&image.repository, &config.digest).await?;
+            let config: Config = serde_json::from_slice(&bytes).unwrap_or_default();
+            config.config.labels.unwrap_or_default()
+        }
+        None => BTreeMap::new(),
+    };
+    expand(template, &TagValues {
+        tag,
+        annotations,
+        labels,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{REVISION, TagValues, expand, map_path, render_tag};
+    use crate::core::{
+        crd::{RepositoryMapping, RewriteRules},
+        registry::{RegistryClient, fake::FakeRegistry, limits::HostLimiter, manifest::media_types},
+        replication::ImageLocation,
+        settings::RateLimits,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn path_rules_apply_in_order_and_are_recorded() {
+        let rules = RewriteRules {
+            strip_prefix: Some("my-team/".into()),
+            repositories: vec![
+                RepositoryMapping {
+                    pattern: "^(?P<name>.+)-service$".into(),
+                    replacement: "services/$name".into(),
+                },
+                RepositoryMapping {
+                    pattern: ".*".into(),
+                    replacement: "never".into(),
+                },
+            ],
+            add_prefix: Some("apps/".into()),
+            tag: None,
+        };
+        let (path, applied) = map_path(&rules, "my-team/billing-service").unwrap();
+        assert_eq!(path, "apps/services/billing");
+        assert_eq!(applied, [
+            "stripPrefix my-team/",
+            "repository ^(?P<name>.+)-service$ -> services/$name",
+            "addPrefix apps/",
+        ]);
+
+        let untouched = RewriteRules::default();
+        assert_eq!(
+            map_path(&untouched, "other/app").unwrap(),
+            ("other/app".into(), vec![])
+        );
+    }
+
+    #[test]
+    fn tag_templates_fill_in_image_values() {
+        let values = TagValues {
+            tag: Some("sha-abc"),
+            annotations: [(REVISION.to_string(), "0123456789abcdef".to_string())].into(),
+            labels: [(
+                "org.opencontainers.image.version".to_string(),
+                "1.2.3".to_string(),
+            )]
+            .into(),
+        };
+        assert_eq!(
+            expand("{label:org.opencontainers.image.version}", &values).unwrap(),
+            "1.2.3"
+        );
+        assert_eq!(expand("git-{shortRevision}", &values).unwrap(), "git-0123456");
+        assert_eq!(expand("{tag}-promoted", &values).unwrap(), "sha-abc-promoted");
+        assert!(expand("{label:missing}", &values).is_err());
+        assert!(expand("{tag", &values).is_err());
+        assert!(expand("v/{tag}", &values).is_err());
+    }
+
+    #[tokio::test]
+    async fn renders_tags_from_the_image_config() {
+        let registry = FakeRegistry::start().await;
+        let config = registry.push_blob(
+            "ci/app",
+            "application/vnd.oci.image.config.v1+json",
+            br#"{"config":{"Labels":{"org.opencontainers.image.version":"1.2.3"}}}"#,
+        );
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": media_types::OCI_MANIFEST,
+            "config": config,
+            "layers": [],
+            "annotations": { REVISION: "fedcba9876543210" },
+        });
+        registry.push_manifest(
+            "ci/app",
+            "sha-fedcba9",
+            media_types::OCI_MANIFEST,
+            manifest.to_string().as_bytes(),
+        );
+        let client = RegistryClient::new(
+            reqwest::Client::new(),
+            registry.endpoint(),
+            Arc::new(HostLimiter::new(&RateLimits::default())),
+        );
+        let image = ImageLocation {
+            endpoint: registry.endpoint(),
+            repository: "ci/app".into(),
+            reference: "sha-fedcba9".into(),
+        };
+        let tag = render_tag(
+            &client,
+            &image,
+            Some("sha-fedcba9"),
+            "{label:org.opencontainers.image.version}-{shortRevision}",
+        )
+        .await
+        .unwrap();
+        assert_eq!(tag, "1.2.3-fedcba9");
+    }
+}
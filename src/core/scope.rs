@@ -0,0 +1,131 @@
+//! Which namespaces the controller watches
+use crate::core::settings::Settings;
+use k8s_openapi::NamespaceResourceScope;
+use kube::{Api, Client, Resource};
+use std::{future::Future, sync::Arc};
+use tokio::sync::Semaphore;
+
+/// Cluster-wide watches need a `ClusterRole`, namespaced ones only a `Role` per namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchScope {
+    Cluster,
+    Namespaces(Vec<String>),
+}
+
+impl WatchScope {
+    #[must_use]
+    pub fn from_settings(settings: &Settings) -> Self {
+        if settings.namespaces.is_empty() {
+            Self::Cluster
+        } else {
+            let mut namespaces = settings.namespaces.clone();
+            namespaces.sort();
+            namespaces.dedup();
+            Self::Namespaces(namespaces)
+        }
+    }
+
+    /// One `Api` per watched namespace, or a single cluster-wide `Api`
+    #[must_use]
+    pub fn apis<K>(&self, client: &Client) -> Vec<Api<K>>
+    where
+        K: Resource<Scope = NamespaceResourceScope>,
+        <K as Resource>::DynamicType: Default,
+    {
+        match self {
+            Self::Cluster => vec![Api::all(client.clone())],
+            Self::Namespaces(namespaces) => namespaces
+                .iter()
+                .map(|ns| Api::namespaced(client.clone(), ns))
+                .collect(),
+        }
+    }
+
+    /// Whether objects in `namespace` are visible to the controller
+    #[must_use]
+    pub fn contains(&self, namespace: &str) -> bool {
+        match self {
+            Self::Cluster => true,
+            Self::Namespaces(namespaces) => namespaces.iter().any(|ns| ns == namespace),
+        }
+    }
+}
+
+/// The `concurrency` limit shared by the controllers of every watched namespace, so it bounds the
+/// reconciles of a resource kind rather than those of each namespace
+#[derive(Clone, Debug, Default)]
+pub struct Concurrency(Option<Arc<Semaphore>>);
+
+impl Concurrency {
+    /// At most `limit` reconciles at the same time, `0` means unbounded
+    #[must_use]
+    pub fn new(limit: u16) -> Self {
+        Self((limit > 0).then(|| Arc::new(Semaphore::new(limit.into()))))
+    }
+
+    /// Run `reconcile` once fewer than `limit` others are running
+    pub async fn run<F: Future>(&self, reconcile: F) -> F::Output {
+        let _permit = match &self.0 {
+            // the semaphore is never closed
+            Some(semaphore) => semaphore.acquire().await.ok(),
+            None => None,
+        };
+        reconcile.await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Concurrency, WatchScope};
+    use crate::core::settings::Settings;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn empty_namespaces_watch_the_cluster() {
+        let scope = WatchScope::from_settings(&Settings::default());
+        assert_eq!(scope, WatchScope::Cluster);
+        assert!(scope.contains("anything"));
+    }
+
+    #[test]
+    fn namespaces_are_deduplicated() {
+        let settings = Settings {
+            namespaces: vec!["team-b".into(), "team-a".into(), "team-b".into()],
+            ..Settings::default()
+        };
+        let scope = WatchScope::from_settings(&settings);
+        assert_eq!(
+            scope,
+            WatchScope::Namespaces(vec!["team-a".into(), "team-b".into()])
+        );
+        assert!(scope.contains("team-a"));
+        assert!(!scope.contains("team-c"));
+    }
+
+    #[tokio::test]
+    async fn concurrency_is_shared_by_every_clone() {
+        let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let reconcile = |limit: Concurrency| {
+            let (running, peak) = (&running, &peak);
+            async move {
+                limit
+                    .run(async {
+                        peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await;
+            }
+        };
+        let limit = Concurrency::new(2);
+        futures::future::join_all((0..6).map(|_| reconcile(limit.clone()))).await;
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        peak.store(0, Ordering::SeqCst);
+        futures::future::join_all((0..6).map(|_| reconcile(Concurrency::new(0)))).await;
+        assert_eq!(peak.load(Ordering::SeqCst), 6);
+    }
+}
@@ -0,0 +1,293 @@
+//! Controller runtime settings, read from the `settings.yair` section of the loco config
+use crate::core::{ErrorWrapper, Result, crd::RepositoryRef};
+use loco_rs::{config::Config, environment::Environment};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Environment variable selecting which `config/<env>.yaml` to load
+pub static ENVIRONMENT_VAR: &str = "ENVIRONMENT";
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct Settings {
+    /// Maximum number of objects reconciled at the same time, `0` means unbounded
+    pub concurrency: u16,
+    pub requeue: RequeueSettings,
+    /// Namespaces to watch, an empty list watches the whole cluster
+    pub namespaces: Vec<String>,
+    /// Name the controller publishes events under
+    pub reporter: String,
+    pub registry: RegistrySettings,
+    pub replication: ReplicationSettings,
+    pub mirror: MirrorSettings,
+    pub shutdown: ShutdownSettings,
+    pub verification: VerificationSettings,
+    pub webhook: WebhookSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            concurrency: 0,
+            requeue: RequeueSettings::default(),
+            namespaces: vec![],
+            reporter: "yair-controller".into(),
+            registry: RegistrySettings::default(),
+            replication: ReplicationSettings::default(),
+            mirror: MirrorSettings::default(),
+            shutdown: ShutdownSettings::default(),
+            verification: VerificationSettings::default(),
+            webhook: WebhookSettings::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RequeueSettings {
+    /// Delay before re-checking an object that reconciled successfully
+    pub interval_secs: u64,
+    /// Delay before retrying an object whose reconcile failed
+    pub error_interval_secs: u64,
+}
+
+impl Default for RequeueSettings {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5 * 60,
+            error_interval_secs: 5 * 60,
+        }
+    }
+}
+
+impl RequeueSettings {
+    #[must_use]
+    pub const fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    #[must_use]
+    pub const fn error_interval(&self) -> Duration {
+        Duration::from_secs(self.error_interval_secs)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RegistrySettings {
+    /// Timeout for establishing a connection to a registry
+    pub connect_timeout_secs: u64,
+    /// Timeout for a single registry request, including blob transfers
+    pub request_timeout_secs: u64,
+    /// Limits applied to every registry host without an entry in `hosts`
+    pub limits: RateLimits,
+    /// Per-host overrides of `limits`, keyed by registry host (including the port if any)
+    pub hosts: BTreeMap<String, RateLimits>,
+}
+
+impl Default for RegistrySettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: 10,
+            request_timeout_secs: 10 * 60,
+            limits: RateLimits::default(),
+            hosts: BTreeMap::new(),
+        }
+    }
+}
+
+impl RegistrySettings {
+    #[must_use]
+    pub const fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+
+    #[must_use]
+    pub const fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+}
+
+/// Token bucket limits for one registry host, `0` disables a limit
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Sustained requests per second
+    pub requests_per_second: u32,
+    /// Requests that may be sent at once before `requests_per_second` kicks in
+    pub burst: u32,
+    /// Sustained blob transfer rate, shared by uploads and downloads
+    pub bytes_per_second: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ReplicationSettings {
+    /// Image copies running at the same time across all replicators, `0` means unbounded
+    pub max_concurrent_jobs: usize,
+    /// Blobs larger than this are uploaded in resumable chunks of this size, `0` always uploads in
+    /// one request
+    pub chunk_size_bytes: u64,
+    /// Directory keeping the ledger of upload checkpoints across restarts, kept in memory if unset
+    pub ledger_dir: Option<PathBuf>,
+    /// Directory caching source blobs, so an image copied to several destinations is pulled once;
+    /// every blob is pulled per destination if unset
+    pub blob_cache_dir: Option<PathBuf>,
+    /// Bytes the blob cache may hold before the least recently used blobs are evicted
+    pub blob_cache_size_bytes: u64,
+    /// Only plan replication for every replicator, as if they all set `dryRun`
+    pub dry_run: bool,
+}
+
+impl Default for ReplicationSettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent_jobs: 4,
+            chunk_size_bytes: 16 * 1024 * 1024,
+            ledger_dir: None,
+            blob_cache_dir: None,
+            blob_cache_size_bytes: 10 * 1024 * 1024 * 1024,
+            dry_run: false,
+        }
+    }
+}
+
+/// Pull-through mirroring for pods that fail to pull images of a source repository
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MirrorSettings {
+    /// Watch pods in `ErrImagePull` or `ImagePullBackOff`
+    pub enabled: bool,
+    /// The cluster-local `DestinationRepository` images are mirrored to, in the pod's namespace
+    /// unless one is given
+    pub destination: Option<RepositoryRef>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// Seconds in-flight copies get to finish after SIGTERM, keep below the pod's
+    /// `terminationGracePeriodSeconds`
+    pub grace_period_secs: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            grace_period_secs: 25,
+        }
+    }
+}
+
+impl ShutdownSettings {
+    #[must_use]
+    pub const fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+/// Trust material for keyless signature verification, which happens offline against these files
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct VerificationSettings {
+    /// PEM files with the Fulcio root (and intermediate) certificates signing certificates chain to
+    pub fulcio_roots: Vec<PathBuf>,
+    /// PEM files with the Rekor public keys whose signed entry timestamps prove when a signature was made
+    pub rekor_keys: Vec<PathBuf>,
+}
+
+/// The TLS listener of the CRD conversion webhook, which the apiserver only calls over https
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct WebhookSettings {
+    /// Port the webhook listens on, next to `server.port`
+    pub port: u16,
+    /// PEM file with the serving certificate chain, the webhook is not served without one
+    pub cert_path: Option<PathBuf>,
+    /// PEM file with the private key of the certificate
+    pub key_path: Option<PathBuf>,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> Self {
+        Self {
+            port: 8443,
+            cert_path: None,
+            key_path: None,
+        }
+    }
+}
+
+impl WebhookSettings {
+    /// The certificate and key files, when both are set
+    #[must_use]
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        self.cert_path.as_deref().zip(self.key_path.as_deref())
+    }
+}
+
+impl Settings {
+    /// Read `settings.yair` from a loaded loco config, falling back to defaults when absent
+    ///
+    /// # Errors
+    ///
+    /// When `settings.yair` does not match the settings schema
+    pub fn from_config(config: &Config) -> Result<Self> {
+        config.settings.as_ref().and_then(|s| s.get("yair")).map_or_else(
+            || Ok(Self::default()),
+            |yair| serde_json::from_value(yair.clone()).map_err(ErrorWrapper::from_serde),
+        )
+    }
+}
+
+/// The environment named by `ENVIRONMENT`, or loco's own `LOCO_ENV` resolution otherwise
+#[must_use]
+pub fn environment() -> Environment {
+    std::env::var(ENVIRONMENT_VAR)
+        .unwrap_or_else(|_| loco_rs::environment::resolve_from_env())
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Settings;
+    use loco_rs::{config::Config, environment::Environment};
+    use serde_json::json;
+
+    fn config_with(settings: serde_json::Value) -> Config {
+        let mut config = Environment::Test.load().expect("test config");
+        config.settings = Some(settings);
+        config
+    }
+
+    #[test]
+    fn missing_section_uses_defaults() {
+        let config = config_with(json!({ "other": true }));
+        assert_eq!(Settings::from_config(&config).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn partial_section_is_merged_with_defaults() {
+        let config = config_with(json!({ "yair": {
+            "concurrency": 3,
+            "namespaces": ["team-a"],
+            "requeue": { "interval_secs": 30 }
+        }}));
+        let settings = Settings::from_config(&config).unwrap();
+        assert_eq!(settings.concurrency, 3);
+        assert_eq!(settings.namespaces, vec!["team-a".to_string()]);
+        assert_eq!(settings.requeue.interval_secs, 30);
+        assert_eq!(settings.requeue.error_interval_secs, 300);
+        assert_eq!(settings.reporter, "yair-controller");
+    }
+
+    #[test]
+    fn malformed_section_is_an_error() {
+        let config = config_with(json!({ "yair": { "concurrency": "lots" } }));
+        assert!(Settings::from_config(&config).is_err());
+    }
+}
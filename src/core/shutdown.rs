@@ -0,0 +1,157 @@
+//! Coordinated shutdown of the controller and the web server.
+//!
+//! On SIGTERM the controller stops taking new work and reports itself not ready, then waits up to the
+//! configured grace period for in-flight copies to finish before the process exits.
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub struct Shutdown {
+    stopping: watch::Sender<bool>,
+    finished: watch::Sender<bool>,
+    in_flight: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: watch::Sender::new(false),
+            finished: watch::Sender::new(false),
+            in_flight: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+/// Marks a unit of work as in flight until dropped
+pub struct InFlight(Arc<watch::Sender<usize>>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+impl Shutdown {
+    /// Stop taking new work
+    pub fn trigger(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    #[must_use]
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    /// Whether the controller takes new work, i.e. the readiness of the pod
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        !self.is_stopping()
+    }
+
+    /// Resolves once shutdown was triggered
+    pub fn stopping(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.stopping.subscribe();
+        async move {
+            let _ = rx.wait_for(|stopping| *stopping).await;
+        }
+    }
+
+    /// Resolves once in-flight work drained (or the grace period ran out) and the process may exit
+    pub fn finished(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut rx = self.finished.subscribe();
+        async move {
+            let _ = rx.wait_for(|finished| *finished).await;
+        }
+    }
+
+    /// Register a unit of work, unless shutdown was already triggered
+    #[must_use]
+    pub fn track(&self) -> Option<InFlight> {
+        if self.is_stopping() {
+            return None;
+        }
+        self.in_flight.send_modify(|n| *n += 1);
+        Some(InFlight(self.in_flight.clone()))
+    }
+
+    /// Work currently in flight
+    #[must_use]
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.borrow()
+    }
+
+    /// Wait up to `grace` for in-flight work to finish, returning whether everything finished
+    pub async fn drain(&self, grace: Duration) -> bool {
+        let mut rx = self.in_flight.subscribe();
+        tokio::time::timeout(grace, rx.wait_for(|n| *n == 0))
+            .await
+            .is_ok()
+    }
+
+    /// Trigger, drain and finish
+    pub async fn shutdown(&self, grace: Duration) {
+        self.trigger();
+        info!(
+            in_flight = self.in_flight(),
+            ?grace,
+            "Shutting down, draining in-flight copies"
+        );
+        if self.drain(grace).await {
+            info!("In-flight copies drained");
+        } else {
+            warn!(
+                in_flight = self.in_flight(),
+                "Grace period ran out, abandoning in-flight copies"
+            );
+        }
+        self.finished.send_replace(true);
+    }
+
+    /// Shut down on SIGTERM or Ctrl+C
+    pub async fn on_signal(self, grace: Duration) {
+        loco_rs::boot::shutdown_signal().await;
+        self.shutdown(grace).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shutdown;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn no_new_work_is_taken_once_stopping() {
+        let shutdown = Shutdown::default();
+        let work = shutdown.track().expect("running");
+        assert_eq!(shutdown.in_flight(), 1);
+        shutdown.trigger();
+        assert!(!shutdown.is_ready());
+        assert!(shutdown.track().is_none());
+        drop(work);
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finishes_after_in_flight_work_drained() {
+        let shutdown = Shutdown::default();
+        let work = shutdown.track().unwrap();
+        let finished = tokio::spawn(shutdown.finished());
+        let draining = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.shutdown(Duration::from_secs(30)).await }
+        });
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(!finished.is_finished());
+        drop(work);
+        draining.await.unwrap();
+        finished.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_grace_period() {
+        let shutdown = Shutdown::default();
+        let _stuck = shutdown.track().unwrap();
+        assert!(!shutdown.drain(Duration::from_secs(30)).await);
+    }
+}
@@ -0,0 +1,282 @@
+//! Which tags of a source image a `TagSelector` promotes.
+//!
+//! Tags are optionally filtered by a regular expression, whose capture groups can extract the part
+//! of the tag the policy orders by (e.g. the timestamp of `main-3f2a1c-1700000000`). The policy then
+//! drops values it can not order and sorts the rest newest first. Artifact tags (`sha256-…`) of
+//! signatures and referrers are never selected.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{SortOrder, TagPolicy, TagSelector},
+    promotion::SelectedImage,
+    registry::{Reference, RegistryClient, RepositoryLocation},
+};
+use regex::Regex;
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+use tracing::debug;
+
+/// The tags of `tags` that `selector` picks, newest first
+///
+/// # Errors
+///
+/// When the filter pattern or the semver range of `selector` is invalid
+pub fn select(tags: &[String], selector: &TagSelector) -> Result<Vec<String>> {
+    let filter = match &selector.filter_tags {
+        Some(filter) => Some((
+            Regex::new(&filter.pattern).map_err(|e| invalid(selector, &e.to_string()))?,
+            filter.extract.as_deref(),
+        )),
+        None => None,
+    };
+    let candidates = tags
+        .iter()
+        .filter(|tag| !tag.starts_with("sha256-"))
+        .filter_map(|tag| {
+            let Some((pattern, extract)) = &filter else {
+                return Some((tag, tag.clone()));
+            };
+            let captures = pattern.captures(tag)?;
+            let value = extract.map_or_else(
+                || tag.clone(),
+                |template| {
+                    let mut value = String::new();
+                    captures.expand(template, &mut value);
+                    value
+                },
+            );
+            Some((tag, value))
+        });
+
+    let mut ordered: Vec<(&String, Key)> = match &selector.policy {
+        TagPolicy::Semver { range } => {
+            let ranges = parse_range(range).map_err(|e| invalid(selector, &e))?;
+            candidates
+                .filter_map(|(tag, value)| Some((tag, version(&value)?)))
+                .filter(|(_, version)| ranges.iter().any(|range| range.matches(version)))
+                .map(|(tag, version)| (tag, Key::Version(version)))
+                .collect()
+        }
+        TagPolicy::Alphabetical { order } => candidates
+            .map(|(tag, value)| (tag, Key::Text(value, *order)))
+            .collect(),
+        TagPolicy::Numerical { order } => candidates
+            .filter_map(|(tag, value)| Some((tag, Key::Number(value.parse().ok()?, *order))))
+            .collect(),
+    };
+    // newest first, ties broken by tag so the result does not depend on the registry's order
+    ordered.sort_by(|(a, key_a), (b, key_b)| key_b.cmp(key_a).then_with(|| a.cmp(b)));
+    let latest = selector.latest.map_or(usize::MAX, |n| n as usize);
+    Ok(ordered
+        .into_iter()
+        .take(latest)
+        .map(|(tag, _)| tag.clone())
+        .collect())
+}
+
+/// List the tags of every selected image in `source` and pick those the selectors ask for
+///
+/// # Errors
+///
+/// When the tags of an image can not be listed, or a selector is invalid
+pub async fn resolve(
+    client: &RegistryClient,
+    source: &RepositoryLocation,
+    selectors: &[TagSelector],
+) -> Result<Vec<SelectedImage>> {
+    let mut selected = vec![];
+    for selector in selectors {
+        let repository = source.repository(&selector.image);
+        let tags = client.tags(&repository).await?;
+        let picked = select(&tags, selector)?;
+        debug!(%repository, tags = tags.len(), selected = picked.len(), "Selected tags");
+        selected.extend(picked.into_iter().map(|tag| SelectedImage {
+            workload: format!("tags/{}", selector.image),
+            image: Reference {
+                registry: source.endpoint.host.clone(),
+                repository: repository.clone(),
+                tag: Some(tag),
+                digest: None,
+            },
+        }));
+    }
+    Ok(selected)
+}
+
+/// What a policy orders by; greater is newer
+#[derive(PartialEq)]
+enum Key {
+    Version(Version),
+    Text(String, SortOrder),
+    Number(f64, SortOrder),
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (ordering, order) = match (self, other) {
+            (Self::Version(a), Self::Version(b)) => (a.cmp(b), SortOrder::Asc),
+            (Self::Text(a, order), Self::Text(b, _)) => (a.cmp(b), *order),
+            (Self::Number(a, order), Self::Number(b, _)) => (a.total_cmp(b), *order),
+            // a selector only ever produces one kind of key
+            _ => (Ordering::Equal, SortOrder::Asc),
+        };
+        match order {
+            SortOrder::Asc => ordering,
+            SortOrder::Desc => ordering.reverse(),
+        }
+    }
+}
+
+/// A range in the space separated syntax of Flux and Helm, e.g. `>=1.4 <2 || ^3`
+fn parse_range(range: &str) -> std::result::Result<Vec<VersionReq>, String> {
+    range
+        .split("||")
+        .map(|alternative| {
+            let mut comparators: Vec<String> = vec![];
+            for token in alternative.split([' ', ',']).filter(|t| !t.is_empty()) {
+                match comparators.last_mut() {
+                    // `>= 1.4` is one comparator
+                    Some(last) if last.chars().all(|c| "<>=~^".contains(c)) => last.push_str(token),
+                    _ => comparators.push(token.to_string()),
+                }
+            }
+            VersionReq::parse(&comparators.join(", ")).map_err(|e| format!("range `{range}`: {e}"))
+        })
+        .collect()
+}
+
+/// A tag as a semantic version, accepting a `v` prefix and missing minor or patch numbers
+fn version(tag: &str) -> Option<Version> {
+    let tag = tag.strip_prefix('v').unwrap_or(tag);
+    Version::parse(tag).ok().or_else(|| {
+        let (core, rest) = tag.split_at(tag.find(['-', '+']).unwrap_or(tag.len()));
+        let parts = core.split('.').count();
+        if parts >= 3 {
+            return None;
+        }
+        Version::parse(&format!("{core}{}{rest}", ".0".repeat(3 - parts))).ok()
+    })
+}
+
+fn invalid(selector: &TagSelector, message: &str) -> loco_rs::Error {
+    ErrorWrapper::from_custom(&format!("invalid tag selector for {}: {message}", selector.image))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{resolve, select};
+    use crate::core::{
+        crd::{RepositoryProvider, RepositorySpec, SortOrder, TagFilter, TagPolicy, TagSelector},
+        registry::{RegistryClient, RepositoryLocation, fake::FakeRegistry, limits::HostLimiter},
+        settings::RateLimits,
+    };
+    use std::sync::Arc;
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(ToString::to_string).collect()
+    }
+
+    fn selector(policy: TagPolicy) -> TagSelector {
+        TagSelector {
+            image: "app".into(),
+            filter_tags: None,
+            policy,
+            latest: None,
+        }
+    }
+
+    fn semver(range: &str) -> TagSelector {
+        selector(TagPolicy::Semver { range: range.into() })
+    }
+
+    #[test]
+    fn semver_ranges_pick_versions_highest_first() {
+        let available = tags(&[
+            "1.3.9",
+            "v1.4.0",
+            "1.4.2",
+            "1.10",
+            "2.0.0",
+            "1.5.0-rc.1",
+            "latest",
+            "sha256-abc.sig",
+        ]);
+        let picked = select(&available, &semver(">=1.4 <2")).unwrap();
+        assert_eq!(picked, ["1.10", "1.4.2", "v1.4.0"]);
+        let picked = select(&available, &semver(">= 1.4, < 1.5 || ^2")).unwrap();
+        assert_eq!(picked, ["2.0.0", "1.4.2", "v1.4.0"]);
+        assert!(select(&available, &semver("not a range")).is_err());
+    }
+
+    #[test]
+    fn extracted_values_are_ordered_and_cut_to_the_latest() {
+        let available = tags(&[
+            "main-3f2a1c-1700000300",
+            "main-9b1d2e-1700000100",
+            "main-77aa00-1700000200",
+            "pr-12-1800000000",
+            "main-dirty",
+        ]);
+        let by_timestamp = TagSelector {
+            filter_tags: Some(TagFilter {
+                pattern: r"^main-[a-f0-9]+-(?P<ts>\d+)$".into(),
+                extract: Some("$ts".into()),
+            }),
+            latest: Some(2),
+            ..selector(TagPolicy::Numerical {
+                order: SortOrder::Asc,
+            })
+        };
+        assert_eq!(select(&available, &by_timestamp).unwrap(), [
+            "main-3f2a1c-1700000300",
+            "main-77aa00-1700000200",
+        ]);
+
+        let oldest_first = TagSelector {
+            latest: Some(1),
+            ..selector(TagPolicy::Alphabetical {
+                order: SortOrder::Desc,
+            })
+        };
+        assert_eq!(select(&available, &oldest_first).unwrap(), [
+            "main-3f2a1c-1700000300"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn resolves_selected_tags_in_the_source_repository() {
+        let registry = FakeRegistry::start().await;
+        for tag in ["1.0.0", "1.1.0", "2.0.0"] {
+            registry.push_image("ci/team/app", tag, &[tag.as_bytes()]);
+        }
+        let client = RegistryClient::new(
+            reqwest::Client::new(),
+            registry.endpoint(),
+            Arc::new(HostLimiter::new(&RateLimits::default())),
+        );
+        let source = RepositoryLocation::from(&RepositorySpec {
+            provider: RepositoryProvider::Generic,
+            name: "ci".into(),
+            registry: Some(registry.host.clone()),
+            insecure: true,
+            ..RepositorySpec::default()
+        });
+        let selector = TagSelector {
+            image: "team/app".into(),
+            ..semver("^1")
+        };
+        let images = resolve(&client, &source, &[selector]).await.unwrap();
+        let images: Vec<_> = images.iter().map(|s| s.image.to_string()).collect();
+        assert_eq!(images, [
+            format!("{}/ci/team/app:1.1.0", registry.host),
+            format!("{}/ci/team/app:1.0.0", registry.host),
+        ]);
+    }
+}
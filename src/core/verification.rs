@@ -0,0 +1,784 @@
+//! Offline cosign signature verification, the gate an image passes before it is promoted.
+//!
+//! The `sha256-<hex>.sig` manifest cosign pushes next to an image holds one layer per signature: a
+//! simple signing payload naming the image digest, with the signature (and for keyless signing the
+//! Fulcio certificate and Rekor bundle) in its annotations. A signature is accepted when it verifies
+//! against one of the public keys of the `ContainerReplicator`, or when its certificate chains to a
+//! configured Fulcio root, names an accepted identity and issuer, and was logged in Rekor while the
+//! certificate was valid. Every certificate of the chain has to be valid when the signature was
+//! logged, every issuer has to be a CA allowed to sign certificates, and the signing certificate has
+//! to be meant for code signing. Nothing is fetched from Fulcio or Rekor themselves.
+use crate::core::{
+    ErrorWrapper, Result,
+    crd::{KeylessIdentity, Verification},
+    registry::{
+        RegistryClient,
+        manifest::{Manifest, referrers_tag},
+    },
+    replication::ImageLocation,
+    settings::VerificationSettings,
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use p256::pkcs8::DecodePublicKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256, Sha384};
+use std::{collections::BTreeMap, fmt, path::Path};
+use tracing::{debug, info};
+use x509_cert::{
+    Certificate,
+    der::{
+        Decode, DecodePem, Encode,
+        asn1::Utf8StringRef,
+        oid::{AssociatedOid, ObjectIdentifier},
+    },
+    ext::pkix::{BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAltName, name::GeneralName},
+};
+
+pub static SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+
+/// Annotations of a signature layer
+pub mod annotations {
+    pub static SIGNATURE: &str = "dev.cosignproject.cosign/signature";
+    pub static CERTIFICATE: &str = "dev.sigstore.cosign/certificate";
+    pub static CHAIN: &str = "dev.sigstore.cosign/chain";
+    pub static BUNDLE: &str = "dev.sigstore.cosign/bundle";
+}
+
+static SUBJECT_ALT_NAME: ObjectIdentifier = ObjectIdentifier::new_unwrap("2.5.29.17");
+/// Extended key usage of certificates for signing code
+static CODE_SIGNING: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.5.5.7.3.3");
+static ECDSA_WITH_SHA256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.2");
+static ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+/// Fulcio's OIDC issuer extension, as a raw string
+static FULCIO_ISSUER_V1: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.1");
+/// Fulcio's OIDC issuer extension, as a DER `UTF8String`
+static FULCIO_ISSUER_V2: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.57264.1.8");
+/// Longest certificate chain followed from a signing certificate to a root
+const MAX_CHAIN: usize = 5;
+
+/// An image without a signature the `ContainerReplicator` accepts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerificationFailed {
+    pub image: String,
+    /// Why each signature found was rejected
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for VerificationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no accepted signature for {}: {}",
+            self.image,
+            self.reasons.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for VerificationFailed {}
+
+impl VerificationFailed {
+    /// The rejection behind `error`, if that is why it failed
+    #[must_use]
+    pub fn find(error: &loco_rs::Error) -> Option<&Self> {
+        ErrorWrapper::find(error)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Hash {
+    Sha256,
+    Sha384,
+}
+
+impl Hash {
+    fn digest(self, message: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(message).to_vec(),
+            Self::Sha384 => Sha384::digest(message).to_vec(),
+        }
+    }
+}
+
+/// An ECDSA public key on one of the curves sigstore uses
+#[derive(Clone, Debug)]
+pub enum PublicKey {
+    P256(p256::ecdsa::VerifyingKey),
+    P384(p384::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// A PEM `PUBLIC KEY`, as written by `cosign generate-key-pair`
+    ///
+    /// # Errors
+    ///
+    /// When `pem` is not an ECDSA P-256 or P-384 public key
+    pub fn from_pem(pem: &str) -> std::result::Result<Self, String> {
+        p256::ecdsa::VerifyingKey::from_public_key_pem(pem)
+            .map(Self::P256)
+            .or_else(|_| p384::ecdsa::VerifyingKey::from_public_key_pem(pem).map(Self::P384))
+            .map_err(|_| "not a PEM encoded ECDSA P-256 or P-384 public key".to_string())
+    }
+
+    fn from_certificate(certificate: &Certificate) -> std::result::Result<Self, String> {
+        let der = certificate
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(|e| e.to_string())?;
+        p256::ecdsa::VerifyingKey::from_public_key_der(&der)
+            .map(Self::P256)
+            .or_else(|_| p384::ecdsa::VerifyingKey::from_public_key_der(&der).map(Self::P384))
+            .map_err(|_| "certificate key is not ECDSA P-256 or P-384".to_string())
+    }
+
+    /// Check a DER encoded signature over `message`
+    fn verify(&self, message: &[u8], signature: &[u8], hash: Hash) -> bool {
+        use p256::ecdsa::signature::hazmat::PrehashVerifier;
+        let prehash = hash.digest(message);
+        match self {
+            Self::P256(key) => p256::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify_prehash(&prehash, &signature).is_ok()),
+            Self::P384(key) => p384::ecdsa::Signature::from_der(signature)
+                .is_ok_and(|signature| key.verify_prehash(&prehash, &signature).is_ok()),
+        }
+    }
+}
+
+/// The Fulcio roots and Rekor keys keyless signatures are checked against
+#[derive(Clone, Debug, Default)]
+pub struct TrustRoot {
+    fulcio: Vec<Certificate>,
+    rekor: Vec<PublicKey>,
+}
+
+impl TrustRoot {
+    #[must_use]
+    pub const fn new(fulcio: Vec<Certificate>, rekor: Vec<PublicKey>) -> Self {
+        Self { fulcio, rekor }
+    }
+
+    /// Read the files named in the settings
+    ///
+    /// # Errors
+    ///
+    /// When a file can not be read, or holds something else than certificates or a public key
+    pub fn load(settings: &VerificationSettings) -> Result<Self> {
+        let mut fulcio = vec![];
+        for path in &settings.fulcio_roots {
+            let pem = read(path)?;
+            fulcio.extend(
+                Certificate::load_pem_chain(pem.as_bytes())
+                    .map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", path.display())))?,
+            );
+        }
+        let mut rekor = vec![];
+        for path in &settings.rekor_keys {
+            rekor.push(
+                PublicKey::from_pem(&read(path)?)
+                    .map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", path.display())))?,
+            );
+        }
+        Ok(Self::new(fulcio, rekor))
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", path.display())))
+}
+
+#[derive(Deserialize)]
+struct SimpleSigning {
+    critical: Critical,
+}
+
+#[derive(Deserialize)]
+struct Critical {
+    image: SignedImage,
+}
+
+#[derive(Deserialize)]
+struct SignedImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+/// Proof of inclusion in Rekor, as attached by cosign
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Bundle {
+    signed_entry_timestamp: String,
+    payload: BundlePayload,
+}
+
+/// The fields are in canonical JSON order, Rekor signs their compact serialization
+#[derive(Deserialize, Serialize)]
+struct BundlePayload {
+    body: String,
+    #[serde(rename = "integratedTime")]
+    integrated_time: i64,
+    #[serde(rename = "logID")]
+    log_id: String,
+    #[serde(rename = "logIndex")]
+    log_index: i64,
+}
+
+/// Check that `image` carries a signature accepted by `policy`, returning the verified digest
+///
+/// # Errors
+///
+/// [`VerificationFailed`] if it does not; other errors are registry failures.
+pub async fn verify(
+    client: &RegistryClient,
+    image: &ImageLocation,
+    policy: &Verification,
+    trust: &TrustRoot,
+) -> Result<String> {
+    let repository = &image.repository;
+    let digest = client.resolve(repository, &image.reference).await?;
+    let rejected = |reasons: Vec<String>| {
+        loco_rs::Error::wrap(VerificationFailed {
+            image: image.to_string(),
+            reasons,
+        })
+    };
+    let tag = format!("{}.sig", referrers_tag(&digest));
+    if client.manifest_digest(repository, &tag).await?.is_none() {
+        return Err(rejected(vec!["image is not signed".into()]));
+    }
+    let signatures = Manifest::parse(&client.manifest(repository, &tag).await?.bytes)?;
+    let mut reasons = vec![];
+    for layer in signatures
+        .layers
+        .iter()
+        .filter(|layer| layer.media_type == SIMPLE_SIGNING)
+    {
+        let payload = client.blob_bytes(repository, &layer.digest).await?;
+        match check(&payload, &layer.annotations, &digest, policy, trust) {
+            Ok(signer) => {
+                info!(%image, %digest, signer, "Signature verified");
+                return Ok(digest);
+            }
+            Err(reason) => {
+                debug!(%image, layer = %layer.digest, reason, "Signature rejected");
+                reasons.push(reason);
+            }
+        }
+    }
+    if reasons.is_empty() {
+        reasons.push("signature manifest holds no signatures".into());
+    }
+    Err(rejected(reasons))
+}
+
+/// Check one signature of the image `digest`, returning who signed it
+fn check(
+    payload: &[u8],
+    annotations: &BTreeMap<String, String>,
+    digest: &str,
+    policy: &Verification,
+    trust: &TrustRoot,
+) -> std::result::Result<String, String> {
+    let signed: SimpleSigning =
+        serde_json::from_slice(payload).map_err(|e| format!("malformed signature payload: {e}"))?;
+    if signed.critical.image.docker_manifest_digest != digest {
+        return Err(format!(
+            "signature is for {}",
+            signed.critical.image.docker_manifest_digest
+        ));
+    }
+    let signature = annotations
+        .get(annotations::SIGNATURE)
+        .ok_or("signature annotation missing")?;
+    let signature = STANDARD
+        .decode(signature)
+        .map_err(|e| format!("signature is not base64: {e}"))?;
+    for (i, key) in policy.public_keys.iter().enumerate() {
+        let key = PublicKey::from_pem(key).map_err(|e| format!("public key {i}: {e}"))?;
+        if key.verify(payload, &signature, Hash::Sha256) {
+            return Ok(format!("public key {i}"));
+        }
+    }
+    match annotations.get(annotations::CERTIFICATE) {
+        Some(certificate) if !policy.keyless.is_empty() => check_keyless(
+            payload,
+            &signature,
+            certificate,
+            annotations,
+            &policy.keyless,
+            trust,
+        ),
+        _ if policy.public_keys.is_empty() => Err("signature has no certificate".into()),
+        _ => Err("signature does not verify against any public key".into()),
+    }
+}
+
+fn check_keyless(
+    payload: &[u8],
+    signature: &[u8],
+    certificate: &str,
+    annotations: &BTreeMap<String, String>,
+    identities: &[KeylessIdentity],
+    trust: &TrustRoot,
+) -> std::result::Result<String, String> {
+    let leaf = Certificate::from_pem(certificate).map_err(|e| format!("malformed certificate: {e}"))?;
+    let intermediates = match annotations.get(annotations::CHAIN) {
+        Some(chain) => Certificate::load_pem_chain(chain.as_bytes())
+            .map_err(|e| format!("malformed certificate chain: {e}"))?,
+        None => vec![],
+    };
+    if !PublicKey::from_certificate(&leaf)?.verify(payload, signature, Hash::Sha256) {
+        return Err("signature does not verify against its certificate".into());
+    }
+
+    // certificates live for minutes, the transparency log entry shows the signature was made in time
+    let bundle = annotations
+        .get(annotations::BUNDLE)
+        .ok_or("signature has no Rekor bundle")?;
+    let bundle: Bundle = serde_json::from_str(bundle).map_err(|e| format!("malformed Rekor bundle: {e}"))?;
+    check_bundle(&bundle, payload, signature, &trust.rekor)?;
+    let validity = &leaf.tbs_certificate.validity;
+    let logged = u64::try_from(bundle.payload.integrated_time).unwrap_or_default();
+    if logged < validity.not_before.to_unix_duration().as_secs()
+        || logged > validity.not_after.to_unix_duration().as_secs()
+    {
+        return Err("signature was logged outside the validity of its certificate".into());
+    }
+    chain_to_root(&leaf, &intermediates, &trust.fulcio, logged)?;
+
+    let subjects = subjects(&leaf);
+    let issuer = fulcio_issuer(&leaf).ok_or("certificate names no OIDC issuer")?;
+    identities
+        .iter()
+        .find(|accepted| accepted.issuer == issuer && subjects.contains(&accepted.identity))
+        .map(|accepted| accepted.identity.clone())
+        .ok_or_else(|| {
+            format!(
+                "signed by {} via {issuer}, which is not accepted",
+                subjects.join(", ")
+            )
+        })
+}
+
+/// Follow issuers from the code signing `leaf` through `intermediates` until one of the trusted
+/// `roots`, using only certificates that were valid at `at` (seconds since the epoch)
+fn chain_to_root(
+    leaf: &Certificate,
+    intermediates: &[Certificate],
+    roots: &[Certificate],
+    at: u64,
+) -> std::result::Result<(), String> {
+    if roots.is_empty() {
+        return Err("no Fulcio root is configured".into());
+    }
+    let for_code_signing = extension(leaf, &ExtendedKeyUsage::OID)
+        .and_then(|value| ExtendedKeyUsage::from_der(value).ok())
+        .is_some_and(|usage| usage.0.contains(&CODE_SIGNING));
+    if !for_code_signing {
+        return Err("certificate is not for code signing".into());
+    }
+    let mut current = leaf;
+    for _ in 0..MAX_CHAIN {
+        if roots
+            .iter()
+            .any(|root| valid_at(root, at) && issued_by(current, root))
+        {
+            return Ok(());
+        }
+        match intermediates
+            .iter()
+            .find(|issuer| *issuer != current && valid_at(issuer, at) && issued_by(current, issuer))
+        {
+            Some(issuer) => current = issuer,
+            None => break,
+        }
+    }
+    Err("certificate does not chain to a configured Fulcio root".into())
+}
+
+/// Whether `issuer` is a CA that signed `certificate`
+fn issued_by(certificate: &Certificate, issuer: &Certificate) -> bool {
+    if certificate.tbs_certificate.issuer != issuer.tbs_certificate.subject || !is_ca(issuer) {
+        return false;
+    }
+    let hash = match &certificate.signature_algorithm.oid {
+        oid if *oid == ECDSA_WITH_SHA256 => Hash::Sha256,
+        oid if *oid == ECDSA_WITH_SHA384 => Hash::Sha384,
+        _ => return false,
+    };
+    let (Ok(key), Ok(tbs)) = (
+        PublicKey::from_certificate(issuer),
+        certificate.tbs_certificate.to_der(),
+    ) else {
+        return false;
+    };
+    key.verify(&tbs, certificate.signature.raw_bytes(), hash)
+}
+
+/// Whether `certificate` is a CA that may sign certificates
+fn is_ca(certificate: &Certificate) -> bool {
+    let ca = extension(certificate, &BasicConstraints::OID)
+        .and_then(|value| BasicConstraints::from_der(value).ok())
+        .is_some_and(|constraints| constraints.ca);
+    let signs_certificates = extension(certificate, &KeyUsage::OID)
+        .and_then(|value| KeyUsage::from_der(value).ok())
+        .is_some_and(|usage| usage.key_cert_sign());
+    ca && signs_certificates
+}
+
+/// Whether `certificate` was valid at `at`, in seconds since the epoch
+fn valid_at(certificate: &Certificate, at: u64) -> bool {
+    let validity = &certificate.tbs_certificate.validity;
+    validity.not_before.to_unix_duration().as_secs() <= at
+        && at <= validity.not_after.to_unix_duration().as_secs()
+}
+
+/// Check the signed entry timestamp of `bundle` and that the entry is for this signature
+fn check_bundle(
+    bundle: &Bundle,
+    payload: &[u8],
+    signature: &[u8],
+    rekor: &[PublicKey],
+) -> std::result::Result<(), String> {
+    if rekor.is_empty() {
+        return Err("no Rekor key is configured".into());
+    }
+    let timestamp = STANDARD
+        .decode(&bundle.signed_entry_timestamp)
+        .map_err(|e| format!("signed entry timestamp is not base64: {e}"))?;
+    let signed = serde_json::to_vec(&bundle.payload).map_err(|e| e.to_string())?;
+    if !rekor
+        .iter()
+        .any(|key| key.verify(&signed, &timestamp, Hash::Sha256))
+    {
+        return Err("Rekor bundle is not signed by a configured Rekor key".into());
+    }
+    let body = STANDARD
+        .decode(&bundle.payload.body)
+        .map_err(|e| format!("Rekor entry is not base64: {e}"))?;
+    let body: serde_json::Value =
+        serde_json::from_slice(&body).map_err(|e| format!("malformed Rekor entry: {e}"))?;
+    let logged_hash = body.pointer("/spec/data/hash/value").and_then(|v| v.as_str());
+    let logged_signature = body
+        .pointer("/spec/signature/content")
+        .and_then(|v| v.as_str())
+        .and_then(|content| STANDARD.decode(content).ok());
+    if logged_hash != Some(&format!("{:x}", Sha256::digest(payload)))
+        || logged_signature.as_deref() != Some(signature)
+    {
+        return Err("Rekor entry is for another signature".into());
+    }
+    Ok(())
+}
+
+/// Email and URI subject alternative names
+fn subjects(certificate: &Certificate) -> Vec<String> {
+    extension(certificate, &SUBJECT_ALT_NAME)
+        .and_then(|value| SubjectAltName::from_der(value).ok())
+        .map(|names| {
+            names
+                .0
+                .into_iter()
+                .filter_map(|name| match name {
+                    GeneralName::Rfc822Name(email) => Some(email.to_string()),
+                    GeneralName::UniformResourceIdentifier(uri) => Some(uri.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn fulcio_issuer(certificate: &Certificate) -> Option<String> {
+    if let Some(value) = extension(certificate, &FULCIO_ISSUER_V2) {
+        return Utf8StringRef::from_der(value).ok().map(|s| s.to_string());
+    }
+    extension(certificate, &FULCIO_ISSUER_V1).and_then(|value| String::from_utf8(value.to_vec()).ok())
+}
+
+fn extension<'a>(certificate: &'a Certificate, oid: &ObjectIdentifier) -> Option<&'a [u8]> {
+    certificate
+        .tbs_certificate
+        .extensions
+        .as_ref()?
+        .iter()
+        .find(|extension| extension.extn_id == *oid)
+        .map(|extension| extension.extn_value.as_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BundlePayload, PublicKey, TrustRoot, VerificationFailed, annotations, check, verify};
+    use crate::core::{
+        crd::{KeylessIdentity, Verification},
+        registry::{RegistryClient, fake::FakeRegistry, limits::HostLimiter},
+        replication::ImageLocation,
+        settings::RateLimits,
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use p256::{
+        ecdsa::{Signature, SigningKey, signature::Signer},
+        pkcs8::{DecodePrivateKey, EncodePublicKey, LineEnding},
+    };
+    use rcgen::{
+        BasicConstraints, CertificateParams, CustomExtension, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+        KeyUsagePurpose, SanType, date_time_ymd,
+    };
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::{collections::BTreeMap, sync::Arc};
+    use x509_cert::{Certificate, der::DecodePem};
+
+    static DIGEST: &str = "sha256:0000000000000000000000000000000000000000000000000000000000000001";
+    static IDENTITY: &str = "release@example.com";
+    static ISSUER: &str = "https://accounts.example.com";
+    /// 2024-01-01 12:00 UTC, while the test certificates are valid
+    const SIGNED_AT: i64 = 1_704_110_400;
+
+    fn payload(digest: &str) -> Vec<u8> {
+        json!({
+            "critical": {
+                "identity": { "docker-reference": "registry.example.com/ci/app" },
+                "image": { "docker-manifest-digest": digest },
+                "type": "cosign container image signature"
+            },
+            "optional": null
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
+
+    fn pem(key: &SigningKey) -> String {
+        key.verifying_key().to_public_key_pem(LineEnding::LF).unwrap()
+    }
+
+    fn sign(key: &SigningKey, message: &[u8]) -> Vec<u8> {
+        let signature: Signature = key.sign(message);
+        signature.to_der().as_bytes().to_vec()
+    }
+
+    fn signed(key: &SigningKey, payload: &[u8]) -> BTreeMap<String, String> {
+        BTreeMap::from([(annotations::SIGNATURE.into(), STANDARD.encode(sign(key, payload)))])
+    }
+
+    fn keys(keys: &[&SigningKey]) -> Verification {
+        Verification {
+            public_keys: keys.iter().map(|key| pem(key)).collect(),
+            keyless: vec![],
+        }
+    }
+
+    fn keyless(identity: &str) -> Verification {
+        Verification {
+            public_keys: vec![],
+            keyless: vec![KeylessIdentity {
+                identity: identity.into(),
+                issuer: ISSUER.into(),
+            }],
+        }
+    }
+
+    #[test]
+    fn signatures_are_checked_against_public_keys() {
+        let (signer, other) = (key(1), key(2));
+        let payload = payload(DIGEST);
+        let trust = TrustRoot::default();
+        let annotations = signed(&signer, &payload);
+
+        assert!(check(&payload, &annotations, DIGEST, &keys(&[&other, &signer]), &trust).is_ok());
+        assert!(check(&payload, &annotations, DIGEST, &keys(&[&other]), &trust).is_err());
+        let elsewhere = DIGEST.replace('1', "2");
+        let err = check(&payload, &annotations, &elsewhere, &keys(&[&signer]), &trust).unwrap_err();
+        assert!(err.contains("signature is for"), "{err}");
+    }
+
+    /// A Fulcio style root and a keyless signature made with a leaf certificate it issued
+    struct Keyless {
+        root: Certificate,
+        rekor: SigningKey,
+        annotations: BTreeMap<String, String>,
+        payload: Vec<u8>,
+    }
+
+    fn keyless_signature(logged_at: i64) -> Keyless {
+        let (root, root_key) = fulcio_root();
+        let leaf_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let leaf = leaf_params().signed_by(&leaf_key, &root, &root_key).unwrap();
+        keyless_signed(&root, &leaf, &leaf_key, None, logged_at)
+    }
+
+    fn fulcio_root() -> (rcgen::Certificate, KeyPair) {
+        let root_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P384_SHA384).unwrap();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+        params.distinguished_name.push(DnType::CommonName, "sigstore");
+        (params.self_signed(&root_key).unwrap(), root_key)
+    }
+
+    /// A code signing certificate for `IDENTITY`, valid on 2024-01-01
+    fn leaf_params() -> CertificateParams {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.subject_alt_names = vec![SanType::Rfc822Name(IDENTITY.try_into().unwrap())];
+        params.custom_extensions = vec![CustomExtension::from_oid_content(
+            &[1, 3, 6, 1, 4, 1, 57264, 1, 1],
+            ISSUER.as_bytes().to_vec(),
+        )];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::CodeSigning];
+        params.not_before = date_time_ymd(2024, 1, 1);
+        params.not_after = date_time_ymd(2024, 1, 2);
+        params
+    }
+
+    /// A signature made with `leaf`, whose issuers up to `root` are in `chain`
+    fn keyless_signed(
+        root: &rcgen::Certificate,
+        leaf: &rcgen::Certificate,
+        leaf_key: &KeyPair,
+        chain: Option<String>,
+        logged_at: i64,
+    ) -> Keyless {
+        let payload = payload(DIGEST);
+        let signer = SigningKey::from_pkcs8_der(&leaf_key.serialize_der()).unwrap();
+        let signature = STANDARD.encode(sign(&signer, &payload));
+        let body = json!({
+            "apiVersion": "0.0.1",
+            "kind": "hashedrekord",
+            "spec": {
+                "data": { "hash": { "algorithm": "sha256", "value": format!("{:x}", Sha256::digest(&payload)) } },
+                "signature": { "content": signature, "publicKey": { "content": STANDARD.encode(leaf.pem()) } }
+            }
+        });
+        let entry = BundlePayload {
+            body: STANDARD.encode(body.to_string()),
+            integrated_time: logged_at,
+            log_id: "c0d23d6ad406973f9559f3ba2d1ca01f84147d8ffc5b8445c224f98b9591801d".into(),
+            log_index: 42,
+        };
+        let rekor = key(9);
+        let bundle = json!({
+            "SignedEntryTimestamp": STANDARD.encode(sign(&rekor, &serde_json::to_vec(&entry).unwrap())),
+            "Payload": entry,
+        });
+        Keyless {
+            root: Certificate::from_pem(root.pem()).unwrap(),
+            rekor,
+            annotations: BTreeMap::from([
+                (annotations::SIGNATURE.into(), signature),
+                (annotations::CERTIFICATE.into(), leaf.pem()),
+                (annotations::BUNDLE.into(), bundle.to_string()),
+            ])
+            .into_iter()
+            .chain(chain.map(|chain| (annotations::CHAIN.into(), chain)))
+            .collect(),
+            payload,
+        }
+    }
+
+    impl Keyless {
+        fn trust(&self) -> TrustRoot {
+            let rekor = PublicKey::from_pem(&pem(&self.rekor)).unwrap();
+            TrustRoot::new(vec![self.root.clone()], vec![rekor])
+        }
+
+        fn check(&self, policy: &Verification, trust: &TrustRoot) -> Result<String, String> {
+            check(&self.payload, &self.annotations, DIGEST, policy, trust)
+        }
+    }
+
+    #[test]
+    fn keyless_signatures_chain_to_the_fulcio_root() {
+        let signature = keyless_signature(SIGNED_AT);
+        let trust = signature.trust();
+        assert_eq!(signature.check(&keyless(IDENTITY), &trust).unwrap(), IDENTITY);
+
+        let err = signature
+            .check(&keyless("someone@example.com"), &trust)
+            .unwrap_err();
+        assert!(err.contains("not accepted"), "{err}");
+
+        let other_root = keyless_signature(SIGNED_AT).root;
+        let rekor = PublicKey::from_pem(&pem(&signature.rekor)).unwrap();
+        let err = signature
+            .check(&keyless(IDENTITY), &TrustRoot::new(vec![other_root], vec![rekor]))
+            .unwrap_err();
+        assert!(err.contains("does not chain"), "{err}");
+
+        let err = signature
+            .check(
+                &keyless(IDENTITY),
+                &TrustRoot::new(vec![signature.root.clone()], vec![]),
+            )
+            .unwrap_err();
+        assert!(err.contains("no Rekor key"), "{err}");
+    }
+
+    #[test]
+    fn only_ca_certificates_issue_signing_certificates() {
+        let (root, root_key) = fulcio_root();
+        let stolen_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let stolen = leaf_params().signed_by(&stolen_key, &root, &root_key).unwrap();
+        let forged_key = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let forged = leaf_params()
+            .signed_by(&forged_key, &stolen, &stolen_key)
+            .unwrap();
+        let signature = keyless_signed(&root, &forged, &forged_key, Some(stolen.pem()), SIGNED_AT);
+        let err = signature
+            .check(&keyless(IDENTITY), &signature.trust())
+            .unwrap_err();
+        assert!(err.contains("does not chain"), "{err}");
+
+        let mut params = leaf_params();
+        params.extended_key_usages = vec![];
+        let leaf = params.signed_by(&stolen_key, &root, &root_key).unwrap();
+        let signature = keyless_signed(&root, &leaf, &stolen_key, None, SIGNED_AT);
+        let err = signature
+            .check(&keyless(IDENTITY), &signature.trust())
+            .unwrap_err();
+        assert!(err.contains("not for code signing"), "{err}");
+    }
+
+    #[test]
+    fn keyless_signatures_must_be_logged_while_the_certificate_was_valid() {
+        let signature = keyless_signature(SIGNED_AT + 7 * 24 * 60 * 60);
+        let err = signature
+            .check(&keyless(IDENTITY), &signature.trust())
+            .unwrap_err();
+        assert!(err.contains("outside the validity"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn images_need_a_signature_in_the_registry() {
+        let registry = FakeRegistry::start().await;
+        let client = RegistryClient::new(
+            reqwest::Client::new(),
+            registry.endpoint(),
+            Arc::new(HostLimiter::new(&RateLimits::default())),
+        );
+        let signer = key(1);
+        let digest = registry.push_image("ci/app", "1", &[b"one"]);
+        let image = ImageLocation {
+            endpoint: registry.endpoint(),
+            repository: "ci/app".into(),
+            reference: "1".into(),
+        };
+
+        let err = verify(&client, &image, &keys(&[&signer]), &TrustRoot::default())
+            .await
+            .unwrap_err();
+        let rejected = VerificationFailed::find(&err).expect("a rejection");
+        assert_eq!(rejected.reasons, ["image is not signed"]);
+
+        let payload = payload(&digest);
+        let annotations = json!(signed(&signer, &payload));
+        registry.push_signatures("ci/app", &digest, &[(&payload, annotations)]);
+        let verified = verify(&client, &image, &keys(&[&signer]), &TrustRoot::default())
+            .await
+            .unwrap();
+        assert_eq!(verified, digest);
+    }
+}
@@ -0,0 +1,423 @@
+//! The vulnerability gate: scan reports attached to an image decide whether it may be promoted.
+//!
+//! Reports are OCI referrers of the image in the source registry, holding Trivy JSON
+//! (`trivy image --format json`) or SARIF. No scanner runs at promotion time, so an image without a
+//! report is blocked rather than waved through.
+use crate::core::{
+    Result,
+    crd::{BlockingFinding, Severity, VulnerabilityGate},
+    registry::{RegistryClient, manifest::Manifest},
+    replication::ImageLocation,
+};
+use serde::Deserialize;
+use std::fmt;
+use tracing::{debug, info};
+
+/// An image the gate refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GateFailed {
+    pub image: String,
+    /// Findings that blocked it, empty if no scan report was attached at all
+    pub findings: Vec<BlockingFinding>,
+}
+
+impl fmt::Display for GateFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.findings.is_empty() {
+            return write!(f, "no vulnerability scan report is attached to {}", self.image);
+        }
+        let ids: Vec<_> = self.findings.iter().map(|finding| finding.id.as_str()).collect();
+        write!(
+            f,
+            "{} has {} blocking findings: {}",
+            self.image,
+            ids.len(),
+            ids.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for GateFailed {}
+
+impl GateFailed {
+    /// The refusal behind `error`, if that is why it failed
+    #[must_use]
+    pub fn find(error: &loco_rs::Error) -> Option<&Self> {
+        crate::core::ErrorWrapper::find(error)
+    }
+}
+
+/// One vulnerability from a scan report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub id: String,
+    pub severity: Severity,
+    pub package: String,
+}
+
+/// Whether a referrer of this artifact type is a scan report
+#[must_use]
+pub fn is_report(artifact_type: &str) -> bool {
+    artifact_type.contains("sarif") || artifact_type.contains("trivy")
+}
+
+/// Check the scan reports attached to the image `digest` against `gate`
+///
+/// # Errors
+///
+/// [`GateFailed`] if the image may not be promoted; other errors are registry failures or
+/// unreadable reports.
+pub async fn check(
+    client: &RegistryClient,
+    image: &ImageLocation,
+    digest: &str,
+    gate: &VulnerabilityGate,
+) -> Result<()> {
+    let repository = &image.repository;
+    let referrers = match client.referrers(repository, digest).await? {
+        Some(referrers) => referrers,
+        None => client.tagged_referrers(repository, digest).await?,
+    };
+    let mut reports = 0;
+    let mut findings = vec![];
+    for referrer in referrers
+        .iter()
+        .filter(|referrer| referrer.artifact_type.as_deref().is_some_and(is_report))
+    {
+        let manifest = Manifest::parse(&client.manifest(repository, &referrer.digest).await?.bytes)?;
+        for layer in &manifest.layers {
+            let report = client.blob_bytes(repository, &layer.digest).await?;
+            findings.extend(parse_report(&report)?);
+            reports += 1;
+        }
+    }
+    let image = image.to_string();
+    if reports == 0 {
+        return Err(loco_rs::Error::wrap(GateFailed {
+            image,
+            findings: vec![],
+        }));
+    }
+    let blocking = blocking(&image, &findings, gate);
+    debug!(%image, reports, findings = findings.len(), blocking = blocking.len(), "Read scan reports");
+    if !blocking.is_empty() {
+        return Err(loco_rs::Error::wrap(GateFailed {
+            image,
+            findings: blocking,
+        }));
+    }
+    info!(%image, "Vulnerability gate passed");
+    Ok(())
+}
+
+/// Findings at or above the gate's severity that are not allowlisted
+#[must_use]
+pub fn blocking(image: &str, findings: &[Finding], gate: &VulnerabilityGate) -> Vec<BlockingFinding> {
+    let mut blocking: Vec<_> = findings
+        .iter()
+        .filter(|finding| finding.severity >= gate.severity && !gate.allowlist.contains(&finding.id))
+        .map(|finding| BlockingFinding {
+            image: image.to_string(),
+            id: finding.id.clone(),
+            severity: finding.severity,
+            package: finding.package.clone(),
+        })
+        .collect();
+    blocking.sort();
+    blocking.dedup();
+    blocking
+}
+
+/// Findings of a Trivy JSON or SARIF report
+///
+/// # Errors
+///
+/// When `bytes` are neither a Trivy JSON nor a SARIF report
+pub fn parse_report(bytes: &[u8]) -> Result<Vec<Finding>> {
+    let report: Report = serde_json::from_slice(bytes).map_err(crate::core::ErrorWrapper::from_serde)?;
+    Ok(match report {
+        Report::Trivy { results, .. } => results
+            .into_iter()
+            .flat_map(|result| result.vulnerabilities)
+            .map(|vulnerability| Finding {
+                id: vulnerability.id,
+                severity: severity(&vulnerability.severity),
+                package: vulnerability.package,
+            })
+            .collect(),
+        Report::Sarif { runs } => runs.iter().flat_map(Run::findings).collect(),
+    })
+}
+
+fn severity(name: &str) -> Severity {
+    match name.to_ascii_uppercase().as_str() {
+        "CRITICAL" => Severity::Critical,
+        "HIGH" => Severity::High,
+        "MEDIUM" => Severity::Medium,
+        "LOW" => Severity::Low,
+        _ => Severity::Unknown,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Report {
+    Sarif {
+        runs: Vec<Run>,
+    },
+    Trivy {
+        #[serde(rename = "SchemaVersion")]
+        #[allow(dead_code)]
+        schema_version: u32,
+        #[serde(rename = "Results", default)]
+        results: Vec<TrivyResult>,
+    },
+}
+
+#[derive(Deserialize)]
+struct TrivyResult {
+    #[serde(rename = "Vulnerabilities", default)]
+    vulnerabilities: Vec<TrivyVulnerability>,
+}
+
+#[derive(Deserialize)]
+struct TrivyVulnerability {
+    #[serde(rename = "VulnerabilityID")]
+    id: String,
+    #[serde(rename = "PkgName", default)]
+    package: String,
+    #[serde(rename = "Severity", default)]
+    severity: String,
+}
+
+#[derive(Deserialize)]
+struct Run {
+    #[serde(default)]
+    tool: Tool,
+    #[serde(default)]
+    results: Vec<SarifResult>,
+}
+
+#[derive(Deserialize, Default)]
+struct Tool {
+    #[serde(default)]
+    driver: Driver,
+}
+
+#[derive(Deserialize, Default)]
+struct Driver {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    id: String,
+    #[serde(default)]
+    properties: Properties,
+}
+
+#[derive(Deserialize, Default)]
+struct Properties {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(rename = "security-severity", default)]
+    security_severity: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SarifResult {
+    #[serde(rename = "ruleId")]
+    rule_id: String,
+    #[serde(default)]
+    level: Option<String>,
+    #[serde(default)]
+    message: Message,
+}
+
+#[derive(Deserialize, Default)]
+struct Message {
+    #[serde(default)]
+    text: String,
+}
+
+impl Run {
+    fn findings(&self) -> impl Iterator<Item = Finding> + '_ {
+        self.results.iter().map(|result| {
+            let rule = self
+                .tool
+                .driver
+                .rules
+                .iter()
+                .find(|rule| rule.id == result.rule_id);
+            Finding {
+                id: result.rule_id.clone(),
+                severity: rule
+                    .and_then(Rule::severity)
+                    .unwrap_or_else(|| level_severity(result.level.as_deref())),
+                // Trivy puts the package into the message, other scanners leave it out
+                package: result
+                    .message
+                    .text
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Package: "))
+                    .unwrap_or_default()
+                    .to_string(),
+            }
+        })
+    }
+}
+
+impl Rule {
+    /// The severity tag, or the CVSS score in `security-severity`
+    fn severity(&self) -> Option<Severity> {
+        let tagged = self
+            .properties
+            .tags
+            .iter()
+            .map(|tag| severity(tag))
+            .find(|severity| *severity != Severity::Unknown);
+        tagged.or_else(|| {
+            let score: f64 = self.properties.security_severity.as_deref()?.parse().ok()?;
+            Some(match score {
+                s if s >= 9.0 => Severity::Critical,
+                s if s >= 7.0 => Severity::High,
+                s if s >= 4.0 => Severity::Medium,
+                s if s > 0.0 => Severity::Low,
+                _ => Severity::Unknown,
+            })
+        })
+    }
+}
+
+fn level_severity(level: Option<&str>) -> Severity {
+    match level {
+        Some("error") => Severity::High,
+        Some("warning") => Severity::Medium,
+        Some("note") => Severity::Low,
+        _ => Severity::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Finding, GateFailed, blocking, check, parse_report};
+    use crate::core::{
+        crd::{Severity, VulnerabilityGate},
+        registry::{RegistryClient, fake::FakeRegistry, limits::HostLimiter},
+        replication::ImageLocation,
+        settings::RateLimits,
+    };
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn trivy() -> Vec<u8> {
+        json!({
+            "SchemaVersion": 2,
+            "ArtifactName": "registry.example.com/ci/app:1",
+            "Results": [
+                { "Target": "debian 12", "Vulnerabilities": [
+                    { "VulnerabilityID": "CVE-2024-0001", "PkgName": "openssl", "Severity": "CRITICAL" },
+                    { "VulnerabilityID": "CVE-2024-0002", "PkgName": "zlib", "Severity": "LOW" }
+                ]},
+                { "Target": "app" }
+            ]
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn finding(id: &str, severity: Severity) -> Finding {
+        Finding {
+            id: id.into(),
+            severity,
+            package: String::new(),
+        }
+    }
+
+    #[test]
+    fn reads_trivy_reports() {
+        let findings = parse_report(&trivy()).unwrap();
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].id, "CVE-2024-0001");
+        assert_eq!(findings[0].severity, Severity::Critical);
+        assert_eq!(findings[0].package, "openssl");
+    }
+
+    #[test]
+    fn reads_sarif_reports() {
+        let sarif = json!({
+            "version": "2.1.0",
+            "runs": [{
+                "tool": { "driver": { "name": "Trivy", "rules": [
+                    { "id": "CVE-2024-0003", "properties": { "tags": ["vulnerability", "HIGH"] } },
+                    { "id": "CVE-2024-0004", "properties": { "security-severity": "9.8" } }
+                ]}},
+                "results": [
+                    { "ruleId": "CVE-2024-0003", "level": "error",
+                      "message": { "text": "Package: libxml2\nInstalled Version: 2.9.14" } },
+                    { "ruleId": "CVE-2024-0004", "level": "error", "message": { "text": "" } },
+                    { "ruleId": "GHSA-xxxx", "level": "note", "message": { "text": "" } }
+                ]
+            }]
+        });
+        let findings = parse_report(sarif.to_string().as_bytes()).unwrap();
+        let severities: Vec<_> = findings.iter().map(|f| f.severity).collect();
+        assert_eq!(severities, [Severity::High, Severity::Critical, Severity::Low]);
+        assert_eq!(findings[0].package, "libxml2");
+    }
+
+    #[test]
+    fn allowlisted_and_minor_findings_do_not_block() {
+        let findings = [
+            finding("CVE-1", Severity::Critical),
+            finding("CVE-2", Severity::High),
+            finding("CVE-3", Severity::Medium),
+        ];
+        let gate = VulnerabilityGate {
+            severity: Severity::High,
+            allowlist: vec!["CVE-1".into()],
+        };
+        let blocked = blocking("ci/app:1", &findings, &gate);
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].id, "CVE-2");
+    }
+
+    #[tokio::test]
+    async fn images_need_a_passing_report() {
+        let registry = FakeRegistry::start().await;
+        let client = RegistryClient::new(
+            reqwest::Client::new(),
+            registry.endpoint(),
+            Arc::new(HostLimiter::new(&RateLimits::default())),
+        );
+        let digest = registry.push_image("ci/app", "1", &[b"one"]);
+        let image = ImageLocation {
+            endpoint: registry.endpoint(),
+            repository: "ci/app".into(),
+            reference: "1".into(),
+        };
+        let gate = VulnerabilityGate::default();
+
+        let err = check(&client, &image, &digest, &gate).await.unwrap_err();
+        assert!(GateFailed::find(&err).unwrap().findings.is_empty(), "{err}");
+
+        registry.push_artifact(
+            "ci/app",
+            None,
+            &digest,
+            "application/vnd.aquasecurity.trivy.report+json",
+            &trivy(),
+        );
+        let err = check(&client, &image, &digest, &gate).await.unwrap_err();
+        let failed = GateFailed::find(&err).unwrap();
+        assert_eq!(failed.findings.len(), 1);
+        assert_eq!(failed.findings[0].id, "CVE-2024-0001");
+
+        let allowed = VulnerabilityGate {
+            allowlist: vec!["CVE-2024-0001".into()],
+            ..gate
+        };
+        check(&client, &image, &digest, &allowed).await.unwrap();
+    }
+}
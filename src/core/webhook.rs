@@ -0,0 +1,148 @@
+//! TLS listener for the CRD conversion webhook, which the apiserver only calls over https.
+//!
+//! The certificate is read again for every connection, so one renewed on disk (e.g. by
+//! cert-manager into the mounted secret) is picked up without restarting the controller.
+use crate::core::{ErrorWrapper, Result};
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use std::{future::Future, path::Path, sync::Arc};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{ServerConfig, crypto::ring},
+};
+use tracing::warn;
+
+/// Serve `router` over TLS on `listener` until `shutdown` completes
+///
+/// # Errors
+///
+/// When the certificate or key can not be loaded at startup, or the listener fails
+pub async fn serve(
+    listener: TcpListener,
+    cert: &Path,
+    key: &Path,
+    router: Router,
+    shutdown: impl Future<Output = ()> + Send,
+) -> Result<()> {
+    // a broken certificate should stop the controller, not fail every review
+    acceptor(cert, key)?;
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = &mut shutdown => return Ok(()),
+        };
+        let (cert, key, router) = (cert.to_path_buf(), key.to_path_buf(), router.clone());
+        tokio::spawn(async move {
+            let served = async {
+                let stream = acceptor(&cert, &key)?.accept(stream).await?;
+                auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), TowerToHyperService::new(router))
+                    .await
+                    .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))
+            };
+            if let Err(e) = served.await {
+                warn!(%peer, "conversion webhook connection failed: {e}");
+            }
+        });
+    }
+}
+
+/// TLS settings from the PEM certificate chain and private key
+fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut read(cert)?.as_slice())
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", cert.display())))?;
+    let key = rustls_pemfile::private_key(&mut read(key)?.as_slice())
+        .map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", key.display())))?
+        .ok_or_else(|| ErrorWrapper::from_custom(&format!("{}: no private key", key.display())))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| ErrorWrapper::from_custom(&e.to_string()))?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| ErrorWrapper::from_custom(&format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::serve;
+    use crate::controllers::conversion;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn reviews_are_answered_over_tls() {
+        let signed = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("tls.crt"), dir.path().join("tls.key"));
+        std::fs::write(&cert, signed.cert.pem()).unwrap();
+        std::fs::write(&key, signed.key_pair.serialize_pem()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve(listener, &cert, &key, conversion::webhook(), async {
+                stopped.await.ok();
+            })
+            .await
+        });
+
+        let client = reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(signed.cert.pem().as_bytes()).unwrap())
+            .build()
+            .unwrap();
+        let review = serde_json::json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "ConversionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "desiredAPIVersion": "replicator.yair.example.com/v1beta1",
+                "objects": []
+            }
+        });
+        let response: serde_json::Value = client
+            .post(format!("https://localhost:{port}/api/conversion"))
+            .json(&review)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            response["response"]["uid"],
+            "705ab4f5-6393-11e8-b7cc-42010a800002"
+        );
+        assert_eq!(response["response"]["result"]["status"], "Success");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn missing_certificates_fail_at_startup() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let missing = dir.path().join("tls.crt");
+        assert!(
+            serve(
+                listener,
+                &missing,
+                &missing,
+                conversion::webhook(),
+                std::future::pending()
+            )
+            .await
+            .is_err()
+        );
+    }
+}
@@ -4,3 +4,4 @@ pub mod core;
 pub mod mailers;
 pub mod tasks;
 pub mod views;
+pub mod workers;
@@ -0,0 +1,25 @@
+use crate::core::ErrorWrapper;
+use loco_rs::prelude::*;
+
+/// Print the ledger as JSON, or write it to `file:<path>`
+pub struct ExportLedger;
+#[async_trait]
+impl Task for ExportLedger {
+    fn task(&self) -> TaskInfo {
+        TaskInfo {
+            name: "export-ledger".to_string(),
+            detail: "Print the ledger as JSON, or write it to file:<path>".to_string(),
+        }
+    }
+
+    async fn run(&self, app_context: &AppContext, vars: &task::Vars) -> Result<()> {
+        let json = super::ledger(app_context)?.export()?;
+        match vars.cli.get("file") {
+            Some(path) => {
+                std::fs::write(path, json).map_err(|e| ErrorWrapper::from_custom(&format!("{path}: {e}")))?;
+            }
+            None => println!("{json}"),
+        }
+        Ok(())
+    }
+}
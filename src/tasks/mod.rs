@@ -1 +1,48 @@
+//! Operational commands, run with `cargo loco task <name> [key:value...]` or the controller
+//! binary's `task` subcommand.
+//!
+//! They talk to the cluster of the current kube config and use the `yair` settings of the selected
+//! environment.
+//!
+//! There is no `gc` task yet: it is to run a `ContainerCleanup` once, and that resource has no spec
+//! so far (`yaml/crd_concepts/containerCleanup.yaml` is empty).
+pub mod export_ledger;
+pub mod replicate;
+pub mod verify;
 
+use crate::core::{
+    ErrorWrapper,
+    crd::ContainerReplicator,
+    kubecontroller::{Context, State},
+    ledger::Ledger,
+    settings::Settings,
+};
+use kube::{Api, Client};
+use loco_rs::{Result, app::AppContext, task::Vars};
+use std::sync::Arc;
+
+/// A controller context for the app's settings, without any controller running
+async fn context(app_context: &AppContext) -> Result<Arc<Context>> {
+    let client = Client::try_default().await.map_err(ErrorWrapper::from_kube)?;
+    let state = State::new(Settings::from_config(&app_context.config)?);
+    Ok(state.to_context(client).await)
+}
+
+/// The replicator named by `replicator:[<namespace>/]<name>`
+async fn replicator(ctx: &Context, vars: &Vars) -> Result<ContainerReplicator> {
+    let name = vars.cli_arg("replicator")?;
+    let (api, name) = match name.split_once('/') {
+        Some((namespace, name)) => (Api::namespaced(ctx.client.clone(), namespace), name),
+        None => (Api::default_namespaced(ctx.client.clone()), name.as_str()),
+    };
+    api.get(name).await.map_err(ErrorWrapper::from_kube)
+}
+
+/// The ledger in `replication.ledger_dir`
+fn ledger(app_context: &AppContext) -> Result<Ledger> {
+    let settings = Settings::from_config(&app_context.config)?;
+    let dir = settings.replication.ledger_dir.ok_or_else(|| {
+        ErrorWrapper::from_custom("replication.ledger_dir is not set, the ledger only lives in memory")
+    })?;
+    Ledger::open(&dir)
+}
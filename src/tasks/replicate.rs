@@ -0,0 +1,28 @@
+use crate::core::{registry::Reference, replicatorcontroller};
+use loco_rs::prelude::*;
+
+/// Copy one image to the destinations of a replicator now, e.g. to promote a hotfix before the
+/// workload rolls out
+pub struct Replicate;
+#[async_trait]
+impl Task for Replicate {
+    fn task(&self) -> TaskInfo {
+        TaskInfo {
+            name: "replicate".to_string(),
+            detail: "Copy one image now: replicator:<namespace>/<name> image:<reference>".to_string(),
+        }
+    }
+
+    async fn run(&self, app_context: &AppContext, vars: &task::Vars) -> Result<()> {
+        let ctx = super::context(app_context).await?;
+        let replicator = super::replicator(&ctx, vars).await?;
+        let image: Reference = vars.cli_arg("image")?.parse()?;
+        for (job, report) in replicatorcontroller::replicate_image(&ctx, &replicator, &image).await? {
+            println!(
+                "{job} {} ({} blobs, {} bytes copied)",
+                report.digest, report.blobs_copied, report.bytes_copied
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use crate::core::{ErrorWrapper, replicatorcontroller};
+use loco_rs::prelude::*;
+
+/// Compare source and destination digests of every image a replicator promotes, failing if any
+/// differ
+pub struct Verify;
+#[async_trait]
+impl Task for Verify {
+    fn task(&self) -> TaskInfo {
+        TaskInfo {
+            name: "verify".to_string(),
+            detail: "Compare source and destination digests: replicator:<namespace>/<name>".to_string(),
+        }
+    }
+
+    async fn run(&self, app_context: &AppContext, vars: &task::Vars) -> Result<()> {
+        let ctx = super::context(app_context).await?;
+        let replicator = super::replicator(&ctx, vars).await?;
+        let comparisons = replicatorcontroller::verify(&ctx, &replicator).await?;
+        let mut differing = 0;
+        for (job, comparison) in &comparisons {
+            let state = match &comparison.destination {
+                _ if comparison.in_sync() => "in sync".to_string(),
+                Some(digest) => format!("differs: {digest}"),
+                None => "missing".to_string(),
+            };
+            differing += usize::from(!comparison.in_sync());
+            println!("{job} {} {state}", comparison.source);
+        }
+        if differing > 0 {
+            return Err(ErrorWrapper::from_custom(&format!(
+                "{differing} of {} images are not in sync",
+                comparisons.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! Replication handed off by the `ContainerReplicator` reconciler, so long copies neither hold up
+//! the controller loop nor count against a reconcile.
+use crate::core::{ErrorWrapper, kubecontroller::Context, replicatorcontroller};
+use loco_rs::{config::WorkerMode, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+pub struct ReplicationWorker {
+    pub ctx: AppContext,
+    /// Context of the controller the jobs replicate for; workers loco builds on its own have none
+    controller: Option<Arc<Context>>,
+}
+
+/// The replicator to replicate; its spec is read again when the job runs
+#[derive(Deserialize, Debug, Serialize)]
+pub struct ReplicationWorkerArgs {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl ReplicationWorker {
+    /// A worker replicating with the context of the running controller
+    #[must_use]
+    pub fn new(app: &AppContext, controller: Arc<Context>) -> Self {
+        Self {
+            ctx: app.clone(),
+            controller: Some(controller),
+        }
+    }
+
+    /// Run the job the way the app's worker mode says, with this worker's controller context
+    ///
+    /// # Errors
+    ///
+    /// When the job can not be queued, or fails while run in the foreground
+    pub async fn queue(self, args: ReplicationWorkerArgs) -> Result<()> {
+        match self.ctx.config.workers.mode {
+            // the queue's own workers pick it up, built from their app context
+            WorkerMode::BackgroundQueue => Self::perform_later(&self.ctx, args).await,
+            WorkerMode::ForegroundBlocking => self.perform(args).await,
+            WorkerMode::BackgroundAsync => {
+                tokio::spawn(async move {
+                    if let Err(err) = self.perform(args).await {
+                        error!(err = err.to_string(), "worker failed to perform job");
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundWorker<ReplicationWorkerArgs> for ReplicationWorker {
+    fn build(ctx: &AppContext) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            controller: None,
+        }
+    }
+
+    async fn perform(&self, args: ReplicationWorkerArgs) -> Result<()> {
+        let ctx = self.controller.as_ref().ok_or_else(|| {
+            ErrorWrapper::from_custom("the replication worker runs without a ContainerReplicator controller")
+        })?;
+        replicatorcontroller::replicate_queued(ctx, &args.namespace, &args.name).await
+    }
+}
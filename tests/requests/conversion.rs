@@ -0,0 +1,41 @@
+use loco_rs::testing;
+use serde_json::json;
+use serial_test::serial;
+use yair::app::App;
+
+#[tokio::test]
+#[serial]
+async fn can_convert_alpha_replicator_to_beta() {
+    testing::request::<App, _, _>(|request, _ctx| async move {
+        let review = json!({
+            "apiVersion": "apiextensions.k8s.io/v1",
+            "kind": "ConversionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "desiredAPIVersion": "replicator.yair.example.com/v1beta1",
+                "objects": [{
+                    "apiVersion": "replicator.yair.example.com/v1alpha1",
+                    "kind": "DestinationRepository",
+                    "metadata": { "name": "prod-eu", "namespace": "my-team" },
+                    "spec": { "repository": {
+                        "name": "sam-docker-registry",
+                        "location": "europe-west1",
+                        "format": "Docker",
+                        "projectID": "example-app-prod-x3",
+                        "Provider": "GCP"
+                    }}
+                }]
+            }
+        });
+        let res = request.post("/api/conversion").json(&review).await;
+        assert_eq!(res.status_code(), 200);
+
+        let body: serde_json::Value = res.json();
+        assert_eq!(body["response"]["uid"], "705ab4f5-6393-11e8-b7cc-42010a800002");
+        assert_eq!(body["response"]["result"]["status"], "Success");
+        let converted = &body["response"]["convertedObjects"][0];
+        assert_eq!(converted["apiVersion"], "replicator.yair.example.com/v1beta1");
+        assert_eq!(converted["spec"]["repository"]["provider"], "GCP");
+    })
+    .await;
+}
@@ -1,3 +1,41 @@
+pub mod conversion;
 pub mod health;
 mod home;
 pub mod metrics;
+pub mod notifications;
+pub mod ready;
+pub mod replications;
+
+use axum_test::{TestServer, TestServerConfig};
+use loco_rs::{app::AppContext, testing};
+use yair::{
+    app::{App, with_state},
+    core::kubecontroller::State,
+};
+
+/// Like `loco_rs::testing::request`, with the controller state the binary serves the routes with
+#[allow(clippy::future_not_send)]
+pub async fn request<F, Fut>(callback: F)
+where
+    F: FnOnce(TestServer, AppContext) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    request_with(&State::default(), callback).await;
+}
+
+/// Like [`request`], serving the given controller `state`
+#[allow(clippy::future_not_send)]
+pub async fn request_with<F, Fut>(state: &State, callback: F)
+where
+    F: FnOnce(TestServer, AppContext) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    let boot = testing::boot_test::<App>().await.unwrap();
+    let config = TestServerConfig {
+        default_content_type: Some("application/json".to_string()),
+        ..Default::default()
+    };
+    let router = with_state(boot.router.unwrap(), state);
+    let server = TestServer::new_with_config(router, config).unwrap();
+    callback(server, boot.app_context).await;
+}
@@ -0,0 +1,36 @@
+use serde_json::json;
+use serial_test::serial;
+
+#[tokio::test]
+#[serial]
+async fn accepts_distribution_envelopes() {
+    super::request(|request, _ctx| async move {
+        let envelope = json!({ "events": [{
+            "action": "push",
+            "target": {
+                "repository": "ci/app", "digest": "sha256:aaa", "tag": "1.0.0",
+                "url": "https://ci.example.com/v2/ci/app/manifests/sha256:aaa",
+            },
+            "request": { "host": "ci.example.com" },
+        }]});
+        let res = request
+            .post("/api/notifications/distribution")
+            .content_type("application/vnd.docker.distribution.events.v1+json")
+            .bytes(envelope.to_string().into())
+            .await;
+        assert_eq!(res.status_code(), 200);
+        let body: serde_json::Value = res.json();
+        assert_eq!(body["images"], 1);
+    })
+    .await;
+}
+
+#[tokio::test]
+#[serial]
+async fn rejects_malformed_notifications() {
+    super::request(|request, _ctx| async move {
+        let res = request.post("/api/notifications/harbor").text("not json").await;
+        assert_eq!(res.status_code(), 400);
+    })
+    .await;
+}
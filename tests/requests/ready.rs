@@ -0,0 +1,13 @@
+use serial_test::serial;
+
+#[tokio::test]
+#[serial]
+async fn is_ready_while_running() {
+    super::request(|request, _ctx| async move {
+        let res = request.get("/api/ready").await;
+        assert_eq!(res.status_code(), 200);
+        let body: serde_json::Value = res.json();
+        assert_eq!(body["ready"], true);
+    })
+    .await;
+}
@@ -0,0 +1,51 @@
+use serial_test::serial;
+use std::sync::Arc;
+use yair::core::{
+    crd::{ContainerReplicator, DestinationRepository, SourceRepository},
+    fixtures::{ReplicationObjects, Scenario, deployment, timeout_after_1s},
+    kubecontroller::State,
+    registry::fake::FakeRegistry,
+    replicatorcontroller,
+};
+
+#[tokio::test]
+#[serial]
+async fn serves_dry_run_plans() {
+    let (source, destination) = (FakeRegistry::start().await, FakeRegistry::start().await);
+    let digest = source.push_image("ci/app", "1.0.0", &[b"layer"]);
+    let image = format!("{}/ci/app:1.0.0", source.host);
+    let mut replicator = ContainerReplicator::test();
+    replicator.spec.dry_run = true;
+    let state = State::default();
+    let (ctx, fakeserver) = state.test_context().await;
+    let objects = ReplicationObjects {
+        replicator: replicator.clone(),
+        source: SourceRepository::test(&source.host),
+        destination: DestinationRepository::test(&destination.host),
+        deployment: deployment(&[&image]),
+    };
+    let mocksrv = fakeserver.run(Scenario::Replication(Box::new(objects), vec![], "False"));
+    replicatorcontroller::reconcile(Arc::new(replicator), ctx)
+        .await
+        .expect("reconciler");
+    timeout_after_1s(mocksrv).await;
+
+    super::request_with(&state, |request, _ctx| async move {
+        let res = request.get("/api/replications/plan").await;
+        assert_eq!(res.status_code(), 200);
+        let body: serde_json::Value = res.json();
+        let plans = body["default/app"]
+            .as_array()
+            .expect("plans of the dry-run replicator");
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0]["source"], image);
+        assert_eq!(
+            plans[0]["destination"],
+            format!("{}/prod/app:1.0.0", destination.host)
+        );
+        assert_eq!(plans[0]["digest"], digest);
+        assert_eq!(plans[0]["manifests"]["copy"].as_array().map(Vec::len), Some(1));
+        assert_eq!(plans[0]["blobs"]["copy"].as_array().map(Vec::len), Some(2));
+    })
+    .await;
+}
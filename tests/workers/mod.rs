@@ -1 +1,2 @@
 // workers mod
+pub mod replication;
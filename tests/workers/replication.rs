@@ -0,0 +1,24 @@
+use loco_rs::{bgworker::BackgroundWorker, testing};
+use serial_test::serial;
+use yair::{
+    app::App,
+    workers::replication::{ReplicationWorker, ReplicationWorkerArgs},
+};
+
+#[tokio::test]
+#[serial]
+async fn workers_built_without_a_controller_refuse_jobs() {
+    let boot = testing::boot_test::<App>().await.unwrap();
+    let args = ReplicationWorkerArgs {
+        namespace: "default".to_string(),
+        name: "app".to_string(),
+    };
+    let err = ReplicationWorker::build(&boot.app_context)
+        .perform(args)
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("without a ContainerReplicator controller")
+    );
+}